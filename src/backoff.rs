@@ -0,0 +1,138 @@
+//! Retry delays used when (re)connecting to a server
+//!
+//! A [`BackoffPolicy`] is used by [`Client`](crate::Client) to re-establish a failed
+//! connection and by [`Pool`](crate::pool::Pool) to open new connections, e.g. while memcached
+//! is restarting. Only connection failures and timeouts are retried.
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::error::MemcacheError;
+
+use log::debug;
+
+/// Exponential backoff with jitter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackoffPolicy {
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+    /// Upper bound of the delay between two attempts
+    pub max_delay: Duration,
+    /// Number of retries after the first attempt failed, 0 disables retrying
+    pub max_retries: u32,
+    /// Randomize each delay between half and all of its value, so that clients do not all
+    /// reconnect at the same time
+    pub jitter: bool,
+}
+
+impl Default for BackoffPolicy {
+    /// Single attempt, no retries
+    fn default() -> Self {
+        BackoffPolicy {
+            initial_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(2),
+            max_retries: 0,
+            jitter: true,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// Create the default policy, which does not retry
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the delay before the first retry, doubled for every following one
+    pub fn initial_delay(mut self, x: Duration) -> Self {
+        self.initial_delay = x;
+        self
+    }
+    /// Set the upper bound of the delay
+    pub fn max_delay(mut self, x: Duration) -> Self {
+        self.max_delay = x;
+        self
+    }
+    /// Set the number of retries
+    pub fn max_retries(mut self, x: u32) -> Self {
+        self.max_retries = x;
+        self
+    }
+    /// Enable or disable the jitter
+    pub fn jitter(mut self, x: bool) -> Self {
+        self.jitter = x;
+        self
+    }
+
+    /// Delay before retry number `retry` (starting at 0)
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry).unwrap_or(u32::MAX);
+        let delay = self
+            .initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay);
+        if !self.jitter {
+            return delay;
+        }
+        let half = delay / 2;
+        half + half.mul_f64(random_fraction())
+    }
+
+    /// Runs `f` until it succeeds, fails with an error other than a connection failure or
+    /// timeout, or the retries are exhausted
+    pub(crate) async fn retry<R, F, Fut>(&self, what: &str, mut f: F) -> Result<R, MemcacheError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<R, MemcacheError>>,
+    {
+        let mut retry = 0;
+        loop {
+            match f().await {
+                Err(e) if e.is_retryable() && retry < self.max_retries => {
+                    let delay = self.delay(retry);
+                    debug!(
+                        "{}: attempt {} failed ({:?}), retrying in {:?}",
+                        what,
+                        retry + 1,
+                        e,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                }
+                x => return x,
+            }
+        }
+    }
+}
+
+/// Random number in [0, 1), good enough for jitter. Every `RandomState` is seeded differently.
+fn random_fraction() -> f64 {
+    let x = RandomState::new().build_hasher().finish();
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_delays() {
+        let policy = BackoffPolicy::new()
+            .initial_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_secs(1))
+            .jitter(false);
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(400));
+        assert_eq!(policy.delay(4), Duration::from_secs(1));
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
+
+        let policy = policy.jitter(true);
+        for retry in 0..8 {
+            let x = policy.delay(retry);
+            let max = policy.jitter(false).delay(retry);
+            assert!(x >= max / 2 && x <= max, "{:?} out of range", x);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! [bb8](https://docs.rs/bb8) integration, enabled with the `bb8` feature
+
+use crate::error::MemcacheError;
+use crate::pool::PoolConnection;
+use crate::{Client, Health};
+
+/// Pool of connections managed by bb8
+pub type Pool = ::bb8::Pool<Manager>;
+
+/// bb8 connection manager for a single memcached server
+#[derive(Debug, Clone)]
+pub struct Manager {
+    url: String,
+}
+
+impl Manager {
+    /// Create a manager connecting to the server described by a connection URL, see
+    /// [`Client::connect`]
+    pub fn new<A: Into<String>>(url: A) -> Self {
+        Manager { url: url.into() }
+    }
+}
+
+impl ::bb8::ManageConnection for Manager {
+    type Connection = Client<PoolConnection>;
+    type Error = MemcacheError;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        crate::pool::open(&self.url).await
+    }
+
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        match conn.ping().await {
+            Health::Healthy { .. } => Ok(()),
+            Health::Unhealthy(e) => Err(e),
+        }
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        conn.connection.is_broken() || !conn.is_healthy()
+    }
+}
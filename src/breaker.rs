@@ -0,0 +1,225 @@
+//! Circuit breaker
+//!
+//! A [`CircuitBreaker`] tracks the health of a single server. After `failure_threshold`
+//! consecutive connection failures or timeouts it opens and commands fail right away with
+//! [`MemcacheError::CircuitOpen`](crate::error::MemcacheError::CircuitOpen) instead of waiting
+//! for the server. Once the cooldown elapsed, a limited number of probe commands are let
+//! through: the first one succeeding closes the breaker again, a failing one reopens it.
+//!
+//! The breaker is cheap to clone, clones share their state, so the same breaker can be
+//! installed on all the connections to a server.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{ErrorKind, MemcacheError};
+
+use log::{debug, error};
+
+/// Configuration of a [`CircuitBreaker`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerOptions {
+    /// Consecutive failures opening the breaker
+    pub failure_threshold: u32,
+    /// How long the breaker stays open before letting probes through
+    pub cooldown: Duration,
+    /// Number of probe commands let through at the same time once the cooldown elapsed
+    pub probes: u32,
+}
+
+impl Default for BreakerOptions {
+    fn default() -> Self {
+        BreakerOptions {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(10),
+            probes: 1,
+        }
+    }
+}
+
+impl BreakerOptions {
+    /// Create the default breaker options
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the number of consecutive failures opening the breaker
+    pub fn failure_threshold(mut self, x: u32) -> Self {
+        self.failure_threshold = x;
+        self
+    }
+    /// Set how long the breaker stays open
+    pub fn cooldown(mut self, x: Duration) -> Self {
+        self.cooldown = x;
+        self
+    }
+    /// Set the number of concurrent probe commands
+    pub fn probes(mut self, x: u32) -> Self {
+        self.probes = x;
+        self
+    }
+}
+
+/// State of a [`CircuitBreaker`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Commands are sent to the server
+    Closed,
+    /// Commands fail right away
+    Open,
+    /// Probe commands are sent to find out whether the server recovered
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probes: u32, since: Instant },
+}
+
+/// Circuit breaker of a single server, see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    options: BreakerOptions,
+    state: Arc<Mutex<State>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(options: BreakerOptions) -> Self {
+        CircuitBreaker {
+            options,
+            state: Arc::new(Mutex::new(State::Closed { failures: 0 })),
+        }
+    }
+
+    /// Current state, an open breaker whose cooldown elapsed is reported as half open
+    pub fn state(&self) -> BreakerState {
+        match *self.lock() {
+            State::Closed { .. } => BreakerState::Closed,
+            State::Open { until } if Instant::now() < until => BreakerState::Open,
+            State::Open { .. } | State::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Close the breaker, e.g. after the server was known to be replaced
+    pub fn reset(&self) {
+        *self.lock() = State::Closed { failures: 0 };
+    }
+
+    /// Fails with [`MemcacheError::CircuitOpen`] if a command may not be sent now
+    pub(crate) fn admit(&self) -> Result<(), MemcacheError> {
+        self.admit_at(Instant::now())
+    }
+
+    /// Records the outcome of an admitted command
+    pub(crate) fn record<R>(&self, result: &Result<R, MemcacheError>) {
+        let failed = result.as_ref().is_err_and(|e| {
+            matches!(
+                e.kind(),
+                ErrorKind::Connection | ErrorKind::Timeout | ErrorKind::Unavailable
+            )
+        });
+        self.record_at(failed, Instant::now());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|x| x.into_inner())
+    }
+
+    fn admit_at(&self, now: Instant) -> Result<(), MemcacheError> {
+        let mut state = self.lock();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now < until => Err(MemcacheError::CircuitOpen),
+            State::Open { .. } => {
+                debug!("circuit breaker: half open");
+                *state = State::HalfOpen {
+                    probes: 1,
+                    since: now,
+                };
+                Ok(())
+            }
+            State::HalfOpen {
+                ref mut probes,
+                since,
+            } => {
+                if *probes < self.options.probes {
+                    *probes += 1;
+                    Ok(())
+                } else if now >= since + self.options.cooldown {
+                    // the probes never reported back, e.g. because they were cancelled
+                    *state = State::HalfOpen {
+                        probes: 1,
+                        since: now,
+                    };
+                    Ok(())
+                } else {
+                    Err(MemcacheError::CircuitOpen)
+                }
+            }
+        }
+    }
+
+    fn record_at(&self, failed: bool, now: Instant) {
+        let mut state = self.lock();
+        if !failed {
+            if !matches!(*state, State::Closed { .. }) {
+                debug!("circuit breaker: closed");
+            }
+            *state = State::Closed { failures: 0 };
+            return;
+        }
+        match *state {
+            State::Closed { failures } if failures + 1 < self.options.failure_threshold => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                };
+            }
+            State::Closed { .. } | State::HalfOpen { .. } => {
+                error!("circuit breaker: open for {:?}", self.options.cooldown);
+                *state = State::Open {
+                    until: now + self.options.cooldown,
+                };
+            }
+            // a command admitted before the breaker opened
+            State::Open { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaker_transitions() {
+        let breaker = CircuitBreaker::new(
+            BreakerOptions::new()
+                .failure_threshold(2)
+                .cooldown(Duration::from_secs(10)),
+        );
+        let start = Instant::now();
+        assert!(breaker.admit_at(start).is_ok());
+        breaker.record_at(true, start);
+        assert!(breaker.admit_at(start).is_ok());
+        breaker.record_at(true, start);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(matches!(
+            breaker.admit_at(start + Duration::from_secs(5)),
+            Err(MemcacheError::CircuitOpen)
+        ));
+
+        // a single probe after the cooldown, failing reopens the breaker
+        let later = start + Duration::from_secs(10);
+        assert!(breaker.admit_at(later).is_ok());
+        assert!(breaker.admit_at(later).is_err());
+        breaker.record_at(true, later);
+        assert!(breaker.admit_at(later + Duration::from_secs(1)).is_err());
+
+        let later = later + Duration::from_secs(10);
+        assert!(breaker.admit_at(later).is_ok());
+        breaker.record_at(false, later);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.admit_at(later).is_ok());
+    }
+}
@@ -0,0 +1,234 @@
+//! Cache-aside helpers
+//!
+//! [`Client::get_or_set`] returns the cached value of a key, or computes it with a loader and
+//! stores it. On a miss the meta get creates an empty placeholder (`N` flag) that only one
+//! client wins: it runs the loader while the others wait for the value instead of all hitting
+//! the backend at once. Without the meta protocol (see
+//! [`Meta::text_only`](crate::protocol::Meta::text_only)) every client that misses runs the
+//! loader.
+//!
+//! A [`CacheCell`] binds a key to its TTL and [`Transcoder`], so the call sites don't have to
+//! repeat them.
+//! ```no_run
+//! use yamemcache::cache::CacheCell;
+//!
+//! # async fn load_user(id: u32) -> Result<String, yamemcache::error::MemcacheError> {
+//! #     Ok(String::new())
+//! # }
+//! # async fn example(client: &mut yamemcache::Client<yamemcache::BoxedConnection>)
+//! # -> Result<(), yamemcache::error::MemcacheError> {
+//! let name: String = client
+//!     .get_or_set("user:42:name", Some(300), || load_user(42))
+//!     .await?;
+//!
+//! let visits = CacheCell::<u64>::new("stats:visits").ttl(Some(60));
+//! visits.refresh(client, &42).await?;
+//! let count = visits.get(client).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::error::MemcacheError;
+use crate::protocol::{DeleteOptions, GetOptions, RawValue};
+use crate::value::{FromMemcacheValue, Native, ToMemcacheValue, Transcoder};
+use crate::{AsyncReadWriteUnpin, Client};
+
+use log::debug;
+
+/// Seconds the placeholder of a value being loaded lives. Clients waiting for the value
+/// load it themselves once it expires, e.g. if the loading client died.
+pub const LOAD_LOCK_TTL: u32 = 30;
+
+/// First and longest delay between two reads of a value being loaded
+const POLL_DELAY: Duration = Duration::from_millis(10);
+const MAX_POLL_DELAY: Duration = Duration::from_millis(200);
+
+impl<T: AsyncReadWriteUnpin> Client<T> {
+    /// GET a value, or compute it with `loader` and STORE it with `ttl` (`None` to never
+    /// expire) on a miss, see the [module documentation](crate::cache). Errors of the loader
+    /// are returned as is, nothing is stored then.
+    pub async fn get_or_set<V, E, F, Fut>(
+        &mut self,
+        key: &str,
+        ttl: Option<u32>,
+        loader: F,
+    ) -> Result<V, E>
+    where
+        V: FromMemcacheValue + ToMemcacheValue,
+        E: From<MemcacheError>,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if self.protocol.is_text_only() {
+            if let Some(x) = self.get(key).await? {
+                return Ok(x);
+            }
+            let value = loader().await?;
+            self.set(key, value.to_memcache_value().set_time(ttl))
+                .await?;
+            return Ok(value);
+        }
+
+        let opts = GetOptions::new().vivify(Some(LOAD_LOCK_TTL));
+        let mut delay = POLL_DELAY;
+        let placeholder = loop {
+            let Some(response) = self.get_with(key, &opts).await? else {
+                break None;
+            };
+            if response.won_recache {
+                break response.value.cas;
+            }
+            // a placeholder being loaded by another client, not a stale value being refreshed
+            if response.recache_pending && !response.stale {
+                debug!("get_or_set: waiting for {} to be loaded", key);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_POLL_DELAY);
+                continue;
+            }
+            return Ok(V::from_memcache_value(response.value)?);
+        };
+
+        debug!("get_or_set: loading {}", key);
+        let value = match loader().await {
+            Ok(x) => x,
+            Err(e) => {
+                // let the next client load it rather than wait for the placeholder to expire
+                if placeholder.is_some() {
+                    let opts = DeleteOptions::new().cas(placeholder);
+                    let _ = self.delete_with(key, &opts).await;
+                }
+                return Err(e);
+            }
+        };
+        self.set(key, value.to_memcache_value().set_time(ttl))
+            .await?;
+        Ok(value)
+    }
+}
+
+/// Value of type `V` stored under a fixed key with a fixed TTL, encoded with `C`
+#[derive(Debug, Clone)]
+pub struct CacheCell<V, C = Native> {
+    key: String,
+    ttl: Option<u32>,
+    transcoder: C,
+    value: PhantomData<fn() -> V>,
+}
+
+impl<V> CacheCell<V> {
+    /// Cell of `key`, never expiring, storing the types implementing [`ToMemcacheValue`] and
+    /// [`FromMemcacheValue`] as is
+    pub fn new(key: impl Into<String>) -> Self {
+        CacheCell {
+            key: key.into(),
+            ttl: None,
+            transcoder: Native,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<V, C> CacheCell<V, C> {
+    /// TTL of the value stored by [`CacheCell::refresh`], in seconds. Values over 30 days are
+    /// read by memcached as unix timestamps, see [`RawValue::expires_in`]. None, the default,
+    /// stores the value without expiration.
+    pub fn ttl(mut self, t: Option<u32>) -> Self {
+        self.ttl = t;
+        self
+    }
+
+    /// Encode the value with `transcoder` instead, e.g. to store a type that isn't stored
+    /// natively. This changes the type of the cell, the key and TTL are kept.
+    pub fn transcoder<D: Transcoder<V>>(self, transcoder: D) -> CacheCell<V, D> {
+        CacheCell {
+            key: self.key,
+            ttl: self.ttl,
+            transcoder,
+            value: PhantomData,
+        }
+    }
+
+    /// Key of the value
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl<V, C: Transcoder<V>> CacheCell<V, C> {
+    /// GET and decode the value, None if it is not cached
+    pub async fn get<T: AsyncReadWriteUnpin>(
+        &self,
+        client: &mut Client<T>,
+    ) -> Result<Option<V>, MemcacheError> {
+        client.get_transcoded(&self.key, &self.transcoder).await
+    }
+
+    /// Encode `value` and STORE it with the TTL of the cell
+    pub async fn refresh<T: AsyncReadWriteUnpin>(
+        &self,
+        client: &mut Client<T>,
+        value: &V,
+    ) -> Result<(), MemcacheError> {
+        let (data, flags) = self.transcoder.encode(value)?;
+        let value = RawValue::from_vec(data).set_flags(flags).set_time(self.ttl);
+        client.set(&self.key, &value).await
+    }
+
+    /// DELETE the value, if it is cached
+    pub async fn invalidate<T: AsyncReadWriteUnpin>(
+        &self,
+        client: &mut Client<T>,
+    ) -> Result<(), MemcacheError> {
+        client.delete(&self.key).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(response: &[u8]) -> Client<impl AsyncReadWriteUnpin> {
+        Client::new(tokio::io::join(
+            std::io::Cursor::new(response.to_vec()),
+            tokio::io::sink(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn get_or_set() {
+        // won the placeholder: load and store
+        let mut c = client(b"VA 0 kkey f0 c5 W\r\n\r\nHD\r\n");
+        let value: Result<String, MemcacheError> = c
+            .get_or_set("key", None, || async { Ok("v".to_string()) })
+            .await;
+        assert_eq!(value.unwrap(), "v");
+
+        // another client is loading it: wait for its value
+        let mut c = client(b"VA 0 kkey f0 c5 Z\r\n\r\nVA 1 kkey f16 c6\r\nw\r\n");
+        let value: Result<String, MemcacheError> = c
+            .get_or_set("key", None, || async { panic!("loaded twice") })
+            .await;
+        assert_eq!(value.unwrap(), "w");
+
+        // failed load: the placeholder is removed
+        let mut c = client(b"VA 0 kkey f0 c5 W\r\n\r\nHD\r\n");
+        let value: Result<String, Box<dyn std::error::Error>> = c
+            .get_or_set("key", None, || async { Err("backend down".into()) })
+            .await;
+        assert_eq!(value.unwrap_err().to_string(), "backend down");
+    }
+
+    #[tokio::test]
+    async fn cache_cell() {
+        let cell = CacheCell::<u32>::new("key").ttl(Some(60));
+        assert_eq!(cell.key(), "key");
+        let mut c = client(b"VA 2 kkey f2 c5\r\n42\r\nHD\r\nDELETED\r\n");
+        assert_eq!(cell.get(&mut c).await.unwrap(), Some(42));
+        cell.refresh(&mut c, &43).await.unwrap();
+        cell.invalidate(&mut c).await.unwrap();
+    }
+}
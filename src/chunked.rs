@@ -0,0 +1,227 @@
+//! Transparent chunking of oversized values
+//!
+//! With [`ClientOptions::chunking`](crate::ClientOptions::chunking) (or [`Meta::chunking`])
+//! [`Meta::set`] splits values larger than [`Meta::max_value_size`] into chunks stored under
+//! `<key>:chunk:<index>`, small enough to leave room for their key and item header under the
+//! limit, then stores a manifest marked with [`FLAG_CHUNKED`] under the key itself.
+//! [`Meta::get`] and [`Meta::get_with`] read the chunks back and reassemble the value.
+//! The other commands see the manifest only. Values are split in at most [`MAX_CHUNKS`].
+//!
+//! The manifest records the size and MD5 digest of the value, a value whose chunks were
+//! evicted or overwritten by a concurrent store is reported as missing. The chunks of a value
+//! replaced by a smaller one are left behind until they expire or are evicted.
+
+use crate::error::MemcacheError;
+use crate::protocol::{Meta, RawValue, StoreResult};
+use crate::AsyncReadWriteUnpin;
+
+use log::{debug, error};
+
+/// Flag of the manifest of a chunked value
+pub const FLAG_CHUNKED: u32 = 1 << 18;
+
+/// Most chunks of a value, larger values are rejected with [`MemcacheError::ValueTooLarge`]
+pub const MAX_CHUNKS: usize = 1024;
+
+/// Part of the item size limit left for the key and the item header of a chunk, the item
+/// size limit of memcached counts them too
+const CHUNK_OVERHEAD: usize = 1024;
+
+/// Key of the chunk `index` of the value stored under `key`
+fn chunk_key(key: &str, index: usize) -> String {
+    format!("{}:chunk:{}", key, index)
+}
+
+/// Chunk count, size and MD5 digest recorded in a manifest
+fn parse_manifest(data: &[u8]) -> Option<(usize, usize, String)> {
+    let mut tokens = std::str::from_utf8(data).ok()?.split_ascii_whitespace();
+    let count = tokens.next()?.parse().ok()?;
+    let size = tokens.next()?.parse().ok()?;
+    let digest = tokens.next()?.to_string();
+    Some((count, size, digest))
+}
+
+impl Meta {
+    /// Size of the chunks, leaving room for their key and header under the item size limit.
+    /// Half the limit for limits too small to spare [`CHUNK_OVERHEAD`].
+    fn chunk_size(&self) -> usize {
+        let limit = self.value_size_limit();
+        limit.saturating_sub(CHUNK_OVERHEAD).max(limit / 2).max(1)
+    }
+
+    /// Stores `data` as chunks followed by its manifest. `data` is already encoded, the
+    /// chunks are stored as is.
+    pub(crate) async fn set_chunked<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        data: &RawValue<&[u8]>,
+    ) -> Result<StoreResult, MemcacheError> {
+        let chunk_size = self.chunk_size();
+        let limit = chunk_size.saturating_mul(MAX_CHUNKS);
+        if data.data.len() > limit {
+            error!(
+                "set: value of {} bytes exceeds the limit of {} chunks",
+                data.data.len(),
+                MAX_CHUNKS
+            );
+            return Err(MemcacheError::ValueTooLarge {
+                size: data.data.len(),
+                limit,
+            });
+        }
+        let keys: Vec<String> = (0..data.data.len().div_ceil(chunk_size))
+            .map(|i| self.wire_key(&chunk_key(key, i)).into_owned())
+            .collect();
+        debug!("set: {} bytes in {} chunks", data.data.len(), keys.len());
+        let keydata: Vec<(&str, RawValue<&[u8]>)> = keys
+            .iter()
+            .map(|x| x.as_str())
+            .zip(
+                data.data
+                    .chunks(chunk_size)
+                    .map(|x| RawValue::new(x).set_time(data.time)),
+            )
+            .collect();
+        self.store_multiple(io, &keydata).await?;
+
+        let manifest = format!(
+            "{} {} {:x}",
+            keys.len(),
+            data.data.len(),
+            md5::compute(data.data)
+        );
+        let manifest = RawValue {
+            data: manifest.as_bytes(),
+            flags: data.flags | FLAG_CHUNKED,
+            time: data.time,
+            cas: None,
+        };
+        self.store(io, &self.wire_key(key), &manifest).await
+    }
+
+    /// Reads the chunks listed in `manifest`, None if any of them is missing or doesn't match
+    pub(crate) async fn get_chunked<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        manifest: RawValue,
+    ) -> Result<Option<RawValue>, MemcacheError> {
+        let Some((count, size, digest)) = parse_manifest(&manifest.data) else {
+            error!("get: bad chunk manifest for {}", key);
+            return Err(MemcacheError::bad_response(&manifest.data));
+        };
+        if count > MAX_CHUNKS || size > count.saturating_mul(self.chunk_size()) {
+            error!("get: chunk manifest for {} out of bounds", key);
+            return Err(MemcacheError::bad_response(&manifest.data));
+        }
+        let keys: Vec<String> = (0..count).map(|i| chunk_key(key, i)).collect();
+        let key_list: Vec<&str> = keys.iter().map(|x| x.as_str()).collect();
+        let mut chunks = self.get_many(io, &key_list).await?;
+        if chunks.len() != count {
+            debug!("get: {} of {} chunks found", chunks.len(), count);
+            return Ok(None);
+        }
+        chunks.sort_by_key(|(x, _)| keys.iter().position(|k| k == x));
+        let mut data = Vec::with_capacity(size);
+        for (_, chunk) in chunks {
+            data.extend_from_slice(&chunk.data);
+        }
+        if data.len() != size || format!("{:x}", md5::compute(&data)) != digest {
+            debug!("get: chunks of {} don't match the manifest", key);
+            return Ok(None);
+        }
+        Ok(Some(RawValue {
+            data,
+            flags: manifest.flags & !FLAG_CHUNKED,
+            time: manifest.time,
+            cas: manifest.cas,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn chunks_fit_the_limit() {
+        let limit = 4096;
+        let meta = Meta::new().max_value_size(limit).chunking(true);
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        server.write_all(&b"HD\r\n".repeat(5)).await.unwrap();
+        let mut io = tokio::io::BufStream::new(client);
+        let data = vec![b'x'; 3 * limit];
+        meta.set(&mut io, "key", &RawValue::new(&data[..]))
+            .await
+            .unwrap();
+        drop(io);
+
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).await.unwrap();
+        let sent = String::from_utf8(sent).unwrap();
+        let headers: Vec<&str> = sent.lines().filter(|x| x.starts_with("ms ")).collect();
+        assert_eq!(headers.len(), 5);
+        for header in &headers[..4] {
+            let key = header.split(' ').nth(1).unwrap();
+            let size: usize = header.split(' ').nth(2).unwrap()[1..].parse().unwrap();
+            // room left for the key and the item header
+            assert!(size + key.len() + 64 < limit, "{}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_reassembles_chunks() {
+        // chunks of 4 bytes
+        let meta = Meta::new().max_value_size(8).chunking(true);
+        let data = b"0123456789";
+        let manifest = format!("3 10 {:x}", md5::compute(data));
+        let response = format!(
+            "VA {} kkey f{} c5\r\n{}\r\n\
+             VA 2 kkey:chunk:2 f0 c1 O2\r\n89\r\n\
+             VA 4 kkey:chunk:0 f0 c1 O0\r\n0123\r\n\
+             VA 4 kkey:chunk:1 f0 c1 O1\r\n4567\r\nMN\r\n",
+            manifest.len(),
+            FLAG_CHUNKED | 3,
+            manifest
+        );
+        let mut io = tokio::io::join(
+            std::io::Cursor::new(response.into_bytes()),
+            tokio::io::sink(),
+        );
+        let value = meta.get(&mut io, "key").await.unwrap().unwrap();
+        assert_eq!(value.data, data);
+        assert_eq!(value.flags, 3);
+        assert_eq!(value.cas, Some(5));
+    }
+
+    #[tokio::test]
+    async fn bad_manifests() {
+        let meta = Meta::new().max_value_size(8).chunking(true);
+        for manifest in ["1000000000000 10 x", "3 13 x", "x"] {
+            let response = format!(
+                "VA {} kkey f{} c5\r\n{}\r\n",
+                manifest.len(),
+                FLAG_CHUNKED,
+                manifest
+            );
+            let mut io = tokio::io::join(
+                std::io::Cursor::new(response.into_bytes()),
+                tokio::io::sink(),
+            );
+            let result = meta.get(&mut io, "key").await;
+            assert!(
+                matches!(result, Err(MemcacheError::BadServerResponse(_))),
+                "{}",
+                manifest
+            );
+        }
+
+        // too many chunks to store
+        let data = vec![0; 4 * MAX_CHUNKS + 1];
+        let mut io = tokio::io::join(std::io::Cursor::new(Vec::new()), tokio::io::sink());
+        let result = meta.set(&mut io, "key", &RawValue::new(&data[..])).await;
+        assert!(matches!(result, Err(MemcacheError::ValueTooLarge { .. })));
+    }
+}
@@ -0,0 +1,413 @@
+//! Transparent compression of large values
+//!
+//! With [`ClientOptions::compression`](crate::ClientOptions::compression) (or
+//! [`Meta::compression`]) values of at least [`Compression::threshold`] bytes are compressed
+//! by the store commands and decompressed by the get commands. Compressed values are marked
+//! with [`FLAG_COMPRESSED`], the zlib flag of pylibmc and python-memcached, so they can be
+//! shared with those clients. The other codecs have their own flags ([`FLAG_ZSTD`] with the
+//! `zstd` feature, [`FLAG_LZ4`] with the `lz4` feature). Values that don't shrink are stored as is.
+//!
+//! [`Codec::PhpZlib`] uses the layout of php-memcached instead: [`FLAG_PHP_COMPRESSED`] with
+//! [`FLAG_PHP_ZLIB`], and the original length prepended to the zlib stream. Those bits are the
+//! same as [`FLAG_TEXT`](crate::value::FLAG_TEXT) and [`FLAG_FLOAT`](crate::value::FLAG_FLOAT),
+//! the type of the values compressed this way is lost. Values compressed by php-memcached are
+//! read whatever the codec, except FastLZ ones.
+//!
+//! Clients without compression return compressed values untouched, all the clients sharing
+//! compressed keys should enable it. Values decompressing to more than
+//! [`Compression::max_size`] fail to decode.
+
+use std::io::{Read, Write};
+
+use crate::chunked::FLAG_CHUNKED;
+use crate::error::MemcacheError;
+use crate::protocol::{Meta, RawValue};
+
+use log::{debug, error};
+
+/// Flag of values compressed with [`Codec::Deflate`] (zlib stream)
+pub const FLAG_COMPRESSED: u32 = 8;
+/// Flag of values compressed with zstd
+pub const FLAG_ZSTD: u32 = 1 << 16;
+/// Flag of values compressed with LZ4
+pub const FLAG_LZ4: u32 = 1 << 17;
+/// Flag of values compressed by php-memcached, with the flag of the algorithm
+pub const FLAG_PHP_COMPRESSED: u32 = 1 << 4;
+/// Flag of php-memcached values compressed with zlib
+pub const FLAG_PHP_ZLIB: u32 = 1 << 5;
+/// Flag of php-memcached values compressed with FastLZ, not supported
+pub const FLAG_PHP_FASTLZ: u32 = 1 << 6;
+/// Smallest value compressed by default
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 2048;
+/// Largest decompressed value by default
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 64 << 20;
+
+/// All the flags marking compressed values
+const COMPRESSION_FLAGS: u32 = FLAG_COMPRESSED | FLAG_ZSTD | FLAG_LZ4;
+/// All the flags of the php-memcached compression
+const PHP_FLAGS: u32 = FLAG_PHP_COMPRESSED | FLAG_PHP_ZLIB | FLAG_PHP_FASTLZ;
+
+/// Whether `flags` mark a value compressed by php-memcached. The compression bit alone is
+/// [`FLAG_TEXT`](crate::value::FLAG_TEXT).
+fn php_compressed(flags: u32) -> bool {
+    flags & FLAG_PHP_COMPRESSED != 0 && flags & (FLAG_PHP_ZLIB | FLAG_PHP_FASTLZ) != 0
+}
+
+/// Compression algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Deflate in a zlib stream, `level` from 0 (none) to 9 (best)
+    Deflate { level: u32 },
+    /// Deflate in a zlib stream prefixed with the original length, as php-memcached does.
+    /// `level` from 0 (none) to 9 (best).
+    PhpZlib { level: u32 },
+    /// Zstandard, `level` from 1 (fastest) to 22 (best), 0 for the default (3). Only read by
+    /// clients built with the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+    /// LZ4 block with the size prepended, much faster but larger than the others. Only read
+    /// by clients built with the `lz4` feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Codec {
+    /// Flag marking values compressed with the codec
+    fn flag(&self) -> u32 {
+        match self {
+            Codec::Deflate { .. } => FLAG_COMPRESSED,
+            Codec::PhpZlib { .. } => FLAG_PHP_COMPRESSED | FLAG_PHP_ZLIB,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd { .. } => FLAG_ZSTD,
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => FLAG_LZ4,
+        }
+    }
+}
+
+/// Codec and threshold of the compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// Algorithm used to compress the values
+    pub codec: Codec,
+    /// Smallest value compressed, in bytes
+    pub threshold: usize,
+    /// Largest value once decompressed, in bytes. Larger values are stored uncompressed and
+    /// values decompressing to more fail to decode, instead of exhausting the memory.
+    pub max_size: usize,
+}
+
+impl Compression {
+    pub fn new(codec: Codec) -> Self {
+        Compression {
+            codec,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            max_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+
+    pub fn threshold(mut self, t: usize) -> Self {
+        self.threshold = t;
+        self
+    }
+
+    pub fn max_size(mut self, n: usize) -> Self {
+        self.max_size = n;
+        self
+    }
+}
+
+impl From<Codec> for Compression {
+    fn from(codec: Codec) -> Self {
+        Compression::new(codec)
+    }
+}
+
+fn compress_error(e: std::io::Error) -> MemcacheError {
+    error!("compress: {}", e);
+    MemcacheError::IOError(e)
+}
+
+fn decompress_error(e: std::io::Error) -> MemcacheError {
+    error!("decompress: {}", e);
+    MemcacheError::IOError(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Appends the zlib stream of `data` to `buf`
+fn deflate(buf: Vec<u8>, data: &[u8], level: u32) -> Result<Vec<u8>, MemcacheError> {
+    let mut encoder = flate2::write::ZlibEncoder::new(buf, flate2::Compression::new(level));
+    encoder
+        .write_all(data)
+        .and_then(|_| encoder.finish())
+        .map_err(compress_error)
+}
+
+/// Reads the whole output of `decoder`, failing once it exceeds `limit` bytes
+fn read_limited(decoder: impl Read, limit: usize, data: &mut Vec<u8>) -> Result<(), MemcacheError> {
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(data)
+        .map_err(decompress_error)?;
+    if data.len() > limit {
+        return Err(too_large(limit));
+    }
+    Ok(())
+}
+
+fn too_large(limit: usize) -> MemcacheError {
+    decompress_error(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("decompressed value larger than {} bytes", limit),
+    ))
+}
+
+/// Splits the original length prepended to compressed data, checked against `limit` before
+/// anything is allocated for it
+fn split_length(data: &[u8], limit: usize) -> Result<(usize, &[u8]), MemcacheError> {
+    let Some((length, compressed)) = data.split_first_chunk::<4>() else {
+        return Err(decompress_error(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "missing the original length",
+        )));
+    };
+    let length = u32::from_le_bytes(*length) as usize;
+    if length > limit {
+        return Err(too_large(limit));
+    }
+    Ok((length, compressed))
+}
+
+/// Decompresses a value stored by php-memcached: the original length followed by the zlib
+/// stream
+fn php_decompress(data: &[u8], flags: u32, limit: usize) -> Result<Vec<u8>, MemcacheError> {
+    if flags & FLAG_PHP_FASTLZ != 0 {
+        return Err(decompress_error(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "fastlz is not supported",
+        )));
+    }
+    let (length, compressed) = split_length(data, limit)?;
+    let mut retval = Vec::with_capacity(length);
+    read_limited(
+        flate2::read::ZlibDecoder::new(compressed),
+        length,
+        &mut retval,
+    )?;
+    if retval.len() != length {
+        return Err(decompress_error(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "wrong original length",
+        )));
+    }
+    Ok(retval)
+}
+
+impl Meta {
+    /// Compresses `data` if compression is enabled and the value is large enough, None if
+    /// it should be stored as is
+    pub(crate) fn compress(
+        &self,
+        data: RawValue<&[u8]>,
+    ) -> Result<Option<RawValue>, MemcacheError> {
+        let Some(compression) = self.compression_options() else {
+            return Ok(None);
+        };
+        if data.data.len() < compression.threshold
+            || data.data.len() > compression.max_size
+            || data.flags & COMPRESSION_FLAGS != 0
+            || php_compressed(data.flags)
+        {
+            return Ok(None);
+        }
+        let compressed = match compression.codec {
+            Codec::Deflate { level } => {
+                deflate(Vec::with_capacity(data.data.len() / 2), data.data, level)?
+            }
+            Codec::PhpZlib { level } => {
+                let Ok(length) = u32::try_from(data.data.len()) else {
+                    return Ok(None);
+                };
+                let mut buf = Vec::with_capacity(data.data.len() / 2);
+                buf.extend_from_slice(&length.to_le_bytes());
+                deflate(buf, data.data, level)?
+            }
+            #[cfg(feature = "zstd")]
+            Codec::Zstd { level } => {
+                zstd::bulk::compress(data.data, level).map_err(compress_error)?
+            }
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => lz4_flex::compress_prepend_size(data.data),
+        };
+        if compressed.len() >= data.data.len() {
+            debug!("compress: {} bytes don't shrink", data.data.len());
+            return Ok(None);
+        }
+        Ok(Some(RawValue {
+            data: compressed,
+            flags: data.flags | compression.codec.flag(),
+            time: data.time,
+            cas: data.cas,
+        }))
+    }
+
+    /// Decompresses `value` if compression is enabled and the value is marked as compressed.
+    /// The manifests of chunked values are left alone, the value is decompressed once
+    /// reassembled.
+    pub(crate) fn decompress(&self, mut value: RawValue) -> Result<RawValue, MemcacheError> {
+        let Some(limit) = self.compression_options().map(|x| x.max_size) else {
+            return Ok(value);
+        };
+        if (value.flags & COMPRESSION_FLAGS == 0 && !php_compressed(value.flags))
+            || value.flags & FLAG_CHUNKED != 0
+        {
+            return Ok(value);
+        }
+        if php_compressed(value.flags) {
+            value.data = php_decompress(&value.data, value.flags, limit)?;
+            value.flags &= !PHP_FLAGS;
+            return Ok(value);
+        }
+        let mut data = Vec::with_capacity((value.data.len() * 2).min(limit));
+        if value.flags & FLAG_COMPRESSED != 0 {
+            let decoder = flate2::read::ZlibDecoder::new(value.data.as_slice());
+            read_limited(decoder, limit, &mut data)?;
+        } else if value.flags & FLAG_ZSTD != 0 {
+            #[cfg(feature = "zstd")]
+            {
+                let decoder = zstd::stream::read::Decoder::new(value.data.as_slice())
+                    .map_err(decompress_error)?;
+                read_limited(decoder, limit, &mut data)?;
+            }
+            #[cfg(not(feature = "zstd"))]
+            return Err(decompress_error(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "zstd support not enabled",
+            )));
+        } else if value.flags & FLAG_LZ4 != 0 {
+            #[cfg(feature = "lz4")]
+            {
+                let (length, compressed) = split_length(&value.data, limit)?;
+                data = lz4_flex::decompress(compressed, length).map_err(|e| {
+                    decompress_error(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                })?;
+            }
+            #[cfg(not(feature = "lz4"))]
+            return Err(decompress_error(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "lz4 support not enabled",
+            )));
+        }
+        value.data = data;
+        value.flags &= !COMPRESSION_FLAGS;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_round_trip() {
+        let protocol = Meta::new().compression(Some(
+            Compression::new(Codec::Deflate { level: 6 }).threshold(64),
+        ));
+        let value = RawValue::from_vec(b"abcd".repeat(100)).set_flags(3);
+        let compressed = protocol.compress(value.as_borrowed()).unwrap().unwrap();
+        assert!(compressed.data.len() < 100);
+        assert_eq!(compressed.flags, 3 | FLAG_COMPRESSED);
+        let decompressed = protocol.decompress(compressed).unwrap();
+        assert_eq!(decompressed.data, value.data);
+        assert_eq!(decompressed.flags, 3);
+
+        // small values and values that don't shrink are kept
+        let small = RawValue::from_vec(b"abcd".to_vec());
+        assert!(protocol.compress(small.as_borrowed()).unwrap().is_none());
+        let random: Vec<u8> = (0..200u32).map(|x| (x * 7919 % 251) as u8).collect();
+        let random = RawValue::from_vec(random);
+        assert!(protocol.compress(random.as_borrowed()).unwrap().is_none());
+
+        #[cfg(feature = "zstd")]
+        {
+            let protocol = Meta::new().compression(Some(Codec::Zstd { level: 3 }.into()));
+            let value = RawValue::from_vec(b"abcd".repeat(1000));
+            let compressed = protocol.compress(value.as_borrowed()).unwrap().unwrap();
+            assert_eq!(compressed.flags, FLAG_ZSTD);
+            let decompressed = protocol.decompress(compressed).unwrap();
+            assert_eq!(decompressed.data, value.data);
+        }
+
+        #[cfg(feature = "lz4")]
+        {
+            let protocol = Meta::new().compression(Some(Codec::Lz4.into()));
+            let value = RawValue::from_vec(b"abcd".repeat(1000));
+            let compressed = protocol.compress(value.as_borrowed()).unwrap().unwrap();
+            assert_eq!(compressed.flags, FLAG_LZ4);
+            let decompressed = protocol.decompress(compressed).unwrap();
+            assert_eq!(decompressed.data, value.data);
+        }
+
+        // stored by php-memcached: the length, then zlib.compress() at level 6
+        let protocol = Meta::new().compression(Some(Codec::PhpZlib { level: 6 }.into()));
+        let php = b"\x32\x00\x00\x00\x78\x9c\xcb\x48\xcd\xc9\xc9\x57\xc8\x4d\xcd\x4d\x4e\x4c\
+            \xce\x48\x4d\x51\x48\x2b\xca\xcf\x55\x28\xc8\x28\xd0\x51\
+            \xc8\xc0\x21\x03\x00\xd5\xe7\x12\x5b";
+        let raw = RawValue::from_vec(php.to_vec()).set_flags(FLAG_PHP_COMPRESSED | FLAG_PHP_ZLIB);
+        let decompressed = protocol.decompress(raw.clone()).unwrap();
+        assert_eq!(
+            decompressed.data,
+            b"hello memcached from php, hello memcached from php"
+        );
+        assert_eq!(decompressed.flags, 0);
+        // read with the other codecs too
+        let deflate = Meta::new().compression(Some(Codec::Deflate { level: 6 }.into()));
+        assert_eq!(deflate.decompress(raw).unwrap().data, decompressed.data);
+
+        let value = RawValue::from_vec(b"abcd".repeat(1000));
+        let compressed = protocol.compress(value.as_borrowed()).unwrap().unwrap();
+        assert_eq!(compressed.flags, FLAG_PHP_COMPRESSED | FLAG_PHP_ZLIB);
+        assert_eq!(compressed.data[..4], 4000u32.to_le_bytes());
+        assert_eq!(protocol.decompress(compressed).unwrap().data, value.data);
+
+        // the compression bit alone is a string, FastLZ is not supported
+        let text = RawValue::from_vec(b"x".to_vec()).set_flags(FLAG_PHP_COMPRESSED);
+        assert_eq!(protocol.decompress(text).unwrap().data, b"x");
+        let fastlz =
+            RawValue::from_vec(php.to_vec()).set_flags(FLAG_PHP_COMPRESSED | FLAG_PHP_FASTLZ);
+        assert!(protocol.decompress(fastlz).is_err());
+
+        // decompression bombs and lengths over the limit are rejected before allocating
+        let protocol = Meta::new().compression(Some(
+            Compression::new(Codec::Deflate { level: 6 }).max_size(1000),
+        ));
+        let bomb = RawValue::from_vec(vec![0; 100_000]);
+        assert!(protocol.compress(bomb.as_borrowed()).unwrap().is_none());
+        let bomb = Meta::new()
+            .compression(Some(Codec::Deflate { level: 6 }.into()))
+            .compress(bomb.as_borrowed())
+            .unwrap()
+            .unwrap();
+        assert!(protocol.decompress(bomb).is_err());
+        let mut php = 0x7fff_ffffu32.to_le_bytes().to_vec();
+        php.extend_from_slice(b"\x78\x9c\x03\x00\x00\x00\x00\x01");
+        let php = RawValue::from_vec(php).set_flags(FLAG_PHP_COMPRESSED | FLAG_PHP_ZLIB);
+        assert!(protocol.decompress(php).is_err());
+        #[cfg(feature = "zstd")]
+        {
+            let bomb = zstd::bulk::compress(&[0; 100_000], 3).unwrap();
+            let bomb = RawValue::from_vec(bomb).set_flags(FLAG_ZSTD);
+            assert!(protocol.decompress(bomb).is_err());
+        }
+        #[cfg(feature = "lz4")]
+        {
+            let mut lz4 = u32::MAX.to_le_bytes().to_vec();
+            lz4.push(0);
+            let lz4 = RawValue::from_vec(lz4).set_flags(FLAG_LZ4);
+            assert!(protocol.decompress(lz4).is_err());
+        }
+
+        // without compression the flag is left alone
+        let raw = RawValue::from_vec(b"x".to_vec()).set_flags(FLAG_COMPRESSED);
+        assert_eq!(Meta::new().decompress(raw).unwrap().data, b"x");
+    }
+}
@@ -0,0 +1,460 @@
+//! Connection helpers: connection URLs and tuned TCP sockets
+//!
+//! Supported URLs:
+//! * `memcache://host[:port]` - plain TCP
+//! * `memcache+tls://host[:port]` - TLS, requires the `tls` or `native-tls` feature
+//! * `memcache+unix:///path/to/socket` - unix domain socket
+//!
+//! Supported query parameters:
+//! * `timeout` - connection timeout, e.g. `500ms` or `2s`
+//! * `read_timeout`, `write_timeout` - command timeouts, see [`ClientOptions`]
+//! * `tcp_nodelay` - `true` to disable Nagle's algorithm
+
+use std::time::Duration;
+
+use tokio::io::BufStream;
+use tokio::net::TcpStream;
+
+use crate::error::MemcacheError;
+use crate::{BoxedConnection, Client, ClientOptions, Connector};
+
+use log::{debug, error};
+
+const DEFAULT_PORT: u16 = 11211;
+
+/// Transport selected by the URL scheme
+#[derive(Debug, PartialEq, Eq)]
+enum Transport {
+    Tcp(String),
+    Tls(String),
+    Unix(String),
+}
+
+/// Parsed connection URL
+#[derive(Debug, PartialEq, Eq)]
+struct ConnectionUrl {
+    transport: Transport,
+    timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    tcp_nodelay: bool,
+}
+
+fn invalid_url(url: &str, reason: &str) -> MemcacheError {
+    error!("connect: {}: {}", reason, url);
+    MemcacheError::IOError(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("{}: {}", reason, url),
+    ))
+}
+
+/// Parses durations like `500ms`, `2s` or `1m`, plain numbers are milliseconds. None if the
+/// duration doesn't fit.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number.parse::<u64>().ok()?;
+    match unit {
+        "us" => Some(Duration::from_micros(number)),
+        "" | "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+/// Adds the default port if the address has none
+pub(crate) fn with_port(host: &str) -> String {
+    let has_port = match host.rsplit_once(':') {
+        Some((x, port)) => port.parse::<u16>().is_ok() && (!x.contains(':') || x.ends_with(']')),
+        None => false,
+    };
+    if has_port {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, DEFAULT_PORT)
+    }
+}
+
+fn parse_url(url: &str) -> Result<ConnectionUrl, MemcacheError> {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return Err(invalid_url(url, "missing scheme"));
+    };
+    let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let location = match scheme {
+        "memcache+unix" => location,
+        _ => location.trim_end_matches('/'),
+    };
+    if location.is_empty() {
+        return Err(invalid_url(url, "missing address"));
+    }
+    let transport = match scheme {
+        "memcache" => Transport::Tcp(with_port(location)),
+        "memcache+tls" => Transport::Tls(with_port(location)),
+        "memcache+unix" => Transport::Unix(location.to_string()),
+        _ => return Err(invalid_url(url, "unsupported scheme")),
+    };
+
+    let mut retval = ConnectionUrl {
+        transport,
+        timeout: None,
+        read_timeout: None,
+        write_timeout: None,
+        tcp_nodelay: false,
+    };
+    for param in query.split('&').filter(|x| !x.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, "true"));
+        match name {
+            "timeout" | "read_timeout" | "write_timeout" => {
+                let Some(x) = parse_duration(value) else {
+                    return Err(invalid_url(url, "bad timeout"));
+                };
+                match name {
+                    "timeout" => retval.timeout = Some(x),
+                    "read_timeout" => retval.read_timeout = Some(x),
+                    _ => retval.write_timeout = Some(x),
+                }
+            }
+            "tcp_nodelay" => match value {
+                "true" | "1" => retval.tcp_nodelay = true,
+                "false" | "0" => retval.tcp_nodelay = false,
+                _ => return Err(invalid_url(url, "bad tcp_nodelay")),
+            },
+            _ => return Err(invalid_url(url, "unsupported parameter")),
+        }
+    }
+    Ok(retval)
+}
+
+/// Opens TCP connections with tuned socket options
+///
+/// ```no_run
+/// # async fn example() -> Result<(), yamemcache::error::MemcacheError> {
+/// let mut client = yamemcache::connect::TcpConnector::new()
+///     .nodelay(true)
+///     .keepalive(true)
+///     .connect("127.0.0.1:11211")
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TcpConnector {
+    /// Disable Nagle's algorithm (`TCP_NODELAY`), recommended for small requests
+    pub nodelay: bool,
+    /// Enable TCP keepalive probes (`SO_KEEPALIVE`)
+    pub keepalive: bool,
+    /// Size of the socket receive buffer (`SO_RCVBUF`)
+    pub recv_buffer_size: Option<u32>,
+    /// Size of the socket send buffer (`SO_SNDBUF`)
+    pub send_buffer_size: Option<u32>,
+}
+
+impl TcpConnector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn nodelay(mut self, x: bool) -> Self {
+        self.nodelay = x;
+        self
+    }
+
+    pub fn keepalive(mut self, x: bool) -> Self {
+        self.keepalive = x;
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, x: Option<u32>) -> Self {
+        self.recv_buffer_size = x;
+        self
+    }
+
+    pub fn send_buffer_size(mut self, x: Option<u32>) -> Self {
+        self.send_buffer_size = x;
+        self
+    }
+
+    /// Open a tuned TCP connection to `addr` ("host:port")
+    pub async fn connect_stream(&self, addr: &str) -> Result<TcpStream, MemcacheError> {
+        let mut last_error = None;
+        for x in tokio::net::lookup_host(addr)
+            .await
+            .map_err(MemcacheError::IOError)?
+        {
+            match self.connect_addr(x).await {
+                Ok(x) => return Ok(x),
+                Err(e) => {
+                    debug!("connect: {} failed: {}", x, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(MemcacheError::IOError(last_error.unwrap_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "address not resolved")
+        })))
+    }
+
+    /// Open a tuned TCP connection to `addr` and wrap it in a [`Client`]
+    pub async fn connect(&self, addr: &str) -> Result<Client<BufStream<TcpStream>>, MemcacheError> {
+        Ok(Client::new(BufStream::new(
+            self.connect_stream(addr).await?,
+        )))
+    }
+
+    async fn connect_addr(&self, addr: std::net::SocketAddr) -> std::io::Result<TcpStream> {
+        let socket = match addr {
+            std::net::SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+            std::net::SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+        };
+        socket.set_nodelay(self.nodelay)?;
+        socket.set_keepalive(self.keepalive)?;
+        if let Some(x) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(x)?;
+        }
+        if let Some(x) = self.send_buffer_size {
+            socket.set_send_buffer_size(x)?;
+        }
+        socket.connect(addr).await
+    }
+}
+
+async fn connect_tcp(addr: &str, tcp_nodelay: bool) -> Result<TcpStream, MemcacheError> {
+    TcpConnector::new()
+        .nodelay(tcp_nodelay)
+        .connect_stream(addr)
+        .await
+}
+
+#[cfg(feature = "tls")]
+async fn connect_tls(addr: &str, tcp_nodelay: bool) -> Result<BoxedConnection, MemcacheError> {
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let stream = connect_tcp(addr, tcp_nodelay).await?;
+    let stream = crate::tls::rustls_handshake(addr, stream, std::sync::Arc::new(config)).await?;
+    Ok(Box::new(BufStream::new(stream)))
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "tls")))]
+async fn connect_tls(addr: &str, tcp_nodelay: bool) -> Result<BoxedConnection, MemcacheError> {
+    let connector = tokio_native_tls::native_tls::TlsConnector::new()
+        .map_err(|e| MemcacheError::IOError(std::io::Error::other(e)))?;
+    let stream = connect_tcp(addr, tcp_nodelay).await?;
+    let stream = crate::tls::native_tls_handshake(addr, stream, connector).await?;
+    Ok(Box::new(BufStream::new(stream)))
+}
+
+#[cfg(not(any(feature = "tls", feature = "native-tls")))]
+async fn connect_tls(addr: &str, _tcp_nodelay: bool) -> Result<BoxedConnection, MemcacheError> {
+    Err(invalid_url(addr, "TLS support is not enabled"))
+}
+
+async fn connect_transport(url: &ConnectionUrl) -> Result<BoxedConnection, MemcacheError> {
+    match &url.transport {
+        Transport::Tcp(addr) => Ok(Box::new(BufStream::new(
+            connect_tcp(addr, url.tcp_nodelay).await?,
+        ))),
+        Transport::Tls(addr) => connect_tls(addr, url.tcp_nodelay).await,
+        #[cfg(unix)]
+        Transport::Unix(path) => Ok(Box::new(BufStream::new(
+            tokio::net::UnixStream::connect(path)
+                .await
+                .map_err(MemcacheError::IOError)?,
+        ))),
+        #[cfg(not(unix))]
+        Transport::Unix(path) => Err(invalid_url(path, "unix sockets are not supported")),
+    }
+}
+
+impl Client<BoxedConnection> {
+    /// Connect to the memcached server described by a connection URL, e.g.
+    /// `memcache://127.0.0.1:11211?timeout=500ms&tcp_nodelay=true`. See the
+    /// [`connect`](crate::connect) module for the supported URLs.
+    ///
+    /// The client reconnects to the same URL when the connection fails, see
+    /// [`Client::with_connector`].
+    pub async fn connect(url: &str) -> Result<Self, MemcacheError> {
+        Self::connect_with_options(url, &ClientOptions::default()).await
+    }
+
+    /// Like [`Client::connect`], applying the timeouts from `options` unless the URL
+    /// overrides them.
+    pub async fn connect_with_options(
+        url: &str,
+        options: &ClientOptions,
+    ) -> Result<Self, MemcacheError> {
+        let (parsed, options) = Self::prepare(url, options)?;
+        let connection = connect_url(&parsed).await?;
+        Self::from_parts(connection, parsed, &options)
+    }
+
+    /// Connects like [`Client::connect_with_options`], returning the connection and the
+    /// options with the settings of the URL applied instead of the client, for callers
+    /// wrapping the connection (e.g. the pools)
+    pub(crate) async fn connect_parts(
+        url: &str,
+        options: &ClientOptions,
+    ) -> Result<(BoxedConnection, ClientOptions), MemcacheError> {
+        let (parsed, options) = Self::prepare(url, options)?;
+        Ok((connect_url(&parsed).await?, options))
+    }
+
+    /// Create a client for the server described by a connection URL without connecting to
+    /// it. The connection is established by the first command, which fails with
+    /// [`MemcacheError::NotConnected`] while the server can't be reached. Only an invalid URL
+    /// or namespace is reported right away.
+    pub fn connect_lazy(url: &str, options: &ClientOptions) -> Result<Self, MemcacheError> {
+        let (parsed, options) = Self::prepare(url, options)?;
+        let mut retval = Self::from_parts(Box::new(Disconnected), parsed, &options)?;
+        retval.broken = true;
+        Ok(retval)
+    }
+
+    /// Parses the URL and merges its timeouts with `options`
+    fn prepare(
+        url: &str,
+        options: &ClientOptions,
+    ) -> Result<(std::sync::Arc<ConnectionUrl>, ClientOptions), MemcacheError> {
+        // fail before connecting
+        crate::protocol::check_namespace(options.namespace.as_deref())?;
+        let mut parsed = parse_url(url)?;
+        parsed.timeout = parsed.timeout.or(options.connect_timeout);
+        let options = ClientOptions {
+            connect_timeout: parsed.timeout,
+            read_timeout: parsed.read_timeout.or(options.read_timeout),
+            write_timeout: parsed.write_timeout.or(options.write_timeout),
+            ..options.clone()
+        };
+        Ok((std::sync::Arc::new(parsed), options))
+    }
+
+    fn from_parts(
+        connection: BoxedConnection,
+        parsed: std::sync::Arc<ConnectionUrl>,
+        options: &ClientOptions,
+    ) -> Result<Self, MemcacheError> {
+        let mut retval = Client::with_options(connection, options)?;
+        retval.connector = Some(Connector(Box::new(move || {
+            let parsed = parsed.clone();
+            Box::pin(async move { connect_url(&parsed).await })
+        })));
+        Ok(retval)
+    }
+}
+
+async fn connect_url(parsed: &ConnectionUrl) -> Result<BoxedConnection, MemcacheError> {
+    debug!("connect {:?}", parsed);
+    match parsed.timeout {
+        Some(x) => match tokio::time::timeout(x, connect_transport(parsed)).await {
+            Ok(x) => x,
+            Err(_) => {
+                error!("connect: timed out connecting to {:?}", parsed.transport);
+                Err(MemcacheError::Timeout)
+            }
+        },
+        None => connect_transport(parsed).await,
+    }
+}
+
+/// Placeholder connection of a lazily connected client
+struct Disconnected;
+
+fn not_connected() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotConnected, "not connected")
+}
+
+impl tokio::io::AsyncRead for Disconnected {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+        _: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Err(not_connected()))
+    }
+}
+
+impl tokio::io::AsyncBufRead for Disconnected {
+    fn poll_fill_buf(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<&[u8]>> {
+        std::task::Poll::Ready(Err(not_connected()))
+    }
+
+    fn consume(self: std::pin::Pin<&mut Self>, _: usize) {}
+}
+
+impl tokio::io::AsyncWrite for Disconnected {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+        _: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::task::Poll::Ready(Err(not_connected()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Err(not_connected()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_connection_urls() {
+        let Ok(x) = parse_url("memcache://10.0.0.1:11212?timeout=500ms&tcp_nodelay=true") else {
+            panic!("parse_url() failed");
+        };
+        assert_eq!(x.transport, Transport::Tcp("10.0.0.1:11212".to_string()));
+        assert_eq!(x.timeout, Some(Duration::from_millis(500)));
+        assert!(x.tcp_nodelay);
+
+        let Ok(x) = parse_url("memcache+tls://cache.example.com") else {
+            panic!("parse_url() failed on tls");
+        };
+        assert_eq!(
+            x.transport,
+            Transport::Tls("cache.example.com:11211".to_string())
+        );
+        assert_eq!(x.timeout, None);
+
+        let Ok(x) = parse_url("memcache://[::1]") else {
+            panic!("parse_url() failed on ipv6");
+        };
+        assert_eq!(x.transport, Transport::Tcp("[::1]:11211".to_string()));
+
+        let Ok(x) = parse_url("memcache+unix:///var/run/memcached.sock?timeout=2s&read_timeout=1")
+        else {
+            panic!("parse_url() failed on unix");
+        };
+        assert_eq!(
+            x.transport,
+            Transport::Unix("/var/run/memcached.sock".to_string())
+        );
+        assert_eq!(x.timeout, Some(Duration::from_secs(2)));
+        assert_eq!(x.read_timeout, Some(Duration::from_millis(1)));
+
+        assert!(parse_url("redis://localhost").is_err());
+        assert!(parse_url("memcache://localhost?timeout=soon").is_err());
+        assert!(parse_url("memcache://localhost?timeout=999999999999999999m").is_err());
+        assert!(parse_url("localhost:11211").is_err());
+    }
+}
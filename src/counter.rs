@@ -0,0 +1,122 @@
+//! Counters created on first use
+//!
+//! [`Client::counter`] updates a counter with the meta arithmetic command, creating it with
+//! its TTL and initial value (`N` and `J` flags) if it doesn't exist. The creation and the
+//! update are a single atomic command, unlike an `add` followed by an `incr` that races with
+//! the other clients. Requires the meta protocol.
+//! ```no_run
+//! # async fn example(client: &mut yamemcache::Client<yamemcache::BoxedConnection>)
+//! # -> Result<(), yamemcache::error::MemcacheError> {
+//! let views = client.counter("page:42:views").ttl(3600).incr_by(1).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::error::MemcacheError;
+use crate::protocol::{ArithmeticMode, ArithmeticOptions, ArithmeticResult};
+use crate::{AsyncReadWriteUnpin, Client};
+
+use log::error;
+
+/// Counter stored under a key, see [`Client::counter`]
+pub struct Counter<'a, T: AsyncReadWriteUnpin> {
+    client: &'a mut Client<T>,
+    key: &'a str,
+    ttl: u32,
+    initial: u64,
+}
+
+impl<T: AsyncReadWriteUnpin> Client<T> {
+    /// Counter stored under `key`, created with the value 0 and no expiration when it doesn't
+    /// exist, see the [module documentation](crate::counter)
+    pub fn counter<'a>(&'a mut self, key: &'a str) -> Counter<'a, T> {
+        Counter {
+            client: self,
+            key,
+            ttl: 0,
+            initial: 0,
+        }
+    }
+}
+
+impl<'a, T: AsyncReadWriteUnpin> Counter<'a, T> {
+    /// TTL of the counter when it is created, 0 to never expire. The TTL of an existing
+    /// counter is left alone.
+    pub fn ttl(mut self, t: u32) -> Self {
+        self.ttl = t;
+        self
+    }
+
+    /// Value of the counter before its first update
+    pub fn initial(mut self, v: u64) -> Self {
+        self.initial = v;
+        self
+    }
+
+    /// Adds `n` to the counter and returns its new value
+    pub async fn incr_by(&mut self, n: u64) -> Result<u64, MemcacheError> {
+        self.update(ArithmeticMode::Increment, n).await
+    }
+
+    /// Subtracts `n` from the counter and returns its new value. Counters don't go below 0.
+    pub async fn decr_by(&mut self, n: u64) -> Result<u64, MemcacheError> {
+        self.update(ArithmeticMode::Decrement, n).await
+    }
+
+    /// Adds 1 to the counter and returns its new value
+    pub async fn incr(&mut self) -> Result<u64, MemcacheError> {
+        self.incr_by(1).await
+    }
+
+    /// Subtracts 1 from the counter and returns its new value
+    pub async fn decr(&mut self) -> Result<u64, MemcacheError> {
+        self.decr_by(1).await
+    }
+
+    async fn update(&mut self, mode: ArithmeticMode, n: u64) -> Result<u64, MemcacheError> {
+        // memcached stores J as is when creating the counter, without applying the delta
+        let created = match mode {
+            ArithmeticMode::Increment => self.initial.saturating_add(n),
+            ArithmeticMode::Decrement => self.initial.saturating_sub(n),
+        };
+        let opts = ArithmeticOptions::new()
+            .mode(mode)
+            .delta(n)
+            .vivify(self.ttl, created)
+            .return_value(true);
+        match self.client.arithmetic(self.key, &opts).await? {
+            ArithmeticResult::Updated(Some(x)) => Ok(x),
+            ArithmeticResult::Updated(None) => {
+                error!("counter: no value returned");
+                Err(MemcacheError::bad_response("HD").context("arithmetic", Some(self.key)))
+            }
+            ArithmeticResult::NotFound => {
+                Err(MemcacheError::NotFound.context("arithmetic", Some(self.key)))
+            }
+            ArithmeticResult::NotStored => {
+                Err(MemcacheError::NotStored.context("arithmetic", Some(self.key)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn counter() {
+        let response = b"VA 1\r\n5\r\nVA 1\r\n3\r\nNS\r\n";
+        let mut c = Client::new(tokio::io::join(
+            std::io::Cursor::new(response.to_vec()),
+            tokio::io::sink(),
+        ));
+        let mut counter = c.counter("hits").ttl(60);
+        assert_eq!(counter.incr_by(5).await.unwrap(), 5);
+        assert_eq!(counter.decr_by(2).await.unwrap(), 3);
+        assert!(matches!(
+            counter.incr().await.unwrap_err().root(),
+            MemcacheError::NotStored
+        ));
+    }
+}
@@ -0,0 +1,75 @@
+//! [deadpool](https://docs.rs/deadpool) integration, enabled with the `deadpool` feature
+
+use tokio::io::BufStream;
+use tokio::net::TcpStream;
+
+use ::deadpool::managed::{self, Metrics, RecycleError, RecycleResult};
+
+use crate::error::MemcacheError;
+use crate::{Client, Health};
+
+use log::debug;
+
+/// Pool of TCP connections managed by deadpool
+pub type Pool = managed::Pool<Manager>;
+
+/// deadpool manager creating TCP connections to a single memcached server
+#[derive(Debug, Clone)]
+pub struct Manager {
+    addr: String,
+}
+
+impl Manager {
+    /// Create a manager connecting to `addr` ("host:port")
+    pub fn new<A: Into<String>>(addr: A) -> Self {
+        Manager { addr: addr.into() }
+    }
+}
+
+impl managed::Manager for Manager {
+    type Type = Client<BufStream<TcpStream>>;
+    type Error = MemcacheError;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        debug!("deadpool: connecting to {}", self.addr);
+        let stream = TcpStream::connect(&self.addr)
+            .await
+            .map_err(MemcacheError::IOError)?;
+        Ok(Client::new(BufStream::new(stream)))
+    }
+
+    async fn recycle(&self, obj: &mut Self::Type, _: &Metrics) -> RecycleResult<Self::Error> {
+        // a ping would be sent over a connection that is out of sync
+        if !obj.is_healthy() {
+            return Err(RecycleError::message("connection broken"));
+        }
+        match obj.ping().await {
+            Health::Healthy { .. } => Ok(()),
+            Health::Unhealthy(e) => Err(RecycleError::Backend(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::deadpool::managed::Manager as _;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn broken_connections_are_not_recycled() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let manager = Manager::new(listener.local_addr().unwrap().to_string());
+        let mut client = manager.create().await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.write_all(b"MN\r\nMN\r\n").await.unwrap();
+
+        let metrics = Metrics::default();
+        assert!(manager.recycle(&mut client, &metrics).await.is_ok());
+        client.broken = true;
+        assert!(matches!(
+            manager.recycle(&mut client, &metrics).await,
+            Err(RecycleError::Message(_))
+        ));
+    }
+}
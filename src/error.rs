@@ -1,5 +1,10 @@
 //! Error return value
 
+/// Longest key recorded in an [`ErrorContext`], in bytes
+pub const CONTEXT_KEY_LENGTH: usize = 64;
+/// Longest response recorded in a [`MemcacheError::BadServerResponse`], in bytes
+pub const RESPONSE_LENGTH: usize = 256;
+
 /// Main error type returned by yamemcache
 #[derive(Debug)]
 pub enum MemcacheError {
@@ -7,8 +12,358 @@ pub enum MemcacheError {
     IOError(std::io::Error),
     /// Key provided did not pass validation
     BadKey,
-    /// Server responded in an unexptected way
-    BadServerResponse,
-    /// Server claims the query is invalid
-    BadQuery,
+    /// Server responded in an unexptected way, with the offending response line (lossily
+    /// decoded, truncated to [`RESPONSE_LENGTH`] bytes)
+    BadServerResponse(String),
+    /// Server answered with an error line (`ERROR`, `CLIENT_ERROR` or `SERVER_ERROR`)
+    Protocol(ProtocolError),
+    /// Value was not stored, e.g. `add` of an existing key
+    NotStored,
+    /// Item was modified since its CAS value was read
+    Exists,
+    /// Item does not exist
+    NotFound,
+    /// Server did not respond in time, the connection state is unknown afterwards
+    Timeout,
+    /// Server closed the connection
+    ConnectionClosed,
+    /// There is no connection to the server and it could not be (re-)established
+    NotConnected,
+    /// The circuit breaker of the server is open, the command was not sent
+    CircuitOpen,
+    /// Value is larger than the configured limit, nothing was sent
+    ValueTooLarge { size: usize, limit: usize },
+    /// Key, namespace included, is longer than the server accepts, nothing was sent
+    KeyTooLong { length: usize, limit: usize },
+    /// Error of a [`Client`](crate::Client) command, with the command and key it failed on.
+    /// Use [`MemcacheError::root`] to match the error itself.
+    Context(Box<ErrorContext>),
+}
+
+/// Category of a [`MemcacheError`], to decide how to handle it, see [`MemcacheError::kind`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The connection failed or was closed, it has to be re-established
+    Connection,
+    /// The server did not respond in time, the connection has to be re-established
+    Timeout,
+    /// The server can't be reached (or its circuit breaker is open), nothing was sent
+    Unavailable,
+    /// The request is invalid (key, value, options or command), retrying won't help. Includes
+    /// the values the server can't store, see [`ProtocolError::is_deterministic`].
+    InvalidRequest,
+    /// The value read couldn't be decoded
+    InvalidData,
+    /// The server failed to process a valid request (`SERVER_ERROR`), e.g. busy
+    Server,
+    /// The response couldn't be parsed, the connection is out of sync
+    Protocol,
+    /// The command was processed but didn't apply (not stored, CAS conflict, not found)
+    Outcome,
+}
+
+/// Prefix of an error line returned by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// `ERROR`, the command is unknown
+    Error,
+    /// `CLIENT_ERROR`, the request is malformed or invalid
+    ClientError,
+    /// `SERVER_ERROR`, the server failed to process a valid request
+    ServerError,
+}
+
+/// Error line returned by the server, e.g. `SERVER_ERROR object too large for cache`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolError {
+    /// Prefix of the line
+    pub code: ErrorCode,
+    /// Rest of the line, empty if there is none
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ProtocolError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Parses an error line, None if `line` isn't one
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        // ERROR is a prefix of the others
+        let (code, message) = if let Some(x) = line.strip_prefix("CLIENT_ERROR") {
+            (ErrorCode::ClientError, x)
+        } else if let Some(x) = line.strip_prefix("SERVER_ERROR") {
+            (ErrorCode::ServerError, x)
+        } else if let Some(x) = line.strip_prefix("ERROR") {
+            (ErrorCode::Error, x)
+        } else {
+            return None;
+        };
+        if !message.is_empty() && !message.starts_with(' ') {
+            return None;
+        }
+        Some(ProtocolError::new(code, message.trim_start()))
+    }
+
+    /// The value is larger than the item size limit of the server
+    pub fn is_object_too_large(&self) -> bool {
+        self.code == ErrorCode::ServerError && self.message.starts_with("object too large")
+    }
+
+    /// The server ran out of memory storing the value
+    pub fn is_out_of_memory(&self) -> bool {
+        self.code == ErrorCode::ServerError && self.message.starts_with("out of memory")
+    }
+
+    /// The server error fails the same way whatever the number of retries: the value is too
+    /// large, or the server can't make room for it
+    pub fn is_deterministic(&self) -> bool {
+        self.is_object_too_large() || self.is_out_of_memory()
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = match self.code {
+            ErrorCode::Error => "unknown command",
+            ErrorCode::ClientError => "client error",
+            ErrorCode::ServerError => "server error",
+        };
+        if self.message.is_empty() {
+            write!(f, "{}", code)
+        } else {
+            write!(f, "{}: {}", code, self.message)
+        }
+    }
+}
+
+/// Command and key of a failed command
+#[derive(Debug)]
+pub struct ErrorContext {
+    /// Name of the command, e.g. `"set"`
+    pub command: &'static str,
+    /// Key of the command, truncated to [`CONTEXT_KEY_LENGTH`] bytes
+    pub key: Option<String>,
+    /// Error the command failed with
+    pub error: MemcacheError,
+}
+
+impl MemcacheError {
+    /// Error of an unexpected or malformed response `line`
+    pub(crate) fn bad_response(line: impl AsRef<[u8]>) -> Self {
+        let line = line.as_ref();
+        let line = line.strip_suffix(b"\r\n").unwrap_or(line);
+        let mut x = String::from_utf8_lossy(&line[..line.len().min(RESPONSE_LENGTH)]).into_owned();
+        if line.len() > RESPONSE_LENGTH {
+            x.push_str("...");
+        }
+        MemcacheError::BadServerResponse(x)
+    }
+
+    /// Attaches the command and key the error occurred on. An error that already has a
+    /// context keeps it.
+    pub fn context(self, command: &'static str, key: Option<&str>) -> Self {
+        if let MemcacheError::Context(_) = self {
+            return self;
+        }
+        let key = key.map(|x| match x.char_indices().nth(CONTEXT_KEY_LENGTH) {
+            Some((end, _)) => format!("{}...", &x[..end]),
+            None => x.to_string(),
+        });
+        MemcacheError::Context(Box::new(ErrorContext {
+            command,
+            key,
+            error: self,
+        }))
+    }
+
+    /// Category of the error
+    pub fn kind(&self) -> ErrorKind {
+        match self.root() {
+            MemcacheError::IOError(e) => match e.kind() {
+                std::io::ErrorKind::InvalidInput | std::io::ErrorKind::Unsupported => {
+                    ErrorKind::InvalidRequest
+                }
+                std::io::ErrorKind::InvalidData => ErrorKind::InvalidData,
+                _ => ErrorKind::Connection,
+            },
+            MemcacheError::ConnectionClosed => ErrorKind::Connection,
+            MemcacheError::Timeout => ErrorKind::Timeout,
+            MemcacheError::NotConnected | MemcacheError::CircuitOpen => ErrorKind::Unavailable,
+            MemcacheError::BadKey
+            | MemcacheError::KeyTooLong { .. }
+            | MemcacheError::ValueTooLarge { .. } => ErrorKind::InvalidRequest,
+            MemcacheError::Protocol(x) => match x.code {
+                ErrorCode::Error | ErrorCode::ClientError => ErrorKind::InvalidRequest,
+                ErrorCode::ServerError if x.is_deterministic() => ErrorKind::InvalidRequest,
+                ErrorCode::ServerError => ErrorKind::Server,
+            },
+            MemcacheError::BadServerResponse(_) => ErrorKind::Protocol,
+            MemcacheError::NotStored | MemcacheError::Exists | MemcacheError::NotFound => {
+                ErrorKind::Outcome
+            }
+            MemcacheError::Context(x) => x.error.kind(),
+        }
+    }
+
+    /// Whether the command may succeed if sent again, on a new connection for connection
+    /// errors and timeouts. Commands that aren't idempotent (e.g. increments or appends) may
+    /// have been applied before a connection error or timeout.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Connection | ErrorKind::Timeout | ErrorKind::Server
+        )
+    }
+
+    /// Whether the connection can't be used anymore and has to be re-established
+    pub fn needs_reconnect(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Connection | ErrorKind::Timeout | ErrorKind::Protocol
+        )
+    }
+
+    /// The error without its context
+    pub fn root(&self) -> &MemcacheError {
+        match self {
+            MemcacheError::Context(x) => &x.error,
+            x => x,
+        }
+    }
+
+    /// The error without its context, by value
+    pub fn into_root(self) -> MemcacheError {
+        match self {
+            MemcacheError::Context(x) => x.error,
+            x => x,
+        }
+    }
+}
+
+impl std::fmt::Display for MemcacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemcacheError::IOError(e) => write!(f, "I/O error: {}", e),
+            MemcacheError::BadKey => write!(f, "invalid key"),
+            MemcacheError::BadServerResponse(x) => write!(f, "unexpected server response {:?}", x),
+            MemcacheError::Protocol(x) => write!(f, "{}", x),
+            MemcacheError::NotStored => write!(f, "value not stored"),
+            MemcacheError::Exists => write!(f, "item modified since it was read"),
+            MemcacheError::NotFound => write!(f, "item not found"),
+            MemcacheError::Timeout => write!(f, "timed out waiting for the server"),
+            MemcacheError::ConnectionClosed => write!(f, "connection closed by the server"),
+            MemcacheError::NotConnected => write!(f, "not connected to the server"),
+            MemcacheError::CircuitOpen => write!(f, "circuit breaker open"),
+            MemcacheError::ValueTooLarge { size, limit } => {
+                write!(f, "value of {} bytes exceeds the limit of {}", size, limit)
+            }
+            MemcacheError::KeyTooLong { length, limit } => {
+                write!(f, "key of {} bytes exceeds the limit of {}", length, limit)
+            }
+            MemcacheError::Context(x) => match &x.key {
+                Some(key) => write!(f, "{} {:?} failed: {}", x.command, key, x.error),
+                None => write!(f, "{} failed: {}", x.command, x.error),
+            },
+        }
+    }
+}
+
+impl std::error::Error for MemcacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MemcacheError::IOError(e) => Some(e),
+            // the message of the error is already part of the context
+            MemcacheError::Context(x) => x.error.source(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn display_and_source() {
+        let e = MemcacheError::ValueTooLarge { size: 5, limit: 4 };
+        assert_eq!(e.to_string(), "value of 5 bytes exceeds the limit of 4");
+        assert!(e.source().is_none());
+
+        let e = MemcacheError::IOError(std::io::Error::other("boom"));
+        assert_eq!(e.to_string(), "I/O error: boom");
+        assert_eq!(e.source().unwrap().to_string(), "boom");
+        let boxed: Box<dyn Error + Send + Sync> = e.into();
+        assert!(boxed.downcast_ref::<MemcacheError>().is_some());
+    }
+
+    #[test]
+    fn context() {
+        let e = MemcacheError::NotStored.context("set", Some("user:42"));
+        assert_eq!(e.to_string(), r#"set "user:42" failed: value not stored"#);
+        assert!(matches!(e.root(), MemcacheError::NotStored));
+        let e = e.context("get", None);
+        assert_eq!(e.to_string(), r#"set "user:42" failed: value not stored"#);
+        assert!(matches!(e.into_root(), MemcacheError::NotStored));
+
+        let key = "k".repeat(100);
+        let e = MemcacheError::BadKey.context("get", Some(&key));
+        let MemcacheError::Context(x) = &e else {
+            panic!("no context");
+        };
+        assert_eq!(
+            x.key.as_deref(),
+            Some(format!("{}...", &key[..64]).as_str())
+        );
+        let e = MemcacheError::IOError(std::io::Error::other("boom")).context("noop", None);
+        assert_eq!(e.to_string(), "noop failed: I/O error: boom");
+        assert_eq!(e.source().unwrap().to_string(), "boom");
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let e = ProtocolError::parse("SERVER_ERROR object too large for cache").unwrap();
+        assert_eq!(e.code, ErrorCode::ServerError);
+        assert_eq!(e.message, "object too large for cache");
+        assert!(e.is_object_too_large() && !e.is_out_of_memory());
+        assert_eq!(e.to_string(), "server error: object too large for cache");
+        let e = ProtocolError::parse("ERROR").unwrap();
+        assert_eq!(e, ProtocolError::new(ErrorCode::Error, ""));
+        assert_eq!(e.to_string(), "unknown command");
+        assert_eq!(
+            ProtocolError::parse("CLIENT_ERROR bad data chunk\r\n"),
+            Some(ProtocolError::new(ErrorCode::ClientError, "bad data chunk"))
+        );
+        assert_eq!(ProtocolError::parse("ERRORS"), None);
+        assert_eq!(ProtocolError::parse("HD"), None);
+    }
+
+    #[test]
+    fn kinds() {
+        let e = MemcacheError::ConnectionClosed.context("get", Some("a"));
+        assert_eq!(e.kind(), ErrorKind::Connection);
+        assert!(e.is_retryable() && e.needs_reconnect());
+        let e = MemcacheError::IOError(std::io::Error::from(std::io::ErrorKind::Unsupported));
+        assert_eq!(e.kind(), ErrorKind::InvalidRequest);
+        assert!(!e.is_retryable() && !e.needs_reconnect());
+        assert_eq!(MemcacheError::Exists.kind(), ErrorKind::Outcome);
+        assert!(!MemcacheError::CircuitOpen.is_retryable());
+        let e = ProtocolError::new(ErrorCode::ServerError, "busy");
+        assert!(MemcacheError::Protocol(e).is_retryable());
+        for line in [
+            "SERVER_ERROR object too large for cache",
+            "SERVER_ERROR out of memory storing object",
+        ] {
+            let e = MemcacheError::Protocol(ProtocolError::parse(line).unwrap());
+            assert_eq!(e.kind(), ErrorKind::InvalidRequest);
+            assert!(!e.is_retryable() && !e.needs_reconnect());
+        }
+        assert!(!MemcacheError::bad_response("?").is_retryable());
+        assert!(MemcacheError::bad_response("?").needs_reconnect());
+    }
 }
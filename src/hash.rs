@@ -0,0 +1,59 @@
+//! Key hashing used by [`ShardedClient`](crate::ShardedClient) to place keys on servers
+//!
+//! The hash has to match the one of the other clients sharing the cluster, otherwise they look
+//! for the same key on different servers. [`Md5Hasher`] is the ketama hash of libmemcached,
+//! [`Crc32Hasher`] the default hash of libmemcached, python-memcached and the PHP extensions
+//! (combined with [`Distribution::Modula`](crate::sharded::Distribution::Modula)).
+
+/// Hash function mapping keys to a position on the ring (or a score, or a bucket, depending on
+/// the distribution)
+pub trait KeyHasher: std::fmt::Debug + Send + Sync {
+    fn hash(&self, key: &[u8]) -> u32;
+}
+
+/// First 32 bits (little endian) of the MD5 digest of the key, as used by ketama
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Md5Hasher;
+
+impl KeyHasher for Md5Hasher {
+    fn hash(&self, key: &[u8]) -> u32 {
+        let digest = md5::compute(key);
+        u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]])
+    }
+}
+
+/// Bits 16 to 30 of the CRC32 of the key (`MEMCACHED_HASH_CRC`)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Crc32Hasher;
+
+impl KeyHasher for Crc32Hasher {
+    fn hash(&self, key: &[u8]) -> u32 {
+        (crc32(key) >> 16) & 0x7fff
+    }
+}
+
+/// CRC-32 (IEEE 802.3), keys are short enough not to need a lookup table
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_match_other_clients() {
+        assert_eq!(Crc32Hasher.hash(b"foo"), 3187);
+        assert_eq!(Crc32Hasher.hash(b"hello world"), 3402);
+        assert_eq!(Md5Hasher.hash(b"foo"), 3675831724);
+        assert_eq!(Md5Hasher.hash(b"hello world"), 3141252702);
+    }
+}
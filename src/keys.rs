@@ -0,0 +1,228 @@
+//! Keys longer than memcached accepts
+//!
+//! Memcached rejects keys longer than [`MAX_KEY_LENGTH`] bytes, by default they fail with
+//! [`MemcacheError::KeyTooLong`] before anything is sent. With
+//! [`ClientOptions::long_keys`](crate::ClientOptions::long_keys) (or [`Meta::long_keys`]) set to
+//! [`LongKeys::Hash`], such keys are replaced by their first bytes followed by the MD5 digest of
+//! the whole key, namespace included. The other keys are sent as is, so clients sharing the
+//! server only need the same policy to find the hashed keys.
+//!
+//! Keys sharing a digest would overwrite each other's values. [`LongKeys::HashAndKeep`] also
+//! stores the original key in front of the value, marked with [`FLAG_ORIGINAL_KEY`]: it shows
+//! what a hashed key holds when inspecting the server, and the get commands report a value
+//! stored under another key as missing.
+
+use std::borrow::Cow;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+use crate::error::MemcacheError;
+use crate::protocol::{check_key_invalid, Meta, RawValue};
+
+use log::error;
+
+/// Longest key accepted by memcached, in bytes
+pub const MAX_KEY_LENGTH: usize = 250;
+/// Flag of values starting with their original key, see [`LongKeys::HashAndKeep`]
+pub const FLAG_ORIGINAL_KEY: u32 = 1 << 19;
+
+/// Bytes of the original key kept in front of the digest
+const HASHED_PREFIX_LENGTH: usize = MAX_KEY_LENGTH - 33;
+
+/// Handling of the keys longer than [`MAX_KEY_LENGTH`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LongKeys {
+    /// Fail with [`MemcacheError::KeyTooLong`]
+    #[default]
+    Reject,
+    /// Replace them by `<first 217 bytes>:<MD5 hex digest>`
+    Hash,
+    /// Hash them and store the original key in front of the value
+    HashAndKeep,
+}
+
+/// `key` shortened to `<prefix>:<digest>`
+fn hash_key(key: &str) -> String {
+    // keys are ASCII, any byte is a char boundary
+    format!(
+        "{}:{:x}",
+        &key[..HASHED_PREFIX_LENGTH],
+        md5::compute(key.as_bytes())
+    )
+}
+
+impl Meta {
+    /// Rejects keys with spaces or control characters, and keys too long to be sent as is
+    pub(crate) fn check_key(&self, key: &str, cmd: &str) -> Result<(), MemcacheError> {
+        // key cannot contain control characters or space
+        if check_key_invalid(key) {
+            error!("{}: invalid key", cmd);
+            return Err(MemcacheError::BadKey);
+        }
+        let length = self.namespaced(key).len();
+        if self.long_keys_policy() == LongKeys::Reject && length > MAX_KEY_LENGTH {
+            error!("{}: key of {} bytes is too long", cmd, length);
+            return Err(MemcacheError::KeyTooLong {
+                length,
+                limit: MAX_KEY_LENGTH,
+            });
+        }
+        Ok(())
+    }
+
+    /// `key` as sent to the server, with the namespace prepended and hashed if too long
+    pub(crate) fn wire_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        let key = self.namespaced(key);
+        if self.long_keys_policy() == LongKeys::Reject || key.len() <= MAX_KEY_LENGTH {
+            return key;
+        }
+        Cow::Owned(hash_key(&key))
+    }
+
+    /// Binary `key` as sent to the server with the namespace prepended: base64 encoded (the
+    /// returned flag is set) or, if the encoded key is too long and the policy allows it,
+    /// hashed. The original key is never stored in front of the value of a binary key.
+    pub(crate) fn wire_bytes_key(
+        &self,
+        key: &[u8],
+        cmd: &str,
+    ) -> Result<(String, bool), MemcacheError> {
+        if key.is_empty() {
+            error!("{}: empty key", cmd);
+            return Err(MemcacheError::BadKey);
+        }
+        let encoded = BASE64_STANDARD.encode(self.namespaced_bytes(key));
+        if encoded.len() <= MAX_KEY_LENGTH {
+            return Ok((encoded, true));
+        }
+        if self.long_keys_policy() == LongKeys::Reject {
+            error!("{}: key of {} bytes is too long", cmd, encoded.len());
+            return Err(MemcacheError::KeyTooLong {
+                length: encoded.len(),
+                limit: MAX_KEY_LENGTH,
+            });
+        }
+        Ok((hash_key(&encoded), false))
+    }
+
+    /// Compresses `data` and prepends the original key if it is hashed, None if it should be
+    /// stored as is
+    pub(crate) fn encode_value(
+        &self,
+        key: &str,
+        data: RawValue<&[u8]>,
+    ) -> Result<Option<RawValue>, MemcacheError> {
+        let compressed = self.compress(data)?;
+        let key = self.namespaced(key);
+        if self.long_keys_policy() != LongKeys::HashAndKeep || key.len() <= MAX_KEY_LENGTH {
+            return Ok(compressed);
+        }
+        let data = compressed.as_ref().map_or(data, RawValue::as_borrowed);
+        Ok(Some(RawValue {
+            data: [key.as_bytes(), b" ", data.data].concat(),
+            flags: data.flags | FLAG_ORIGINAL_KEY,
+            time: data.time,
+            cas: data.cas,
+        }))
+    }
+
+    /// Removes the original key in front of `value` and decompresses it. None if `value`
+    /// was stored under another key sharing the hashed key.
+    pub(crate) fn decode_value(
+        &self,
+        key: &str,
+        mut value: RawValue,
+    ) -> Result<Option<RawValue>, MemcacheError> {
+        if self.long_keys_policy() != LongKeys::Reject && value.flags & FLAG_ORIGINAL_KEY != 0 {
+            let Some(end) = value.data.iter().position(|x| *x == b' ') else {
+                error!("get: missing original key");
+                return Err(MemcacheError::bad_response(&value.data));
+            };
+            if value.data[..end] != *self.namespaced(key).as_bytes() {
+                error!(
+                    "get: {} holds the value of {}",
+                    key,
+                    String::from_utf8_lossy(&value.data[..end])
+                );
+                return Ok(None);
+            }
+            value.data.drain(..=end);
+            value.flags &= !FLAG_ORIGINAL_KEY;
+        }
+        self.decompress(value).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_keys() {
+        let long = "k".repeat(300);
+        let protocol = Meta::new().namespace(Some("app:".to_string())).unwrap();
+        assert!(protocol.check_key(&"k".repeat(246), "test").is_ok());
+        assert!(matches!(
+            protocol.check_key(&"k".repeat(247), "test"),
+            Err(MemcacheError::KeyTooLong {
+                length: 251,
+                limit: MAX_KEY_LENGTH
+            })
+        ));
+        assert!(matches!(
+            protocol.check_key("a b", "test"),
+            Err(MemcacheError::BadKey)
+        ));
+
+        let protocol = protocol.long_keys(LongKeys::Hash);
+        assert!(protocol.check_key(&long, "test").is_ok());
+        assert_eq!(protocol.wire_key("short"), "app:short");
+        let hashed = protocol.wire_key(&long);
+        assert_eq!(hashed.len(), MAX_KEY_LENGTH);
+        assert!(hashed.starts_with("app:kkk"));
+        assert_ne!(hashed, protocol.wire_key(&"k".repeat(301)));
+        let value = RawValue::from_vec(b"data".to_vec());
+        assert!(protocol
+            .encode_value(&long, value.as_borrowed())
+            .unwrap()
+            .is_none());
+
+        let protocol = protocol.long_keys(LongKeys::HashAndKeep);
+        let stored = protocol
+            .encode_value(&long, value.as_borrowed())
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.flags, FLAG_ORIGINAL_KEY);
+        assert_eq!(stored.data, format!("app:{} data", long).into_bytes());
+        let read = protocol.decode_value(&long, stored.clone()).unwrap();
+        assert_eq!(read, Some(value));
+        // another key with the same hashed key
+        assert_eq!(protocol.decode_value("other", stored).unwrap(), None);
+    }
+
+    #[test]
+    fn long_binary_keys() {
+        let key = [0xffu8; 200];
+        let protocol = Meta::new();
+        let (short, base64) = protocol.wire_bytes_key(&key[..100], "test").unwrap();
+        assert!(base64);
+        assert_eq!(short.len(), 136);
+        assert!(matches!(
+            protocol.wire_bytes_key(&key, "test"),
+            Err(MemcacheError::KeyTooLong {
+                length: 268,
+                limit: MAX_KEY_LENGTH
+            })
+        ));
+        assert!(matches!(
+            protocol.wire_bytes_key(&[], "test"),
+            Err(MemcacheError::BadKey)
+        ));
+
+        let protocol = protocol.long_keys(LongKeys::Hash);
+        let (hashed, base64) = protocol.wire_bytes_key(&key, "test").unwrap();
+        assert!(!base64);
+        assert_eq!(hashed.len(), MAX_KEY_LENGTH);
+        assert!(!check_key_invalid(&hashed));
+    }
+}
@@ -16,20 +16,74 @@
 //!
 //!   let mut client = yamemcache::Client::new(stream);
 //!
-//!   if let Ok(x) = client.get("hello").await {
+//!   if let Ok(x) = client.get::<String>("hello").await {
 //!     match x {
-//!       Some(x) => println!("Received data: {}", String::from_utf8_lossy(&x.data)),
+//!       Some(x) => println!("Received data: {}", x),
 //!       None => println!("No data received"),
 //!     }
 //!   }
 //! }
 //! ```
 
+pub mod backoff;
+#[cfg(feature = "bb8")]
+pub mod bb8;
+pub mod breaker;
+pub mod cache;
+pub mod chunked;
+pub mod compress;
+pub mod connect;
+pub mod counter;
+#[cfg(feature = "deadpool")]
+pub mod deadpool;
 pub mod error;
+pub mod hash;
+pub mod keys;
+pub mod lock;
+pub mod mirrored;
+pub mod pipeline;
+pub mod pool;
 pub mod protocol;
+pub mod ratelimit;
+pub mod sharded;
+pub mod shared;
+pub mod stats;
+mod text;
+mod timeout;
+#[cfg(any(feature = "tls", feature = "native-tls"))]
+mod tls;
+#[cfg(feature = "serde")]
+pub mod typed;
+pub mod value;
+pub mod watch;
 
-use error::MemcacheError;
-use protocol::RawValue;
+pub use bytes;
+pub use mirrored::MirroredClient;
+pub use sharded::ShardedClient;
+pub use shared::SharedClient;
+#[cfg(feature = "native-tls")]
+pub use tokio_native_tls::native_tls;
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+
+use std::time::Duration;
+
+use backoff::BackoffPolicy;
+use breaker::CircuitBreaker;
+use compress::Compression;
+use error::{ErrorKind, MemcacheError};
+use futures_util::{Stream, StreamExt};
+use keys::LongKeys;
+use log::{debug, error};
+use pipeline::{Pipeline, PipelineResponse};
+use protocol::{
+    ArithmeticOptions, ArithmeticResult, AutomoveMode, CrawlerClasses, DeleteOptions, DeleteResult,
+    GetOptions, GetResponse, ItemMetadata, LruCrawlerCommand, LruCrawlerResult, LruMode, RawValue,
+    ReassignResult, SetOptions, StoreResult,
+};
+use stats::{ConnectionStats, ServerStats, SettingsStats, SizeStats, SlabStats};
+use value::{FromMemcacheValue, ToMemcacheValue};
+use watch::{WatchEvent, WatchTarget};
 
 /// Helper trait that combines all the required traits for the io
 pub trait AsyncReadWriteUnpin:
@@ -41,11 +95,170 @@ impl<T: tokio::io::AsyncWrite + tokio::io::AsyncBufRead + std::marker::Unpin> As
 {
 }
 
+/// Type erased connection, used when the transport is only known at runtime
+pub type BoxedConnection = Box<dyn AsyncReadWriteUnpin + Send>;
+
+/// Future returned by a [`Client`] connector
+pub type ConnectFuture<T> =
+    std::pin::Pin<Box<dyn std::future::Future<Output = Result<T, MemcacheError>> + Send>>;
+
+/// Creates new connections for a [`Client`] that lost its connection
+struct Connector<T>(Box<dyn Fn() -> ConnectFuture<T> + Send + Sync>);
+
+impl<T> std::fmt::Debug for Connector<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Connector")
+    }
+}
+
+/// Runs a command. If it fails with an I/O error and the client has a connector, the
+/// connection is re-established and the command retried once. Commands are rejected while the
+/// circuit breaker, if any, is open. Given a command name and key, the errors carry them as
+/// context.
+macro_rules! reconnecting {
+    ($self:ident, $cmd:literal, $key:expr, $call:expr) => {
+        reconnecting!($self, $call).map_err(|e| e.context($cmd, $key))
+    };
+    ($self:ident, $call:expr) => {{
+        if let Some(breaker) = &$self.breaker {
+            breaker.admit()?;
+        }
+        let retval = 'call: {
+            if let Err(e) = $self.ensure_connected().await {
+                break 'call Err(e);
+            }
+            match $call {
+                Err(e) if e.kind() == ErrorKind::Connection && $self.connector.is_some() => {
+                    debug!("connection failed ({}), reconnecting", e);
+                    $self.broken = true;
+                    if let Err(e) = $self.reconnect().await {
+                        break 'call Err(e);
+                    }
+                    let retval = $call;
+                    if retval.as_ref().is_err_and(MemcacheError::needs_reconnect) {
+                        $self.broken = true;
+                    }
+                    retval
+                }
+                Err(e) if e.needs_reconnect() => {
+                    // after a timeout or a garbled response a late response would be read by
+                    // the next command, replace the connection
+                    $self.broken = true;
+                    Err(e)
+                }
+                x => x,
+            }
+        };
+        if let Some(breaker) = &$self.breaker {
+            breaker.record(&retval);
+        }
+        retval
+    }};
+}
+
+/// Settings of a [`Client`]: timeouts (`None` waits forever), reconnection and the handling
+/// of keys and values
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ClientOptions {
+    /// Retries when re-establishing a failed connection, single attempt by default
+    pub backoff: BackoffPolicy,
+    /// Time allowed to establish a connection
+    pub connect_timeout: Option<Duration>,
+    /// Time to wait for the server to send (more of) a response
+    pub read_timeout: Option<Duration>,
+    /// Time to wait for the server to accept (more of) a request
+    pub write_timeout: Option<Duration>,
+    /// Only use the classic text commands, see [`protocol::Meta::text_only`]
+    pub text_only: bool,
+    /// Compression of large values, see [`compress`]
+    pub compression: Option<Compression>,
+    /// Split values larger than the item size limit, see [`chunked`]
+    pub chunking: bool,
+    /// Prefix of all the keys, see [`protocol::Meta::namespace`]
+    pub namespace: Option<String>,
+    /// Handling of the keys longer than memcached accepts, see [`keys`]
+    pub long_keys: LongKeys,
+}
+
+impl ClientOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connect_timeout(mut self, t: Option<Duration>) -> Self {
+        self.connect_timeout = t;
+        self
+    }
+
+    pub fn read_timeout(mut self, t: Option<Duration>) -> Self {
+        self.read_timeout = t;
+        self
+    }
+
+    pub fn write_timeout(mut self, t: Option<Duration>) -> Self {
+        self.write_timeout = t;
+        self
+    }
+
+    pub fn backoff(mut self, x: BackoffPolicy) -> Self {
+        self.backoff = x;
+        self
+    }
+
+    pub fn text_only(mut self, x: bool) -> Self {
+        self.text_only = x;
+        self
+    }
+
+    pub fn compression(mut self, c: impl Into<Compression>) -> Self {
+        self.compression = Some(c.into());
+        self
+    }
+
+    pub fn chunking(mut self, x: bool) -> Self {
+        self.chunking = x;
+        self
+    }
+
+    pub fn namespace(mut self, prefix: impl Into<String>) -> Self {
+        self.namespace = Some(prefix.into());
+        self
+    }
+
+    pub fn long_keys(mut self, x: LongKeys) -> Self {
+        self.long_keys = x;
+        self
+    }
+}
+
+/// Time allowed for [`Client::ping`] to get a response
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Outcome of [`Client::ping`]
+#[derive(Debug)]
+pub enum Health {
+    /// The server responded after `latency`
+    Healthy { latency: Duration },
+    /// The server did not respond properly or in time
+    Unhealthy(MemcacheError),
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, Health::Healthy { .. })
+    }
+}
+
 /// Memcached client abstraction
 #[derive(Debug)]
 pub struct Client<T: AsyncReadWriteUnpin> {
     protocol: protocol::Meta,
     connection: T,
+    connector: Option<Connector<T>>,
+    backoff: BackoffPolicy,
+    breaker: Option<CircuitBreaker>,
+    broken: bool,
+    credentials: Option<(String, String)>,
 }
 
 impl<T: AsyncReadWriteUnpin> Client<T> {
@@ -54,37 +267,982 @@ impl<T: AsyncReadWriteUnpin> Client<T> {
         Client {
             protocol: protocol::Meta::new(),
             connection,
+            connector: None,
+            backoff: BackoffPolicy::default(),
+            breaker: None,
+            broken: false,
+            credentials: None,
         }
     }
 
-    /// GET a value from memcached based on the provided key.
-    pub async fn get(&mut self, key: &str) -> Result<Option<RawValue>, MemcacheError> {
-        self.protocol.get(&mut self.connection, key).await
+    /// Create a new Client instance applying `options`, except the connect timeout. A command
+    /// that times out fails with [`MemcacheError::Timeout`]. Fails if the namespace is
+    /// invalid, see [`protocol::Meta::namespace`].
+    pub fn with_options(connection: T, options: &ClientOptions) -> Result<Self, MemcacheError> {
+        let mut retval = Client::new(connection);
+        retval.protocol =
+            protocol::Meta::with_timeouts(options.read_timeout, options.write_timeout)
+                .text_only(options.text_only)
+                .compression(options.compression)
+                .chunking(options.chunking)
+                .namespace(options.namespace.clone())?
+                .long_keys(options.long_keys);
+        retval.backoff = options.backoff;
+        Ok(retval)
+    }
+
+    /// Create a new Client instance that re-establishes the connection using `connector` when
+    /// it fails. A command interrupted by an I/O error is retried once on the new connection,
+    /// so non-idempotent commands (e.g. [`Client::arithmetic`]) may be applied twice.
+    pub fn with_connector<F>(connection: T, connector: F) -> Self
+    where
+        F: Fn() -> ConnectFuture<T> + Send + Sync + 'static,
+    {
+        let mut retval = Client::new(connection);
+        retval.connector = Some(Connector(Box::new(connector)));
+        retval
+    }
+
+    /// Set how often and how fast the connector is retried when the connection failed
+    pub fn set_backoff(&mut self, policy: BackoffPolicy) {
+        self.backoff = policy;
+    }
+
+    /// Set the largest value accepted by the store commands, see
+    /// [`protocol::Meta::max_value_size`]
+    pub fn set_max_value_size(&mut self, limit: usize) {
+        let protocol = std::mem::take(&mut self.protocol);
+        self.protocol = protocol.max_value_size(limit);
+    }
+
+    /// Restrict the commands to the classic text protocol, see [`protocol::Meta::text_only`]
+    pub fn set_text_only(&mut self, text_only: bool) {
+        let protocol = std::mem::take(&mut self.protocol);
+        self.protocol = protocol.text_only(text_only);
+    }
+
+    /// Compress large values, see [`compress`]
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        let protocol = std::mem::take(&mut self.protocol);
+        self.protocol = protocol.compression(compression);
+    }
+
+    /// Split values larger than the item size limit, see [`chunked`]
+    pub fn set_chunking(&mut self, chunking: bool) {
+        let protocol = std::mem::take(&mut self.protocol);
+        self.protocol = protocol.chunking(chunking);
     }
 
-    /// GET any number of values from memcached.
+    /// Prefix all the keys, see [`protocol::Meta::namespace`]. The namespace is left
+    /// unchanged if `prefix` is invalid.
+    pub fn set_namespace(&mut self, prefix: Option<String>) -> Result<(), MemcacheError> {
+        protocol::check_namespace(prefix.as_deref())?;
+        let protocol = std::mem::take(&mut self.protocol);
+        self.protocol = protocol.namespace(prefix)?;
+        Ok(())
+    }
+
+    /// Hash the keys longer than memcached accepts, see [`keys`]
+    pub fn set_long_keys(&mut self, long_keys: LongKeys) {
+        let protocol = std::mem::take(&mut self.protocol);
+        self.protocol = protocol.long_keys(long_keys);
+    }
+
+    /// Install a circuit breaker, shared with the other clients of the same server
+    pub fn set_circuit_breaker(&mut self, breaker: Option<CircuitBreaker>) {
+        self.breaker = breaker;
+    }
+
+    /// Returns false if the client is not connected yet (see [`Client::connect_lazy`]) or the
+    /// last command failed with an I/O error and the connection was not re-established since.
+    pub fn is_healthy(&self) -> bool {
+        !self.broken
+    }
+
+    /// Replace a failed connection with a new one from the connector, if any
+    async fn ensure_connected(&mut self) -> Result<(), MemcacheError> {
+        if self.broken && self.connector.is_some() {
+            self.reconnect().await?;
+        }
+        Ok(())
+    }
+
+    /// Open a new connection using the connector and restore the authentication. Fails with
+    /// [`MemcacheError::NotConnected`] if the server can't be reached within the retries of
+    /// the backoff policy.
+    async fn reconnect(&mut self) -> Result<(), MemcacheError> {
+        let Some(connector) = &self.connector else {
+            return Ok(());
+        };
+        let connection = self.backoff.retry("reconnect", || (connector.0)()).await;
+        self.connection = connection.map_err(|e| match e {
+            MemcacheError::IOError(e) => {
+                error!("unable to connect: {}", e);
+                MemcacheError::NotConnected
+            }
+            e => e,
+        })?;
+        if let Some((username, password)) = &self.credentials {
+            self.protocol
+                .authenticate(&mut self.connection, username, password)
+                .await?;
+        }
+        self.broken = false;
+        Ok(())
+    }
+
+    /// Authenticate the connection, required by servers started with `-Y`. Has to be called
+    /// before any other command. Uses the text protocol authentication of memcached 1.6+.
+    pub async fn authenticate(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<(), MemcacheError> {
+        reconnecting!(
+            self,
+            self.protocol
+                .authenticate(&mut self.connection, username, password)
+                .await
+        )?;
+        self.credentials = Some((username.to_string(), password.to_string()));
+        Ok(())
+    }
+
+    /// GET a value from memcached based on the provided key. The value can be read as a
+    /// [`RawValue`] or converted to any [`FromMemcacheValue`] type, see [`value`].
+    pub async fn get<V: FromMemcacheValue>(
+        &mut self,
+        key: impl AsRef<str>,
+    ) -> Result<Option<V>, MemcacheError> {
+        let key = key.as_ref();
+        match reconnecting!(
+            self,
+            "get",
+            Some(key),
+            self.protocol.get(&mut self.connection, key).await
+        )? {
+            Some(x) => V::from_memcache_value(x).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// GET a value from memcached, requesting additional information (e.g. the remaining TTL
+    /// or the last access time) as set in `opts`. A deadline set in `opts` overrides the
+    /// client timeouts for this call.
+    pub async fn get_with(
+        &mut self,
+        key: &str,
+        opts: &GetOptions,
+    ) -> Result<Option<GetResponse>, MemcacheError> {
+        reconnecting!(
+            self,
+            "get",
+            Some(key),
+            self.protocol
+                .get_with(&mut self.connection, key, opts)
+                .await
+        )
+    }
+
+    /// GET a value along with its CAS token, to store it back with [`Client::cas`] if it was
+    /// not modified in the meantime:
+    /// ```no_run
+    /// # async fn example(client: &mut yamemcache::Client<yamemcache::BoxedConnection>)
+    /// # -> Result<(), yamemcache::error::MemcacheError> {
+    /// use yamemcache::protocol::{RawValue, StoreResult};
+    /// use yamemcache::value::FromMemcacheValue;
+    ///
+    /// loop {
+    ///     let Some((value, cas)) = client.get_with_cas("visits").await? else {
+    ///         break;
+    ///     };
+    ///     let visits = u64::from_memcache_value(value)? + 1;
+    ///     let value = RawValue::from_vec(visits.to_string().into_bytes()).set_cas(Some(cas));
+    ///     if client.cas("visits", &value).await? != StoreResult::Exists {
+    ///         break;
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_with_cas(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<(RawValue, u64)>, MemcacheError> {
+        let Some(value) = self.get::<RawValue>(key).await? else {
+            return Ok(None);
+        };
+        match value.cas {
+            Some(cas) => Ok(Some((value, cas))),
+            None => {
+                error!("get_with_cas: no CAS token returned");
+                Err(MemcacheError::bad_response("no CAS token").context("get", Some(key)))
+            }
+        }
+    }
+
+    /// GET a value stored under a binary key. Unlike [`Client::get`] the key may contain
+    /// spaces, control characters or arbitrary bytes.
+    pub async fn get_bytes_key(&mut self, key: &[u8]) -> Result<Option<RawValue>, MemcacheError> {
+        reconnecting!(
+            self,
+            "get_bytes_key",
+            None,
+            self.protocol.get_bytes_key(&mut self.connection, key).await
+        )
+    }
+
+    /// GET any number of values from memcached. The keys can be given as a slice, a `Vec` of
+    /// `String` or any iterator of strings.
     /// The result is a vector of (key, value) tuples. If a key is not present in the vector then
     /// it was not found.
     pub async fn get_many(
         &mut self,
-        key_list: &[&str],
+        key_list: impl IntoIterator<Item = impl AsRef<str>>,
     ) -> Result<Vec<(String, RawValue)>, MemcacheError> {
-        self.protocol.get_many(&mut self.connection, key_list).await
+        let keys: Vec<_> = key_list.into_iter().collect();
+        let key_list: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+        reconnecting!(
+            self,
+            "get_many",
+            None,
+            self.protocol
+                .get_many(&mut self.connection, &key_list)
+                .await
+        )
+    }
+
+    /// GET any number of values, yielding them as their responses arrive instead of collecting
+    /// them first, e.g. for multigets too large to buffer. The keys can be given as in
+    /// [`Client::get_many`], those not found are skipped. The client can be used again once the
+    /// stream is exhausted. If it is dropped early or fails, the connection is out of sync and
+    /// replaced before the next command (with a connector, see [`Client::is_healthy`]).
+    pub async fn get_many_stream(
+        &mut self,
+        key_list: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<impl Stream<Item = Result<(String, RawValue), MemcacheError>> + '_, MemcacheError>
+    {
+        let keys: Vec<_> = key_list.into_iter().collect();
+        let key_list: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+        self.ensure_connected().await?;
+        let stream = match self
+            .protocol
+            .get_many_stream(&mut self.connection, &key_list)
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                if e.needs_reconnect() {
+                    self.broken = true;
+                }
+                return Err(e.context("get_many_stream", None));
+            }
+        };
+        // the responses not read yet would be read by the next command
+        self.broken = true;
+        Ok(futures_util::stream::unfold(
+            (Some(Box::pin(stream)), &mut self.broken),
+            |(stream, broken)| async move {
+                let mut stream = stream?;
+                match stream.next().await {
+                    Some(Ok(x)) => Some((Ok(x), (Some(stream), broken))),
+                    Some(Err(e)) => Some((Err(e.context("get_many_stream", None)), (None, broken))),
+                    None => {
+                        *broken = false;
+                        None
+                    }
+                }
+            },
+        ))
+    }
+
+    /// STORE a value in memcached using the provided key. The value can be a [`RawValue`] or
+    /// any [`ToMemcacheValue`] type, see [`value`].
+    pub async fn set<V: ToMemcacheValue>(
+        &mut self,
+        key: impl AsRef<str>,
+        data: V,
+    ) -> Result<(), MemcacheError> {
+        let key = key.as_ref();
+        let data = data.to_memcache_value();
+        reconnecting!(
+            self,
+            "set",
+            Some(key),
+            self.protocol.set(&mut self.connection, key, &data).await
+        )
+    }
+
+    /// STORE any number of values in a single round trip, see [`protocol::Meta::set_many`].
+    /// Returns the outcome of every item, in order.
+    pub async fn set_many<D: AsRef<[u8]>>(
+        &mut self,
+        keydata: &[(&str, &RawValue<D>)],
+    ) -> Result<Vec<StoreResult>, MemcacheError> {
+        reconnecting!(
+            self,
+            "set_many",
+            None,
+            self.protocol.set_many(&mut self.connection, keydata).await
+        )
+    }
+
+    /// STORE borrowed data with the given flags and TTL (`None` to never expire), without
+    /// building a [`RawValue`] or copying the data.
+    pub async fn set_raw(
+        &mut self,
+        key: &str,
+        data: &[u8],
+        flags: u32,
+        ttl: Option<u32>,
+    ) -> Result<(), MemcacheError> {
+        let data = RawValue {
+            data,
+            flags,
+            time: ttl,
+            cas: None,
+        };
+        reconnecting!(
+            self,
+            "set",
+            Some(key),
+            self.protocol.set(&mut self.connection, key, &data).await
+        )
+    }
+
+    /// STORE `len` bytes read from `data.data` without holding them in memory, see
+    /// [`protocol::Meta::set_from_reader`]. The reader can't be replayed, the command is not
+    /// retried after a connection failure.
+    pub async fn set_from_reader<R: tokio::io::AsyncRead + Unpin>(
+        &mut self,
+        key: &str,
+        len: usize,
+        data: RawValue<R>,
+        opts: &SetOptions,
+    ) -> Result<StoreResult, MemcacheError> {
+        if let Some(breaker) = &self.breaker {
+            breaker.admit()?;
+        }
+        let retval = match self.ensure_connected().await {
+            Ok(()) => match self.protocol.check_set_from_reader(key, len) {
+                Ok(()) => {
+                    // after any failure the request may be incomplete, whatever the error (e.g.
+                    // from the reader), and the next command would be read as data
+                    self.broken = true;
+                    let retval = self
+                        .protocol
+                        .set_from_reader(&mut self.connection, key, len, data, opts)
+                        .await;
+                    self.broken = retval.is_err();
+                    retval
+                }
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        if let Some(breaker) = &self.breaker {
+            breaker.record(&retval);
+        }
+        retval.map_err(|e| e.context("set_from_reader", Some(key)))
     }
 
-    /// STORE a value in memcached using the provided key.
-    pub async fn set(&mut self, key: &str, data: &RawValue) -> Result<(), MemcacheError> {
-        self.protocol.set(&mut self.connection, key, data).await
+    /// Compare-And-Store a value: it is only stored if the item was not modified since
+    /// `data.cas` was read, e.g. with [`Client::get_with_cas`]. Unlike [`Client::set`] this
+    /// reports the outcome as a [`StoreResult`].
+    pub async fn cas<D: AsRef<[u8]>>(
+        &mut self,
+        key: &str,
+        data: &RawValue<D>,
+    ) -> Result<StoreResult, MemcacheError> {
+        reconnecting!(
+            self,
+            "cas",
+            Some(key),
+            self.protocol.cas(&mut self.connection, key, data).await
+        )
+    }
+
+    /// STORE a value with additional options, see [`SetOptions`]. Like [`Client::cas`] the
+    /// store is conditional if `data.cas` is set.
+    pub async fn set_with<D: AsRef<[u8]>>(
+        &mut self,
+        key: &str,
+        data: &RawValue<D>,
+        opts: &SetOptions,
+    ) -> Result<StoreResult, MemcacheError> {
+        reconnecting!(
+            self,
+            "set",
+            Some(key),
+            self.protocol
+                .set_with(&mut self.connection, key, data, opts)
+                .await
+        )
+    }
+
+    /// STORE a value without waiting for the response. The server only replies on failure and
+    /// the request is not flushed until [`Client::noop`] is called, which also reports any
+    /// failures. Much faster than [`Client::set`] for bulk loads.
+    pub async fn set_quiet<D: AsRef<[u8]>>(
+        &mut self,
+        key: &str,
+        data: &RawValue<D>,
+    ) -> Result<(), MemcacheError> {
+        reconnecting!(
+            self,
+            "set_quiet",
+            Some(key),
+            self.protocol
+                .set_quiet(&mut self.connection, key, data)
+                .await
+        )
+    }
+
+    /// STORE a value in memcached using a binary key. Unlike [`Client::set`] the key may
+    /// contain spaces, control characters or arbitrary bytes.
+    pub async fn set_bytes_key<D: AsRef<[u8]>>(
+        &mut self,
+        key: &[u8],
+        data: &RawValue<D>,
+    ) -> Result<(), MemcacheError> {
+        reconnecting!(
+            self,
+            "set_bytes_key",
+            None,
+            self.protocol
+                .set_bytes_key(&mut self.connection, key, data)
+                .await
+        )
+    }
+
+    /// Update the TTL of any number of keys in a single round trip, see
+    /// [`protocol::Meta::touch_many`]. The keys can be given as in [`Client::get_many`].
+    /// Returns whether every key existed, in order.
+    pub async fn touch_many(
+        &mut self,
+        key_list: impl IntoIterator<Item = impl AsRef<str>>,
+        ttl: u32,
+    ) -> Result<Vec<bool>, MemcacheError> {
+        let keys: Vec<_> = key_list.into_iter().collect();
+        let key_list: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+        reconnecting!(
+            self,
+            "touch_many",
+            None,
+            self.protocol
+                .touch_many(&mut self.connection, &key_list, ttl)
+                .await
+        )
+    }
+
+    /// DELETE any number of keys in a single round trip, see [`protocol::Meta::delete_many`].
+    /// The keys can be given as in [`Client::get_many`]. Returns whether every key existed, in
+    /// order.
+    pub async fn delete_many(
+        &mut self,
+        key_list: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Vec<bool>, MemcacheError> {
+        let keys: Vec<_> = key_list.into_iter().collect();
+        let key_list: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+        reconnecting!(
+            self,
+            "delete_many",
+            None,
+            self.protocol
+                .delete_many(&mut self.connection, &key_list)
+                .await
+        )
     }
 
     /// DELETE a value from memcached attached to the provided key
-    pub async fn delete(&mut self, key: &str) -> Result<Option<()>, MemcacheError> {
-        self.protocol.delete(&mut self.connection, key).await
+    pub async fn delete(&mut self, key: impl AsRef<str>) -> Result<Option<()>, MemcacheError> {
+        let key = key.as_ref();
+        reconnecting!(
+            self,
+            "delete",
+            Some(key),
+            self.protocol.delete(&mut self.connection, key).await
+        )
+    }
+
+    /// DELETE a value stored under a binary key, see [`Client::get_bytes_key`]
+    pub async fn delete_bytes_key(&mut self, key: &[u8]) -> Result<Option<()>, MemcacheError> {
+        reconnecting!(
+            self,
+            "delete_bytes_key",
+            None,
+            self.protocol
+                .delete_bytes_key(&mut self.connection, key)
+                .await
+        )
+    }
+
+    /// DELETE a value without waiting for the server to respond.
+    pub async fn delete_noreply(&mut self, key: &str) -> Result<(), MemcacheError> {
+        reconnecting!(
+            self,
+            "delete_noreply",
+            Some(key),
+            self.protocol
+                .delete_noreply(&mut self.connection, key)
+                .await
+        )
+    }
+
+    /// Invalidate all the items stored in memcached, optionally after `delay` seconds.
+    pub async fn flush_all(&mut self, delay: Option<u32>) -> Result<(), MemcacheError> {
+        reconnecting!(
+            self,
+            self.protocol.flush_all(&mut self.connection, delay).await
+        )
+    }
+
+    /// Invalidate all the items stored in memcached without waiting for the server to respond.
+    pub async fn flush_all_noreply(&mut self, delay: Option<u32>) -> Result<(), MemcacheError> {
+        reconnecting!(
+            self,
+            self.protocol
+                .flush_all_noreply(&mut self.connection, delay)
+                .await
+        )
+    }
+
+    /// DELETE a value using the meta delete command. Unlike [`Client::delete`] this allows
+    /// invalidating the value instead of removing it and deleting conditionally on CAS.
+    pub async fn delete_with(
+        &mut self,
+        key: &str,
+        opts: &DeleteOptions,
+    ) -> Result<DeleteResult, MemcacheError> {
+        reconnecting!(
+            self,
+            "delete",
+            Some(key),
+            self.protocol
+                .delete_with(&mut self.connection, key, opts)
+                .await
+        )
+    }
+
+    /// DELETE a value, leaving a stale tombstone behind for `ttl` seconds. Until the tombstone
+    /// expires, readers see the item as stale and only one of them wins the right to
+    /// re-populate it, which prevents racy re-population from stale data.
+    pub async fn delete_keep_tombstone(
+        &mut self,
+        key: &str,
+        ttl: u32,
+    ) -> Result<DeleteResult, MemcacheError> {
+        let opts = DeleteOptions::new().invalidate(true).ttl(Some(ttl));
+        reconnecting!(
+            self,
+            "delete",
+            Some(key),
+            self.protocol
+                .delete_with(&mut self.connection, key, &opts)
+                .await
+        )
+    }
+
+    /// Increment or decrement a counter stored in memcached, see [`ArithmeticOptions`].
+    pub async fn arithmetic(
+        &mut self,
+        key: &str,
+        opts: &ArithmeticOptions,
+    ) -> Result<ArithmeticResult, MemcacheError> {
+        reconnecting!(
+            self,
+            "arithmetic",
+            Some(key),
+            self.protocol
+                .arithmetic(&mut self.connection, key, opts)
+                .await
+        )
+    }
+
+    /// Send several commands with a single flush and read their responses in order, e.g.
+    /// `client.pipelined(|p| { p.set("a", &value); p.get("b"); })`.
+    /// The responses are in the order the commands were queued, see [`protocol::Meta::pipeline`].
+    pub async fn pipelined<'a, F>(
+        &mut self,
+        f: F,
+    ) -> Result<Vec<Result<PipelineResponse, MemcacheError>>, MemcacheError>
+    where
+        F: FnOnce(&mut Pipeline<'a>),
+    {
+        let mut pipeline = Pipeline::new();
+        f(&mut pipeline);
+        self.pipeline(&pipeline).await
+    }
+
+    /// Send the commands of a pipeline with a single flush and read their responses in order,
+    /// see [`protocol::Meta::pipeline`] and [`Pipeline::execute`]
+    pub async fn pipeline(
+        &mut self,
+        pipeline: &Pipeline<'_>,
+    ) -> Result<Vec<Result<PipelineResponse, MemcacheError>>, MemcacheError> {
+        reconnecting!(
+            self,
+            self.protocol.pipeline(&mut self.connection, pipeline).await
+        )
+    }
+
+    /// Send a no-op command and wait for the reply. Can be used as a cheap ping.
+    pub async fn noop(&mut self) -> Result<(), MemcacheError> {
+        reconnecting!(self, self.protocol.noop(&mut self.connection).await)
+    }
+
+    /// Check that the server responds, e.g. for readiness probes. Sends a no-op command and
+    /// waits up to one second for the reply.
+    pub async fn ping(&mut self) -> Health {
+        let start = std::time::Instant::now();
+        match tokio::time::timeout(PING_TIMEOUT, self.noop()).await {
+            Ok(Ok(())) => Health::Healthy {
+                latency: start.elapsed(),
+            },
+            Ok(Err(e)) => Health::Unhealthy(e),
+            Err(_) => {
+                // the reply may still arrive, don't let the next command read it
+                self.broken = true;
+                if let Some(breaker) = &self.breaker {
+                    breaker.record::<()>(&Err(MemcacheError::Timeout));
+                }
+                Health::Unhealthy(MemcacheError::Timeout)
+            }
+        }
+    }
+
+    /// Shut the server down, optionally gracefully. The server has to be started with `-A`.
+    /// The connection is closed by the server on success.
+    pub async fn shutdown(&mut self, graceful: bool) -> Result<(), MemcacheError> {
+        self.protocol.shutdown(&mut self.connection, graceful).await
+    }
+
+    /// Move a memory page from slab class `src` (-1 for any class) to slab class `dst`.
+    pub async fn slabs_reassign(
+        &mut self,
+        src: i32,
+        dst: u32,
+    ) -> Result<ReassignResult, MemcacheError> {
+        reconnecting!(
+            self,
+            self.protocol
+                .slabs_reassign(&mut self.connection, src, dst)
+                .await
+        )
+    }
+
+    /// Change how the server automatically rebalances slab memory.
+    pub async fn slabs_automove(&mut self, mode: AutomoveMode) -> Result<(), MemcacheError> {
+        reconnecting!(
+            self,
+            self.protocol
+                .slabs_automove(&mut self.connection, mode)
+                .await
+        )
+    }
+
+    /// Switch the LRU algorithm and set the percentage of memory used by the HOT and WARM
+    /// segments of the segmented LRU.
+    pub async fn lru_tune(
+        &mut self,
+        mode: LruMode,
+        hot_pct: u32,
+        warm_pct: u32,
+    ) -> Result<(), MemcacheError> {
+        reconnecting!(
+            self,
+            self.protocol
+                .lru_tune(&mut self.connection, mode, hot_pct, warm_pct)
+                .await
+        )
+    }
+
+    /// Control the LRU crawler: start/stop it, tune it or trigger a crawl of specific slab
+    /// classes.
+    pub async fn lru_crawler(
+        &mut self,
+        command: &LruCrawlerCommand,
+    ) -> Result<LruCrawlerResult, MemcacheError> {
+        reconnecting!(
+            self,
+            self.protocol
+                .lru_crawler(&mut self.connection, command)
+                .await
+        )
+    }
+
+    /// List the metadata (key, expiration, size, ...) of all the items in the given slab
+    /// classes, e.g. to take an inventory of the key space or to warm up another cache.
+    /// The client can be used again once the stream is exhausted. If it is dropped early or
+    /// fails, the connection is out of sync and replaced before the next command (with a
+    /// connector, see [`Client::is_healthy`]).
+    pub async fn metadump(
+        &mut self,
+        classes: &CrawlerClasses,
+    ) -> Result<impl Stream<Item = ItemMetadata> + '_, MemcacheError> {
+        self.ensure_connected().await?;
+        let stream = match self
+            .protocol
+            .metadump_results(&mut self.connection, classes)
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                if e.needs_reconnect() {
+                    self.broken = true;
+                }
+                return Err(e);
+            }
+        };
+        // the lines not read yet would be read by the next command
+        self.broken = true;
+        Ok(futures_util::stream::unfold(
+            (Some(Box::pin(stream)), &mut self.broken),
+            |(stream, broken)| async move {
+                let mut stream = stream?;
+                match stream.next().await {
+                    Some(Ok(x)) => Some((x, (Some(stream), broken))),
+                    // already logged, the stream ends early
+                    Some(Err(_)) => None,
+                    None => {
+                        *broken = false;
+                        None
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Subscribe to the server's watcher logs, e.g. to analyse the traffic in real time. The
+    /// client is consumed as the connection can't be used for other commands afterwards.
+    pub async fn watch(
+        self,
+        targets: &[WatchTarget],
+    ) -> Result<impl Stream<Item = WatchEvent>, MemcacheError> {
+        self.protocol.watch(self.connection, targets).await
     }
 
     /// Read memcached version.
     pub async fn version(&mut self) -> Result<String, MemcacheError> {
-        self.protocol.version(&mut self.connection).await
+        reconnecting!(self, self.protocol.version(&mut self.connection).await)
+    }
+
+    /// Read the general statistics of the server: items, memory usage, hits and misses, ...
+    pub async fn stats(&mut self) -> Result<ServerStats, MemcacheError> {
+        reconnecting!(self, self.protocol.stats(&mut self.connection).await)
+    }
+
+    /// Read per slab class statistics, useful for detecting slab fragmentation.
+    pub async fn stats_slabs(&mut self) -> Result<SlabStats, MemcacheError> {
+        reconnecting!(self, self.protocol.stats_slabs(&mut self.connection).await)
+    }
+
+    /// Read the histogram of item sizes stored in memcached.
+    pub async fn stats_sizes(&mut self) -> Result<SizeStats, MemcacheError> {
+        reconnecting!(self, self.protocol.stats_sizes(&mut self.connection).await)
+    }
+
+    /// Read the server configuration. Among others this reports the maximum item size the
+    /// server accepts.
+    pub async fn stats_settings(&mut self) -> Result<SettingsStats, MemcacheError> {
+        reconnecting!(
+            self,
+            self.protocol.stats_settings(&mut self.connection).await
+        )
+    }
+
+    /// Read the state of every connection open on the server, including this one.
+    pub async fn stats_conns(&mut self) -> Result<Vec<ConnectionStats>, MemcacheError> {
+        reconnecting!(self, self.protocol.stats_conns(&mut self.connection).await)
+    }
+}
+
+#[cfg(unix)]
+impl Client<tokio::io::BufStream<tokio::net::UnixStream>> {
+    /// Connect to a memcached server listening on a unix domain socket (`-s`).
+    pub async fn connect_unix<P: AsRef<std::path::Path>>(path: P) -> Result<Self, MemcacheError> {
+        let stream = tokio::net::UnixStream::connect(path)
+            .await
+            .map_err(MemcacheError::IOError)?;
+        Ok(Client::new(tokio::io::BufStream::new(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn with_options() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let options = ClientOptions::new().text_only(true).namespace("app:");
+        let mut c = Client::with_options(tokio::io::BufStream::new(client), &options).unwrap();
+        server
+            .write_all(b"VALUE app:key 0 1\r\nx\r\nEND\r\n")
+            .await
+            .unwrap();
+        assert_eq!(c.get::<String>("key").await.unwrap().unwrap(), "x");
+        let mut request = vec![0; 14];
+        server.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, b"gets app:key\r\n");
+
+        let options = ClientOptions::new().namespace("app :");
+        let io = tokio::io::join(std::io::Cursor::new(Vec::new()), tokio::io::sink());
+        assert!(Client::with_options(io, &options).is_err());
+        assert!(Client::connect_lazy("memcache://localhost", &options).is_err());
+        assert!(c.set_namespace(Some("a\nb".to_string())).is_err());
+    }
+
+    #[tokio::test]
+    async fn metadump_marks_connection() {
+        let response = b"key=a exp=-1 la=1 cas=3 fetch=no cls=1 size=63\n\
+                         key=b exp=-1 la=1 cas=4 fetch=no cls=1 size=63\n\
+                         END\r\n";
+        let mut c = Client::new(tokio::io::join(
+            std::io::Cursor::new(response.repeat(2)),
+            tokio::io::sink(),
+        ));
+        let stream = c.metadump(&CrawlerClasses::All).await.unwrap();
+        assert_eq!(stream.count().await, 2);
+        assert!(c.is_healthy());
+
+        // dropped before the end
+        let mut stream = Box::pin(c.metadump(&CrawlerClasses::All).await.unwrap());
+        assert!(stream.next().await.is_some());
+        drop(stream);
+        assert!(!c.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn bulk_commands_take_owned_keys() {
+        let mut c = Client::new(tokio::io::join(
+            std::io::Cursor::new(
+                b"HD O0\r\nNF O1\r\nHD O1\r\nMN\r\nVA 1 kb f0 O1\r\nx\r\nMN\r\n".to_vec(),
+            ),
+            tokio::io::sink(),
+        ));
+        let keys = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(c.delete_many(&keys).await.unwrap(), vec![true, false]);
+        let touched = c.touch_many(keys.iter().rev(), 60).await.unwrap();
+        assert_eq!(touched, vec![false, true]);
+        let values: Vec<_> = c.get_many_stream(keys).await.unwrap().collect().await;
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].as_ref().unwrap().0, "b");
+    }
+
+    #[tokio::test]
+    async fn corrupt_values_keep_connection() {
+        let options = ClientOptions::new().compression(compress::Codec::Deflate { level: 6 });
+        let response = b"VA 3 ka f8 O0\r\nbad\r\nVA 1 kb f0 O1\r\nx\r\nMN\r\n\
+                         VA 3 f8 ka\r\nbad\r\nVA 1 f0 kb\r\nx\r\n\
+                         VERSION 1.6.0\r\n";
+        let mut c = Client::with_options(
+            tokio::io::join(std::io::Cursor::new(response.to_vec()), tokio::io::sink()),
+            &options,
+        )
+        .unwrap();
+        let err = c.get_many(["a", "b"]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(c.is_healthy());
+
+        let mut pipeline = Pipeline::new();
+        pipeline.get("a");
+        pipeline.get("b");
+        let responses = c.pipeline(&pipeline).await.unwrap();
+        assert!(responses[0].is_err());
+        assert!(matches!(&responses[1], Ok(PipelineResponse::Get(Some(_)))));
+        assert_eq!(c.version().await.unwrap(), "1.6.0");
+
+        let options = options.text_only(true);
+        let response = b"VALUE a 8 3\r\nbad\r\nVALUE b 0 1\r\nx\r\nEND\r\nVERSION 1.6.0\r\n";
+        let mut c = Client::with_options(
+            tokio::io::join(std::io::Cursor::new(response.to_vec()), tokio::io::sink()),
+            &options,
+        )
+        .unwrap();
+        let err = c.get_many(["a", "b"]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(c.version().await.unwrap(), "1.6.0");
+    }
+
+    #[tokio::test]
+    async fn reconnects_once() {
+        // connections handed out by the connector, the last one first
+        let mut servers = Vec::new();
+        let mut connections = Vec::new();
+        for response in [&b""[..], b"", b"VERSION 1.6.0\r\n", b""] {
+            let (client, mut server) = tokio::io::duplex(1024);
+            server.write_all(response).await.unwrap();
+            connections.push(tokio::io::BufStream::new(client));
+            servers.push(server);
+        }
+        // the initial and third connections are closed by the server
+        drop(servers.remove(3));
+        drop(servers.remove(1));
+        let connections = std::sync::Arc::new(std::sync::Mutex::new(connections));
+        let initial = connections.lock().unwrap().pop().unwrap();
+        let mut c = Client::with_connector(initial, move || {
+            let next = connections.lock().unwrap().pop();
+            Box::pin(async move { next.ok_or(MemcacheError::NotConnected) })
+        });
+
+        // the write fails, the command succeeds on a new connection
+        assert_eq!(c.version().await.unwrap(), "1.6.0");
+        assert!(c.is_healthy());
+
+        // that connection is closed as well and the retry fails too, marking it broken
+        drop(servers.pop());
+        let err = c.version().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Connection);
+        assert!(!c.is_healthy());
+    }
+
+    /// Reader failing after its data
+    struct FailingReader(&'static [u8]);
+
+    impl tokio::io::AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.0.is_empty() {
+                let e = std::io::Error::new(std::io::ErrorKind::InvalidData, "bad source");
+                return std::task::Poll::Ready(Err(e));
+            }
+            buf.put_slice(self.0);
+            self.0 = b"";
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn set_from_reader_marks_connection() {
+        let mut c = Client::new(tokio::io::join(
+            std::io::Cursor::new(b"HD\r\n".to_vec()),
+            tokio::io::sink(),
+        ));
+        let opts = SetOptions::new();
+        let result = c
+            .set_from_reader("key", 4, RawValue::new(&b"1234"[..]), &opts)
+            .await;
+        assert_eq!(result.unwrap(), StoreResult::Stored);
+        assert!(c.is_healthy());
+
+        // rejected before sending anything
+        let result = c
+            .set_from_reader("bad key", 4, RawValue::new(FailingReader(b"12")), &opts)
+            .await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidRequest);
+        assert!(c.is_healthy());
+
+        // the error of the reader isn't a connection error, the request is incomplete anyway
+        let result = c
+            .set_from_reader("key", 4, RawValue::new(FailingReader(b"12")), &opts)
+            .await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(!c.is_healthy());
     }
 }
 
@@ -93,6 +1251,7 @@ mod integration_tests {
     use super::*;
 
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants)]
     async fn basic_commands() {
         // connect
         let Ok(stream) = tokio::net::TcpStream::connect("127.0.0.1:11211").await
@@ -149,7 +1308,7 @@ mod integration_tests {
         assert_eq!(found, 3, "Client.get_many() returned a different value.");
 
         // check ::get()
-        let Ok(Some(retval)) = client.get(key1).await else {
+        let Ok(Some(retval)) = client.get::<RawValue>(key1).await else {
             assert!(false, "Client::get() failed");
             return;
         };
@@ -164,7 +1323,7 @@ mod integration_tests {
 
         // check ::delete()
         assert!(client.delete(key1).await.is_ok(), "Client.delete() failed");
-        let Ok(retval) = client.get(key1).await else {
+        let Ok(retval) = client.get::<RawValue>(key1).await else {
             assert!(false, "Client.get() after .delete() failed");
             return;
         };
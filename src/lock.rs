@@ -0,0 +1,182 @@
+//! Distributed lock
+//!
+//! A [`MemcacheLock`] is held by whoever manages to `add` its key, with a random token as the
+//! value and a TTL so that the lock of a crashed owner eventually expires. The returned
+//! [`LockGuard`] releases it with a delete conditioned on the CAS value of the token, so an
+//! owner whose lock expired can't release the lock since taken by someone else.
+//!
+//! Dropping the guard releases the lock in a background task, [`LockGuard::release`] waits
+//! for it. Requires the meta protocol.
+//! ```no_run
+//! # async fn example(client: yamemcache::SharedClient<yamemcache::BoxedConnection>)
+//! # -> Result<(), yamemcache::error::MemcacheError> {
+//! use yamemcache::lock::MemcacheLock;
+//!
+//! let lock = MemcacheLock::new(client, "lock:report", 30);
+//! if let Some(guard) = lock.acquire(std::time::Duration::from_secs(5)).await? {
+//!     // generate the report
+//!     guard.release().await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::error::MemcacheError;
+use crate::protocol::{
+    DeleteOptions, DeleteResult, GetOptions, RawValue, SetOptions, StoreMode, StoreResult,
+};
+use crate::{AsyncReadWriteUnpin, SharedClient};
+
+use log::debug;
+
+/// First and longest delay between two attempts of [`MemcacheLock::acquire`]
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Token identifying an owner of a lock, unique across processes
+fn new_token() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |x| x.as_nanos());
+    let seed = format!(
+        "{} {} {}",
+        std::process::id(),
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    format!("{:x}", md5::compute(seed))
+}
+
+/// Lock stored under a key
+pub struct MemcacheLock<T: AsyncReadWriteUnpin> {
+    client: SharedClient<T>,
+    key: String,
+    ttl: u32,
+}
+
+impl<T: AsyncReadWriteUnpin + Send + 'static> MemcacheLock<T> {
+    /// Lock stored under `key`, held for at most `ttl` seconds. The TTL should be longer than
+    /// the work done under the lock, a `ttl` of 0 is raised to 1 since 0 never expires.
+    pub fn new(client: SharedClient<T>, key: impl Into<String>, ttl: u32) -> Self {
+        MemcacheLock {
+            client,
+            key: key.into(),
+            ttl: ttl.max(1),
+        }
+    }
+
+    /// Key of the lock
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Take the lock if it is free, None if it is held
+    pub async fn try_acquire(&self) -> Result<Option<LockGuard<T>>, MemcacheError> {
+        let token = new_token();
+        let value = RawValue::from_vec(token.clone().into_bytes());
+        let opts = SetOptions::new().mode(StoreMode::Add).ttl(Some(self.ttl));
+        let result = self.client.set_with(&self.key, value, &opts).await?;
+        if result != StoreResult::Stored {
+            debug!("lock: {} is held", self.key);
+            return Ok(None);
+        }
+        debug!("lock: {} acquired", self.key);
+        Ok(Some(LockGuard {
+            client: self.client.clone(),
+            key: self.key.clone(),
+            token: Some(token),
+        }))
+    }
+
+    /// Take the lock, waiting up to `wait` for it to be free. None if it is still held then.
+    pub async fn acquire(&self, wait: Duration) -> Result<Option<LockGuard<T>>, MemcacheError> {
+        let deadline = Instant::now() + wait;
+        let mut delay = RETRY_DELAY;
+        loop {
+            if let Some(x) = self.try_acquire().await? {
+                return Ok(Some(x));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(delay.min(deadline - now)).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+/// Ownership of a [`MemcacheLock`], released when dropped
+pub struct LockGuard<T: AsyncReadWriteUnpin + Send + 'static> {
+    client: SharedClient<T>,
+    key: String,
+    /// None once released
+    token: Option<String>,
+}
+
+impl<T: AsyncReadWriteUnpin + Send + 'static> LockGuard<T> {
+    /// Key of the lock
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Release the lock. False if it had expired, it may have been taken by someone else since.
+    pub async fn release(mut self) -> Result<bool, MemcacheError> {
+        let token = self.token.take().unwrap_or_default();
+        release(&self.client, &self.key, &token).await
+    }
+}
+
+impl<T: AsyncReadWriteUnpin + Send + 'static> Drop for LockGuard<T> {
+    fn drop(&mut self) {
+        let Some(token) = self.token.take() else {
+            return;
+        };
+        // without a runtime the lock is left to expire
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            debug!("lock: no runtime to release {}", self.key);
+            return;
+        };
+        let client = self.client.clone();
+        let key = std::mem::take(&mut self.key);
+        runtime.spawn(async move {
+            let _ = release(&client, &key, &token).await;
+        });
+    }
+}
+
+/// Deletes the lock `key` if it still holds `token`
+async fn release<T: AsyncReadWriteUnpin + Send + 'static>(
+    client: &SharedClient<T>,
+    key: &str,
+    token: &str,
+) -> Result<bool, MemcacheError> {
+    let Some(x) = client.get_with(key, &GetOptions::new()).await? else {
+        debug!("lock: {} expired", key);
+        return Ok(false);
+    };
+    if x.value.data != token.as_bytes() {
+        debug!("lock: {} expired and was taken", key);
+        return Ok(false);
+    }
+    let opts = DeleteOptions::new().cas(x.value.cas);
+    let released = client.delete_with(key, &opts).await? == DeleteResult::Deleted;
+    debug!("lock: {} released: {}", key, released);
+    Ok(released)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let a = new_token();
+        assert_eq!(a.len(), 32);
+        assert_ne!(a, new_token());
+    }
+}
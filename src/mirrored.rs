@@ -0,0 +1,285 @@
+//! Dual writes for cluster migrations
+//!
+//! [`MirroredClient`] sends every write to a primary and a shadow cluster and every read to the
+//! primary only. It lets a new fleet fill up with the live working set before the clients are
+//! switched over to it. The outcome of a command is always the one of the primary: failures
+//! of the shadow are logged and counted (see [`MirroredClient::shadow_errors`]) but never
+//! returned.
+//!
+//! Both writes are sent concurrently, a slow shadow still delays the commands up to
+//! [`MirroredClient::set_shadow_timeout`]. A shadow write that takes longer is abandoned and
+//! counted as failed, and the connections to the shadow are re-established.
+
+use std::future::Future;
+use std::time::Duration;
+
+use futures_util::future::join;
+
+use crate::error::MemcacheError;
+use crate::protocol::{
+    ArithmeticOptions, ArithmeticResult, DeleteOptions, DeleteResult, GetOptions, GetResponse,
+    RawValue, SetOptions, StoreResult,
+};
+use crate::ShardedClient;
+
+use log::{debug, error};
+
+/// Longest time a write waits for the shadow by default
+pub const DEFAULT_SHADOW_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// `request` sent to the shadow, failing with [`MemcacheError::Timeout`] after `timeout`
+async fn bounded<R>(
+    timeout: Duration,
+    request: impl Future<Output = Result<R, MemcacheError>>,
+) -> Result<R, MemcacheError> {
+    tokio::time::timeout(timeout, request)
+        .await
+        .unwrap_or(Err(MemcacheError::Timeout))
+}
+
+/// Client writing to two clusters, see the [module documentation](self)
+#[derive(Debug)]
+pub struct MirroredClient {
+    primary: ShardedClient,
+    shadow: ShardedClient,
+    shadow_timeout: Duration,
+    shadow_errors: u64,
+}
+
+impl MirroredClient {
+    /// Mirror the writes sent to `primary` to `shadow`
+    pub fn new(primary: ShardedClient, shadow: ShardedClient) -> Self {
+        MirroredClient {
+            primary,
+            shadow,
+            shadow_timeout: DEFAULT_SHADOW_TIMEOUT,
+            shadow_errors: 0,
+        }
+    }
+
+    /// Set how long the writes wait for the shadow, [`DEFAULT_SHADOW_TIMEOUT`] by default
+    pub fn set_shadow_timeout(&mut self, t: Duration) {
+        self.shadow_timeout = t;
+    }
+
+    /// Client of the primary cluster
+    pub fn primary(&mut self) -> &mut ShardedClient {
+        &mut self.primary
+    }
+
+    /// Client of the shadow cluster
+    pub fn shadow(&mut self) -> &mut ShardedClient {
+        &mut self.shadow
+    }
+
+    /// Stop mirroring, returns the primary and shadow clients
+    pub fn into_parts(self) -> (ShardedClient, ShardedClient) {
+        (self.primary, self.shadow)
+    }
+
+    /// Number of writes that failed on the shadow cluster
+    pub fn shadow_errors(&self) -> u64 {
+        self.shadow_errors
+    }
+
+    /// Logs and counts a failed shadow write
+    fn check_shadow<R>(&mut self, cmd: &str, key: &str, result: Result<R, MemcacheError>) {
+        if let Err(e) = result {
+            error!("mirrored: shadow {} {} failed: {:?}", cmd, key, e);
+            self.shadow_errors += 1;
+            if matches!(e, MemcacheError::Timeout) {
+                // the response may still arrive, don't let the next command read it
+                self.shadow.reset_connections();
+            }
+        }
+    }
+
+    /// GET a value from the primary, see [`ShardedClient::get`]
+    pub async fn get(&mut self, key: &str) -> Result<Option<RawValue>, MemcacheError> {
+        self.primary.get(key).await
+    }
+
+    /// GET a value with additional information from the primary, see
+    /// [`ShardedClient::get_with`]
+    pub async fn get_with(
+        &mut self,
+        key: &str,
+        opts: &GetOptions,
+    ) -> Result<Option<GetResponse>, MemcacheError> {
+        self.primary.get_with(key, opts).await
+    }
+
+    /// GET any number of values from the primary, see [`ShardedClient::get_many`]
+    pub async fn get_many(
+        &mut self,
+        key_list: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Vec<(String, RawValue)>, MemcacheError> {
+        self.primary.get_many(key_list).await
+    }
+
+    /// STORE a value on both clusters, see [`ShardedClient::set`]
+    pub async fn set(&mut self, key: &str, data: &RawValue) -> Result<(), MemcacheError> {
+        let (retval, shadow) = join(
+            self.primary.set(key, data),
+            bounded(self.shadow_timeout, self.shadow.set(key, data)),
+        )
+        .await;
+        self.check_shadow("set", key, shadow);
+        retval
+    }
+
+    /// STORE a value with additional options on both clusters, see
+    /// [`ShardedClient::set_with`]. A conditional store (`data.cas` set) is handled like
+    /// [`MirroredClient::cas`].
+    pub async fn set_with(
+        &mut self,
+        key: &str,
+        data: &RawValue,
+        opts: &SetOptions,
+    ) -> Result<StoreResult, MemcacheError> {
+        if data.cas.is_some() {
+            let retval = self.primary.set_with(key, data, opts).await?;
+            self.copy_to_shadow(key, data, opts, retval).await;
+            return Ok(retval);
+        }
+        let (retval, shadow) = join(
+            self.primary.set_with(key, data, opts),
+            bounded(self.shadow_timeout, self.shadow.set_with(key, data, opts)),
+        )
+        .await;
+        self.check_shadow("set_with", key, shadow);
+        retval
+    }
+
+    /// Compare-And-Store on the primary, see [`ShardedClient::cas`]. The CAS values of the
+    /// shadow differ, a value stored on the primary is copied to it unconditionally.
+    pub async fn cas(&mut self, key: &str, data: &RawValue) -> Result<StoreResult, MemcacheError> {
+        let retval = self.primary.cas(key, data).await?;
+        self.copy_to_shadow(key, data, &SetOptions::default(), retval)
+            .await;
+        Ok(retval)
+    }
+
+    /// Copies a value stored on the primary to the shadow, with the same options but without
+    /// the CAS condition
+    async fn copy_to_shadow(
+        &mut self,
+        key: &str,
+        data: &RawValue,
+        opts: &SetOptions,
+        result: StoreResult,
+    ) {
+        if result != StoreResult::Stored {
+            return;
+        }
+        let data = &RawValue {
+            cas: None,
+            ..data.clone()
+        };
+        let shadow = bounded(self.shadow_timeout, self.shadow.set_with(key, data, opts)).await;
+        self.check_shadow("cas", key, shadow);
+    }
+
+    /// DELETE a value from both clusters, see [`ShardedClient::delete`]
+    pub async fn delete(&mut self, key: &str) -> Result<Option<()>, MemcacheError> {
+        let (retval, shadow) = join(
+            self.primary.delete(key),
+            bounded(self.shadow_timeout, self.shadow.delete(key)),
+        )
+        .await;
+        self.check_shadow("delete", key, shadow);
+        retval
+    }
+
+    /// DELETE a value from both clusters using the meta delete command, see
+    /// [`ShardedClient::delete_with`]
+    pub async fn delete_with(
+        &mut self,
+        key: &str,
+        opts: &DeleteOptions,
+    ) -> Result<DeleteResult, MemcacheError> {
+        let (retval, shadow) = join(
+            self.primary.delete_with(key, opts),
+            bounded(self.shadow_timeout, self.shadow.delete_with(key, opts)),
+        )
+        .await;
+        self.check_shadow("delete_with", key, shadow);
+        retval
+    }
+
+    /// Increment or decrement a counter on both clusters, see [`ShardedClient::arithmetic`].
+    /// Counters missing on the shadow are not created unless `opts` vivifies them.
+    pub async fn arithmetic(
+        &mut self,
+        key: &str,
+        opts: &ArithmeticOptions,
+    ) -> Result<ArithmeticResult, MemcacheError> {
+        let (retval, shadow) = join(
+            self.primary.arithmetic(key, opts),
+            bounded(self.shadow_timeout, self.shadow.arithmetic(key, opts)),
+        )
+        .await;
+        if let Ok(ArithmeticResult::NotFound) = shadow {
+            debug!("mirrored: counter {} missing on the shadow", key);
+        }
+        self.check_shadow("arithmetic", key, shadow);
+        retval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::StoreMode;
+    use crate::sharded::ShardedOptions;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    /// Mirrored single server clusters replaying the given responses, and the server ends of
+    /// the primary and shadow connections
+    async fn mirrored(primary: &[u8], shadow: &[u8]) -> (MirroredClient, Vec<DuplexStream>) {
+        let options = ShardedOptions::new();
+        let (primary, mut servers) = ShardedClient::mock(&[primary], &options).await;
+        let (shadow, shadow_servers) = ShardedClient::mock(&[shadow], &options).await;
+        servers.extend(shadow_servers);
+        (MirroredClient::new(primary, shadow), servers)
+    }
+
+    #[tokio::test]
+    async fn shadow_failures_are_counted() {
+        let (mut c, _servers) = mirrored(
+            b"HD\r\nDELETED\r\n",
+            b"SERVER_ERROR out of memory\r\nSERVER_ERROR busy\r\n",
+        )
+        .await;
+        let data = RawValue::from_vec(b"value".to_vec());
+        c.set("key", &data).await.unwrap();
+        assert_eq!(c.shadow_errors(), 1);
+        assert_eq!(c.delete("key").await.unwrap(), Some(()));
+        assert_eq!(c.shadow_errors(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_shadow_times_out() {
+        // the shadow never responds
+        let (mut c, _servers) = mirrored(b"HD\r\n", b"").await;
+        let data = RawValue::from_vec(b"value".to_vec());
+        c.set("key", &data).await.unwrap();
+        assert_eq!(c.shadow_errors(), 1);
+    }
+
+    #[tokio::test]
+    async fn conditional_stores_are_copied() {
+        let (mut c, mut servers) = mirrored(b"HD\r\n", b"HD\r\n").await;
+        let data = RawValue::from_vec(b"-tail".to_vec()).set_cas(Some(5));
+        let opts = SetOptions::new().mode(StoreMode::Append).ttl(Some(60));
+        let retval = c.set_with("key", &data, &opts).await.unwrap();
+        assert_eq!(retval, StoreResult::Stored);
+        assert_eq!(c.shadow_errors(), 0);
+        drop(c);
+
+        let mut sent = String::new();
+        servers[1].read_to_string(&mut sent).await.unwrap();
+        // appended without the CAS of the primary
+        assert_eq!(sent, "ms key S5 T60 F0 MA\r\n-tail\r\n");
+    }
+}
@@ -0,0 +1,442 @@
+//! Pipelining of meta commands
+//!
+//! A [`Pipeline`] collects commands that are written to the server at once and flushed a single
+//! time. The responses are then read in order, saving a round-trip per command.
+//!
+//! Every queued command returns a [`PipelineHandle`] which yields the result of that command
+//! once the pipeline was executed:
+//! ```no_run
+//! # async fn example(client: &mut yamemcache::Client<yamemcache::BoxedConnection>) {
+//! use yamemcache::pipeline::Pipeline;
+//! use yamemcache::protocol::RawValue;
+//!
+//! let value = RawValue::from_vec(b"hello".to_vec());
+//! let mut pipeline = Pipeline::new();
+//! let stored = pipeline.set("a", &value);
+//! let fetched = pipeline.get("b");
+//! let deleted = pipeline.delete("c");
+//! if pipeline.execute(client).await.is_ok() {
+//!     println!("{:?} {:?}", stored.result(), deleted.result());
+//!     if let Ok(Some(x)) = fetched.result() {
+//!         println!("b = {:?}", x.as_str());
+//!     }
+//! }
+//! # }
+//! ```
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::AsyncWriteExt;
+
+use crate::error::{ErrorContext, MemcacheError};
+use crate::protocol::{
+    arithmetic_request, delete_request, get_request, io_error, read_arithmetic_response,
+    read_delete_response, read_get_response, read_store_response, set_request, store_flags,
+    ArithmeticOptions, ArithmeticResult, DeleteOptions, DeleteResult, GetOptions, GetResponse,
+    Meta, RawValue, SetOptions, StoreResult,
+};
+use crate::{AsyncReadWriteUnpin, Client};
+
+use log::debug;
+
+enum Command<'a> {
+    Get(&'a str, GetOptions),
+    Set(&'a str, RawValue<&'a [u8]>, SetOptions),
+    Delete(&'a str, DeleteOptions),
+    Arithmetic(&'a str, ArithmeticOptions),
+}
+
+impl Command<'_> {
+    fn key(&self) -> &str {
+        match self {
+            Command::Get(key, _)
+            | Command::Set(key, _, _)
+            | Command::Delete(key, _)
+            | Command::Arithmetic(key, _) => key,
+        }
+    }
+}
+
+/// Response to a single pipelined command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineResponse {
+    /// Response to [`Pipeline::get`] and [`Pipeline::get_with`]
+    Get(Option<GetResponse>),
+    /// Response to [`Pipeline::set`] and [`Pipeline::set_with`]
+    Store(StoreResult),
+    /// Response to [`Pipeline::delete`] and [`Pipeline::delete_with`]
+    Delete(DeleteResult),
+    /// Response to [`Pipeline::arithmetic`]
+    Arithmetic(ArithmeticResult),
+}
+
+type Slot = Arc<Mutex<Option<Result<PipelineResponse, MemcacheError>>>>;
+
+fn lock(slot: &Slot) -> std::sync::MutexGuard<'_, Option<Result<PipelineResponse, MemcacheError>>> {
+    slot.lock().unwrap_or_else(|x| x.into_inner())
+}
+
+/// Result of a single command queued in a [`Pipeline`]
+pub struct PipelineHandle<R> {
+    index: usize,
+    slot: Slot,
+    extract: fn(PipelineResponse) -> Result<R, PipelineResponse>,
+}
+
+impl<R> PipelineHandle<R> {
+    /// Position of the command in the pipeline, and of its response in the result of
+    /// [`Meta::pipeline`]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Result of the command. Fails with [`MemcacheError::NotConnected`] if the pipeline was not
+    /// executed (or its execution was cancelled), with the error of the pipeline if it failed
+    /// as a whole.
+    pub fn result(self) -> Result<R, MemcacheError> {
+        let Some(x) = lock(&self.slot).take() else {
+            return Err(MemcacheError::NotConnected);
+        };
+        x.and_then(|x| {
+            (self.extract)(x).map_err(|x| MemcacheError::bad_response(format!("{:?}", x)))
+        })
+    }
+}
+
+/// Copies the error of a failed pipeline to the handles of its commands
+fn copy_error(e: &MemcacheError) -> MemcacheError {
+    match e {
+        MemcacheError::IOError(e) => {
+            MemcacheError::IOError(std::io::Error::new(e.kind(), e.to_string()))
+        }
+        MemcacheError::BadKey => MemcacheError::BadKey,
+        MemcacheError::BadServerResponse(x) => MemcacheError::BadServerResponse(x.clone()),
+        MemcacheError::Protocol(x) => MemcacheError::Protocol(x.clone()),
+        MemcacheError::NotStored => MemcacheError::NotStored,
+        MemcacheError::Exists => MemcacheError::Exists,
+        MemcacheError::NotFound => MemcacheError::NotFound,
+        MemcacheError::Timeout => MemcacheError::Timeout,
+        MemcacheError::ConnectionClosed => MemcacheError::ConnectionClosed,
+        MemcacheError::NotConnected => MemcacheError::NotConnected,
+        MemcacheError::CircuitOpen => MemcacheError::CircuitOpen,
+        MemcacheError::ValueTooLarge { size, limit } => MemcacheError::ValueTooLarge {
+            size: *size,
+            limit: *limit,
+        },
+        MemcacheError::KeyTooLong { length, limit } => MemcacheError::KeyTooLong {
+            length: *length,
+            limit: *limit,
+        },
+        MemcacheError::Context(x) => MemcacheError::Context(Box::new(ErrorContext {
+            command: x.command,
+            key: x.key.clone(),
+            error: copy_error(&x.error),
+        })),
+    }
+}
+
+/// Commands to send in a single batch, mixing any kind of command. Every method returns a
+/// handle to the result of the queued command.
+#[derive(Default)]
+pub struct Pipeline<'a> {
+    commands: Vec<(Command<'a>, Slot)>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of queued commands
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    fn push<R>(
+        &mut self,
+        command: Command<'a>,
+        extract: fn(PipelineResponse) -> Result<R, PipelineResponse>,
+    ) -> PipelineHandle<R> {
+        let slot = Slot::default();
+        self.commands.push((command, slot.clone()));
+        PipelineHandle {
+            index: self.commands.len() - 1,
+            slot,
+            extract,
+        }
+    }
+
+    /// Queue a GET
+    pub fn get(&mut self, key: &'a str) -> PipelineHandle<Option<RawValue>> {
+        self.push(Command::Get(key, GetOptions::default()), |x| match x {
+            PipelineResponse::Get(x) => Ok(x.map(|x| x.value)),
+            x => Err(x),
+        })
+    }
+
+    /// Queue a GET with additional options. The deadline of `opts` is ignored.
+    pub fn get_with(
+        &mut self,
+        key: &'a str,
+        opts: &GetOptions,
+    ) -> PipelineHandle<Option<GetResponse>> {
+        self.push(Command::Get(key, opts.clone()), |x| match x {
+            PipelineResponse::Get(x) => Ok(x),
+            x => Err(x),
+        })
+    }
+
+    /// Queue a STORE
+    pub fn set<D: AsRef<[u8]>>(
+        &mut self,
+        key: &'a str,
+        data: &'a RawValue<D>,
+    ) -> PipelineHandle<StoreResult> {
+        self.set_with(key, data, &SetOptions::default())
+    }
+
+    /// Queue a STORE with additional options. data.cas is used as a precondition if set.
+    pub fn set_with<D: AsRef<[u8]>>(
+        &mut self,
+        key: &'a str,
+        data: &'a RawValue<D>,
+        opts: &SetOptions,
+    ) -> PipelineHandle<StoreResult> {
+        self.push(
+            Command::Set(key, data.as_borrowed(), opts.clone()),
+            |x| match x {
+                PipelineResponse::Store(x) => Ok(x),
+                x => Err(x),
+            },
+        )
+    }
+
+    /// Queue a meta DELETE
+    pub fn delete(&mut self, key: &'a str) -> PipelineHandle<DeleteResult> {
+        self.delete_with(key, &DeleteOptions::default())
+    }
+
+    /// Queue a meta DELETE with additional options
+    pub fn delete_with(
+        &mut self,
+        key: &'a str,
+        opts: &DeleteOptions,
+    ) -> PipelineHandle<DeleteResult> {
+        self.push(Command::Delete(key, opts.clone()), |x| match x {
+            PipelineResponse::Delete(x) => Ok(x),
+            x => Err(x),
+        })
+    }
+
+    /// Queue an increment or decrement of a counter
+    pub fn arithmetic(
+        &mut self,
+        key: &'a str,
+        opts: &ArithmeticOptions,
+    ) -> PipelineHandle<ArithmeticResult> {
+        self.push(Command::Arithmetic(key, opts.clone()), |x| match x {
+            PipelineResponse::Arithmetic(x) => Ok(x),
+            x => Err(x),
+        })
+    }
+
+    /// Send the commands using `client`, see [`Client::pipeline`]. The results are delivered
+    /// to the handles returned when queuing the commands.
+    pub async fn execute<T: AsyncReadWriteUnpin>(
+        self,
+        client: &mut Client<T>,
+    ) -> Result<(), MemcacheError> {
+        match client.pipeline(&self).await {
+            Ok(responses) => {
+                for ((_, slot), response) in self.commands.iter().zip(responses) {
+                    *lock(slot) = Some(response);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                for (_, slot) in self.commands.iter() {
+                    *lock(slot) = Some(Err(copy_error(&e)));
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Meta {
+    /// Sends all the commands of `pipeline`, flushes once and reads the responses in order.
+    ///
+    /// Nothing is sent if a key or value is invalid. A command rejected by the server
+    /// ([`MemcacheError::Protocol`]) or a value that can't be decompressed only fails its own
+    /// response, any other error fails the whole pipeline since the remaining responses can't
+    /// be matched to their commands.
+    pub async fn pipeline<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        pipeline: &Pipeline<'_>,
+    ) -> Result<Vec<Result<PipelineResponse, MemcacheError>>, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("pipeline: {} commands", pipeline.len());
+        if self.is_text_only() {
+            return Err(crate::text::unsupported("pipeline"));
+        }
+        // compressed values, if enabled
+        let mut compressed = Vec::with_capacity(pipeline.len());
+        for (command, _) in pipeline.commands.iter() {
+            self.check_key(command.key(), "pipeline")?;
+            let value = match command {
+                Command::Set(key, data, _) => self.encode_value(key, *data)?,
+                _ => None,
+            };
+            compressed.push(value);
+        }
+        // values to store
+        let values: Vec<_> = pipeline
+            .commands
+            .iter()
+            .zip(compressed.iter())
+            .map(|((command, _), compressed)| match command {
+                Command::Set(_, data, opts) => {
+                    let data = compressed.as_ref().map_or(*data, RawValue::as_borrowed);
+                    Some(data.set_time(opts.ttl_of(&data)))
+                }
+                _ => None,
+            })
+            .collect();
+        for data in values.iter().flatten() {
+            self.check_value_size(data, "pipeline")?;
+        }
+
+        let keys: Vec<_> = pipeline
+            .commands
+            .iter()
+            .map(|(command, _)| self.wire_key(command.key()))
+            .collect();
+
+        let marker = [0x0D, 0x0A];
+        for (((command, _), value), key) in
+            pipeline.commands.iter().zip(values.iter()).zip(keys.iter())
+        {
+            let request = match command {
+                Command::Get(_, opts) => get_request(key, false, opts),
+                Command::Set(_, data, opts) => {
+                    let data = value.as_ref().unwrap_or(data);
+                    set_request(key, data, &store_flags(data, opts))
+                }
+                Command::Delete(_, opts) => delete_request(key, opts),
+                Command::Arithmetic(_, opts) => arithmetic_request(key, opts),
+            };
+            io.write_all(request.as_bytes()).await.map_err(io_error)?;
+            if let Some(data) = value {
+                io.write_all(data.data)
+                    .await
+                    .and(io.write_all(&marker).await)
+                    .map_err(io_error)?;
+            }
+        }
+        io.flush().await.map_err(io_error)?;
+
+        let mut retval = Vec::with_capacity(pipeline.len());
+        for ((command, _), key) in pipeline.commands.iter().zip(keys.iter()) {
+            let response = match command {
+                Command::Get(original, opts) => match read_get_response(io, key, opts).await {
+                    Ok(Some(mut x)) => match self.decode_value(original, x.value) {
+                        Ok(Some(value)) => {
+                            x.value = value;
+                            Ok(PipelineResponse::Get(Some(x)))
+                        }
+                        Ok(None) => Ok(PipelineResponse::Get(None)),
+                        Err(e) => {
+                            // only this command failed, the responses that follow are read
+                            retval.push(Err(e));
+                            continue;
+                        }
+                    },
+                    x => x.map(PipelineResponse::Get),
+                },
+                Command::Set(_, _, opts) => read_store_response(io, opts.opaque)
+                    .await
+                    .map(PipelineResponse::Store),
+                Command::Delete(_, opts) => read_delete_response(io, opts)
+                    .await
+                    .map(PipelineResponse::Delete),
+                Command::Arithmetic(_, opts) => read_arithmetic_response(io, opts)
+                    .await
+                    .map(PipelineResponse::Arithmetic),
+            };
+            match response {
+                Ok(x) => retval.push(Ok(x)),
+                Err(e @ MemcacheError::Protocol(_)) => retval.push(Err(e)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(retval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pipeline_reads_responses_in_order() {
+        let meta = Meta::new();
+        let mut io = tokio::io::join(
+            std::io::Cursor::new(
+                b"HD\r\nVA 2 f5 c9 kb\r\nhi\r\nEN\r\nCLIENT_ERROR bad command line format\r\nNF\r\n"
+                    .to_vec(),
+            ),
+            tokio::io::sink(),
+        );
+        let value = RawValue::from_vec(b"hello".to_vec());
+        let opts = ArithmeticOptions::new();
+        let mut pipeline = Pipeline::new();
+        assert_eq!(pipeline.set("a", &value).index(), 0);
+        pipeline.get("b");
+        pipeline.get("c");
+        pipeline.arithmetic("d", &opts);
+        pipeline.delete("e");
+
+        let Ok(responses) = meta.pipeline(&mut io, &pipeline).await else {
+            panic!("Meta.pipeline() failed");
+        };
+        assert_eq!(responses.len(), 5);
+        assert!(matches!(
+            responses[0],
+            Ok(PipelineResponse::Store(StoreResult::Stored))
+        ));
+        let Ok(PipelineResponse::Get(Some(x))) = &responses[1] else {
+            panic!("bad get response");
+        };
+        assert_eq!(x.value.data, b"hi");
+        assert_eq!(x.value.flags, 5);
+        assert!(matches!(responses[2], Ok(PipelineResponse::Get(None))));
+        assert!(
+            matches!(&responses[3], Err(MemcacheError::Protocol(x)) if x.message == "bad command line format")
+        );
+        assert!(matches!(
+            responses[4],
+            Ok(PipelineResponse::Delete(DeleteResult::NotFound))
+        ));
+    }
+
+    #[test]
+    fn handles_resolve_to_their_response() {
+        let value = RawValue::from_vec(b"hello".to_vec());
+        let mut pipeline = Pipeline::new();
+        let stored = pipeline.set("a", &value);
+        let fetched = pipeline.get("b");
+        let deleted = pipeline.delete("c");
+        let slots: Vec<Slot> = pipeline.commands.iter().map(|(_, x)| x.clone()).collect();
+        *lock(&slots[0]) = Some(Ok(PipelineResponse::Store(StoreResult::Stored)));
+        *lock(&slots[1]) = Some(Ok(PipelineResponse::Get(None)));
+
+        assert!(matches!(stored.result(), Ok(StoreResult::Stored)));
+        assert!(matches!(fetched.result(), Ok(None)));
+        assert!(matches!(deleted.result(), Err(MemcacheError::NotConnected)));
+    }
+}
@@ -0,0 +1,463 @@
+//! Connection pool
+//!
+//! The pool hands out [`PooledClient`] guards that dereference to a [`Client`] and return the
+//! connection to the pool when dropped. Connections that failed with an I/O error (or reached
+//! EOF) are dropped instead and replaced by a new connection on a later checkout.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::backoff::BackoffPolicy;
+use crate::breaker::CircuitBreaker;
+use crate::error::MemcacheError;
+use crate::{BoxedConnection, Client, ClientOptions};
+
+use log::{debug, error};
+
+/// Configuration of a [`Pool`]
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    /// Connections opened when the pool is created
+    pub min_connections: usize,
+    /// Maximum number of connections, checked out or idle
+    pub max_connections: usize,
+    /// How long [`Pool::get`] waits for a free connection, `None` to wait forever
+    pub checkout_timeout: Option<Duration>,
+    /// Retries when opening a new connection fails, single attempt by default
+    pub backoff: BackoffPolicy,
+    /// Circuit breaker shared by all the connections of the pool
+    pub circuit_breaker: Option<CircuitBreaker>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            min_connections: 0,
+            max_connections: 16,
+            checkout_timeout: Some(Duration::from_secs(5)),
+            backoff: BackoffPolicy::default(),
+            circuit_breaker: None,
+        }
+    }
+}
+
+impl PoolOptions {
+    /// Create the default pool options
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the number of connections opened when the pool is created
+    pub fn min_connections(mut self, x: usize) -> Self {
+        self.min_connections = x;
+        self
+    }
+    /// Set the maximum number of connections
+    pub fn max_connections(mut self, x: usize) -> Self {
+        self.max_connections = x;
+        self
+    }
+    /// Set how long to wait for a free connection
+    pub fn checkout_timeout(mut self, x: Option<Duration>) -> Self {
+        self.checkout_timeout = x;
+        self
+    }
+    /// Set the retries when opening a new connection fails
+    pub fn backoff(mut self, x: BackoffPolicy) -> Self {
+        self.backoff = x;
+        self
+    }
+    /// Set the circuit breaker, opening new connections fails fast while it is open
+    pub fn circuit_breaker(mut self, x: Option<CircuitBreaker>) -> Self {
+        self.circuit_breaker = x;
+        self
+    }
+}
+
+/// Connection used by pooled clients, remembers whether it failed
+pub struct PoolConnection {
+    inner: BoxedConnection,
+    broken: bool,
+}
+
+impl PoolConnection {
+    fn new(inner: BoxedConnection) -> Self {
+        PoolConnection {
+            inner,
+            broken: false,
+        }
+    }
+
+    /// Whether the connection failed and should not be reused
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    fn track<T>(&mut self, x: Poll<std::io::Result<T>>) -> Poll<std::io::Result<T>> {
+        if let Poll::Ready(Err(_)) = &x {
+            self.broken = true;
+        }
+        x
+    }
+}
+
+impl AsyncRead for PoolConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let x = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = &x {
+            if buf.remaining() > 0 && buf.filled().len() == filled {
+                self.broken = true;
+            }
+        }
+        self.track(x)
+    }
+}
+
+impl AsyncBufRead for PoolConnection {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_fill_buf(cx) {
+            Poll::Ready(Ok(x)) => {
+                if x.is_empty() {
+                    this.broken = true;
+                }
+                Poll::Ready(Ok(x))
+            }
+            Poll::Ready(Err(e)) => {
+                this.broken = true;
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.inner).consume(amt)
+    }
+}
+
+impl AsyncWrite for PoolConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let x = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.track(x)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let x = Pin::new(&mut self.inner).poll_flush(cx);
+        self.track(x)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.broken = true;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+struct PoolInner {
+    url: String,
+    options: PoolOptions,
+    idle: Mutex<Vec<Client<PoolConnection>>>,
+    permits: Arc<Semaphore>,
+}
+
+/// Pool of connections to a single memcached server. Cheap to clone, clones share the
+/// connections.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pool")
+            .field("url", &self.inner.url)
+            .field("options", &self.inner.options)
+            .finish()
+    }
+}
+
+impl Pool {
+    /// Create a pool of connections to the server described by a connection URL, see
+    /// [`Client::connect`]. `options.min_connections` connections are opened right away.
+    pub async fn connect(url: &str, options: PoolOptions) -> Result<Self, MemcacheError> {
+        let max = options.max_connections.max(1);
+        let mut idle = Vec::with_capacity(options.min_connections);
+        for _ in 0..options.min_connections.min(max) {
+            idle.push(open_with(url, &options).await?);
+        }
+        Ok(Pool {
+            inner: Arc::new(PoolInner {
+                url: url.to_string(),
+                options,
+                idle: Mutex::new(idle),
+                permits: Arc::new(Semaphore::new(max)),
+            }),
+        })
+    }
+
+    /// Check out a connection, opening a new one if none is idle. Waits for a connection to be
+    /// returned if `max_connections` are already in use.
+    pub async fn get(&self) -> Result<PooledClient, MemcacheError> {
+        let permit = self.inner.permits.clone().acquire_owned();
+        let permit = match self.inner.options.checkout_timeout {
+            Some(x) => match tokio::time::timeout(x, permit).await {
+                Ok(x) => x,
+                Err(_) => {
+                    error!("pool: timed out waiting for a connection");
+                    return Err(MemcacheError::Timeout);
+                }
+            },
+            None => permit.await,
+        };
+        // the semaphore is never closed
+        let permit = permit.expect("pool semaphore closed");
+
+        let idle = self
+            .inner
+            .idle
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .pop();
+        let client = match idle {
+            Some(x) => x,
+            None => open_with(&self.inner.url, &self.inner.options).await?,
+        };
+        Ok(PooledClient {
+            client: Some(client),
+            pool: self.inner.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// Number of idle connections
+    pub fn idle(&self) -> usize {
+        self.inner
+            .idle
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .len()
+    }
+}
+
+/// Opens a new tracked connection to the server described by a connection URL, with the
+/// timeouts set by the URL
+pub(crate) async fn open(url: &str) -> Result<Client<PoolConnection>, MemcacheError> {
+    debug!("pool: opening a new connection to {}", url);
+    let (connection, options) = Client::connect_parts(url, &ClientOptions::default()).await?;
+    Client::with_options(PoolConnection::new(connection), &options)
+}
+
+/// Opens a new connection applying the backoff policy and circuit breaker of `options`
+async fn open_with(
+    url: &str,
+    options: &PoolOptions,
+) -> Result<Client<PoolConnection>, MemcacheError> {
+    let Some(breaker) = &options.circuit_breaker else {
+        return options.backoff.retry("pool", || open(url)).await;
+    };
+    breaker.admit()?;
+    let retval = options.backoff.retry("pool", || open(url)).await;
+    breaker.record(&retval);
+    let mut client = retval?;
+    client.set_circuit_breaker(Some(breaker.clone()));
+    Ok(client)
+}
+
+/// A client checked out of a [`Pool`], returned to the pool when dropped
+pub struct PooledClient {
+    client: Option<Client<PoolConnection>>,
+    pool: Arc<PoolInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledClient {
+    /// Drop the connection instead of returning it to the pool, e.g. after a command failed
+    /// with a protocol error or its future was cancelled, either of which may leave unread
+    /// responses on the connection.
+    pub fn mark_broken(&mut self) {
+        if let Some(x) = self.client.as_mut() {
+            x.connection.broken = true;
+        }
+    }
+}
+
+impl std::ops::Deref for PooledClient {
+    type Target = Client<PoolConnection>;
+
+    fn deref(&self) -> &Self::Target {
+        self.client.as_ref().expect("client taken")
+    }
+}
+
+impl std::ops::DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.client.as_mut().expect("client taken")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        if client.connection.is_broken() {
+            debug!("pool: dropping a broken connection");
+            return;
+        }
+        self.pool
+            .idle
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .push(client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::BreakerOptions;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// Pool holding `count` idle in-memory connections, returns their server ends. New
+    /// connections can't be opened.
+    fn pool(options: PoolOptions, count: usize) -> (Pool, Vec<DuplexStream>) {
+        let mut idle = Vec::new();
+        let mut servers = Vec::new();
+        for _ in 0..count {
+            let (client, server) = tokio::io::duplex(1024);
+            let connection: BoxedConnection = Box::new(tokio::io::BufStream::new(client));
+            idle.push(Client::new(PoolConnection::new(connection)));
+            servers.push(server);
+        }
+        let pool = Pool {
+            inner: Arc::new(PoolInner {
+                url: "memcache://127.0.0.1:1".to_string(),
+                permits: Arc::new(Semaphore::new(options.max_connections)),
+                options,
+                idle: Mutex::new(idle),
+            }),
+        };
+        (pool, servers)
+    }
+
+    fn checkout_failed<T>(_: T) -> PooledClient {
+        panic!("checkout failed")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn checkout_timeout() {
+        let options = PoolOptions::new()
+            .max_connections(1)
+            .checkout_timeout(Some(Duration::from_secs(1)));
+        let (pool, _servers) = pool(options, 1);
+        let c = pool.get().await.unwrap_or_else(checkout_failed);
+        assert!(matches!(pool.get().await, Err(MemcacheError::Timeout)));
+
+        // a connection returned in the meantime is handed out
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.get().await.is_ok() }
+        });
+        tokio::task::yield_now().await;
+        drop(c);
+        assert!(waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused() {
+        let (pool, mut servers) = pool(PoolOptions::new(), 1);
+        servers[0].write_all(b"VERSION 1.6.0\r\n").await.unwrap();
+        let mut c = pool.get().await.unwrap_or_else(checkout_failed);
+        assert_eq!(pool.idle(), 0);
+        assert_eq!(c.version().await.unwrap(), "1.6.0");
+        drop(c);
+        assert_eq!(pool.idle(), 1);
+
+        servers[0].write_all(b"VERSION 1.6.1\r\n").await.unwrap();
+        let mut c = pool.get().await.unwrap_or_else(checkout_failed);
+        assert_eq!(c.version().await.unwrap(), "1.6.1");
+        drop(c);
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[tokio::test]
+    async fn broken_connections_are_dropped() {
+        let (pool, mut servers) = pool(PoolOptions::new(), 2);
+        // closed by the server, the last idle connection is checked out first
+        servers.pop();
+        let mut c = pool.get().await.unwrap_or_else(checkout_failed);
+        assert!(c.version().await.is_err());
+        assert!(c.connection.is_broken());
+        drop(c);
+        assert_eq!(pool.idle(), 1);
+
+        // marked by the caller, e.g. after cancelling a command
+        let mut c = pool.get().await.unwrap_or_else(checkout_failed);
+        c.mark_broken();
+        drop(c);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[tokio::test]
+    async fn eof_marks_connection() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut connection = PoolConnection::new(Box::new(tokio::io::BufStream::new(client)));
+        drop(server);
+        let mut buf = [0; 8];
+        assert_eq!(connection.read(&mut buf).await.unwrap(), 0);
+        assert!(connection.is_broken());
+
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut connection = PoolConnection::new(Box::new(tokio::io::BufStream::new(client)));
+        server.write_all(b"x").await.unwrap();
+        drop(server);
+        // through poll_fill_buf
+        let mut line = Vec::new();
+        connection.read_until(b'\n', &mut line).await.unwrap();
+        assert_eq!(line, b"x");
+        assert!(connection.is_broken());
+    }
+
+    #[tokio::test]
+    async fn breaker_guards_new_connections() {
+        let breaker = CircuitBreaker::new(BreakerOptions::new().failure_threshold(1));
+        let options = PoolOptions::new().circuit_breaker(Some(breaker));
+        // nothing listens on port 1
+        let pool = Pool::connect("memcache://127.0.0.1:1", options)
+            .await
+            .unwrap();
+        assert!(matches!(pool.get().await, Err(MemcacheError::IOError(_))));
+        assert!(matches!(pool.get().await, Err(MemcacheError::CircuitOpen)));
+    }
+
+    #[tokio::test]
+    async fn url_timeouts_apply() {
+        // accepts connections but never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "memcache://{}?read_timeout=50",
+            listener.local_addr().unwrap()
+        );
+        let accept = tokio::spawn(async move { listener.accept().await });
+        let pool = Pool::connect(&url, PoolOptions::new()).await.unwrap();
+        let mut c = pool
+            .get()
+            .await
+            .unwrap_or_else(|_| panic!("checkout failed"));
+        let _server = accept.await.unwrap().unwrap();
+        assert!(matches!(c.version().await, Err(MemcacheError::Timeout)));
+    }
+}
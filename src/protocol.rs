@@ -2,25 +2,43 @@
 //!
 //! reference: [`protocol.txt`](https://github.com/memcached/memcached/blob/master/doc/protocol.txt)
 
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use std::borrow::Cow;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::error::MemcacheError;
+use bytes::Bytes;
+use futures_util::Stream;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::chunked::FLAG_CHUNKED;
+use crate::compress::Compression;
+use crate::error::{ErrorCode, MemcacheError, ProtocolError};
+use crate::keys::LongKeys;
+use crate::stats::{ConnectionStats, ServerStats, SettingsStats, SizeStats, SlabStats};
+use crate::text;
+use crate::timeout::TimedIo;
+use crate::value::FLAG_TEXT;
+use crate::watch::{WatchEvent, WatchTarget};
 use crate::AsyncReadWriteUnpin;
 
 use log::{debug, error};
 
 pub type FrameData = RawValue;
 
-/// Data that can be represented when storing or reading a value
-pub struct RawValue {
+/// Data that can be represented when storing or reading a value. Values read from memcached
+/// own their data, values being stored may also borrow it (`RawValue<&[u8]>`) or share it
+/// (see [`BytesValue`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RawValue<D = Vec<u8>> {
     /// Raw data as stored in memcached.
-    pub data: Vec<u8>,
+    pub data: D,
     /// Flags associated with the key
     pub flags: u32,
     /// Time for the value to expire in seconds, None if it shouldn't expire (NOTE: memcached MAY remove the key ANYWAY if it reaches the memory limit)
+    /// Values over 30 days are read by memcached as unix timestamps, see [`RawValue::expires_in`].
+    /// When reading, only filled if requested with [`GetOptions::return_ttl`].
     pub time: Option<u32>,
-    /// Key used for Compare-And-Store operations. Not used yet.
-    pub cas: Option<u32>,
+    /// Key used for Compare-And-Store operations. Filled by the meta get commands.
+    pub cas: Option<u64>,
 }
 
 impl std::convert::From<Vec<u8>> for RawValue {
@@ -33,6 +51,35 @@ impl std::convert::From<Vec<u8>> for RawValue {
         }
     }
 }
+
+/// UTF-8 string, flagged as text like [`ToMemcacheValue`](crate::value::ToMemcacheValue) does
+impl From<String> for RawValue {
+    fn from(v: String) -> Self {
+        RawValue::from_vec(v.into_bytes()).set_flags(FLAG_TEXT)
+    }
+}
+
+impl From<&str> for RawValue {
+    fn from(v: &str) -> Self {
+        v.to_string().into()
+    }
+}
+
+/// Value holding its data in a shared [`Bytes`] buffer, which can be sliced and passed
+/// around without copying
+pub type BytesValue = RawValue<Bytes>;
+
+impl From<RawValue> for BytesValue {
+    fn from(v: RawValue) -> Self {
+        RawValue {
+            data: Bytes::from(v.data),
+            flags: v.flags,
+            time: v.time,
+            cas: v.cas,
+        }
+    }
+}
+
 impl RawValue {
     pub fn from_vec(v: Vec<u8>) -> Self {
         Self {
@@ -43,12 +90,56 @@ impl RawValue {
         }
     }
 
+    /// Data as an UTF-8 string
+    pub fn into_string(self) -> Result<String, std::string::FromUtf8Error> {
+        String::from_utf8(self.data)
+    }
+}
+
+impl<D: AsRef<[u8]>> RawValue<D> {
+    /// Value borrowing the data of this one
+    pub fn as_borrowed(&self) -> RawValue<&[u8]> {
+        RawValue {
+            data: self.data.as_ref(),
+            flags: self.flags,
+            time: self.time,
+            cas: self.cas,
+        }
+    }
+
+    /// Data as an UTF-8 string
+    pub fn as_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(self.data.as_ref())
+    }
+}
+
+impl<D> RawValue<D> {
+    /// Value holding `data`, e.g. a reader for [`Meta::set_from_reader`]
+    pub fn new(data: D) -> Self {
+        Self {
+            data,
+            flags: 0,
+            time: None,
+            cas: None,
+        }
+    }
+
     pub fn set_time(mut self, t: Option<u32>) -> Self {
         self.time = t;
         self
     }
 
-    pub fn set_cas(mut self, c: Option<u32>) -> Self {
+    /// Expire the value after `d`, see [`ttl_from_duration`]
+    pub fn expires_in(self, d: Duration) -> Self {
+        self.set_time(Some(ttl_from_duration(d)))
+    }
+
+    /// Expire the value at `t`, see [`ttl_from_system_time`]
+    pub fn expires_at(self, t: SystemTime) -> Self {
+        self.set_time(Some(ttl_from_system_time(t)))
+    }
+
+    pub fn set_cas(mut self, c: Option<u64>) -> Self {
         self.cas = c;
         self
     }
@@ -59,9 +150,50 @@ impl RawValue {
     }
 }
 
+/// Longest TTL memcached reads as a number of seconds, larger ones are unix timestamps
+const MAX_RELATIVE_TTL: u32 = 60 * 60 * 24 * 30;
+
+/// TTL expiring a value after `d`, rounded up to the next second since 0 never expires.
+/// Durations over 30 days are converted to an absolute unix timestamp, saturating at the
+/// largest one.
+pub fn ttl_from_duration(d: Duration) -> u32 {
+    let secs = d.as_secs().saturating_add(u64::from(d.subsec_nanos() > 0));
+    if secs > MAX_RELATIVE_TTL as u64 {
+        return match SystemTime::now().checked_add(Duration::from_secs(secs)) {
+            Some(x) => ttl_from_system_time(x),
+            None => u32::MAX,
+        };
+    }
+    secs.max(1) as u32
+}
+
+/// TTL expiring a value at `t`, as an absolute unix timestamp. Times in the past expire the
+/// value immediately.
+pub fn ttl_from_system_time(t: SystemTime) -> u32 {
+    match t.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(x) => x
+            .as_secs()
+            .clamp(MAX_RELATIVE_TTL as u64 + 1, u32::MAX as u64) as u32,
+        // still read as a timestamp, long gone
+        Err(_) => MAX_RELATIVE_TTL + 1,
+    }
+}
+
+/// Largest value stored by default, matches the default item size limit of memcached (`-I`)
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 1024 * 1024;
+
 /// Fake object representing the META protocol (TEXT protocol extended with additional commands)
 #[derive(Debug)]
-pub struct Meta {}
+pub struct Meta {
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_value_size: usize,
+    text_only: bool,
+    compression: Option<Compression>,
+    chunking: bool,
+    namespace: Option<String>,
+    long_keys: LongKeys,
+}
 
 /*
 * flags set:
@@ -83,8 +215,17 @@ pub struct Meta {}
 *  T = UPDATE time remaining
 */
 
+/// Rejects namespaces that would make the keys invalid, see [`Meta::namespace`]
+pub(crate) fn check_namespace(prefix: Option<&str>) -> Result<(), MemcacheError> {
+    if prefix.is_some_and(check_key_invalid) {
+        error!("namespace: invalid prefix");
+        return Err(MemcacheError::BadKey);
+    }
+    Ok(())
+}
+
 /// key cannot contain control characters or space
-fn check_key_invalid(key: &str) -> bool {
+pub(crate) fn check_key_invalid(key: &str) -> bool {
     for b in key.bytes() {
         if b <= 32 || b >= 127 {
             return true;
@@ -93,353 +234,1928 @@ fn check_key_invalid(key: &str) -> bool {
     false
 }
 
-impl Default for Meta {
+/// Mode of the meta arithmetic command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArithmeticMode {
+    /// Increment the counter (`MI`)
+    #[default]
+    Increment,
+    /// Decrement the counter (`MD`). memcached does not let counters go below 0.
+    Decrement,
+}
+
+/// Options for the meta arithmetic (`ma`) command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArithmeticOptions {
+    /// Increment or decrement
+    pub mode: ArithmeticMode,
+    /// Amount to add or subtract (`D`)
+    pub delta: u64,
+    /// Create the counter with this TTL if it does not exist (`N`)
+    pub vivify_ttl: Option<u32>,
+    /// Value of a counter created by `vivify_ttl` (`J`). Ignored unless `vivify_ttl` is set.
+    pub initial: Option<u64>,
+    /// Update the TTL of the counter (`T`)
+    pub ttl: Option<u32>,
+    /// Return the value of the counter after the operation (`v`)
+    pub return_value: bool,
+    /// Opaque token sent with the request and echoed back by the server (`O`)
+    pub opaque: Option<u32>,
+}
+
+impl Default for ArithmeticOptions {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl Meta {
+impl ArithmeticOptions {
+    /// Increment by 1, do not create missing counters, do not return the new value
     pub fn new() -> Self {
-        Meta {}
+        ArithmeticOptions {
+            mode: ArithmeticMode::Increment,
+            delta: 1,
+            vivify_ttl: None,
+            initial: None,
+            ttl: None,
+            return_value: false,
+            opaque: None,
+        }
     }
 
-    /// GET a value from memcached
-    /// returns Ok(Some(x)) when key is found
-    /// returns Ok(None) if key was not found
-    pub async fn get<T: AsyncReadWriteUnpin>(
-        &self,
-        io: &mut T,
-        key: &str,
-    ) -> Result<Option<RawValue>, MemcacheError> {
-        debug!("get {}", key);
-        // key cannot contain control characters or space
-        if check_key_invalid(key) {
-            error!("get: invalid key");
-            return Err(MemcacheError::BadKey);
-        }
-        let request = format!("mg {} f v\r\n", key).into_bytes();
-        io.write_all(&request)
-            .await
-            .and(io.flush().await)
-            .map_err(MemcacheError::IOError)?;
+    pub fn mode(mut self, m: ArithmeticMode) -> Self {
+        self.mode = m;
+        self
+    }
 
-        let mut response_hdr: Vec<u8> = Vec::new();
-        let _ = io
-            .read_until(0xA, &mut response_hdr)
-            .await
-            .map_err(MemcacheError::IOError)?;
-        //debug!("RESPONSE HDR: {}", hex::encode(&response_hdr));
-        if response_hdr.len() >= 2 {
-            response_hdr.truncate(response_hdr.len() - 2);
-        }
+    pub fn delta(mut self, d: u64) -> Self {
+        self.delta = d;
+        self
+    }
 
-        // header shoul be just ASCII
-        let Ok(response_hdr_base) = String::from_utf8(response_hdr) else {
-            error!("get: non-ASCII response");
-            return Err(MemcacheError::BadServerResponse);
-        };
-        let mut response_hdr = response_hdr_base.split_ascii_whitespace();
+    pub fn vivify(mut self, ttl: u32, initial: u64) -> Self {
+        self.vivify_ttl = Some(ttl);
+        self.initial = Some(initial);
+        self
+    }
 
-        let Some(response_cmd) = response_hdr.next() else {
-            error!("get: malformed response {}", response_hdr_base);
-            return Err(MemcacheError::BadServerResponse);
-        };
-        if response_cmd == "EN" {
-            debug!("get: no key");
-            return Ok(None);
-        } else if response_cmd != "VA" {
-            error!("get: malformed response key {}", response_cmd);
-            return Err(MemcacheError::BadServerResponse);
-        }
+    pub fn ttl(mut self, t: Option<u32>) -> Self {
+        self.ttl = t;
+        self
+    }
 
-        let Some(data_length) = response_hdr.next().and_then(|x| x.parse::<usize>().ok()) else {
-            error!("get: bad data_length");
-            return Err(MemcacheError::BadServerResponse);
-        };
+    pub fn return_value(mut self, v: bool) -> Self {
+        self.return_value = v;
+        self
+    }
 
-        let Some(flags) = response_hdr.next().and_then(|x|{
-                if x.as_bytes().first() == Some(&b'f') {
-                    (x[1..]).parse::<u32>().ok()
-                } else {
-                    None
-                }
-            }) else {
-            error!("get: missing flags");
-            return Err(MemcacheError::BadServerResponse);
-        };
+    pub fn opaque(mut self, o: Option<u32>) -> Self {
+        self.opaque = o;
+        self
+    }
+}
 
-        if response_hdr.next().is_some() {
-            error!("get: header too long");
-            return Err(MemcacheError::BadServerResponse);
-        };
+/// Outcome of the meta arithmetic command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticResult {
+    /// The counter was updated (or created). Contains its new value if it was requested.
+    Updated(Option<u64>),
+    /// The counter does not exist
+    NotFound,
+    /// The counter could not be created
+    NotStored,
+}
 
-        let mut response_data: Vec<u8> = vec![0; data_length + 2];
-        let _ = io
-            .read_exact(&mut response_data)
-            .await
-            .map_err(MemcacheError::IOError)?;
-        response_data.truncate(data_length);
+/// Options for the meta get (`mg`) command
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GetOptions {
+    /// Return the remaining TTL of the item in `RawValue.time` (`t`)
+    pub return_ttl: bool,
+    /// Return the number of seconds since the item was last accessed (`l`)
+    pub return_last_access: bool,
+    /// Return whether the item has been fetched since it was stored (`h`)
+    pub return_hit_before: bool,
+    /// On a miss, create an empty item with this TTL (`N`). The client that created it gets
+    /// [`GetResponse::won_recache`] set and is expected to populate the item.
+    pub vivify_ttl: Option<u32>,
+    /// If the remaining TTL of the item is lower than this, the first client to read it wins
+    /// the right to refresh it (`R`) while the others keep getting the old value
+    pub recache_ttl: Option<u32>,
+    /// Update the TTL of the item as a side effect of reading it (`T`)
+    pub touch_ttl: Option<u32>,
+    /// Opaque token sent with the request and echoed back by the server (`O`)
+    pub opaque: Option<u32>,
+    /// Fail with [`MemcacheError::Timeout`] if the command is not done by then. Overrides the
+    /// read and write timeouts of the client.
+    pub deadline: Option<Instant>,
+}
 
-        debug!("get: received data");
-        Ok(Some(RawValue {
-            data: response_data,
-            flags,
-            time: None,
-            cas: None,
-        }))
+impl GetOptions {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    /// GET multiple values from memcached
-    /// returns Ok(Vec((key,RawValue))) with a list of key-value tuples
-    ///
-    /// If a key is not found in the response then it does not exist currently
-    /// in memcached
-    // clippy complains about a read to a zero-length vector, but read_until appends data
-    // to a vector
-    #[allow(clippy::read_zero_byte_vec)]
-    pub async fn get_many<T: AsyncReadWriteUnpin>(
-        &self,
-        io: &mut T,
-        key_list: &[&str],
-    ) -> Result<Vec<(String, RawValue)>, MemcacheError> {
-        let mut keysize = 0;
-        for k in key_list {
-            if check_key_invalid(k) {
-                error!("get_multi: invalid key");
-                return Err(MemcacheError::BadKey);
-            }
-            keysize += k.len();
-        }
-        //get key_1 key_2 key_3\r\n
-        //
-        //VALUE key_1 FLAG SIZE\r\n
-        //DATA\r\n
-        //VALUE key_3 FLAG SIZE\r\n
-        //DATA\r\n
-        //END\r\n
-        let mut send = String::with_capacity(10 + key_list.len() + keysize); // 5 should be enough, but
-                                                                             // let's not chance it
-        send.push_str("get");
-        for k in key_list {
-            send.push(' ');
-            send.push_str(k);
-        }
-        send.push_str("\r\n");
-        io.write_all(&send.into_bytes())
-            .await
-            .and(io.flush().await)
-            .map_err(MemcacheError::IOError)?;
+    pub fn return_ttl(mut self, t: bool) -> Self {
+        self.return_ttl = t;
+        self
+    }
 
-        let mut retval = Vec::new();
-        let mut buffer = Vec::new();
-        loop {
-            buffer.clear();
-            let _ = io
-                .read_until(0xA, &mut buffer)
-                .await
-                .map_err(MemcacheError::IOError)?;
-            if buffer.len() >= 2 {
-                buffer.truncate(buffer.len() - 2);
-            }
-            if buffer == b"END" {
-                return Ok(retval);
-            }
-            let Ok(response) = String::from_utf8(buffer.clone()) else {
-                //error!("get_multi: non-ASCII response: {}", hex::encode(buffer));
-                error!("get_multi: non-ASCII response");
-                return Err(MemcacheError::BadServerResponse);
-            };
-            let mut response_hdr = response.split_ascii_whitespace();
-            let Some(response_cmd) = response_hdr.next() else {
-                error!("get_mutli: malformed response {}", response);
-                return Err(MemcacheError::BadServerResponse);
-            };
-            if response_cmd != "VALUE" {
-                error!("get_multi: server response error: {}", response_cmd);
-                return Err(MemcacheError::BadServerResponse);
-            }
+    pub fn return_last_access(mut self, l: bool) -> Self {
+        self.return_last_access = l;
+        self
+    }
 
-            let Some(key) = response_hdr.next() else {
-                error!("get_multi: missing key");
-                return Err(MemcacheError::BadServerResponse);
-            };
+    pub fn return_hit_before(mut self, h: bool) -> Self {
+        self.return_hit_before = h;
+        self
+    }
 
-            let Some(flags) = response_hdr.next().and_then(|x| x.parse::<u32>().ok()) else {
-                error!("get_multi: bad flags");
-                return Err(MemcacheError::BadServerResponse);
-            };
+    pub fn vivify(mut self, ttl: Option<u32>) -> Self {
+        self.vivify_ttl = ttl;
+        self
+    }
 
-            let Some(data_length) = response_hdr.next().and_then(|x| x.parse::<usize>().ok()) else {
-                error!("get_multi: bad data_length");
-                return Err(MemcacheError::BadServerResponse);
-            };
+    pub fn recache(mut self, ttl: Option<u32>) -> Self {
+        self.recache_ttl = ttl;
+        self
+    }
 
-            if response_hdr.next().is_some() {
-                error!("get_multi: header too long");
-                return Err(MemcacheError::BadServerResponse);
-            };
+    pub fn touch(mut self, ttl: Option<u32>) -> Self {
+        self.touch_ttl = ttl;
+        self
+    }
 
-            buffer.resize(data_length + 2, 0);
-            let _ = io
-                .read_exact(&mut buffer)
-                .await
-                .map_err(MemcacheError::IOError)?;
-            buffer.truncate(data_length);
+    pub fn opaque(mut self, o: Option<u32>) -> Self {
+        self.opaque = o;
+        self
+    }
 
-            retval.push((
-                key.to_string(),
-                RawValue {
-                    data: buffer.clone(),
-                    flags,
-                    time: None,
-                    cas: None,
-                },
-            ));
-        }
+    pub fn deadline(mut self, d: Instant) -> Self {
+        self.deadline = Some(d);
+        self
     }
+}
 
-    /// STORE function. Stores provided data using the provided key.
-    /// data.time determines for how many seconds memcached should keep the data. Setting it to
-    /// None will make memcached keep the data for as long as possible (data may still be dropped
-    /// if memcached reaches its memory limit)
-    /// WARNING: CAS is not yet supported.
-    pub async fn set<T: AsyncReadWriteUnpin>(
-        &self,
-        io: &mut T,
-        key: &str,
-        data: &RawValue,
-    ) -> Result<(), MemcacheError> {
-        debug!("set {}", key);
-        // key cannot contain control characters or space
-        if check_key_invalid(key) {
-            error!("set: invalid key");
-            return Err(MemcacheError::BadKey);
-        }
-        let request = format!(
-            "ms {} S{} T{} F{}\r\n",
-            key,
-            data.data.len(),
-            data.time.unwrap_or(0),
-            data.flags
-        );
-        let request = request.into_bytes();
-        let marker = [0x0D, 0x0A];
-        io.write_all(&request)
-            .await
-            .and(io.write_all(&data.data).await)
-            .and(io.write_all(&marker).await)
-            .and(io.flush().await)
-            .map_err(MemcacheError::IOError)?;
+/// Value returned by [`Meta::get_with`] along with the requested item metadata
+///
+/// When an item is stale (invalidated) or about to expire (see [`GetOptions::recache_ttl`]),
+/// exactly one client gets `won_recache` set and should refresh the item. The other clients get
+/// `recache_pending` set and can keep using the old value in the meantime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetResponse {
+    /// The value itself
+    pub value: RawValue,
+    /// Seconds since the item was last accessed, if requested
+    pub last_access: Option<u32>,
+    /// Whether the item has been fetched before since it was stored, if requested
+    pub hit_before: Option<bool>,
+    /// This client won the right to populate the item (`W`), e.g. the item was just created
+    /// because of [`GetOptions::vivify_ttl`]
+    pub won_recache: bool,
+    /// The item was invalidated and is served stale (`X`)
+    pub stale: bool,
+    /// Another client already won the right to refresh the item (`Z`)
+    pub recache_pending: bool,
+}
 
-        let mut response_hdr: Vec<u8> = Vec::new();
-        let _ = io
-            .read_until(0xA, &mut response_hdr)
-            .await
-            .map_err(MemcacheError::IOError)?;
-        if response_hdr.len() >= 2 {
-            response_hdr.truncate(response_hdr.len() - 2);
-        }
+/// Outcome of the meta set command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreResult {
+    /// The value was stored (`HD`)
+    Stored,
+    /// The CAS value did not match, the value was not stored (`EX`)
+    Exists,
+    /// The item to compare against does not exist (`NF`)
+    NotFound,
+    /// The value was not stored (`NS`)
+    NotStored,
+}
 
-        let Ok(response_hdr) = String::from_utf8(response_hdr) else {
-            error!("set: bad header");
-            return Err(MemcacheError::BadServerResponse);
-        };
-        let mut response_hdr = response_hdr.split_ascii_whitespace();
+/// Mode of the meta set command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StoreMode {
+    /// Store the value unconditionally (`MS`)
+    #[default]
+    Set,
+    /// Store only if the item does not exist (`ME`)
+    Add,
+    /// Append the data to an existing item (`MA`)
+    Append,
+    /// Prepend the data to an existing item (`MP`)
+    Prepend,
+    /// Store only if the item already exists (`MR`)
+    Replace,
+}
 
-        let Some(response_cmd) = response_hdr.next() else {
-            return Err(MemcacheError::BadServerResponse);
-        };
-        match response_cmd {
-            "OK" => {
-                debug!("set: OK");
-                Ok(())
-            }
-            "HD" => {
-                debug!("set: OK");
-                Ok(())
-            }
-            "CLIENT_ERROR" => {
-                debug!("set: client error");
-                Err(MemcacheError::BadQuery)
-            }
-            x => {
-                error!("set: unexpected reponse {}", x);
-                Err(MemcacheError::BadServerResponse)
-            }
+impl StoreMode {
+    /// Mode token used on the wire
+    fn token(&self) -> &'static str {
+        match self {
+            StoreMode::Set => "S",
+            StoreMode::Add => "E",
+            StoreMode::Append => "A",
+            StoreMode::Prepend => "P",
+            StoreMode::Replace => "R",
         }
     }
+}
 
-    /// STORE function. Stores provided data using the provided key.
-    /// data.time determines for how many seconds memcached should keep the data. Setting it to
-    /// None will make memcached keep the data for as long as possible (data may still be dropped
-    /// if memcached reaches its memory limit)
-    /// WARNING: CAS is not yet supported.
-    pub async fn set_multiple<'a, 'b, T: AsyncReadWriteUnpin>(
-        &self,
-        io: &mut T,
-        keydata: &[(&str, &RawValue)],
-    ) -> Result<(), MemcacheError> {
-        debug!("set_multiple");
-        // key cannot contain control characters or space
-        let marker = [0x0D, 0x0A];
-        for (key, data) in keydata.iter() {
-            if check_key_invalid(key) {
-                error!("set: invalid key");
-                return Err(MemcacheError::BadKey);
-            }
-            let request = format!(
-                "ms {} S{} T{} F{}\r\n",
-                key,
-                data.data.len(),
-                data.time.unwrap_or(0),
-                data.flags
-            )
-            .into_bytes();
-            io.write_all(&request)
-                .await
-                .and(io.write_all(&data.data).await)
-                .and(io.write_all(&marker).await)
-                .map_err(MemcacheError::IOError)?;
-        }
-        io.flush().await.map_err(MemcacheError::IOError)?;
-
-        let mut response_hdr: Vec<u8> = Vec::new();
-        let _ = io
-            .read_until(0xA, &mut response_hdr)
-            .await
-            .map_err(MemcacheError::IOError)?;
-        if response_hdr.len() >= 2 {
-            response_hdr.truncate(response_hdr.len() - 2);
-        }
-
-        let Ok(response_hdr) = String::from_utf8(response_hdr) else {
-            error!("set: bad header");
-            return Err(MemcacheError::BadServerResponse);
-        };
-        let mut response_hdr = response_hdr.split_ascii_whitespace();
+/// Options for the meta set (`ms`) command
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SetOptions {
+    /// How to store the value (`M`). Conditional modes return [`StoreResult::NotStored`] when
+    /// their condition is not met.
+    pub mode: StoreMode,
+    /// If the CAS value of the stored data is older than the one of the item, store it anyway
+    /// but mark it as stale (`I`). Readers then get [`GetResponse::stale`] set.
+    pub invalidate: bool,
+    /// Opaque token sent with the request and echoed back by the server (`O`)
+    pub opaque: Option<u32>,
+    /// TTL of the value (`T`), overrides `data.time` if set
+    pub ttl: Option<u32>,
+}
 
-        let Some(response_cmd) = response_hdr.next() else {
-            return Err(MemcacheError::BadServerResponse);
-        };
-        match response_cmd {
-            "OK" => {
-                debug!("set: OK");
-                Ok(())
-            }
-            "HD" => {
-                debug!("set: OK");
-                Ok(())
-            }
-            "CLIENT_ERROR" => {
-                debug!("set: client error");
-                Err(MemcacheError::BadQuery)
-            }
-            x => {
-                error!("set: unexpected reponse {}", x);
-                Err(MemcacheError::BadServerResponse)
-            }
-        }
+impl SetOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ttl(mut self, t: Option<u32>) -> Self {
+        self.ttl = t;
+        self
+    }
+
+    /// Expire the value after `d`, see [`ttl_from_duration`]
+    pub fn expires_in(self, d: Duration) -> Self {
+        self.ttl(Some(ttl_from_duration(d)))
+    }
+
+    /// Expire the value at `t`, see [`ttl_from_system_time`]
+    pub fn expires_at(self, t: SystemTime) -> Self {
+        self.ttl(Some(ttl_from_system_time(t)))
+    }
+
+    /// TTL of `data` once stored with these options
+    pub(crate) fn ttl_of<D>(&self, data: &RawValue<D>) -> Option<u32> {
+        self.ttl.or(data.time)
+    }
+
+    pub fn mode(mut self, m: StoreMode) -> Self {
+        self.mode = m;
+        self
+    }
+
+    pub fn invalidate(mut self, i: bool) -> Self {
+        self.invalidate = i;
+        self
+    }
+
+    pub fn opaque(mut self, o: Option<u32>) -> Self {
+        self.opaque = o;
+        self
+    }
+}
+
+/// Options for the meta delete (`md`) command
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DeleteOptions {
+    /// Mark the item as stale instead of removing it (`I`). Readers then get
+    /// [`GetResponse::stale`] set until the item is replaced.
+    pub invalidate: bool,
+    /// New TTL of an invalidated item (`T`)
+    pub ttl: Option<u32>,
+    /// Only delete if the CAS value of the item matches (`C`)
+    pub cas: Option<u64>,
+    /// Opaque token sent with the request and echoed back by the server (`O`)
+    pub opaque: Option<u32>,
+}
+
+impl DeleteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn invalidate(mut self, i: bool) -> Self {
+        self.invalidate = i;
+        self
+    }
+
+    pub fn ttl(mut self, t: Option<u32>) -> Self {
+        self.ttl = t;
+        self
+    }
+
+    pub fn cas(mut self, c: Option<u64>) -> Self {
+        self.cas = c;
+        self
+    }
+
+    pub fn opaque(mut self, o: Option<u32>) -> Self {
+        self.opaque = o;
+        self
+    }
+}
+
+/// Outcome of the meta delete command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteResult {
+    /// The item was deleted (or invalidated)
+    Deleted,
+    /// The item does not exist
+    NotFound,
+    /// The CAS value did not match, the item was not deleted
+    Exists,
+}
+
+/// Slab automove mode, see [`Meta::slabs_automove`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutomoveMode {
+    /// Never move pages automatically (`0`)
+    Disabled,
+    /// Let the background thread decide when to move pages (`1`)
+    Enabled,
+    /// Move a page to a class as soon as it evicts (`2`). Not recommended outside of testing.
+    Aggressive,
+}
+
+/// Outcome of the `slabs reassign` command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReassignResult {
+    /// Page move started
+    Ok,
+    /// A page is already being moved, try again later
+    Busy,
+    /// Invalid source or destination class
+    BadClass,
+    /// The source class has no spare pages
+    NoSpare,
+    /// The source class does not have enough pages to spare
+    NotFull,
+    /// The page could not be moved safely
+    Unsafe,
+    /// Source and destination are the same
+    Same,
+}
+
+/// LRU algorithm used by the server, see [`Meta::lru_tune`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LruMode {
+    /// A single LRU per slab class
+    Flat,
+    /// Segmented HOT/WARM/COLD LRU
+    Segmented,
+}
+
+/// Slab classes the LRU crawler should process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrawlerClasses {
+    /// Every slab class (`all`)
+    All,
+    /// The listed slab classes
+    Classes(Vec<u32>),
+}
+
+impl CrawlerClasses {
+    /// Class list as used on the wire
+    fn token(&self) -> String {
+        match self {
+            CrawlerClasses::All => "all".to_string(),
+            CrawlerClasses::Classes(x) => x
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+}
+
+/// LRU crawler control commands, see [`Meta::lru_crawler`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LruCrawlerCommand {
+    /// Start the LRU crawler thread
+    Enable,
+    /// Stop the LRU crawler thread
+    Disable,
+    /// Microseconds to sleep between items while crawling
+    Sleep(u32),
+    /// Maximum number of items to inspect per slab class per run (0 for unlimited)
+    ToCrawl(u32),
+    /// Crawl the given slab classes now, reclaiming expired items
+    Crawl(CrawlerClasses),
+}
+
+/// Outcome of an LRU crawler command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LruCrawlerResult {
+    /// Command accepted
+    Ok,
+    /// The crawler is already processing a request
+    Busy,
+    /// Invalid slab class requested
+    BadClass,
+}
+
+/// Metadata of a single item as reported by `lru_crawler metadump`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ItemMetadata {
+    /// Key of the item, URI encoded as sent by the server
+    pub key: String,
+    /// Expiration time as a unix timestamp, -1 if the item never expires
+    pub exp: i64,
+    /// Last access time as a unix timestamp
+    pub la: u64,
+    /// CAS value of the item
+    pub cas: u64,
+    /// Whether the item was fetched since it was stored
+    pub fetch: bool,
+    /// Slab class id
+    pub cls: u32,
+    /// Total size of the item in bytes
+    pub size: u64,
+}
+
+impl ItemMetadata {
+    /// Parses a single metadump line
+    fn parse(line: &str) -> Option<Self> {
+        let mut retval = ItemMetadata::default();
+        let mut has_key = false;
+        for token in line.split_ascii_whitespace() {
+            let (name, value) = token.split_once('=')?;
+            match name {
+                "key" => {
+                    retval.key = value.to_string();
+                    has_key = true;
+                }
+                "exp" => retval.exp = value.parse().ok()?,
+                "la" => retval.la = value.parse().ok()?,
+                "cas" => retval.cas = value.parse().ok()?,
+                "fetch" => retval.fetch = value == "yes",
+                "cls" => retval.cls = value.parse().ok()?,
+                "size" => retval.size = value.parse().ok()?,
+                _ => {}
+            }
+        }
+        has_key.then_some(retval)
+    }
+}
+
+/// Header line of a meta command response
+struct MetaHeader {
+    /// Two letter return code (`HD`, `VA`, `EN`, ...)
+    code: String,
+    /// Size of the data block that follows the header (`VA` only)
+    size: Option<usize>,
+    /// Returned flags as (flag, token) pairs
+    flags: Vec<(u8, String)>,
+    /// The whole line, for error reports
+    line: String,
+}
+
+impl MetaHeader {
+    /// Returns the token attached to a returned flag
+    fn flag(&self, f: u8) -> Option<&str> {
+        self.flags
+            .iter()
+            .find(|(x, _)| *x == f)
+            .map(|(_, token)| token.as_str())
+    }
+
+    /// Returns the CAS value of the item (`c`)
+    fn cas(&self) -> Option<u64> {
+        self.flag(b'c').and_then(|x| x.parse::<u64>().ok())
+    }
+
+    /// Returns the opaque token echoed by the server
+    fn opaque(&self) -> Option<u32> {
+        self.flag(b'O').and_then(|x| x.parse::<u32>().ok())
+    }
+
+    /// Verifies that the key echoed by the server (`k`) matches the requested one.
+    /// A mismatch means the responses are no longer in sync with the requests.
+    fn check_key(&self, expected: &str, cmd: &str) -> Result<(), MemcacheError> {
+        // miss responses of some commands don't carry any flags
+        if self.flags.is_empty() && self.code != "HD" && self.code != "VA" {
+            return Ok(());
+        }
+        if self.flag(b'k') != Some(expected) {
+            error!("{}: key mismatch, expected {}", cmd, expected);
+            return Err(MemcacheError::bad_response(&self.line));
+        }
+        Ok(())
+    }
+
+    /// Verifies that the server echoed back the opaque token attached to the request.
+    /// A mismatch means the responses are no longer in sync with the requests.
+    fn check_opaque(&self, expected: Option<u32>, cmd: &str) -> Result<(), MemcacheError> {
+        let Some(expected) = expected else {
+            return Ok(());
+        };
+        // miss responses of some commands don't carry any flags
+        if self.flags.is_empty() && self.code != "HD" && self.code != "VA" {
+            return Ok(());
+        }
+        if self.opaque() != Some(expected) {
+            error!("{}: opaque mismatch, expected {}", cmd, expected);
+            return Err(MemcacheError::bad_response(&self.line));
+        }
+        Ok(())
+    }
+}
+
+/// Builds the header of a meta set command. `extra` is appended to the flags.
+pub(crate) fn set_request(key: &str, data: &RawValue<&[u8]>, extra: &str) -> String {
+    set_header(key, data.data.len(), data, extra)
+}
+
+/// Builds the header of a meta set command for `size` bytes of data
+fn set_header<D>(key: &str, size: usize, data: &RawValue<D>, extra: &str) -> String {
+    format!(
+        "ms {} S{} T{} F{}{}\r\n",
+        key,
+        size,
+        data.time.unwrap_or(0),
+        data.flags,
+        extra
+    )
+}
+
+/// Builds a meta get request for an already validated (or encoded) key
+pub(crate) fn get_request(key: &str, base64: bool, opts: &GetOptions) -> String {
+    let mut request = format!("mg {} k f c v", key);
+    if base64 {
+        request.push_str(" b");
+    }
+    if opts.return_ttl {
+        request.push_str(" t");
+    }
+    if opts.return_last_access {
+        request.push_str(" l");
+    }
+    if opts.return_hit_before {
+        request.push_str(" h");
+    }
+    if let Some(ttl) = opts.vivify_ttl {
+        request.push_str(&format!(" N{}", ttl));
+    }
+    if let Some(ttl) = opts.recache_ttl {
+        request.push_str(&format!(" R{}", ttl));
+    }
+    if let Some(ttl) = opts.touch_ttl {
+        request.push_str(&format!(" T{}", ttl));
+    }
+    if let Some(opaque) = opts.opaque {
+        request.push_str(&format!(" O{}", opaque));
+    }
+    request.push_str("\r\n");
+    request
+}
+
+/// Reads the response to a meta get request
+pub(crate) async fn read_get_response<T: AsyncReadWriteUnpin>(
+    io: &mut T,
+    key: &str,
+    opts: &GetOptions,
+) -> Result<Option<GetResponse>, MemcacheError> {
+    let header = read_meta_header(io, "get").await?;
+    header.check_opaque(opts.opaque, "get")?;
+    header.check_key(key, "get")?;
+    match header.code.as_str() {
+        "EN" => {
+            debug!("get: no key");
+            return Ok(None);
+        }
+        "VA" => {}
+        x => {
+            error!("get: malformed response key {}", x);
+            return Err(MemcacheError::bad_response(&header.line));
+        }
+    }
+
+    let Some(flags) = header.flag(b'f').and_then(|x| x.parse::<u32>().ok()) else {
+        error!("get: missing flags");
+        return Err(MemcacheError::bad_response(&header.line));
+    };
+
+    // -1 means the item never expires
+    let time = if opts.return_ttl {
+        let Some(ttl) = header.flag(b't').and_then(|x| x.parse::<i64>().ok()) else {
+            error!("get: missing ttl");
+            return Err(MemcacheError::bad_response(&header.line));
+        };
+        u32::try_from(ttl).ok()
+    } else {
+        None
+    };
+
+    let last_access = if opts.return_last_access {
+        let Some(l) = header.flag(b'l').and_then(|x| x.parse::<u32>().ok()) else {
+            error!("get: missing last access time");
+            return Err(MemcacheError::bad_response(&header.line));
+        };
+        Some(l)
+    } else {
+        None
+    };
+
+    let hit_before = if opts.return_hit_before {
+        match header.flag(b'h') {
+            Some("0") => Some(false),
+            Some("1") => Some(true),
+            _ => {
+                error!("get: missing hit before flag");
+                return Err(MemcacheError::bad_response(&header.line));
+            }
+        }
+    } else {
+        None
+    };
+
+    let cas = header.cas();
+
+    let data = read_data_block(io, header.size.unwrap_or(0)).await?;
+
+    debug!("get: received data");
+    Ok(Some(GetResponse {
+        value: RawValue {
+            data,
+            flags,
+            time,
+            cas,
+        },
+        last_access,
+        hit_before,
+        won_recache: header.flag(b'W').is_some(),
+        stale: header.flag(b'X').is_some(),
+        recache_pending: header.flag(b'Z').is_some(),
+    }))
+}
+
+/// Builds the flags of a meta set request for `opts` and the CAS value of `data`
+pub(crate) fn store_flags<D>(data: &RawValue<D>, opts: &SetOptions) -> String {
+    let mut extra = String::new();
+    if opts.mode != StoreMode::Set {
+        extra.push_str(&format!(" M{}", opts.mode.token()));
+    }
+    if let Some(cas) = data.cas {
+        extra.push_str(&format!(" C{}", cas));
+    }
+    if opts.invalidate {
+        extra.push_str(" I");
+    }
+    if let Some(opaque) = opts.opaque {
+        extra.push_str(&format!(" O{}", opaque));
+    }
+    extra
+}
+
+/// Reads the response to a meta set request
+pub(crate) async fn read_store_response<T: AsyncReadWriteUnpin>(
+    io: &mut T,
+    opaque: Option<u32>,
+) -> Result<StoreResult, MemcacheError> {
+    let header = read_meta_header(io, "set").await?;
+    header.check_opaque(opaque, "set")?;
+    match header.code.as_str() {
+        "OK" | "HD" => {
+            debug!("set: OK");
+            Ok(StoreResult::Stored)
+        }
+        "NS" => {
+            debug!("set: not stored");
+            Ok(StoreResult::NotStored)
+        }
+        "EX" => {
+            debug!("set: CAS mismatch");
+            Ok(StoreResult::Exists)
+        }
+        "NF" => {
+            debug!("set: not found");
+            Ok(StoreResult::NotFound)
+        }
+        x => {
+            error!("set: unexpected reponse {}", x);
+            Err(MemcacheError::bad_response(&header.line))
+        }
+    }
+}
+
+/// Builds a meta arithmetic request
+pub(crate) fn arithmetic_request(key: &str, opts: &ArithmeticOptions) -> String {
+    let mut request = format!("ma {} D{}", key, opts.delta);
+    if opts.mode == ArithmeticMode::Decrement {
+        request.push_str(" MD");
+    }
+    if let Some(ttl) = opts.vivify_ttl {
+        request.push_str(&format!(" N{}", ttl));
+        if let Some(initial) = opts.initial {
+            request.push_str(&format!(" J{}", initial));
+        }
+    }
+    if let Some(ttl) = opts.ttl {
+        request.push_str(&format!(" T{}", ttl));
+    }
+    if opts.return_value {
+        request.push_str(" v");
+    }
+    if let Some(opaque) = opts.opaque {
+        request.push_str(&format!(" O{}", opaque));
+    }
+    request.push_str("\r\n");
+    request
+}
+
+/// Reads the response to a meta arithmetic request
+pub(crate) async fn read_arithmetic_response<T: AsyncReadWriteUnpin>(
+    io: &mut T,
+    opts: &ArithmeticOptions,
+) -> Result<ArithmeticResult, MemcacheError> {
+    let header = read_meta_header(io, "arithmetic").await?;
+    header.check_opaque(opts.opaque, "arithmetic")?;
+    match header.code.as_str() {
+        "HD" => Ok(ArithmeticResult::Updated(None)),
+        "VA" => {
+            let data = read_data_block(io, header.size.unwrap_or(0)).await?;
+            let Some(value) = std::str::from_utf8(&data)
+                .ok()
+                .and_then(|x| x.parse::<u64>().ok())
+            else {
+                error!("arithmetic: non-numeric value returned");
+                return Err(MemcacheError::bad_response(&data));
+            };
+            Ok(ArithmeticResult::Updated(Some(value)))
+        }
+        "NF" => {
+            debug!("arithmetic: no key");
+            Ok(ArithmeticResult::NotFound)
+        }
+        "NS" => {
+            debug!("arithmetic: not stored");
+            Ok(ArithmeticResult::NotStored)
+        }
+        "EX" => {
+            debug!("arithmetic: CAS mismatch");
+            Err(MemcacheError::Exists)
+        }
+        x => {
+            error!("arithmetic: unexpected response {}", x);
+            Err(MemcacheError::bad_response(&header.line))
+        }
+    }
+}
+
+/// Builds a meta delete request
+pub(crate) fn delete_request(key: &str, opts: &DeleteOptions) -> String {
+    let mut request = format!("md {}", key);
+    if opts.invalidate {
+        request.push_str(" I");
+    }
+    if let Some(ttl) = opts.ttl {
+        request.push_str(&format!(" T{}", ttl));
+    }
+    if let Some(cas) = opts.cas {
+        request.push_str(&format!(" C{}", cas));
+    }
+    if let Some(opaque) = opts.opaque {
+        request.push_str(&format!(" O{}", opaque));
+    }
+    request.push_str("\r\n");
+    request
+}
+
+/// Reads the response to a meta delete request
+pub(crate) async fn read_delete_response<T: AsyncReadWriteUnpin>(
+    io: &mut T,
+    opts: &DeleteOptions,
+) -> Result<DeleteResult, MemcacheError> {
+    let header = read_meta_header(io, "delete_with").await?;
+    header.check_opaque(opts.opaque, "delete_with")?;
+    match header.code.as_str() {
+        "HD" => {
+            debug!("delete_with: OK");
+            Ok(DeleteResult::Deleted)
+        }
+        "NF" => {
+            debug!("delete_with: NOT FOUND");
+            Ok(DeleteResult::NotFound)
+        }
+        "EX" => {
+            debug!("delete_with: CAS mismatch");
+            Ok(DeleteResult::Exists)
+        }
+        x => {
+            error!("delete_with: unexpected response {}", x);
+            Err(MemcacheError::bad_response(&header.line))
+        }
+    }
+}
+
+/// Sends a text command and returns its single line response without the \r\n marker.
+/// Generic errors (`ERROR`, `CLIENT_ERROR`, `SERVER_ERROR`) are reported as
+/// [`MemcacheError::Protocol`].
+pub(crate) async fn text_command<T: AsyncReadWriteUnpin>(
+    io: &mut T,
+    request: &str,
+    cmd: &str,
+) -> Result<String, MemcacheError> {
+    io.write_all(request.as_bytes())
+        .await
+        .and(io.flush().await)
+        .map_err(io_error)?;
+    read_text_response(io, cmd).await
+}
+
+/// Reads the single line response of a text command, see [`text_command`]
+pub(crate) async fn read_text_response<T: AsyncReadWriteUnpin>(
+    io: &mut T,
+    cmd: &str,
+) -> Result<String, MemcacheError> {
+    let mut response_hdr: Vec<u8> = Vec::new();
+    read_line(io, &mut response_hdr).await?;
+    // some admin commands (e.g. metadump) terminate their lines with a bare "\n"
+    if response_hdr.ends_with(b"\n") {
+        response_hdr.pop();
+    }
+    if response_hdr.ends_with(b"\r") {
+        response_hdr.pop();
+    }
+    let response_hdr = String::from_utf8(response_hdr).map_err(|e| {
+        error!("{}: non-ASCII response", cmd);
+        MemcacheError::bad_response(e.as_bytes())
+    })?;
+    if let Some(e) = error_line(&response_hdr) {
+        debug!("{}: {}", cmd, response_hdr);
+        return Err(e);
+    }
+    Ok(response_hdr)
+}
+
+/// Error reported by a generic error line (`ERROR`, `CLIENT_ERROR`, `SERVER_ERROR`)
+pub(crate) fn error_line(line: &str) -> Option<MemcacheError> {
+    ProtocolError::parse(line).map(MemcacheError::Protocol)
+}
+
+/// Error of a store result other than [`StoreResult::Stored`]
+pub(crate) fn store_error(result: StoreResult) -> MemcacheError {
+    match result {
+        StoreResult::Stored => MemcacheError::bad_response("HD"),
+        StoreResult::Exists => MemcacheError::Exists,
+        StoreResult::NotFound => MemcacheError::NotFound,
+        StoreResult::NotStored => MemcacheError::NotStored,
+    }
+}
+
+/// Error of a failed quiet command, from its return code
+fn status_error(code: &str) -> MemcacheError {
+    match code {
+        "NS" => MemcacheError::NotStored,
+        "EX" => MemcacheError::Exists,
+        "NF" => MemcacheError::NotFound,
+        _ => MemcacheError::bad_response(code),
+    }
+}
+
+/// Converts I/O errors, reporting timeouts as [`MemcacheError::Timeout`] and a connection
+/// closed mid-response as [`MemcacheError::ConnectionClosed`]
+pub(crate) fn io_error(e: std::io::Error) -> MemcacheError {
+    match e.kind() {
+        std::io::ErrorKind::TimedOut => MemcacheError::Timeout,
+        std::io::ErrorKind::UnexpectedEof => MemcacheError::ConnectionClosed,
+        _ => MemcacheError::IOError(e),
+    }
+}
+
+/// Reads a single line, a closed connection is reported as an I/O error
+pub(crate) async fn read_line<T: AsyncReadWriteUnpin>(
+    io: &mut T,
+    buffer: &mut Vec<u8>,
+) -> Result<(), MemcacheError> {
+    let read = io.read_until(0xA, buffer).await.map_err(io_error)?;
+    if read == 0 {
+        debug!("connection closed by the server");
+        return Err(MemcacheError::ConnectionClosed);
+    }
+    Ok(())
+}
+
+/// Reads and parses the header line of a meta command response
+async fn read_meta_header<T: AsyncReadWriteUnpin>(
+    io: &mut T,
+    cmd: &str,
+) -> Result<MetaHeader, MemcacheError> {
+    let mut response_hdr: Vec<u8> = Vec::new();
+    read_line(io, &mut response_hdr).await?;
+    if response_hdr.len() >= 2 {
+        response_hdr.truncate(response_hdr.len() - 2);
+    }
+    let response_hdr = String::from_utf8(response_hdr).map_err(|e| {
+        error!("{}: non-ASCII response", cmd);
+        MemcacheError::bad_response(e.as_bytes())
+    })?;
+    if let Some(e) = error_line(&response_hdr) {
+        debug!("{}: {}", cmd, response_hdr);
+        return Err(e);
+    }
+    let mut tokens = response_hdr.split_ascii_whitespace();
+    let Some(code) = tokens.next() else {
+        error!("{}: empty response", cmd);
+        return Err(MemcacheError::bad_response(&response_hdr));
+    };
+    if code.len() != 2 {
+        error!("{}: unexpected response {}", cmd, response_hdr);
+        return Err(MemcacheError::bad_response(&response_hdr));
+    }
+    let size = if code == "VA" {
+        let Some(size) = tokens.next().and_then(|x| x.parse::<usize>().ok()) else {
+            error!("{}: bad data_length", cmd);
+            return Err(MemcacheError::bad_response(&response_hdr));
+        };
+        Some(size)
+    } else {
+        None
+    };
+    let mut flags = Vec::new();
+    for x in tokens {
+        // flags are single ASCII letters, anything else is garbled
+        if !x.as_bytes()[0].is_ascii() {
+            error!("{}: bad flag {}", cmd, x);
+            return Err(MemcacheError::bad_response(&response_hdr));
+        }
+        flags.push((x.as_bytes()[0], x[1..].to_string()));
+    }
+    Ok(MetaHeader {
+        code: code.to_string(),
+        size,
+        flags,
+        line: response_hdr,
+    })
+}
+
+/// Reads a data block of the given size followed by the \r\n marker
+pub(crate) async fn read_data_block<T: AsyncReadWriteUnpin>(
+    io: &mut T,
+    size: usize,
+) -> Result<Vec<u8>, MemcacheError> {
+    let mut data: Vec<u8> = vec![0; size + 2];
+    let _ = io.read_exact(&mut data).await.map_err(io_error)?;
+    data.truncate(size);
+    Ok(data)
+}
+
+impl Default for Meta {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Meta {
+    pub fn new() -> Self {
+        Meta {
+            read_timeout: None,
+            write_timeout: None,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            text_only: false,
+            compression: None,
+            chunking: false,
+            namespace: None,
+            long_keys: LongKeys::Reject,
+        }
+    }
+
+    /// Create a protocol instance failing commands with [`MemcacheError::Timeout`] when the
+    /// server doesn't respond or accept data within the given time
+    pub fn with_timeouts(read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> Self {
+        Meta {
+            read_timeout,
+            write_timeout,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            text_only: false,
+            compression: None,
+            chunking: false,
+            namespace: None,
+            long_keys: LongKeys::Reject,
+        }
+    }
+
+    /// Set the largest value accepted by the store commands. Larger values are rejected with
+    /// [`MemcacheError::ValueTooLarge`] before anything is sent. Should match the item size
+    /// limit of the server (`-I`), which also counts the key and item header. With
+    /// [`Meta::chunking`] the chunks are kept under it, key and header included.
+    pub fn max_value_size(mut self, limit: usize) -> Self {
+        self.max_value_size = limit;
+        self
+    }
+
+    /// Largest value accepted by the store commands
+    pub(crate) fn value_size_limit(&self) -> usize {
+        self.max_value_size
+    }
+
+    /// Restrict the commands to the classic text protocol, for proxies rejecting the meta
+    /// commands (e.g. twemproxy). Commands and options without a text equivalent fail with an
+    /// [`std::io::ErrorKind::Unsupported`] error.
+    pub fn text_only(mut self, x: bool) -> Self {
+        self.text_only = x;
+        self
+    }
+
+    /// Whether the commands are restricted to the classic text protocol
+    pub fn is_text_only(&self) -> bool {
+        self.text_only
+    }
+
+    /// Compress large values when storing them and decompress them when reading them back,
+    /// see [`crate::compress`]
+    pub fn compression(mut self, c: Option<Compression>) -> Self {
+        self.compression = c;
+        self
+    }
+
+    /// Compression of the values, if enabled
+    pub(crate) fn compression_options(&self) -> Option<&Compression> {
+        self.compression.as_ref()
+    }
+
+    /// Split the values larger than [`Meta::max_value_size`] into several items instead of
+    /// rejecting them, see [`chunked`](crate::chunked)
+    pub fn chunking(mut self, x: bool) -> Self {
+        self.chunking = x;
+        self
+    }
+
+    /// Prefix every key with `prefix`, e.g. `"myapp:"`, so several applications can share a
+    /// server. [`Meta::get_many`] returns the keys without it. [`Meta::metadump`] and
+    /// [`Meta::watch`] report the keys as stored. Fails with [`MemcacheError::BadKey`] if the
+    /// prefix contains spaces or control characters.
+    pub fn namespace(mut self, prefix: Option<String>) -> Result<Self, MemcacheError> {
+        check_namespace(prefix.as_deref())?;
+        self.namespace = prefix;
+        Ok(self)
+    }
+
+    /// Hash the keys longer than memcached accepts instead of rejecting them, see
+    /// [`keys`](crate::keys)
+    pub fn long_keys(mut self, x: LongKeys) -> Self {
+        self.long_keys = x;
+        self
+    }
+
+    /// Handling of the long keys
+    pub(crate) fn long_keys_policy(&self) -> LongKeys {
+        self.long_keys
+    }
+
+    /// `key` with the namespace prepended
+    pub(crate) fn namespaced<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match &self.namespace {
+            Some(x) => Cow::Owned(format!("{}{}", x, key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Binary `key` with the namespace prepended
+    pub(crate) fn namespaced_bytes<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.namespace {
+            Some(x) => Cow::Owned([x.as_bytes(), key].concat()),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Rejects values the server would refuse after receiving them
+    pub(crate) fn check_value_size(
+        &self,
+        data: &RawValue<&[u8]>,
+        cmd: &str,
+    ) -> Result<(), MemcacheError> {
+        self.check_size(data.data.len(), cmd)
+    }
+
+    /// Rejects `size` bytes of data if the server would refuse them
+    fn check_size(&self, size: usize, cmd: &str) -> Result<(), MemcacheError> {
+        if size > self.max_value_size {
+            error!(
+                "{}: value of {} bytes exceeds the limit of {}",
+                cmd, size, self.max_value_size
+            );
+            return Err(MemcacheError::ValueTooLarge {
+                size,
+                limit: self.max_value_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Applies the configured timeouts to the connection
+    pub(crate) fn timed<'a, T>(&self, io: &'a mut T) -> TimedIo<'a, T> {
+        TimedIo::new(io, self.read_timeout, self.write_timeout)
+    }
+
+    /// Applies the per-call deadline if set, the configured timeouts otherwise
+    fn timed_until<'a, T>(&self, io: &'a mut T, deadline: Option<Instant>) -> TimedIo<'a, T> {
+        match deadline {
+            Some(x) => TimedIo::with_deadline(io, x.into()),
+            None => self.timed(io),
+        }
+    }
+
+    /// GET a value from memcached
+    /// returns Ok(Some(x)) when key is found
+    /// returns Ok(None) if key was not found
+    pub async fn get<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+    ) -> Result<Option<RawValue>, MemcacheError> {
+        let io = &mut self.timed(io);
+        Ok(self
+            .get_with(io, key, &GetOptions::default())
+            .await?
+            .map(|x| x.value))
+    }
+
+    /// GET a value from memcached, requesting additional information as set in `opts`
+    /// returns Ok(Some(x)) when key is found
+    /// returns Ok(None) if key was not found
+    pub async fn get_with<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        opts: &GetOptions,
+    ) -> Result<Option<GetResponse>, MemcacheError> {
+        let io = &mut self.timed_until(io, opts.deadline);
+        debug!("get {}", key);
+        self.check_key(key, "get")?;
+        let response = if self.text_only {
+            self.get_text(io, &self.wire_key(key), opts).await?
+        } else {
+            self.get_wire(io, &self.wire_key(key), false, opts).await?
+        };
+        match response {
+            Some(mut x) => {
+                if self.chunking && x.value.flags & FLAG_CHUNKED != 0 {
+                    match self.get_chunked(io, key, x.value).await? {
+                        Some(value) => x.value = value,
+                        None => return Ok(None),
+                    }
+                }
+                match self.decode_value(key, x.value)? {
+                    Some(value) => x.value = value,
+                    None => return Ok(None),
+                }
+                Ok(Some(x))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// GET a value stored under a binary key. The key is base64 encoded on the wire, so it
+    /// may contain any bytes. Keys too long once encoded are handled according to
+    /// [`Meta::long_keys`].
+    /// returns Ok(Some(x)) when key is found
+    /// returns Ok(None) if key was not found
+    pub async fn get_bytes_key<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &[u8],
+    ) -> Result<Option<RawValue>, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("get_bytes_key");
+        if self.text_only {
+            return Err(text::unsupported("get_bytes_key"));
+        }
+        let (key, base64) = self.wire_bytes_key(key, "get_bytes_key")?;
+        match self
+            .get_wire(io, &key, base64, &GetOptions::default())
+            .await?
+        {
+            Some(x) => self.decompress(x.value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Sends a meta get for an already validated (or encoded) key
+    async fn get_wire<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        base64: bool,
+        opts: &GetOptions,
+    ) -> Result<Option<GetResponse>, MemcacheError> {
+        let request = get_request(key, base64, opts);
+        io.write_all(request.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        read_get_response(io, key, opts).await
+    }
+
+    /// GET multiple values from memcached
+    /// returns Ok(Vec((key,RawValue))) with a list of key-value tuples
+    ///
+    /// If a key is not found in the response then it does not exist currently
+    /// in memcached
+    ///
+    /// Pipelines a quiet meta get (`mg <key> k f c v q O<index>`) for every key followed by a
+    /// no-op, so misses don't generate any response. The opaque token carries the index of the
+    /// key in `key_list`, the echoed key is checked against it.
+    pub async fn get_many<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key_list: &[&str],
+    ) -> Result<Vec<(String, RawValue)>, MemcacheError> {
+        if self.text_only {
+            for k in key_list {
+                self.check_key(k, "get_many")?;
+            }
+            return self.get_many_text(io, key_list).await;
+        }
+        let io = &mut self.timed(io);
+        let wire_keys = self.send_get_many(io, key_list).await?;
+        let mut retval = Ok(Vec::new());
+        while let Some((idx, value)) = self.read_many_raw(io, key_list, &wire_keys).await? {
+            self.collect_value(&mut retval, key_list[idx], value);
+        }
+        retval
+    }
+
+    /// Decodes a value of a multi-get into `retval`. A value that can't be decoded doesn't stop
+    /// the read of the response, the connection would be left out of sync: the first error is
+    /// kept and returned once the whole response is read.
+    fn collect_value(
+        &self,
+        retval: &mut Result<Vec<(String, RawValue)>, MemcacheError>,
+        key: &str,
+        value: RawValue,
+    ) {
+        match (self.decode_value(key, value), retval.as_mut()) {
+            (Ok(Some(value)), Ok(values)) => values.push((key.to_string(), value)),
+            (Err(e), Ok(_)) => *retval = Err(e),
+            _ => {}
+        }
+    }
+
+    /// [`Meta::get_many`] yielding the values as their responses arrive, instead of collecting
+    /// all of them first. The connection can be used again once the returned stream is
+    /// exhausted. The stream ends after the first error, the connection is then out of sync.
+    pub async fn get_many_stream<'a, T: AsyncReadWriteUnpin>(
+        &'a self,
+        io: &'a mut T,
+        key_list: &[&str],
+    ) -> Result<impl Stream<Item = Result<(String, RawValue), MemcacheError>> + 'a, MemcacheError>
+    {
+        let wire_keys = if self.text_only {
+            self.send_get_many_text(&mut self.timed(io), key_list)
+                .await?
+        } else {
+            self.send_get_many(&mut self.timed(io), key_list).await?
+        };
+        let wire_keys: Vec<String> = wire_keys.into_iter().map(Cow::into_owned).collect();
+        let key_list: Vec<String> = key_list.iter().map(|x| x.to_string()).collect();
+        Ok(futures_util::stream::unfold(
+            Some((io, key_list, wire_keys)),
+            move |state| async move {
+                let (io, key_list, wire_keys) = state?;
+                let io_timed = &mut self.timed(&mut *io);
+                let next = if self.text_only {
+                    self.read_many_value_text(io_timed, &key_list, &wire_keys)
+                        .await
+                } else {
+                    self.read_many_value(io_timed, &key_list, &wire_keys).await
+                };
+                match next {
+                    Ok(Some(x)) => Some((Ok(x), Some((io, key_list, wire_keys)))),
+                    Ok(None) => None,
+                    Err(e) => Some((Err(e), None)),
+                }
+            },
+        ))
+    }
+
+    /// Sends the pipelined gets of [`Meta::get_many`], returns the keys as sent
+    async fn send_get_many<'k, T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key_list: &[&'k str],
+    ) -> Result<Vec<Cow<'k, str>>, MemcacheError> {
+        let mut keysize = 0;
+        for k in key_list {
+            self.check_key(k, "get_many")?;
+            keysize += k.len();
+        }
+        let wire_keys: Vec<_> = key_list.iter().map(|x| self.wire_key(x)).collect();
+        let mut send = String::with_capacity(key_list.len() * 24 + keysize + 4);
+        for (idx, k) in wire_keys.iter().enumerate() {
+            send.push_str(&format!("mg {} k f c v q O{}\r\n", k, idx));
+        }
+        send.push_str("mn\r\n");
+        io.write_all(send.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+        Ok(wire_keys)
+    }
+
+    /// Reads the responses of [`Meta::send_get_many`] up to the next value, None once the
+    /// no-op closing them is reached
+    async fn read_many_value<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key_list: &[impl AsRef<str>],
+        wire_keys: &[impl AsRef<str>],
+    ) -> Result<Option<(String, RawValue)>, MemcacheError> {
+        while let Some((idx, value)) = self.read_many_raw(io, key_list, wire_keys).await? {
+            let key = key_list[idx].as_ref();
+            if let Some(value) = self.decode_value(key, value)? {
+                return Ok(Some((key.to_string(), value)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the next value of the responses of [`Meta::send_get_many`] as stored, with the
+    /// index of its key
+    async fn read_many_raw<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key_list: &[impl AsRef<str>],
+        wire_keys: &[impl AsRef<str>],
+    ) -> Result<Option<(usize, RawValue)>, MemcacheError> {
+        let header = read_meta_header(io, "get_many").await?;
+        match header.code.as_str() {
+            "MN" => return Ok(None),
+            "VA" => {}
+            x => {
+                error!("get_many: unexpected response {}", x);
+                return Err(MemcacheError::bad_response(&header.line));
+            }
+        }
+        let Some(idx) = header.opaque().filter(|x| (*x as usize) < key_list.len()) else {
+            error!("get_many: missing or bad opaque token");
+            return Err(MemcacheError::bad_response(&header.line));
+        };
+        header.check_key(wire_keys[idx as usize].as_ref(), "get_many")?;
+        let Some(flags) = header.flag(b'f').and_then(|x| x.parse::<u32>().ok()) else {
+            error!("get_many: missing flags");
+            return Err(MemcacheError::bad_response(&header.line));
+        };
+        let cas = header.cas();
+        let data = read_data_block(io, header.size.unwrap_or(0)).await?;
+        let value = RawValue {
+            data,
+            flags,
+            time: None,
+            cas,
+        };
+        Ok(Some((idx as usize, value)))
+    }
+
+    /// GET multiple values from memcached using the classic text `get` command
+    /// returns Ok(Vec((key,RawValue))) with a list of key-value tuples
+    ///
+    /// If a key is not found in the response then it does not exist currently
+    /// in memcached
+    pub async fn get_many_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key_list: &[&str],
+    ) -> Result<Vec<(String, RawValue)>, MemcacheError> {
+        let io = &mut self.timed(io);
+        let wire_keys = self.send_get_many_text(io, key_list).await?;
+        let mut retval = Ok(Vec::new());
+        while let Some((idx, value)) = self.read_many_raw_text(io, &wire_keys).await? {
+            self.collect_value(&mut retval, key_list[idx], value);
+        }
+        retval
+    }
+
+    /// Sends the `get` command of [`Meta::get_many_text`], returns the keys as sent
+    async fn send_get_many_text<'k, T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key_list: &[&'k str],
+    ) -> Result<Vec<Cow<'k, str>>, MemcacheError> {
+        let mut keysize = 0;
+        for k in key_list {
+            self.check_key(k, "get_multi")?;
+            keysize += k.len();
+        }
+        //get key_1 key_2 key_3\r\n
+        //
+        //VALUE key_1 FLAG SIZE\r\n
+        //DATA\r\n
+        //VALUE key_3 FLAG SIZE\r\n
+        //DATA\r\n
+        //END\r\n
+        let mut send = String::with_capacity(10 + key_list.len() + keysize); // 5 should be enough, but
+                                                                             // let's not chance it
+        let wire_keys: Vec<_> = key_list.iter().map(|x| self.wire_key(x)).collect();
+        send.push_str("get");
+        for k in wire_keys.iter() {
+            send.push(' ');
+            send.push_str(k);
+        }
+        send.push_str("\r\n");
+        io.write_all(&send.into_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+        Ok(wire_keys)
+    }
+
+    /// Reads the response of [`Meta::send_get_many_text`] up to the next value, None once
+    /// `END` is reached
+    async fn read_many_value_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key_list: &[impl AsRef<str>],
+        wire_keys: &[impl AsRef<str>],
+    ) -> Result<Option<(String, RawValue)>, MemcacheError> {
+        while let Some((idx, value)) = self.read_many_raw_text(io, wire_keys).await? {
+            let key = key_list[idx].as_ref();
+            if let Some(value) = self.decode_value(key, value)? {
+                return Ok(Some((key.to_string(), value)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the next value of the response of [`Meta::send_get_many_text`] as stored, with
+    /// the index of its key
+    // clippy complains about a read to a zero-length vector, but read_until appends data
+    // to a vector
+    #[allow(clippy::read_zero_byte_vec)]
+    async fn read_many_raw_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        wire_keys: &[impl AsRef<str>],
+    ) -> Result<Option<(usize, RawValue)>, MemcacheError> {
+        let mut buffer = Vec::new();
+        read_line(io, &mut buffer).await?;
+        if buffer.len() >= 2 {
+            buffer.truncate(buffer.len() - 2);
+        }
+        if buffer == b"END" {
+            return Ok(None);
+        }
+        let Ok(response) = String::from_utf8(buffer.clone()) else {
+            //error!("get_multi: non-ASCII response: {}", hex::encode(buffer));
+            error!("get_multi: non-ASCII response");
+            return Err(MemcacheError::bad_response(&buffer));
+        };
+        let mut response_hdr = response.split_ascii_whitespace();
+        let Some(response_cmd) = response_hdr.next() else {
+            error!("get_mutli: malformed response {}", response);
+            return Err(MemcacheError::bad_response(&response));
+        };
+        if response_cmd != "VALUE" {
+            error!("get_multi: server response error: {}", response_cmd);
+            return Err(MemcacheError::bad_response(&response));
+        }
+
+        let Some(key) = response_hdr.next() else {
+            error!("get_multi: missing key");
+            return Err(MemcacheError::bad_response(&response));
+        };
+
+        let Some(flags) = response_hdr.next().and_then(|x| x.parse::<u32>().ok()) else {
+            error!("get_multi: bad flags");
+            return Err(MemcacheError::bad_response(&response));
+        };
+
+        let Some(data_length) = response_hdr.next().and_then(|x| x.parse::<usize>().ok()) else {
+            error!("get_multi: bad data_length");
+            return Err(MemcacheError::bad_response(&response));
+        };
+
+        if response_hdr.next().is_some() {
+            error!("get_multi: header too long");
+            return Err(MemcacheError::bad_response(&response));
+        };
+
+        buffer.resize(data_length + 2, 0);
+        let _ = io.read_exact(&mut buffer).await.map_err(io_error)?;
+        buffer.truncate(data_length);
+
+        let Some(idx) = wire_keys.iter().position(|x| x.as_ref() == key) else {
+            error!("get_multi: unexpected key {}", key);
+            return Err(MemcacheError::bad_response(&response));
+        };
+        let value = RawValue {
+            data: buffer,
+            flags,
+            time: None,
+            cas: None,
+        };
+        Ok(Some((idx, value)))
+    }
+
+    /// STORE function. Stores provided data using the provided key.
+    /// data.time determines for how many seconds memcached should keep the data. Setting it to
+    /// None will make memcached keep the data for as long as possible (data may still be dropped
+    /// if memcached reaches its memory limit)
+    /// data.cas is ignored, use [`Meta::cas`] for conditional stores.
+    pub async fn set<T: AsyncReadWriteUnpin, D: AsRef<[u8]>>(
+        &self,
+        io: &mut T,
+        key: &str,
+        data: &RawValue<D>,
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("set {}", key);
+        self.check_key(key, "set")?;
+        let compressed = self.encode_value(key, data.as_borrowed())?;
+        let data = &compressed
+            .as_ref()
+            .map_or(data.as_borrowed(), RawValue::as_borrowed);
+        let result = if self.chunking && data.data.len() > self.max_value_size {
+            self.set_chunked(io, key, data).await?
+        } else {
+            self.store(io, &self.wire_key(key), data).await?
+        };
+        match result {
+            StoreResult::Stored => Ok(()),
+            x => {
+                debug!("set: {:?}", x);
+                Err(store_error(x))
+            }
+        }
+    }
+
+    /// Unconditional store of already validated and compressed data
+    pub(crate) async fn store<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        data: &RawValue<&[u8]>,
+    ) -> Result<StoreResult, MemcacheError> {
+        if self.text_only {
+            self.set_text(io, key, data, None, &SetOptions::default())
+                .await
+        } else {
+            self.set_wire(io, key, "", data, None).await
+        }
+    }
+
+    /// Compare-And-Store function. Stores provided data only if the CAS value of the item in
+    /// memcached still matches data.cas (as returned by a previous get). If data.cas is None
+    /// this works like an unconditional [`Meta::set`].
+    /// returns Ok(StoreResult::Exists) if the item was modified in the meantime
+    /// returns Ok(StoreResult::NotFound) if the item no longer exists
+    pub async fn cas<T: AsyncReadWriteUnpin, D: AsRef<[u8]>>(
+        &self,
+        io: &mut T,
+        key: &str,
+        data: &RawValue<D>,
+    ) -> Result<StoreResult, MemcacheError> {
+        let io = &mut self.timed(io);
+        self.set_with(io, key, data, &SetOptions::default()).await
+    }
+
+    /// STORE function with additional options. Like [`Meta::cas`], data.cas is used as a
+    /// precondition if set.
+    pub async fn set_with<T: AsyncReadWriteUnpin, D: AsRef<[u8]>>(
+        &self,
+        io: &mut T,
+        key: &str,
+        data: &RawValue<D>,
+        opts: &SetOptions,
+    ) -> Result<StoreResult, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("set_with {}", key);
+        self.check_key(key, "set_with")?;
+        let compressed = self.encode_value(key, data.as_borrowed())?;
+        let data = &compressed
+            .as_ref()
+            .map_or(data.as_borrowed(), RawValue::as_borrowed);
+        let data = &data.set_time(opts.ttl_of(data));
+        let key = &self.wire_key(key);
+        if self.text_only {
+            return self.set_text(io, key, data, data.cas, opts).await;
+        }
+        let extra = store_flags(data, opts);
+        self.set_wire(io, key, &extra, data, opts.opaque).await
+    }
+
+    /// STORE `len` bytes read from `data.data`, streamed to the server without loading them in
+    /// memory. Like [`Meta::set_with`] data.cas is used as a precondition if set. The value is
+    /// never compressed.
+    ///
+    /// If the reader fails or ends early the request can't be completed, the connection has to
+    /// be closed.
+    pub async fn set_from_reader<T: AsyncReadWriteUnpin, R: AsyncRead + Unpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        len: usize,
+        mut data: RawValue<R>,
+        opts: &SetOptions,
+    ) -> Result<StoreResult, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("set_from_reader {}", key);
+        self.check_set_from_reader(key, len)?;
+        data.time = opts.ttl_of(&data);
+        let request = set_header(&self.wire_key(key), len, &data, &store_flags(&data, opts));
+        io.write_all(request.as_bytes()).await.map_err(io_error)?;
+        let copied = tokio::io::copy(&mut data.data.take(len as u64), io)
+            .await
+            .map_err(io_error)?;
+        if copied < len as u64 {
+            error!("set_from_reader: reader ended after {} bytes", copied);
+            return Err(MemcacheError::IOError(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "reader ended early",
+            )));
+        }
+        let marker = [0x0D, 0x0A];
+        io.write_all(&marker)
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        read_store_response(io, opts.opaque).await
+    }
+
+    /// Checks everything [`Meta::set_from_reader`] checks before sending the request
+    pub(crate) fn check_set_from_reader(&self, key: &str, len: usize) -> Result<(), MemcacheError> {
+        if self.text_only {
+            return Err(text::unsupported("set_from_reader"));
+        }
+        self.check_key(key, "set_from_reader")?;
+        self.check_size(len, "set_from_reader")
+    }
+
+    /// STORE function for binary keys. The key is base64 encoded on the wire, so it may contain
+    /// any bytes. See [`Meta::set`].
+    pub async fn set_bytes_key<T: AsyncReadWriteUnpin, D: AsRef<[u8]>>(
+        &self,
+        io: &mut T,
+        key: &[u8],
+        data: &RawValue<D>,
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("set_bytes_key");
+        if self.text_only {
+            return Err(text::unsupported("set_bytes_key"));
+        }
+        let (key, base64) = self.wire_bytes_key(key, "set_bytes_key")?;
+        let compressed = self.compress(data.as_borrowed())?;
+        let data = &compressed
+            .as_ref()
+            .map_or(data.as_borrowed(), RawValue::as_borrowed);
+        let extra = if base64 { " b" } else { "" };
+        match self.set_wire(io, &key, extra, data, None).await? {
+            StoreResult::Stored => Ok(()),
+            x => {
+                debug!("set_bytes_key: {:?}", x);
+                Err(store_error(x))
+            }
+        }
+    }
+
+    /// Quiet STORE function. Works like [`Meta::set`], but the server only responds if the
+    /// command fails and the request is not flushed. Call [`Meta::noop`] afterwards to flush
+    /// the requests and collect the failures.
+    pub async fn set_quiet<T: AsyncReadWriteUnpin, D: AsRef<[u8]>>(
+        &self,
+        io: &mut T,
+        key: &str,
+        data: &RawValue<D>,
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("set_quiet {}", key);
+        if self.text_only {
+            return Err(text::unsupported("set_quiet"));
+        }
+        self.check_key(key, "set_quiet")?;
+        let compressed = self.encode_value(key, data.as_borrowed())?;
+        let data = &compressed
+            .as_ref()
+            .map_or(data.as_borrowed(), RawValue::as_borrowed);
+        self.check_value_size(data, "set_quiet")?;
+        let request = set_request(&self.wire_key(key), data, " q").into_bytes();
+        let marker = [0x0D, 0x0A];
+        io.write_all(&request)
+            .await
+            .and(io.write_all(data.data).await)
+            .and(io.write_all(&marker).await)
+            .map_err(io_error)
+    }
+
+    /// Sends a meta set for an already validated (or encoded) key. `extra` is appended to the
+    /// request flags.
+    async fn set_wire<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        extra: &str,
+        data: &RawValue<&[u8]>,
+        opaque: Option<u32>,
+    ) -> Result<StoreResult, MemcacheError> {
+        self.check_value_size(data, "set")?;
+        let request = set_request(key, data, extra).into_bytes();
+        let marker = [0x0D, 0x0A];
+        io.write_all(&request)
+            .await
+            .and(io.write_all(data.data).await)
+            .and(io.write_all(&marker).await)
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        read_store_response(io, opaque).await
+    }
+
+    /// STORE function. Stores provided data using the provided key.
+    /// data.time determines for how many seconds memcached should keep the data. Setting it to
+    /// None will make memcached keep the data for as long as possible (data may still be dropped
+    /// if memcached reaches its memory limit)
+    /// WARNING: CAS is not yet supported.
+    pub async fn set_multiple<T: AsyncReadWriteUnpin, D: AsRef<[u8]>>(
+        &self,
+        io: &mut T,
+        keydata: &[(&str, &RawValue<D>)],
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("set_multiple");
+        // validate everything first, nothing is sent if any of the items is rejected
+        let mut compressed = Vec::with_capacity(keydata.len());
+        for (key, data) in keydata.iter() {
+            self.check_key(key, "set")?;
+            compressed.push(self.encode_value(key, data.as_borrowed())?);
+        }
+        let keys: Vec<_> = keydata.iter().map(|(x, _)| self.wire_key(x)).collect();
+        let keydata: Vec<(&str, RawValue<&[u8]>)> = keydata
+            .iter()
+            .zip(compressed.iter())
+            .zip(keys.iter())
+            .map(|(((_, data), compressed), key)| {
+                let data = compressed
+                    .as_ref()
+                    .map_or(data.as_borrowed(), RawValue::as_borrowed);
+                (key.as_ref(), data)
+            })
+            .collect();
+        for (_, data) in keydata.iter() {
+            self.check_value_size(data, "set_multiple")?;
+        }
+        self.store_multiple(io, &keydata).await
+    }
+
+    /// Unconditional pipelined stores of already validated and encoded data, under the keys
+    /// as sent to the server
+    pub(crate) async fn store_multiple<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        keydata: &[(&str, RawValue<&[u8]>)],
+    ) -> Result<(), MemcacheError> {
+        if self.text_only {
+            return self.set_multiple_text(io, keydata).await;
+        }
+        let marker = [0x0D, 0x0A];
+        for (key, data) in keydata.iter() {
+            let request = format!(
+                "ms {} S{} T{} F{}\r\n",
+                key,
+                data.data.len(),
+                data.time.unwrap_or(0),
+                data.flags
+            )
+            .into_bytes();
+            io.write_all(&request)
+                .await
+                .and(io.write_all(data.data).await)
+                .and(io.write_all(&marker).await)
+                .map_err(io_error)?;
+        }
+        io.flush().await.map_err(io_error)?;
+
+        // one response per item, read them all to keep the connection in sync
+        let mut retval = Ok(());
+        for _ in keydata.iter() {
+            let mut response_hdr: Vec<u8> = Vec::new();
+            read_line(io, &mut response_hdr).await?;
+            if response_hdr.len() >= 2 {
+                response_hdr.truncate(response_hdr.len() - 2);
+            }
+
+            let response_hdr = String::from_utf8(response_hdr).map_err(|e| {
+                error!("set: bad header");
+                MemcacheError::bad_response(e.as_bytes())
+            })?;
+            if let Some(e) = error_line(&response_hdr) {
+                debug!("set: {}", response_hdr);
+                retval = retval.and(Err(e));
+                continue;
+            }
+            let Some(response_cmd) = response_hdr.split_ascii_whitespace().next() else {
+                return Err(MemcacheError::bad_response(&response_hdr));
+            };
+            match response_cmd {
+                "OK" | "HD" => {
+                    debug!("set: OK");
+                }
+                "NS" | "EX" | "NF" => {
+                    debug!("set: {}", response_cmd);
+                    retval = retval.and(Err(status_error(response_cmd)));
+                }
+                x => {
+                    error!("set: unexpected reponse {}", x);
+                    retval = retval.and(Err(MemcacheError::bad_response(&response_hdr)));
+                }
+            }
+        }
+        retval
+    }
+
+    /// STORE multiple values in a single round trip
+    /// returns Ok(Vec(StoreResult)) with the outcome of every item, in order
+    ///
+    /// Pipelines a quiet meta set (`ms <key> S<size> T<ttl> F<flags> q O<index>`) for every
+    /// item followed by a no-op, so only the items that weren't stored generate a response.
+    /// Nothing is sent if any key or value is invalid. Values are never chunked.
+    pub async fn set_many<T: AsyncReadWriteUnpin, D: AsRef<[u8]>>(
+        &self,
+        io: &mut T,
+        keydata: &[(&str, &RawValue<D>)],
+    ) -> Result<Vec<StoreResult>, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("set_many: {} items", keydata.len());
+        let mut compressed = Vec::with_capacity(keydata.len());
+        for (key, data) in keydata.iter() {
+            self.check_key(key, "set_many")?;
+            compressed.push(self.encode_value(key, data.as_borrowed())?);
+        }
+        let keys: Vec<_> = keydata.iter().map(|(x, _)| self.wire_key(x)).collect();
+        let keydata: Vec<(&str, RawValue<&[u8]>)> = keydata
+            .iter()
+            .zip(compressed.iter())
+            .zip(keys.iter())
+            .map(|(((_, data), compressed), key)| {
+                let data = compressed
+                    .as_ref()
+                    .map_or(data.as_borrowed(), RawValue::as_borrowed);
+                (key.as_ref(), data)
+            })
+            .collect();
+        for (_, data) in keydata.iter() {
+            self.check_value_size(data, "set_many")?;
+        }
+        if self.text_only {
+            return self.set_many_text(io, &keydata).await;
+        }
+        let marker = [0x0D, 0x0A];
+        for (idx, (key, data)) in keydata.iter().enumerate() {
+            let request = format!(
+                "ms {} S{} T{} F{} q O{}\r\n",
+                key,
+                data.data.len(),
+                data.time.unwrap_or(0),
+                data.flags,
+                idx
+            );
+            io.write_all(request.as_bytes())
+                .await
+                .and(io.write_all(data.data).await)
+                .and(io.write_all(&marker).await)
+                .map_err(io_error)?;
+        }
+        io.write_all(b"mn\r\n")
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        // read up to MN even after an error line to keep the connection in sync
+        let mut retval = vec![StoreResult::Stored; keydata.len()];
+        let mut error = None;
+        loop {
+            let header = match read_meta_header(io, "set_many").await {
+                Ok(x) => x,
+                Err(e @ MemcacheError::Protocol(_)) => {
+                    error.get_or_insert(e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let result = match header.code.as_str() {
+                "MN" => break,
+                "NS" => StoreResult::NotStored,
+                "EX" => StoreResult::Exists,
+                "NF" => StoreResult::NotFound,
+                x => {
+                    error!("set_many: unexpected response {}", x);
+                    return Err(MemcacheError::bad_response(&header.line));
+                }
+            };
+            let Some(idx) = header.opaque().filter(|x| (*x as usize) < keydata.len()) else {
+                error!("set_many: missing or bad opaque token");
+                return Err(MemcacheError::bad_response(&header.line));
+            };
+            debug!("set_many: {} {:?}", keydata[idx as usize].0, result);
+            retval[idx as usize] = result;
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(retval),
+        }
     }
 
     /// Removes a key from memcached
@@ -448,39 +2164,457 @@ impl Meta {
         io: &mut T,
         key: &str,
     ) -> Result<Option<()>, MemcacheError> {
+        let io = &mut self.timed(io);
         debug!("delete: {}", key);
-        // key cannot contain control characters or space
-        if check_key_invalid(key) {
-            error!("delete: invalid key");
-            return Err(MemcacheError::BadKey);
+        self.check_key(key, "delete")?;
+        let request = format!("delete {}\r\n", self.wire_key(key)).into_bytes();
+        io.write_all(&request)
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        let mut response_hdr: Vec<u8> = Vec::new();
+        read_line(io, &mut response_hdr).await?;
+        if response_hdr.len() >= 2 {
+            response_hdr.truncate(response_hdr.len() - 2);
+        }
+
+        if response_hdr == b"DELETED" {
+            debug!("delete: OK");
+            return Ok(Some(()));
+        } else if response_hdr == b"NOT_FOUND" {
+            debug!("delete: NOT FOUND");
+            return Ok(None);
+        }
+        error!(
+            "delte: malformed reponse {}",
+            String::from_utf8_lossy(&response_hdr)
+        );
+        Err(MemcacheError::bad_response(&response_hdr))
+    }
+
+    /// Removes a value stored under a binary key, see [`Meta::get_bytes_key`]
+    pub async fn delete_bytes_key<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &[u8],
+    ) -> Result<Option<()>, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("delete_bytes_key");
+        if self.text_only {
+            return Err(text::unsupported("delete_bytes_key"));
+        }
+        let (key, base64) = self.wire_bytes_key(key, "delete_bytes_key")?;
+        let request = format!("md {}{}\r\n", key, if base64 { " b" } else { "" });
+        io.write_all(request.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        match read_delete_response(io, &DeleteOptions::default()).await? {
+            DeleteResult::Deleted => Ok(Some(())),
+            DeleteResult::NotFound => Ok(None),
+            x => {
+                error!("delete_bytes_key: unexpected result {:?}", x);
+                Err(MemcacheError::bad_response(format!("{:?}", x)))
+            }
         }
-        let request = format!("delete {}\r\n", key).into_bytes();
+    }
+
+    /// Removes a key from memcached without waiting for a response (`noreply`).
+    /// There is no way to tell whether the key existed.
+    pub async fn delete_noreply<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("delete_noreply: {}", key);
+        self.check_key(key, "delete_noreply")?;
+        let request = format!("delete {} noreply\r\n", self.wire_key(key)).into_bytes();
         io.write_all(&request)
             .await
             .and(io.flush().await)
-            .map_err(MemcacheError::IOError)?;
+            .map_err(io_error)
+    }
+
+    /// Invalidates all the items in memcached, optionally after `delay` seconds
+    pub async fn flush_all<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        delay: Option<u32>,
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("flush_all");
+        let request = match delay {
+            Some(x) => format!("flush_all {}\r\n", x),
+            None => "flush_all\r\n".to_string(),
+        };
+        io.write_all(request.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        let mut response_hdr: Vec<u8> = Vec::new();
+        read_line(io, &mut response_hdr).await?;
+        if response_hdr.len() >= 2 {
+            response_hdr.truncate(response_hdr.len() - 2);
+        }
+
+        if response_hdr == b"OK" {
+            debug!("flush_all: OK");
+            return Ok(());
+        }
+        error!(
+            "flush_all: malformed reponse {}",
+            String::from_utf8_lossy(&response_hdr)
+        );
+        Err(MemcacheError::bad_response(&response_hdr))
+    }
+
+    /// Invalidates all the items in memcached without waiting for a response (`noreply`)
+    pub async fn flush_all_noreply<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        delay: Option<u32>,
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("flush_all_noreply");
+        let request = match delay {
+            Some(x) => format!("flush_all {} noreply\r\n", x),
+            None => "flush_all noreply\r\n".to_string(),
+        };
+        io.write_all(request.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)
+    }
+
+    /// Asks the server to shut down. With `graceful` set the server stops accepting connections
+    /// and waits for the existing ones to finish (SIGUSR1). The server closes the connection on
+    /// success, so it is unusable afterwards. memcached has to be started with `-A` for this to
+    /// work.
+    pub async fn shutdown<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        graceful: bool,
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("shutdown graceful={}", graceful);
+        let request: &[u8] = if graceful {
+            b"shutdown graceful\r\n"
+        } else {
+            b"shutdown\r\n"
+        };
+        io.write_all(request)
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        let mut response_hdr: Vec<u8> = Vec::new();
+        let read = io
+            .read_until(0xA, &mut response_hdr)
+            .await
+            .map_err(io_error)?;
+        if read == 0 {
+            debug!("shutdown: connection closed");
+            return Ok(());
+        }
+        error!(
+            "shutdown: server refused: {}",
+            String::from_utf8_lossy(&response_hdr).trim()
+        );
+        let response = String::from_utf8_lossy(&response_hdr);
+        Err(error_line(response.trim())
+            .unwrap_or_else(|| MemcacheError::bad_response(&response_hdr)))
+    }
+
+    /// Moves a memory page from slab class `src` to class `dst`. `src` can be -1 to take a page
+    /// from any class. The server needs to run with `slab_reassign` enabled.
+    pub async fn slabs_reassign<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        src: i32,
+        dst: u32,
+    ) -> Result<ReassignResult, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("slabs reassign {} {}", src, dst);
+        let request = format!("slabs reassign {} {}\r\n", src, dst);
+        let response = text_command(io, &request, "slabs_reassign").await?;
+        // the status may be followed by a human readable explanation
+        match response.split_ascii_whitespace().next() {
+            Some("OK") => Ok(ReassignResult::Ok),
+            Some("BUSY") => Ok(ReassignResult::Busy),
+            Some("BADCLASS") => Ok(ReassignResult::BadClass),
+            Some("NOSPARE") => Ok(ReassignResult::NoSpare),
+            Some("NOTFULL") => Ok(ReassignResult::NotFull),
+            Some("UNSAFE") => Ok(ReassignResult::Unsafe),
+            Some("SAME") => Ok(ReassignResult::Same),
+            _ => {
+                error!("slabs_reassign: unexpected response {}", response);
+                Err(MemcacheError::bad_response(&response))
+            }
+        }
+    }
+
+    /// Changes the slab automove mode of the server
+    pub async fn slabs_automove<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        mode: AutomoveMode,
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("slabs automove {:?}", mode);
+        let mode = match mode {
+            AutomoveMode::Disabled => 0,
+            AutomoveMode::Enabled => 1,
+            AutomoveMode::Aggressive => 2,
+        };
+        let request = format!("slabs automove {}\r\n", mode);
+        let response = text_command(io, &request, "slabs_automove").await?;
+        if response == "OK" {
+            Ok(())
+        } else {
+            error!("slabs_automove: unexpected response {}", response);
+            Err(MemcacheError::bad_response(&response))
+        }
+    }
+
+    /// Switches the LRU algorithm and sets the percentage of memory reserved for the HOT
+    /// and WARM segments. The age factors of the segments are kept as they are.
+    pub async fn lru_tune<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        mode: LruMode,
+        hot_pct: u32,
+        warm_pct: u32,
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("lru mode {:?} tune {} {}", mode, hot_pct, warm_pct);
+        let request = match mode {
+            LruMode::Flat => "lru mode flat\r\n",
+            LruMode::Segmented => "lru mode segmented\r\n",
+        };
+        let response = text_command(io, request, "lru_tune").await?;
+        if response != "OK" {
+            error!("lru_tune: unexpected response {}", response);
+            return Err(MemcacheError::bad_response(&response));
+        }
+
+        // `lru tune` requires the age factors as well, reuse the current ones
+        let settings = self.stats_raw(io, Some("settings")).await?;
+        let factor = |name: &str, default: &str| {
+            settings
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+                .unwrap_or_else(|| default.to_string())
+        };
+        let request = format!(
+            "lru tune {} {} {} {}\r\n",
+            hot_pct,
+            warm_pct,
+            factor("hot_max_factor", "0.20"),
+            factor("warm_max_factor", "2.00")
+        );
+        let response = text_command(io, &request, "lru_tune").await?;
+        if response == "OK" {
+            Ok(())
+        } else {
+            error!("lru_tune: unexpected response {}", response);
+            Err(MemcacheError::bad_response(&response))
+        }
+    }
+
+    /// Controls the LRU crawler, see [`LruCrawlerCommand`]
+    pub async fn lru_crawler<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        command: &LruCrawlerCommand,
+    ) -> Result<LruCrawlerResult, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("lru_crawler {:?}", command);
+        let request = match command {
+            LruCrawlerCommand::Enable => "lru_crawler enable\r\n".to_string(),
+            LruCrawlerCommand::Disable => "lru_crawler disable\r\n".to_string(),
+            LruCrawlerCommand::Sleep(x) => format!("lru_crawler sleep {}\r\n", x),
+            LruCrawlerCommand::ToCrawl(x) => format!("lru_crawler tocrawl {}\r\n", x),
+            LruCrawlerCommand::Crawl(x) => format!("lru_crawler crawl {}\r\n", x.token()),
+        };
+        let response = text_command(io, &request, "lru_crawler").await?;
+        match response.split_ascii_whitespace().next() {
+            Some("OK") => Ok(LruCrawlerResult::Ok),
+            Some("BUSY") => Ok(LruCrawlerResult::Busy),
+            Some("BADCLASS") => Ok(LruCrawlerResult::BadClass),
+            _ => {
+                error!("lru_crawler: unexpected response {}", response);
+                Err(MemcacheError::bad_response(&response))
+            }
+        }
+    }
+
+    /// Subscribes to the watcher logs of the server. The connection can't be used for anything
+    /// else afterwards, so it is consumed by the returned stream, which ends when the
+    /// connection is closed or fails.
+    pub async fn watch<T: AsyncReadWriteUnpin>(
+        &self,
+        mut io: T,
+        targets: &[WatchTarget],
+    ) -> Result<impl Stream<Item = WatchEvent>, MemcacheError> {
+        let mut request = "watch".to_string();
+        for x in targets {
+            request.push(' ');
+            request.push_str(x.token());
+        }
+        debug!("{}", request);
+        request.push_str("\r\n");
+        let response = text_command(&mut io, &request, "watch").await?;
+        if response != "OK" {
+            error!("watch: unexpected response {}", response);
+            return Err(MemcacheError::bad_response(&response));
+        }
+
+        Ok(futures_util::stream::unfold(
+            (io, Vec::new()),
+            |(mut io, mut buffer)| async move {
+                loop {
+                    buffer.clear();
+                    match io.read_until(0xA, &mut buffer).await {
+                        Ok(0) => {
+                            debug!("watch: connection closed");
+                            return None;
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            error!("watch: {}", e);
+                            return None;
+                        }
+                    }
+                    let line = String::from_utf8_lossy(&buffer);
+                    if let Some(x) = crate::watch::parse_line(&line) {
+                        return Some((x, (io, buffer)));
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Lists the metadata of every item in the given slab classes. The connection can be used
+    /// again once the returned stream is exhausted. The stream ends early if the server sends
+    /// a malformed line or the connection fails.
+    pub async fn metadump<'a, T: AsyncReadWriteUnpin>(
+        &self,
+        io: &'a mut T,
+        classes: &CrawlerClasses,
+    ) -> Result<impl Stream<Item = ItemMetadata> + 'a, MemcacheError> {
+        use futures_util::StreamExt;
+
+        Ok(self
+            .metadump_results(io, classes)
+            .await?
+            .take_while(|x| std::future::ready(x.is_ok()))
+            .filter_map(|x| std::future::ready(x.ok())))
+    }
+
+    /// [`Meta::metadump`] yielding the error that ended the stream early, if any
+    pub(crate) async fn metadump_results<'a, T: AsyncReadWriteUnpin>(
+        &self,
+        io: &'a mut T,
+        classes: &CrawlerClasses,
+    ) -> Result<impl Stream<Item = Result<ItemMetadata, MemcacheError>> + 'a, MemcacheError> {
+        debug!("lru_crawler metadump {:?}", classes);
+        let request = format!("lru_crawler metadump {}\r\n", classes.token());
+        let response = text_command(io, &request, "metadump").await?;
+        let first = match response.split_ascii_whitespace().next() {
+            Some("END") => None,
+            Some("BUSY") => {
+                error!("metadump: {}", response);
+                return Err(MemcacheError::bad_response(&response));
+            }
+            Some("BADCLASS") => {
+                debug!("metadump: {}", response);
+                return Err(MemcacheError::Protocol(ProtocolError::new(
+                    ErrorCode::ClientError,
+                    response,
+                )));
+            }
+            _ => match ItemMetadata::parse(&response) {
+                Some(x) => Some(x),
+                None => {
+                    error!("metadump: unexpected response {}", response);
+                    return Err(MemcacheError::bad_response(&response));
+                }
+            },
+        };
+
+        let done = first.is_none();
+        Ok(futures_util::stream::unfold(
+            (io, Vec::new(), first, done),
+            |(io, mut buffer, first, done)| async move {
+                if let Some(x) = first {
+                    return Some((Ok(x), (io, buffer, None, false)));
+                }
+                if done {
+                    return None;
+                }
+                buffer.clear();
+                match io.read_until(0xA, &mut buffer).await {
+                    Ok(0) => {
+                        error!("metadump: connection closed");
+                        return Some((
+                            Err(MemcacheError::ConnectionClosed),
+                            (io, buffer, None, true),
+                        ));
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("metadump: {}", e);
+                        return Some((Err(io_error(e)), (io, buffer, None, true)));
+                    }
+                }
+                let line = String::from_utf8_lossy(&buffer);
+                let line = line.trim_end();
+                if line == "END" {
+                    return None;
+                }
+                match ItemMetadata::parse(line) {
+                    Some(x) => Some((Ok(x), (io, buffer, None, false))),
+                    None => {
+                        error!("metadump: unexpected response {}", line);
+                        let e = MemcacheError::bad_response(line);
+                        Some((Err(e), (io, buffer, None, true)))
+                    }
+                }
+            },
+        ))
+    }
 
-        let mut response_hdr: Vec<u8> = Vec::new();
-        let _ = io
-            .read_until(0xA, &mut response_hdr)
-            .await
-            .map_err(MemcacheError::IOError)?;
-        if response_hdr.len() >= 2 {
-            response_hdr.truncate(response_hdr.len() - 2);
+    /// Authenticates the connection using the text protocol authentication of memcached 1.6+
+    /// (server started with `-Y`). Has to be done before any other command is sent.
+    pub async fn authenticate<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        username: &str,
+        password: &str,
+    ) -> Result<(), MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("authenticate {}", username);
+        if username.contains(char::is_whitespace) || password.contains(['\r', '\n']) {
+            debug!("authenticate: invalid credentials format");
+            return Err(MemcacheError::Protocol(ProtocolError::new(
+                ErrorCode::ClientError,
+                "invalid credentials format",
+            )));
         }
-
-        if response_hdr == b"DELETED" {
-            debug!("delete: OK");
-            return Ok(Some(()));
-        } else if response_hdr == b"NOT_FOUND" {
-            debug!("delete: NOT FOUND");
-            return Ok(None);
+        let credentials = format!("{} {}", username, password);
+        let request = format!("set auth 0 0 {}\r\n{}\r\n", credentials.len(), credentials);
+        let response = text_command(io, &request, "authenticate").await?;
+        if response == "STORED" {
+            Ok(())
+        } else {
+            error!("authenticate: unexpected response {}", response);
+            Err(MemcacheError::bad_response(&response))
         }
-        error!(
-            "delte: malformed reponse {}",
-            String::from_utf8_lossy(&response_hdr)
-        );
-        Err(MemcacheError::BadServerResponse)
     }
 
     /// Checks memcached server version and returns it as a string.
@@ -488,25 +2622,898 @@ impl Meta {
         &self,
         io: &mut T,
     ) -> Result<String, MemcacheError> {
+        let io = &mut self.timed(io);
         let request = b"version\r\n";
         io.write_all(request)
             .await
             .and(io.flush().await)
-            .map_err(MemcacheError::IOError)?;
+            .map_err(io_error)?;
 
         let mut response_hdr: Vec<u8> = Vec::new();
-        let _ = io
-            .read_until(0xA, &mut response_hdr)
-            .await
-            .map_err(MemcacheError::IOError)?;
-        let Ok(response_hdr) = String::from_utf8(response_hdr) else {
-            return Err(MemcacheError::BadServerResponse);
-        };
+        read_line(io, &mut response_hdr).await?;
+        let response_hdr = String::from_utf8(response_hdr)
+            .map_err(|e| MemcacheError::bad_response(e.as_bytes()))?;
         let response_hdr = response_hdr.trim();
         if response_hdr.len() > 8 && response_hdr.starts_with("VERSION ") {
             Ok(response_hdr[8..].to_string())
         } else {
-            Err(MemcacheError::BadServerResponse)
+            Err(MemcacheError::bad_response(response_hdr))
+        }
+    }
+
+    /// Sends a `stats` command (with optional arguments) and returns the raw list of
+    /// `(name, value)` pairs reported by the server.
+    pub async fn stats_raw<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        args: Option<&str>,
+    ) -> Result<Vec<(String, String)>, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("stats {}", args.unwrap_or(""));
+        let request = match args {
+            Some(x) => format!("stats {}\r\n", x),
+            None => "stats\r\n".to_string(),
+        };
+        io.write_all(request.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        let mut retval = Vec::new();
+        let mut buffer = Vec::new();
+        loop {
+            buffer.clear();
+            read_line(io, &mut buffer).await?;
+            if buffer.len() >= 2 {
+                buffer.truncate(buffer.len() - 2);
+            }
+            if buffer == b"END" {
+                return Ok(retval);
+            }
+            let Ok(response) = std::str::from_utf8(&buffer) else {
+                error!("stats: non-ASCII response");
+                return Err(MemcacheError::bad_response(&buffer));
+            };
+            let mut response_hdr = response.splitn(3, ' ');
+            if response_hdr.next() != Some("STAT") {
+                error!("stats: malformed response {}", response);
+                return Err(MemcacheError::bad_response(response));
+            }
+            let (Some(name), Some(value)) = (response_hdr.next(), response_hdr.next()) else {
+                error!("stats: malformed response {}", response);
+                return Err(MemcacheError::bad_response(response));
+            };
+            retval.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    /// Reads the general statistics (`stats`)
+    pub async fn stats<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+    ) -> Result<ServerStats, MemcacheError> {
+        let io = &mut self.timed(io);
+        let raw = self.stats_raw(io, None).await?;
+        ServerStats::from_raw(&raw)
+    }
+
+    /// Reads slab statistics (`stats slabs`)
+    pub async fn stats_slabs<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+    ) -> Result<SlabStats, MemcacheError> {
+        let io = &mut self.timed(io);
+        let raw = self.stats_raw(io, Some("slabs")).await?;
+        SlabStats::from_raw(&raw)
+    }
+
+    /// Reads the item size histogram (`stats sizes`)
+    pub async fn stats_sizes<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+    ) -> Result<SizeStats, MemcacheError> {
+        let io = &mut self.timed(io);
+        let raw = self.stats_raw(io, Some("sizes")).await?;
+        SizeStats::from_raw(&raw)
+    }
+
+    /// Reads the server configuration (`stats settings`)
+    pub async fn stats_settings<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+    ) -> Result<SettingsStats, MemcacheError> {
+        let io = &mut self.timed(io);
+        let raw = self.stats_raw(io, Some("settings")).await?;
+        SettingsStats::from_raw(&raw)
+    }
+
+    /// Reads the state of every connection open on the server (`stats conns`)
+    pub async fn stats_conns<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+    ) -> Result<Vec<ConnectionStats>, MemcacheError> {
+        let io = &mut self.timed(io);
+        let raw = self.stats_raw(io, Some("conns")).await?;
+        ConnectionStats::from_raw(&raw)
+    }
+
+    /// Increments or decrements a numeric value (meta arithmetic, `ma`).
+    /// Setting `opts.vivify_ttl` makes memcached atomically create missing counters.
+    pub async fn arithmetic<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        opts: &ArithmeticOptions,
+    ) -> Result<ArithmeticResult, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("arithmetic {}", key);
+        self.check_key(key, "arithmetic")?;
+        let key = &self.wire_key(key);
+        if self.text_only {
+            return self.arithmetic_text(io, key, opts).await;
+        }
+        let request = arithmetic_request(key, opts);
+        io.write_all(request.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        read_arithmetic_response(io, opts).await
+    }
+
+    /// Sends a meta no-op (`mn`) and waits for the server to answer with `MN`.
+    /// Since memcached answers in order, all the responses to previously sent commands
+    /// have been received once this returns.
+    ///
+    /// Any response received before `MN` belongs to a failed quiet command. Those are consumed
+    /// and the first failure is returned as an error once `MN` arrives.
+    pub async fn noop<T: AsyncReadWriteUnpin>(&self, io: &mut T) -> Result<(), MemcacheError> {
+        if self.text_only {
+            return self.noop_text(io).await;
+        }
+        io.write_all(b"mn\r\n")
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        let mut retval = Ok(());
+        loop {
+            match read_meta_header(io, "noop").await {
+                Ok(header) if header.code == "MN" => return retval,
+                Ok(header) => {
+                    error!("noop: quiet command failed: {}", header.code);
+                    if retval.is_ok() {
+                        retval = Err(status_error(&header.code));
+                    }
+                }
+                Err(e @ MemcacheError::Protocol(_)) => {
+                    if retval.is_ok() {
+                        retval = Err(e);
+                    }
+                }
+                Err(x) => return Err(x),
+            }
+        }
+    }
+
+    /// Removes (or invalidates) a key from memcached using the meta delete command (`md`)
+    pub async fn delete_with<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        opts: &DeleteOptions,
+    ) -> Result<DeleteResult, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("delete_with: {}", key);
+        self.check_key(key, "delete_with")?;
+        if self.text_only {
+            return self.delete_text(io, key, opts).await;
+        }
+        let request = delete_request(&self.wire_key(key), opts);
+        io.write_all(request.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        read_delete_response(io, opts).await
+    }
+
+    /// Updates the TTL of multiple keys in a single round trip
+    /// returns Ok(Vec(bool)) telling whether every key existed, in order
+    ///
+    /// Pipelines a quiet meta get without value (`mg <key> T<ttl> q O<index>`) for every key
+    /// followed by a no-op, so only the keys found generate a response.
+    pub async fn touch_many<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key_list: &[&str],
+        ttl: u32,
+    ) -> Result<Vec<bool>, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("touch_many: {} keys", key_list.len());
+        for key in key_list {
+            self.check_key(key, "touch_many")?;
+        }
+        let keys: Vec<_> = key_list.iter().map(|x| self.wire_key(x)).collect();
+        if self.text_only {
+            return self.touch_many_text(io, &keys, ttl).await;
+        }
+        let mut send = String::new();
+        for (idx, key) in keys.iter().enumerate() {
+            send.push_str(&format!("mg {} T{} q O{}\r\n", key, ttl, idx));
+        }
+        send.push_str("mn\r\n");
+        io.write_all(send.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        // read up to MN even after an error line to keep the connection in sync
+        let mut retval = vec![false; keys.len()];
+        let mut error = None;
+        loop {
+            let header = match read_meta_header(io, "touch_many").await {
+                Ok(x) => x,
+                Err(e @ MemcacheError::Protocol(_)) => {
+                    error.get_or_insert(e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            match header.code.as_str() {
+                "MN" => break,
+                "HD" => {}
+                x => {
+                    error!("touch_many: unexpected response {}", x);
+                    return Err(MemcacheError::bad_response(&header.line));
+                }
+            }
+            let Some(idx) = header.opaque().filter(|x| (*x as usize) < keys.len()) else {
+                error!("touch_many: missing or bad opaque token");
+                return Err(MemcacheError::bad_response(&header.line));
+            };
+            retval[idx as usize] = true;
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(retval),
+        }
+    }
+
+    /// Removes multiple keys in a single round trip
+    /// returns Ok(Vec(bool)) telling whether every key existed, in order
+    ///
+    /// Pipelines a meta delete (`md <key> O<index>`) for every key. They aren't quiet since
+    /// memcached doesn't report missing keys to quiet deletes.
+    pub async fn delete_many<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key_list: &[&str],
+    ) -> Result<Vec<bool>, MemcacheError> {
+        let io = &mut self.timed(io);
+        debug!("delete_many: {} keys", key_list.len());
+        for key in key_list {
+            self.check_key(key, "delete_many")?;
+        }
+        let keys: Vec<_> = key_list.iter().map(|x| self.wire_key(x)).collect();
+        if self.text_only {
+            return self.delete_many_text(io, &keys).await;
+        }
+        let mut send = String::new();
+        for (idx, key) in keys.iter().enumerate() {
+            let opts = DeleteOptions::new().opaque(Some(idx as u32));
+            send.push_str(&delete_request(key, &opts));
+        }
+        io.write_all(send.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        // read every response to keep the connection in sync
+        let mut retval = Vec::with_capacity(keys.len());
+        let mut error = None;
+        for idx in 0..keys.len() {
+            let opts = DeleteOptions::new().opaque(Some(idx as u32));
+            match read_delete_response(io, &opts).await {
+                Ok(x) => retval.push(x == DeleteResult::Deleted),
+                Err(e @ MemcacheError::Protocol(_)) => {
+                    error.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(retval),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fake connection replaying a canned server response and discarding the requests
+    fn mock(response: &[u8]) -> tokio::io::Join<std::io::Cursor<Vec<u8>>, tokio::io::Sink> {
+        tokio::io::join(std::io::Cursor::new(response.to_vec()), tokio::io::sink())
+    }
+
+    #[tokio::test]
+    async fn watch_streams_events() {
+        use futures_util::StreamExt;
+
+        let meta = Meta::new();
+        let io = mock(
+            b"OK\r\nts=1.5 gid=1 type=item_get key=a status=found\r\n\
+              ts=1.6 gid=2 type=item_get key=b status=not_found\r\n",
+        );
+        let Ok(stream) = meta.watch(io, &[WatchTarget::Fetchers]).await else {
+            panic!("Meta.watch() failed");
+        };
+        let events: Vec<WatchEvent> = stream.collect().await;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].key.as_deref(), Some("a"));
+        assert_eq!(events[1].field("status"), Some("not_found"));
+    }
+
+    #[tokio::test]
+    async fn metadump_lists_items() {
+        use futures_util::StreamExt;
+
+        let meta = Meta::new();
+        let mut io = mock(
+            b"key=a exp=-1 la=1700000000 cas=3 fetch=no cls=1 size=63\n\
+              key=b exp=1700000100 la=1700000001 cas=4 fetch=yes cls=2 size=120 flags=0\n\
+              END\r\n",
+        );
+        let Ok(stream) = meta.metadump(&mut io, &CrawlerClasses::All).await else {
+            panic!("Meta.metadump() failed");
+        };
+        let items: Vec<ItemMetadata> = stream.collect().await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].key, "a");
+        assert_eq!(items[0].exp, -1);
+        assert_eq!(items[0].size, 63);
+        assert!(!items[0].fetch);
+        assert_eq!(items[1].cls, 2);
+        assert!(items[1].fetch);
+
+        let mut io = mock(b"END\r\n");
+        let Ok(stream) = meta
+            .metadump(&mut io, &CrawlerClasses::Classes(vec![1]))
+            .await
+        else {
+            panic!("Meta.metadump() failed");
+        };
+        assert_eq!(stream.count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn error_responses() {
+        let meta = Meta::new();
+        let value = RawValue::from_vec(b"v".to_vec());
+
+        let mut io = mock(b"NS\r\n");
+        let result = meta.set(&mut io, "key", &value).await;
+        assert!(matches!(result, Err(MemcacheError::NotStored)));
+
+        let mut io = mock(b"SERVER_ERROR out of memory storing object\r\n");
+        let result = meta.set(&mut io, "key", &value).await;
+        assert!(matches!(result, Err(MemcacheError::Protocol(x)) if x.is_out_of_memory()));
+
+        let mut io = mock(b"EX\r\nMN\r\n");
+        assert!(matches!(
+            meta.noop(&mut io).await,
+            Err(MemcacheError::Exists)
+        ));
+
+        let mut io = mock(b"");
+        let result = meta.get(&mut io, "key").await;
+        assert!(matches!(result, Err(MemcacheError::ConnectionClosed)));
+
+        // the offending line is kept
+        let mut io = mock(b"XX garbage\r\n");
+        let result = meta.get(&mut io, "key").await;
+        assert!(matches!(result, Err(MemcacheError::BadServerResponse(x)) if x == "XX garbage"));
+
+        let mut io = mock("VA 1 kkey \u{e9}1\r\nv\r\n".as_bytes());
+        let result = meta.get(&mut io, "key").await;
+        assert!(matches!(result, Err(MemcacheError::BadServerResponse(_))));
+    }
+
+    #[test]
+    fn arithmetic_request_flags() {
+        let opts = ArithmeticOptions::new();
+        assert_eq!(arithmetic_request("n", &opts), "ma n D1\r\n");
+
+        let opts = ArithmeticOptions::new()
+            .mode(ArithmeticMode::Decrement)
+            .delta(5)
+            .vivify(60, 10)
+            .ttl(Some(30))
+            .return_value(true)
+            .opaque(Some(7));
+        assert_eq!(
+            arithmetic_request("n", &opts),
+            "ma n D5 MD N60 J10 T30 v O7\r\n"
+        );
+
+        // the initial value is only sent along with the vivify TTL
+        let mut opts = ArithmeticOptions::new();
+        opts.initial = Some(10);
+        assert_eq!(arithmetic_request("n", &opts), "ma n D1\r\n");
+    }
+
+    #[tokio::test]
+    async fn arithmetic_responses() {
+        let meta = Meta::new();
+        let opts = ArithmeticOptions::new();
+
+        let mut io = mock(b"HD\r\n");
+        let result = meta.arithmetic(&mut io, "n", &opts).await;
+        assert_eq!(result.unwrap(), ArithmeticResult::Updated(None));
+
+        let mut io = mock(b"VA 2\r\n42\r\n");
+        let result = meta
+            .arithmetic(&mut io, "n", &opts.clone().return_value(true))
+            .await;
+        assert_eq!(result.unwrap(), ArithmeticResult::Updated(Some(42)));
+
+        let mut io = mock(b"NF\r\n");
+        let result = meta.arithmetic(&mut io, "n", &opts).await;
+        assert_eq!(result.unwrap(), ArithmeticResult::NotFound);
+
+        let mut io = mock(b"NS\r\n");
+        let result = meta.arithmetic(&mut io, "n", &opts).await;
+        assert_eq!(result.unwrap(), ArithmeticResult::NotStored);
+
+        let mut io = mock(b"EX\r\n");
+        let result = meta.arithmetic(&mut io, "n", &opts).await;
+        assert!(matches!(result, Err(MemcacheError::Exists)));
+
+        let mut io = mock(b"VA 3\r\nabc\r\n");
+        let result = meta
+            .arithmetic(&mut io, "n", &opts.clone().return_value(true))
+            .await;
+        assert!(matches!(result, Err(MemcacheError::BadServerResponse(x)) if x == "abc"));
+    }
+
+    #[tokio::test]
+    async fn set_many_reports_failures() {
+        let meta = Meta::new();
+        let a = RawValue::from_vec(b"a".to_vec());
+        let b = RawValue::from_vec(b"b".to_vec());
+        let mut io = mock(b"NS O1\r\nMN\r\n");
+        let result = meta.set_many(&mut io, &[("a", &a), ("b", &b)]).await;
+        assert_eq!(
+            result.unwrap(),
+            vec![StoreResult::Stored, StoreResult::NotStored]
+        );
+
+        let mut io = mock(b"CLIENT_ERROR bad data chunk\r\nMN\r\n");
+        let result = meta.set_many(&mut io, &[("a", &a)]).await;
+        assert!(matches!(result, Err(MemcacheError::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn delete_many_reports_missing_keys() {
+        let meta = Meta::new();
+        let mut io = mock(b"HD O0\r\nNF O1\r\nHD O2\r\n");
+        let result = meta.delete_many(&mut io, &["a", "b", "c"]).await;
+        assert_eq!(result.unwrap(), vec![true, false, true]);
+
+        let mut io = mock(b"HD O1\r\n");
+        let result = meta.delete_many(&mut io, &["a"]).await;
+        assert!(matches!(result, Err(MemcacheError::BadServerResponse(_))));
+    }
+
+    #[tokio::test]
+    async fn touch_many_reports_missing_keys() {
+        let meta = Meta::new();
+        let mut io = mock(b"HD O2\r\nHD O0\r\nMN\r\n");
+        let result = meta.touch_many(&mut io, &["a", "b", "c"], 60).await;
+        assert_eq!(result.unwrap(), vec![true, false, true]);
+    }
+
+    #[tokio::test]
+    async fn authenticate_reports_failure() {
+        let meta = Meta::new();
+
+        let mut io = mock(b"STORED\r\n");
+        assert!(meta.authenticate(&mut io, "user", "pass").await.is_ok());
+
+        let mut io = mock(b"CLIENT_ERROR authentication failure\r\n");
+        assert!(matches!(
+            meta.authenticate(&mut io, "user", "wrong").await,
+            Err(MemcacheError::Protocol(x)) if x.message == "authentication failure"
+        ));
+    }
+
+    #[tokio::test]
+    async fn set_rejects_large_values() {
+        let meta = Meta::new().max_value_size(4);
+
+        let mut io = mock(b"HD\r\n");
+        let data = RawValue::from_vec(b"12345".to_vec());
+        assert!(matches!(
+            meta.set(&mut io, "key", &data).await,
+            Err(MemcacheError::ValueTooLarge { size: 5, limit: 4 })
+        ));
+        // the response was not consumed, nothing was sent
+        let data = RawValue::from_vec(b"1234".to_vec());
+        assert!(meta.set(&mut io, "key", &data).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn namespace_prefixes_keys() {
+        let meta = Meta::new().namespace(Some("app:".to_string())).unwrap();
+        assert!(matches!(
+            Meta::new().namespace(Some("app:\r\nflush_all".to_string())),
+            Err(MemcacheError::BadKey)
+        ));
+        let mut io = mock(b"VA 1 kapp:b f0 c1 O1\r\nx\r\nMN\r\n");
+        let values = meta.get_many(&mut io, &["a", "b"]).await.unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].0, "b");
+
+        // the key echoed by the server includes the namespace
+        let mut io = mock(b"VA 1 kb f0 c1 O1\r\nx\r\nMN\r\n");
+        assert!(meta.get_many(&mut io, &["a", "b"]).await.is_err());
+
+        let meta = meta.text_only(true);
+        let mut io = mock(b"VALUE app:b 0 1\r\nx\r\nEND\r\n");
+        let values = meta.get_many(&mut io, &["a", "b"]).await.unwrap();
+        assert_eq!(values[0].0, "b");
+    }
+
+    /// Fake connection replaying a canned server response, see [`sent`]
+    async fn recording(
+        response: &[u8],
+    ) -> (
+        tokio::io::BufStream<tokio::io::DuplexStream>,
+        tokio::io::DuplexStream,
+    ) {
+        let (client, mut server) = tokio::io::duplex(4096);
+        server.write_all(response).await.unwrap();
+        (tokio::io::BufStream::new(client), server)
+    }
+
+    /// Requests sent over a connection created by [`recording`]
+    async fn sent(
+        io: tokio::io::BufStream<tokio::io::DuplexStream>,
+        mut server: tokio::io::DuplexStream,
+    ) -> String {
+        drop(io);
+        let mut request = String::new();
+        server.read_to_string(&mut request).await.unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn binary_keys_are_base64_encoded() {
+        let meta = Meta::new();
+        let value = RawValue::from_vec(b"v".to_vec());
+
+        let (mut io, server) = recording(b"VA 1 kYSBiDQo= b f0 c1\r\nv\r\n").await;
+        let result = meta.get_bytes_key(&mut io, b"a b\r\n").await.unwrap();
+        assert_eq!(result.unwrap().data, b"v");
+        let request = sent(io, server).await;
+        assert_eq!(request, "mg YSBiDQo= k f c v b\r\n");
+
+        let (mut io, server) = recording(b"HD\r\n").await;
+        meta.set_bytes_key(&mut io, b"a b\r\n", &value)
+            .await
+            .unwrap();
+        assert_eq!(sent(io, server).await, "ms YSBiDQo= S1 T0 F0 b\r\nv\r\n");
+
+        let (mut io, server) = recording(b"HD\r\n").await;
+        let result = meta.delete_bytes_key(&mut io, b"a b\r\n").await.unwrap();
+        assert_eq!(result, Some(()));
+        assert_eq!(sent(io, server).await, "md YSBiDQo= b\r\n");
+
+        // the namespace is encoded along with the key
+        let meta = Meta::new().namespace(Some("app:".to_string())).unwrap();
+        let (mut io, server) = recording(b"NF\r\n").await;
+        let result = meta.delete_bytes_key(&mut io, &[0, 0xff]).await.unwrap();
+        assert_eq!(result, None);
+        assert_eq!(sent(io, server).await, "md YXBwOgD/ b\r\n");
+
+        // hashed keys are plain text
+        let meta = Meta::new().long_keys(LongKeys::Hash);
+        let (mut io, server) = recording(b"HD\r\n").await;
+        meta.delete_bytes_key(&mut io, &[0xff; 200]).await.unwrap();
+        let request = sent(io, server).await;
+        assert!(request.starts_with("md "), "{}", request);
+        assert!(!request.contains(" b"), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn quiet_set() {
+        let meta = Meta::new();
+        let value = RawValue::from_vec(b"v".to_vec());
+
+        // nothing is read on success, the noop only gets its own response
+        let mut io = mock(b"MN\r\n");
+        meta.set_quiet(&mut io, "a", &value).await.unwrap();
+        meta.set_quiet(&mut io, "b", &value).await.unwrap();
+        meta.noop(&mut io).await.unwrap();
+
+        let (mut io, server) = recording(b"MN\r\n").await;
+        meta.set_quiet(&mut io, "a", &value).await.unwrap();
+        meta.noop(&mut io).await.unwrap();
+        assert_eq!(sent(io, server).await, "ms a S1 T0 F0 q\r\nv\r\nmn\r\n");
+
+        // failures are reported by the noop, which still reads up to its response
+        let mut io = mock(b"NS\r\nMN\r\nMN\r\n");
+        meta.set_quiet(&mut io, "a", &value).await.unwrap();
+        meta.set_quiet(&mut io, "b", &value).await.unwrap();
+        assert!(matches!(
+            meta.noop(&mut io).await,
+            Err(MemcacheError::NotStored)
+        ));
+        meta.noop(&mut io).await.unwrap();
+
+        let mut io = mock(b"SERVER_ERROR out of memory storing object\r\nMN\r\n");
+        meta.set_quiet(&mut io, "a", &value).await.unwrap();
+        assert!(matches!(
+            meta.noop(&mut io).await,
+            Err(MemcacheError::Protocol(x)) if x.is_out_of_memory()
+        ));
+    }
+
+    #[tokio::test]
+    async fn slab_commands() {
+        let meta = Meta::new();
+
+        let (mut io, server) = recording(b"OK\r\n").await;
+        let result = meta.slabs_reassign(&mut io, -1, 5).await.unwrap();
+        assert_eq!(result, ReassignResult::Ok);
+        assert_eq!(sent(io, server).await, "slabs reassign -1 5\r\n");
+
+        // the status may come with an explanation
+        let mut io = mock(b"BUSY currently processing reassign request\r\n");
+        let result = meta.slabs_reassign(&mut io, 1, 2).await.unwrap();
+        assert_eq!(result, ReassignResult::Busy);
+        let mut io = mock(b"NOSPARE source class has no spare pages\r\n");
+        let result = meta.slabs_reassign(&mut io, 1, 2).await.unwrap();
+        assert_eq!(result, ReassignResult::NoSpare);
+        let mut io = mock(b"BADCLASS invalid src or dst class id\r\n");
+        let result = meta.slabs_reassign(&mut io, 1, 99).await.unwrap();
+        assert_eq!(result, ReassignResult::BadClass);
+
+        let mut io = mock(b"CLIENT_ERROR bad command line format\r\n");
+        let result = meta.slabs_reassign(&mut io, 1, 2).await;
+        assert!(matches!(result, Err(MemcacheError::Protocol(_))));
+        let mut io = mock(b"WHAT\r\n");
+        let result = meta.slabs_reassign(&mut io, 1, 2).await;
+        assert!(matches!(result, Err(MemcacheError::BadServerResponse(x)) if x == "WHAT"));
+
+        let (mut io, server) = recording(b"OK\r\n").await;
+        meta.slabs_automove(&mut io, AutomoveMode::Aggressive)
+            .await
+            .unwrap();
+        assert_eq!(sent(io, server).await, "slabs automove 2\r\n");
+
+        let (mut io, server) = recording(b"OK\r\n").await;
+        meta.slabs_automove(&mut io, AutomoveMode::Disabled)
+            .await
+            .unwrap();
+        assert_eq!(sent(io, server).await, "slabs automove 0\r\n");
+
+        let mut io = mock(b"ERROR\r\n");
+        let result = meta.slabs_automove(&mut io, AutomoveMode::Enabled).await;
+        assert!(matches!(result, Err(MemcacheError::Protocol(_))));
+        let mut io = mock(b"BUSY\r\n");
+        let result = meta.slabs_automove(&mut io, AutomoveMode::Enabled).await;
+        assert!(matches!(result, Err(MemcacheError::BadServerResponse(x)) if x == "BUSY"));
+    }
+
+    #[tokio::test]
+    async fn lru_crawler_commands() {
+        let meta = Meta::new();
+        let commands = [
+            (LruCrawlerCommand::Enable, "lru_crawler enable\r\n"),
+            (LruCrawlerCommand::Disable, "lru_crawler disable\r\n"),
+            (LruCrawlerCommand::Sleep(100), "lru_crawler sleep 100\r\n"),
+            (LruCrawlerCommand::ToCrawl(0), "lru_crawler tocrawl 0\r\n"),
+            (
+                LruCrawlerCommand::Crawl(CrawlerClasses::All),
+                "lru_crawler crawl all\r\n",
+            ),
+            (
+                LruCrawlerCommand::Crawl(CrawlerClasses::Classes(vec![1, 5, 12])),
+                "lru_crawler crawl 1,5,12\r\n",
+            ),
+        ];
+        for (command, request) in commands {
+            let (mut io, server) = recording(b"OK\r\n").await;
+            let result = meta.lru_crawler(&mut io, &command).await.unwrap();
+            assert_eq!(result, LruCrawlerResult::Ok);
+            assert_eq!(sent(io, server).await, request);
         }
+
+        let crawl = LruCrawlerCommand::Crawl(CrawlerClasses::Classes(vec![99]));
+        let mut io = mock(b"BADCLASS 99\r\n");
+        let result = meta.lru_crawler(&mut io, &crawl).await.unwrap();
+        assert_eq!(result, LruCrawlerResult::BadClass);
+        let mut io = mock(b"BUSY currently processing crawler request\r\n");
+        let result = meta.lru_crawler(&mut io, &crawl).await.unwrap();
+        assert_eq!(result, LruCrawlerResult::Busy);
+
+        let mut io = mock(b"ERROR failed to start crawler thread\r\n");
+        let result = meta.lru_crawler(&mut io, &LruCrawlerCommand::Enable).await;
+        assert!(matches!(result, Err(MemcacheError::Protocol(_))));
+        let mut io = mock(b"CLIENT_ERROR bad command line format\r\n");
+        let result = meta
+            .lru_crawler(&mut io, &LruCrawlerCommand::Sleep(u32::MAX))
+            .await;
+        assert!(matches!(result, Err(MemcacheError::Protocol(_))));
+        let mut io = mock(b"DONE\r\n");
+        let result = meta.lru_crawler(&mut io, &LruCrawlerCommand::Disable).await;
+        assert!(matches!(result, Err(MemcacheError::BadServerResponse(x)) if x == "DONE"));
+    }
+
+    #[test]
+    fn raw_value_traits() {
+        let value = RawValue::from_vec(b"abc".to_vec()).set_flags(2);
+        assert_eq!(value.clone(), value);
+        assert_ne!(value, RawValue::default());
+        assert_eq!(
+            RawValue::<Vec<u8>>::default(),
+            RawValue::from_vec(Vec::new())
+        );
+        assert!(format!("{:?}", value).contains("flags: 2"));
+
+        let value = RawValue::from("héllo");
+        assert_eq!(value.as_str(), Ok("héllo"));
+        assert_eq!(value.flags, FLAG_TEXT);
+        assert_eq!(value.into_string().unwrap(), "héllo");
+        assert!(RawValue::from_vec(vec![0xff]).as_str().is_err());
+        assert!(RawValue::from_vec(vec![0xff]).into_string().is_err());
+    }
+
+    #[test]
+    fn ttl_conversions() {
+        assert_eq!(ttl_from_duration(Duration::from_secs(60)), 60);
+        assert_eq!(ttl_from_duration(Duration::from_millis(1500)), 2);
+        assert_eq!(ttl_from_duration(Duration::ZERO), 1);
+        assert_eq!(ttl_from_duration(Duration::MAX), u32::MAX);
+        assert_eq!(ttl_from_duration(Duration::from_secs(u64::MAX)), u32::MAX);
+        // 60 days are sent as a timestamp
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let ttl = ttl_from_duration(Duration::from_secs(60 * 86400)) as u64;
+        assert!((now + 60 * 86400..now + 60 * 86400 + 5).contains(&ttl));
+        assert_eq!(
+            ttl_from_duration(Duration::from_secs(30 * 86400)),
+            30 * 86400
+        );
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(ttl_from_system_time(t), 1_700_000_000);
+        let t = SystemTime::UNIX_EPOCH - Duration::from_secs(1);
+        assert!(ttl_from_system_time(t) > MAX_RELATIVE_TTL);
+
+        let data = RawValue::from_vec(vec![]).set_time(Some(5));
+        let opts = SetOptions::new().expires_in(Duration::from_secs(10));
+        assert_eq!(opts.ttl_of(&data), Some(10));
+        assert_eq!(SetOptions::new().ttl_of(&data), Some(5));
+    }
+
+    #[tokio::test]
+    async fn set_from_reader_checks_length() {
+        let meta = Meta::new();
+        let opts = SetOptions::new();
+
+        let mut io = mock(b"HD\r\n");
+        let data = RawValue::new(&b"12345"[..]);
+        let result = meta.set_from_reader(&mut io, "key", 4, data, &opts).await;
+        assert_eq!(result.unwrap(), StoreResult::Stored);
+
+        let mut io = mock(b"HD\r\n");
+        let data = RawValue::new(&b"123"[..]);
+        let result = meta.set_from_reader(&mut io, "key", 4, data, &opts).await;
+        assert!(matches!(result, Err(MemcacheError::IOError(_))));
+    }
+
+    #[tokio::test]
+    async fn read_timeout() {
+        let meta = Meta::with_timeouts(Some(Duration::from_millis(50)), None);
+        // the other end is kept open but never responds
+        let (client, _server) = tokio::io::duplex(1024);
+        let mut io = tokio::io::BufStream::new(client);
+        assert!(matches!(
+            meta.version(&mut io).await,
+            Err(MemcacheError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn get_deadline_overrides_timeout() {
+        let meta = Meta::with_timeouts(Some(Duration::from_secs(60)), None);
+        let (client, _server) = tokio::io::duplex(1024);
+        let mut io = tokio::io::BufStream::new(client);
+        let opts = GetOptions::new().deadline(Instant::now() + Duration::from_millis(50));
+        assert!(matches!(
+            meta.get_with(&mut io, "key", &opts).await,
+            Err(MemcacheError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn get_many_detects_desync() {
+        let meta = Meta::new();
+
+        let mut io = mock(b"VA 1 kkey2 f0 O1\r\nb\r\nMN\r\n");
+        let retval = meta.get_many(&mut io, &["key1", "key2"]).await;
+        let Ok(retval) = retval else {
+            panic!("Meta.get_many() failed");
+        };
+        assert_eq!(retval.len(), 1);
+        assert_eq!(retval[0].0, "key2");
+        assert_eq!(retval[0].1.data, b"b");
+
+        let mut io = mock(b"VA 1 kkey1 f0 O1\r\na\r\nMN\r\n");
+        assert!(
+            matches!(
+                meta.get_many(&mut io, &["key1", "key2"]).await,
+                Err(MemcacheError::BadServerResponse(_))
+            ),
+            "Meta.get_many() accepted a mismatched key"
+        );
+    }
+
+    #[tokio::test]
+    async fn get_many_stream_yields_values() {
+        use futures_util::StreamExt;
+
+        let meta = Meta::new();
+        let mut io = mock(b"VA 1 ka f0 O0\r\nx\r\nVA 1 kc f0 O2\r\nz\r\nMN\r\n");
+        let Ok(stream) = meta.get_many_stream(&mut io, &["a", "b", "c"]).await else {
+            panic!("Meta.get_many_stream() failed");
+        };
+        let values: Vec<_> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0].0, "a");
+        assert_eq!(values[1].0, "c");
+        assert_eq!(values[1].1.data, b"z");
+
+        // the stream ends with the first error
+        let mut io = mock(b"VA 1 ka f0 O0\r\nx\r\nXX\r\nMN\r\n");
+        let stream = meta.get_many_stream(&mut io, &["a"]).await.unwrap();
+        let values: Vec<_> = stream.collect().await;
+        assert_eq!(values.len(), 2);
+        assert!(matches!(
+            values[1],
+            Err(MemcacheError::BadServerResponse(_))
+        ));
+
+        let meta = meta.text_only(true);
+        let mut io = mock(b"VALUE b 0 1\r\ny\r\nEND\r\n");
+        let stream = meta.get_many_stream(&mut io, &["a", "b"]).await.unwrap();
+        let values: Vec<_> = stream.map(Result::unwrap).collect().await;
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].0, "b");
+    }
+
+    #[tokio::test]
+    async fn get_with_recache_flags() {
+        let meta = Meta::new();
+        let opts = GetOptions::new();
+
+        let mut io = mock(b"VA 1 kkey f5 c10 X W\r\na\r\n");
+        let Ok(Some(retval)) = meta.get_with(&mut io, "key", &opts).await else {
+            panic!("Meta.get_with() failed");
+        };
+        assert_eq!(retval.value.flags, 5);
+        assert_eq!(retval.value.cas, Some(10));
+        assert!(retval.stale && retval.won_recache && !retval.recache_pending);
+
+        let mut io = mock(b"VA 1 kkey f5 c10 X Z\r\na\r\n");
+        let Ok(Some(retval)) = meta.get_with(&mut io, "key", &opts).await else {
+            panic!("Meta.get_with() failed");
+        };
+        assert!(retval.stale && !retval.won_recache && retval.recache_pending);
     }
 }
@@ -0,0 +1,185 @@
+//! Rate limiting backed by memcached counters
+//!
+//! A [`RateLimiter`] allows `limit` requests per window of `window` seconds and key, e.g. per
+//! user or API token. Every [`RateLimiter::check`] increments the counter of the current
+//! window, `<key>:<window number>`, created on first use with [`Client::counter`] so that it
+//! expires with its window. Windows are aligned on the unix epoch, all the clients sharing the
+//! server count in the same windows.
+//!
+//! With [`Window::Fixed`] the quota is reset at the start of every window, a client may send up
+//! to twice the limit around the boundary. [`Window::Sliding`] also weights the count of the
+//! previous window by the part of it still in the last `window` seconds, which smoothes this
+//! out at the cost of an extra read. Requires the meta protocol.
+//! ```no_run
+//! # async fn example(client: &mut yamemcache::Client<yamemcache::BoxedConnection>)
+//! # -> Result<(), yamemcache::error::MemcacheError> {
+//! use yamemcache::ratelimit::{RateLimiter, Window};
+//!
+//! let limiter = RateLimiter::new(100, 60).window(Window::Sliding);
+//! let status = limiter.check(client, "api:user42").await?;
+//! if !status.allowed {
+//!     println!("retry in {:?}", status.reset);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::{Duration, SystemTime};
+
+use crate::error::MemcacheError;
+use crate::protocol::ttl_from_duration;
+use crate::{AsyncReadWriteUnpin, Client};
+
+use log::debug;
+
+/// How the requests of the previous window are counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Window {
+    /// Only the requests of the current window count
+    #[default]
+    Fixed,
+    /// The requests of the previous window count proportionally to its overlap with the last
+    /// `window` seconds
+    Sliding,
+}
+
+/// Outcome of [`RateLimiter::check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The request is within the limit
+    pub allowed: bool,
+    /// Requests left in the current window
+    pub remaining: u64,
+    /// Time until the current window ends
+    pub reset: Duration,
+}
+
+/// Limit of `limit` requests per `window` seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimiter {
+    limit: u64,
+    window: u32,
+    kind: Window,
+}
+
+impl RateLimiter {
+    /// Fixed windows of `window` seconds, raised to 1 if 0
+    pub fn new(limit: u64, window: u32) -> Self {
+        RateLimiter {
+            limit,
+            window: window.max(1),
+            kind: Window::Fixed,
+        }
+    }
+
+    pub fn window(mut self, w: Window) -> Self {
+        self.kind = w;
+        self
+    }
+
+    /// Count a request for `key` and check it against the limit
+    pub async fn check<T: AsyncReadWriteUnpin>(
+        &self,
+        client: &mut Client<T>,
+        key: &str,
+    ) -> Result<RateLimit, MemcacheError> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.check_at(client, key, now).await
+    }
+
+    /// [`RateLimiter::check`] at `now`, since the unix epoch
+    async fn check_at<T: AsyncReadWriteUnpin>(
+        &self,
+        client: &mut Client<T>,
+        key: &str,
+        now: Duration,
+    ) -> Result<RateLimit, MemcacheError> {
+        let window = u64::from(self.window);
+        let index = now.as_secs() / window;
+        let end = Duration::from_secs((index + 1) * window);
+        // the previous window has to be kept for the sliding count
+        let ttl = match self.kind {
+            Window::Fixed => window,
+            Window::Sliding => window * 2,
+        };
+        // memcached reads TTLs over 30 days as timestamps
+        let ttl = ttl_from_duration(Duration::from_secs(ttl));
+        let current = client
+            .counter(&format!("{}:{}", key, index))
+            .ttl(ttl)
+            .incr()
+            .await?;
+        let count = match self.kind {
+            Window::Fixed => current,
+            Window::Sliding => {
+                let previous = client
+                    .get::<u64>(format!("{}:{}", key, index.wrapping_sub(1)))
+                    .await?
+                    .unwrap_or(0);
+                let overlap = (end - now).as_secs_f64() / window as f64;
+                current + (previous as f64 * overlap) as u64
+            }
+        };
+        debug!("ratelimit: {} at {} of {}", key, count, self.limit);
+        Ok(RateLimit {
+            allowed: count <= self.limit,
+            remaining: self.limit.saturating_sub(count),
+            reset: end - now,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn long_window() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        server.write_all(b"VA 1\r\n1\r\n").await.unwrap();
+        let mut c = Client::new(tokio::io::BufStream::new(client));
+        // a month, longer than the 30 days memcached accepts as a relative TTL
+        let limiter = RateLimiter::new(10, 31 * 86400);
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        limiter.check_at(&mut c, "key", now).await.unwrap();
+        drop(c);
+
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await.unwrap();
+        let ttl: u64 = sent
+            .split_ascii_whitespace()
+            .find_map(|x| x.strip_prefix('N'))
+            .unwrap()
+            .parse()
+            .unwrap();
+        // sent as the unix timestamp of the expiration
+        assert!(ttl >= now.as_secs() + 31 * 86400, "{}", sent);
+    }
+
+    #[tokio::test]
+    async fn sliding_window() {
+        // 3 requests in this window, 8 in the previous one
+        let response = b"VA 1\r\n3\r\nVA 1 kkey:9 f2 c1\r\n8\r\n";
+        let mut c = Client::new(tokio::io::join(
+            std::io::Cursor::new(response.to_vec()),
+            tokio::io::sink(),
+        ));
+        let limiter = RateLimiter::new(10, 60).window(Window::Sliding);
+        // a quarter into the window, 3/4 of the previous one still counts
+        let now = Duration::from_secs(10 * 60 + 15);
+        let status = limiter.check_at(&mut c, "key", now).await.unwrap();
+        assert_eq!(
+            status,
+            RateLimit {
+                allowed: true,
+                remaining: 1,
+                reset: Duration::from_secs(45),
+            }
+        );
+    }
+}
@@ -0,0 +1,1052 @@
+//! Client for a cluster of memcached servers
+//!
+//! [`ShardedClient`] spreads the keys over several servers, see [`Distribution`] for the
+//! available strategies. The default is ketama consistent hashing, the scheme of libmemcached
+//! (`MEMCACHED_BEHAVIOR_KETAMA`): every server owns 160 points (see
+//! [`ShardedOptions::points_per_server`]) on a 32-bit ring and a key
+//! belongs to the first point following its hash. Adding or removing a server only moves the
+//! keys it owns (or takes over).
+//!
+//! Servers can be given a weight (see [`ShardedClient::with_weights`]) to receive a share of
+//! the keys proportional to it, e.g. their memory size.
+//!
+//! With [`ShardedOptions::replicas`] every key is stored on several servers, the ones following
+//! its own in the placement order. Writes are sent to all of them, reads fall back to the next
+//! replica when a server is unreachable, so that a single failing server does not lose the keys.
+//! Without replication, [`FailoverPolicy::NextServer`] still lets reads move on to the next
+//! servers, which only find the keys written there while the owner was down.
+//!
+//! [`ShardedClient::discover`] builds the cluster from the addresses a DNS name resolves to, e.g.
+//! a Kubernetes headless service, and resolves it again every
+//! [`ShardedOptions::discovery_interval`] to follow servers being added or removed.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use tokio::io::AsyncWriteExt;
+
+use crate::connect::with_port;
+use crate::error::{ErrorKind, MemcacheError};
+use crate::hash::{KeyHasher, Md5Hasher};
+use crate::protocol::{
+    ArithmeticOptions, ArithmeticResult, DeleteOptions, DeleteResult, GetOptions, GetResponse,
+    RawValue, SetOptions, StoreResult,
+};
+use crate::stats::ServerStats;
+use crate::{BoxedConnection, Client, ClientOptions};
+
+use log::{debug, error};
+
+/// Default points of every server on the ring, when all the servers have the same weight
+pub const DEFAULT_POINTS_PER_SERVER: usize = 160;
+/// Points derived from a single MD5 digest
+const POINTS_PER_HASH: usize = 4;
+
+/// Name of a server on the ring, libmemcached leaves out the default port
+fn ring_name(addr: &str) -> &str {
+    addr.strip_suffix(":11211").unwrap_or(addr)
+}
+
+/// Reads the `x`-th little endian 32-bit word of an MD5 digest
+fn ketama_point(digest: &[u8; 16], x: usize) -> u32 {
+    u32::from_le_bytes([
+        digest[x * 4],
+        digest[x * 4 + 1],
+        digest[x * 4 + 2],
+        digest[x * 4 + 3],
+    ])
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a continuing from `state`
+fn fnv1a(mut state: u64, data: &[u8]) -> u64 {
+    for b in data {
+        state ^= u64::from(*b);
+        state = state.wrapping_mul(FNV_PRIME);
+    }
+    state
+}
+
+/// Finalizer of splitmix64, spreads the bits of the seed and key hash over the whole score
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Greatest common divisor
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Weighted rendezvous score, `-weight / ln(x)` with `x` uniformly distributed in (0, 1)
+/// derived from the server seed and key hash
+fn rendezvous_score(seed: u64, weight: f64, hash: u32) -> f64 {
+    let x = ((mix(seed ^ u64::from(hash)) >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    -weight / x.ln()
+}
+
+/// Strategy mapping keys to servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Distribution {
+    /// Ketama consistent hashing, places keys like libmemcached
+    #[default]
+    Ketama,
+    /// Rendezvous (highest random weight) hashing: every server is scored for each key and
+    /// the highest score wins. The keys of a removed server are spread evenly over the
+    /// remaining ones, even in small clusters where a ring is rarely balanced. Costs one hash
+    /// per server for every key.
+    Rendezvous,
+    /// Key hash modulo the number of servers. Moves most keys when the servers change, only
+    /// meant for sharing a cluster with clients using it (e.g. python-memcached). Weights
+    /// sharing a common divisor are reduced by it, unlike python-memcached.
+    Modula,
+}
+
+/// What reads do when the server owning the key is unreachable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FailoverPolicy {
+    /// Try the replicas of the key (see [`ShardedOptions::replicas`]), then fail
+    #[default]
+    Fail,
+    /// Keep trying the following servers in placement order until one is reachable. Writes are
+    /// not re-routed, a missing server turns into cache misses rather than errors.
+    NextServer,
+}
+
+/// Options of a [`ShardedClient`]
+#[derive(Debug, Clone)]
+pub struct ShardedOptions {
+    /// Options applied to the connection of every server
+    pub client_options: ClientOptions,
+    /// How keys are mapped to servers
+    pub distribution: Distribution,
+    /// Points of every server on the ketama ring (scaled by the weights), rounded down to a
+    /// multiple of 4. More points balance the keys better but take longer to build the ring.
+    pub points_per_server: usize,
+    /// Hash of the keys, [`Md5Hasher`] by default
+    pub hasher: Arc<dyn KeyHasher>,
+    /// Number of servers every key is stored on, 1 (no replication) by default
+    pub replicas: usize,
+    /// What reads do when a server is unreachable
+    pub failover: FailoverPolicy,
+    /// How often the DNS name of a discovered cluster is resolved again, 30 seconds by default
+    pub discovery_interval: Duration,
+}
+
+impl Default for ShardedOptions {
+    fn default() -> Self {
+        ShardedOptions {
+            client_options: ClientOptions::default(),
+            distribution: Distribution::default(),
+            points_per_server: DEFAULT_POINTS_PER_SERVER,
+            hasher: Arc::new(Md5Hasher),
+            replicas: 1,
+            failover: FailoverPolicy::default(),
+            discovery_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ShardedOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn client_options(mut self, x: ClientOptions) -> Self {
+        self.client_options = x;
+        self
+    }
+
+    pub fn distribution(mut self, x: Distribution) -> Self {
+        self.distribution = x;
+        self
+    }
+
+    pub fn points_per_server(mut self, x: usize) -> Self {
+        self.points_per_server = x;
+        self
+    }
+
+    pub fn hasher<H: KeyHasher + 'static>(mut self, x: H) -> Self {
+        self.hasher = Arc::new(x);
+        self
+    }
+
+    pub fn replicas(mut self, x: usize) -> Self {
+        self.replicas = x;
+        self
+    }
+
+    pub fn failover(mut self, x: FailoverPolicy) -> Self {
+        self.failover = x;
+        self
+    }
+
+    pub fn discovery_interval(mut self, x: Duration) -> Self {
+        self.discovery_interval = x;
+        self
+    }
+}
+
+#[derive(Debug)]
+enum Placement {
+    /// Ketama continuum, sorted points each owned by the server at the given index
+    Ring(Vec<(u32, usize)>),
+    /// Seed of the score and weight of every server
+    Rendezvous(Vec<(u64, f64)>),
+    /// Cumulative weights: server `i` owns the buckets from the end of server `i - 1` up to
+    /// its own end
+    Modula(Vec<u64>),
+}
+
+/// Maps keys to the index of their server
+#[derive(Debug)]
+struct Router {
+    hasher: Arc<dyn KeyHasher>,
+    placement: Placement,
+}
+
+impl Router {
+    /// `servers` are the names and weights of the servers
+    fn new(options: &ShardedOptions, servers: &[(&str, u32)]) -> Self {
+        let total: u64 = servers.iter().map(|(_, weight)| u64::from(*weight)).sum();
+        let placement = match options.distribution {
+            Distribution::Ketama => {
+                let per_server = options.points_per_server.max(POINTS_PER_HASH);
+                let mut points = Vec::with_capacity(servers.len() * per_server);
+                for (idx, (name, weight)) in servers.iter().enumerate() {
+                    // same rounding as libmemcached, so that weighted rings match
+                    let share = *weight as f32 / total as f32;
+                    let hashes =
+                        (share * (per_server / POINTS_PER_HASH) as f32 * servers.len() as f32
+                            + 0.000_000_000_1)
+                            .floor() as usize;
+                    for i in 0..hashes {
+                        let digest = md5::compute(format!("{}-{}", name, i));
+                        for x in 0..POINTS_PER_HASH {
+                            points.push((ketama_point(&digest, x), idx));
+                        }
+                    }
+                }
+                points.sort_unstable();
+                Placement::Ring(points)
+            }
+            Distribution::Rendezvous => Placement::Rendezvous(
+                servers
+                    .iter()
+                    .map(|(name, weight)| (fnv1a(FNV_OFFSET, name.as_bytes()), f64::from(*weight)))
+                    .collect(),
+            ),
+            // every server gets as many buckets as its weight, like python-memcached. The
+            // weights are reduced first, the keys would all go to the first server if the
+            // buckets outnumbered the hashes.
+            Distribution::Modula => {
+                let divisor = servers
+                    .iter()
+                    .fold(0, |x, (_, weight)| gcd(x, *weight))
+                    .max(1);
+                Placement::Modula(
+                    servers
+                        .iter()
+                        .scan(0, |end, (_, weight)| {
+                            *end += u64::from(*weight / divisor);
+                            Some(*end)
+                        })
+                        .collect(),
+                )
+            }
+        };
+        Router {
+            hasher: options.hasher.clone(),
+            placement,
+        }
+    }
+
+    /// Index of the server owning `key`
+    fn node(&self, key: &str) -> usize {
+        self.nodes(key, 1)[0]
+    }
+
+    /// Indexes of up to `count` distinct servers for `key`, the owner first followed by the
+    /// servers taking over its keys if it was removed
+    fn nodes(&self, key: &str, count: usize) -> Vec<usize> {
+        let hash = self.hasher.hash(key.as_bytes());
+        let mut retval = Vec::with_capacity(count);
+        match &self.placement {
+            Placement::Ring(points) => {
+                // past the last point wraps around to the first one
+                let start = points.partition_point(|(point, _)| *point < hash);
+                for (_, idx) in points[start..].iter().chain(&points[..start]) {
+                    if retval.len() == count {
+                        break;
+                    }
+                    if !retval.contains(idx) {
+                        retval.push(*idx);
+                    }
+                }
+            }
+            Placement::Rendezvous(seeds) => {
+                let mut scores: Vec<(usize, f64)> = seeds
+                    .iter()
+                    .map(|(seed, weight)| rendezvous_score(*seed, *weight, hash))
+                    .enumerate()
+                    .collect();
+                scores.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+                retval.extend(scores.iter().take(count).map(|(idx, _)| *idx));
+            }
+            Placement::Modula(ends) => {
+                let Some(total) = ends.last().copied().filter(|x| *x > 0) else {
+                    return retval;
+                };
+                let bucket = u64::from(hash) % total;
+                let owner = ends.partition_point(|end| *end <= bucket);
+                // the next buckets belong to the following servers, without a weight of 0
+                for idx in (owner..ends.len()).chain(0..owner) {
+                    if retval.len() == count {
+                        break;
+                    }
+                    let start = if idx == 0 { 0 } else { ends[idx - 1] };
+                    if ends[idx] > start {
+                        retval.push(idx);
+                    }
+                }
+            }
+        }
+        retval
+    }
+}
+
+/// Whether the server could not be reached, the command may be retried on a replica
+fn is_unreachable(e: &MemcacheError) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::Connection | ErrorKind::Timeout | ErrorKind::Unavailable
+    )
+}
+
+/// Sends a read to the servers of `$key` in turn, moving on only if a server is unreachable
+macro_rules! failover {
+    ($self:ident, $key:expr, |$client:ident| $call:expr) => {{
+        let mut failure = None;
+        for idx in $self.router.nodes($key, $self.read_fanout()) {
+            debug!("sharded: {} -> {}", $key, $self.nodes[idx].addr);
+            let $client = &mut $self.nodes[idx].client;
+            match $call.await {
+                Err(e) if is_unreachable(&e) => {
+                    error!("sharded: {} unreachable: {:?}", $self.nodes[idx].addr, e);
+                    failure = Some(e);
+                }
+                x => return x,
+            }
+        }
+        Err(failure.unwrap_or(MemcacheError::NotConnected))
+    }};
+}
+
+/// Sends a write to all the replicas of `$key` concurrently. Returns the result of the first
+/// replica (in placement order) that succeeded, or the first error if all of them failed.
+macro_rules! replicated {
+    ($self:ident, $key:expr, |$client:ident| $call:expr) => {{
+        let targets = $self.router.nodes($key, $self.replicas());
+        let requests = $self
+            .nodes
+            .iter_mut()
+            .enumerate()
+            .filter(|(idx, _)| targets.contains(idx))
+            .map(|(idx, node)| {
+                let $client = &mut node.client;
+                let request = $call;
+                async move { (idx, request.await) }
+            });
+        let mut results = join_all(requests).await;
+        results.sort_by_key(|(idx, _)| targets.iter().position(|x| x == idx));
+        let mut retval = None;
+        let mut failure = None;
+        for (idx, result) in results {
+            match result {
+                Ok(x) => {
+                    retval.get_or_insert(x);
+                }
+                Err(e) => {
+                    error!(
+                        "sharded: {} failed on {}: {:?}",
+                        $key, $self.nodes[idx].addr, e
+                    );
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        match (retval, failure) {
+            (Some(x), _) => Ok(x),
+            (None, Some(e)) => Err(e),
+            (None, None) => unreachable!("no replica for {}", $key),
+        }
+    }};
+}
+
+struct Node {
+    addr: String,
+    weight: u32,
+    client: Client<BoxedConnection>,
+}
+
+/// DNS name a cluster was discovered from
+#[derive(Debug)]
+struct Discovery {
+    name: String,
+    next: Instant,
+}
+
+/// Resolves `name` to the sorted addresses of the servers
+async fn resolve(name: &str) -> Result<Vec<String>, MemcacheError> {
+    let mut retval: Vec<String> = tokio::net::lookup_host(name)
+        .await
+        .map_err(MemcacheError::IOError)?
+        .map(|x| x.to_string())
+        .collect();
+    retval.sort_unstable();
+    retval.dedup();
+    if retval.is_empty() {
+        error!("sharded: {} did not resolve to any address", name);
+        return Err(MemcacheError::IOError(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no servers",
+        )));
+    }
+    Ok(retval)
+}
+
+/// Statistics of a whole cluster, see [`ShardedClient::cluster_stats`]
+#[derive(Debug)]
+pub struct ClusterStats {
+    /// Statistics of every server by address, or the error it failed with
+    pub servers: Vec<(String, Result<ServerStats, MemcacheError>)>,
+    /// Counters summed over the servers that answered. `pid`, `version` and `other` are left
+    /// empty, `uptime` is the one of the most recently started server.
+    pub total: ServerStats,
+}
+
+impl ClusterStats {
+    fn new(servers: Vec<(String, Result<ServerStats, MemcacheError>)>) -> Self {
+        let mut total = ServerStats::default();
+        let mut uptime = None;
+        for x in servers.iter().filter_map(|(_, x)| x.as_ref().ok()) {
+            uptime = Some(uptime.map_or(x.uptime, |u: u64| u.min(x.uptime)));
+            total.curr_connections += x.curr_connections;
+            total.total_connections += x.total_connections;
+            total.curr_items += x.curr_items;
+            total.total_items += x.total_items;
+            total.bytes += x.bytes;
+            total.limit_maxbytes += x.limit_maxbytes;
+            total.cmd_get += x.cmd_get;
+            total.cmd_set += x.cmd_set;
+            total.get_hits += x.get_hits;
+            total.get_misses += x.get_misses;
+            total.evictions += x.evictions;
+            total.bytes_read += x.bytes_read;
+            total.bytes_written += x.bytes_written;
+        }
+        total.uptime = uptime.unwrap_or(0);
+        ClusterStats { servers, total }
+    }
+}
+
+/// Closes the connections to removed servers
+async fn drain(removed: Vec<Node>) {
+    let requests = removed.into_iter().map(|mut node| async move {
+        debug!("sharded: closing {}", node.addr);
+        if let Err(e) = node.client.connection.shutdown().await {
+            debug!("sharded: closing {} failed: {}", node.addr, e);
+        }
+    });
+    join_all(requests).await;
+}
+
+/// Client distributing the keys over several servers
+pub struct ShardedClient {
+    nodes: Vec<Node>,
+    router: Router,
+    options: ShardedOptions,
+    discovery: Option<Discovery>,
+}
+
+impl std::fmt::Debug for ShardedClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let servers: Vec<&str> = self.nodes.iter().map(|x| x.addr.as_str()).collect();
+        f.debug_struct("ShardedClient")
+            .field("servers", &servers)
+            .field("replicas", &self.replicas())
+            .field("failover", &self.options.failover)
+            .field("discovery", &self.discovery)
+            .finish()
+    }
+}
+
+impl ShardedClient {
+    /// Create a client for the given servers, either "host:port" addresses or connection URLs
+    /// (see [`Client::connect`]). The connection to a server is established by the first
+    /// command sent to it, only invalid addresses are reported right away.
+    pub fn new<S: AsRef<str>>(addrs: Vec<S>) -> Result<Self, MemcacheError> {
+        Self::with_options(addrs, &ShardedOptions::default())
+    }
+
+    /// Like [`ShardedClient::new`], with additional options
+    pub fn with_options<S: AsRef<str>>(
+        addrs: Vec<S>,
+        options: &ShardedOptions,
+    ) -> Result<Self, MemcacheError> {
+        let servers = addrs.into_iter().map(|x| (x, 1)).collect();
+        Self::with_weights(servers, options)
+    }
+
+    /// Like [`ShardedClient::with_options`], giving every server a share of the keys
+    /// proportional to its weight. Weights have to be at least 1.
+    pub fn with_weights<S: AsRef<str>>(
+        servers: Vec<(S, u32)>,
+        options: &ShardedOptions,
+    ) -> Result<Self, MemcacheError> {
+        let mut retval = ShardedClient {
+            nodes: Vec::new(),
+            router: Router::new(options, &[]),
+            options: options.clone(),
+            discovery: None,
+        };
+        retval.set_servers(servers)?;
+        Ok(retval)
+    }
+
+    /// Create a client for the servers `name` ("host" or "host:port") resolves to. The name is
+    /// resolved again before a command once [`ShardedOptions::discovery_interval`] elapsed.
+    pub async fn discover(name: &str, options: &ShardedOptions) -> Result<Self, MemcacheError> {
+        let name = with_port(name);
+        let servers = resolve(&name).await?;
+        let mut retval = Self::with_options(servers, options)?;
+        retval.discovery = Some(Discovery {
+            name,
+            next: Instant::now() + options.discovery_interval,
+        });
+        Ok(retval)
+    }
+
+    /// Resolve the DNS name of a discovered cluster again right away and update the servers.
+    /// Connections to the servers still present are kept.
+    pub async fn rediscover(&mut self) -> Result<(), MemcacheError> {
+        let Some(discovery) = &mut self.discovery else {
+            return Ok(());
+        };
+        discovery.next = Instant::now() + self.options.discovery_interval;
+        let servers = resolve(&discovery.name).await?;
+        let current: Vec<&str> = self.nodes.iter().map(|x| x.addr.as_str()).collect();
+        if current != servers {
+            debug!("sharded: servers changed to {:?}", servers);
+            let removed = self.set_servers(servers.into_iter().map(|x| (x, 1)).collect())?;
+            drain(removed).await;
+        }
+        Ok(())
+    }
+
+    /// Addresses of the servers
+    pub fn servers(&self) -> impl Iterator<Item = &str> {
+        self.nodes.iter().map(|x| x.addr.as_str())
+    }
+
+    /// Add a server with a weight of 1. The ring is rebuilt, the new server takes over its
+    /// share of the keys right away. On a discovered cluster the change only lasts until the
+    /// next rediscovery.
+    pub fn add_node(&mut self, addr: &str) -> Result<(), MemcacheError> {
+        let mut servers: Vec<(String, u32)> = self
+            .nodes
+            .iter()
+            .map(|x| (x.addr.clone(), x.weight))
+            .collect();
+        servers.push((addr.to_string(), 1));
+        self.set_servers(servers)?;
+        Ok(())
+    }
+
+    /// Remove a server, its keys move to the other servers. The connection to it is closed
+    /// once the ring no longer uses it. Returns false if the server was not part of the
+    /// cluster, fails when removing the last one.
+    pub async fn remove_node(&mut self, addr: &str) -> Result<bool, MemcacheError> {
+        if !self.nodes.iter().any(|x| x.addr == addr) {
+            return Ok(false);
+        }
+        let servers: Vec<(String, u32)> = self
+            .nodes
+            .iter()
+            .filter(|x| x.addr != addr)
+            .map(|x| (x.addr.clone(), x.weight))
+            .collect();
+        let removed = self.set_servers(servers)?;
+        drain(removed).await;
+        Ok(true)
+    }
+
+    /// Rediscovers the servers if due, keeping the current ones if DNS fails
+    async fn refresh(&mut self) {
+        match &self.discovery {
+            Some(x) if Instant::now() >= x.next => {}
+            _ => return,
+        }
+        if let Err(e) = self.rediscover().await {
+            error!("sharded: rediscovery failed: {:?}", e);
+        }
+    }
+
+    /// Replaces the servers, reusing the clients of the ones already known. Returns the servers
+    /// no longer used.
+    fn set_servers<S: AsRef<str>>(
+        &mut self,
+        servers: Vec<(S, u32)>,
+    ) -> Result<Vec<Node>, MemcacheError> {
+        if servers.is_empty() {
+            error!("sharded: no servers");
+            return Err(MemcacheError::IOError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no servers",
+            )));
+        }
+        if let Some((addr, _)) = servers.iter().find(|(_, weight)| *weight == 0) {
+            error!("sharded: zero weight for {}", addr.as_ref());
+            return Err(MemcacheError::IOError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "zero server weight",
+            )));
+        }
+        for (idx, (addr, _)) in servers.iter().enumerate() {
+            if servers[..idx]
+                .iter()
+                .any(|(x, _)| x.as_ref() == addr.as_ref())
+            {
+                error!("sharded: {} listed twice", addr.as_ref());
+                return Err(MemcacheError::IOError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "duplicate server",
+                )));
+            }
+        }
+        // open the new servers first, nothing changes if an address is invalid
+        let mut clients = Vec::with_capacity(servers.len());
+        for (addr, _) in servers.iter() {
+            let addr = addr.as_ref();
+            let client = if self.nodes.iter().any(|x| x.addr == addr) {
+                None
+            } else if addr.contains("://") {
+                Some(Client::connect_lazy(addr, &self.options.client_options)?)
+            } else {
+                let url = format!("memcache://{}", addr);
+                Some(Client::connect_lazy(&url, &self.options.client_options)?)
+            };
+            clients.push(client);
+        }
+        let mut nodes = Vec::with_capacity(servers.len());
+        for ((addr, weight), client) in servers.iter().zip(clients) {
+            let addr = addr.as_ref();
+            let client = match client {
+                Some(x) => x,
+                None => {
+                    let idx = self.nodes.iter().position(|x| x.addr == addr);
+                    self.nodes.swap_remove(idx.expect("known server")).client
+                }
+            };
+            nodes.push(Node {
+                addr: addr.to_string(),
+                weight: *weight,
+                client,
+            });
+        }
+        let names: Vec<(&str, u32)> = nodes
+            .iter()
+            .map(|x| (ring_name(&x.addr), x.weight))
+            .collect();
+        self.router = Router::new(&self.options, &names);
+        Ok(std::mem::replace(&mut self.nodes, nodes))
+    }
+
+    /// Replace the connections to all the servers before their next command, e.g. after a
+    /// command was abandoned halfway
+    pub(crate) fn reset_connections(&mut self) {
+        for node in self.nodes.iter_mut() {
+            node.client.broken = true;
+        }
+    }
+
+    /// Cluster of in-memory servers replaying `responses`, returns their end of the
+    /// connections
+    #[cfg(test)]
+    pub(crate) async fn mock(
+        responses: &[&[u8]],
+        options: &ShardedOptions,
+    ) -> (Self, Vec<tokio::io::DuplexStream>) {
+        let addrs: Vec<String> = (0..responses.len())
+            .map(|x| format!("10.0.0.{}", x + 1))
+            .collect();
+        let mut retval = Self::with_options(addrs, options).unwrap();
+        let mut servers = Vec::new();
+        for (node, response) in retval.nodes.iter_mut().zip(responses) {
+            let (client, mut server) = tokio::io::duplex(64 * 1024);
+            server.write_all(response).await.unwrap();
+            node.client = Client::new(Box::new(tokio::io::BufStream::new(client)));
+            servers.push(server);
+        }
+        (retval, servers)
+    }
+
+    /// Number of servers every key is written to
+    fn replicas(&self) -> usize {
+        self.options.replicas.clamp(1, self.nodes.len().max(1))
+    }
+
+    /// Address of the server `key` is stored on
+    pub fn server_for(&self, key: &str) -> &str {
+        &self.nodes[self.router.node(key)].addr
+    }
+
+    /// Number of servers a read may be sent to
+    fn read_fanout(&self) -> usize {
+        match self.options.failover {
+            FailoverPolicy::Fail => self.replicas(),
+            FailoverPolicy::NextServer => self.nodes.len(),
+        }
+    }
+
+    /// Read the statistics of all the servers concurrently, see [`Client::stats`]. Servers
+    /// failing to answer are reported in [`ClusterStats::servers`] and left out of the total.
+    pub async fn cluster_stats(&mut self) -> ClusterStats {
+        self.refresh().await;
+        let requests = self
+            .nodes
+            .iter_mut()
+            .map(|node| async move { (node.addr.clone(), node.client.stats().await) });
+        ClusterStats::new(join_all(requests).await)
+    }
+
+    /// GET a value, see [`Client::get`]. Tries the replicas in turn while the servers are
+    /// unreachable, see [`FailoverPolicy`].
+    pub async fn get(&mut self, key: &str) -> Result<Option<RawValue>, MemcacheError> {
+        self.refresh().await;
+        failover!(self, key, |client| client.get(key))
+    }
+
+    /// GET a value with additional information, see [`Client::get_with`]
+    pub async fn get_with(
+        &mut self,
+        key: &str,
+        opts: &GetOptions,
+    ) -> Result<Option<GetResponse>, MemcacheError> {
+        self.refresh().await;
+        failover!(self, key, |client| client.get_with(key, opts))
+    }
+
+    /// GET any number of values. The keys are grouped by server and a single `get_many` is sent
+    /// to each of them concurrently. Fails if any of the servers fails, unless its keys can be
+    /// read from a replica or the next server.
+    pub async fn get_many(
+        &mut self,
+        key_list: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Vec<(String, RawValue)>, MemcacheError> {
+        self.refresh().await;
+        let keys: Vec<_> = key_list.into_iter().collect();
+        let mut retval = Vec::with_capacity(keys.len());
+        let mut pending: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+        let fanout = self.read_fanout();
+        for rank in 0..fanout {
+            let mut groups: Vec<Vec<&str>> = vec![Vec::new(); self.nodes.len()];
+            for key in pending.drain(..) {
+                let targets = self.router.nodes(key, rank + 1);
+                // fewer servers than replicas for this key, it was already tried everywhere
+                if let Some(idx) = targets.get(rank) {
+                    groups[*idx].push(key);
+                }
+            }
+            let requests = self
+                .nodes
+                .iter_mut()
+                .zip(groups.iter())
+                .filter(|(_, keys)| !keys.is_empty())
+                .map(|(node, keys)| async move { (keys, node.client.get_many(keys).await) });
+            for (keys, x) in join_all(requests).await {
+                match x {
+                    Ok(x) => retval.extend(x),
+                    Err(e) if is_unreachable(&e) && rank + 1 < fanout => {
+                        error!("sharded: get_many failed, trying the next servers: {:?}", e);
+                        pending.extend(keys);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if pending.is_empty() {
+                break;
+            }
+        }
+        Ok(retval)
+    }
+
+    /// STORE a value on all the replicas, see [`Client::set`]
+    pub async fn set(&mut self, key: &str, data: &RawValue) -> Result<(), MemcacheError> {
+        self.refresh().await;
+        replicated!(self, key, |client| client.set(key, data))
+    }
+
+    /// STORE a value with additional options, see [`Client::set_with`]. A conditional store
+    /// (`data.cas` set) is only sent to the first replica, see [`ShardedClient::cas`].
+    pub async fn set_with(
+        &mut self,
+        key: &str,
+        data: &RawValue,
+        opts: &SetOptions,
+    ) -> Result<StoreResult, MemcacheError> {
+        self.refresh().await;
+        if data.cas.is_some() && self.replicas() > 1 {
+            let idx = self.router.node(key);
+            let retval = self.nodes[idx].client.set_with(key, data, opts).await?;
+            return self.copy_to_replicas(key, data, opts, retval).await;
+        }
+        replicated!(self, key, |client| client.set_with(key, data, opts))
+    }
+
+    /// Compare-And-Store, see [`Client::cas`]. CAS values differ between servers, the
+    /// condition is checked on the first replica only and the value is then copied to the
+    /// others.
+    pub async fn cas(&mut self, key: &str, data: &RawValue) -> Result<StoreResult, MemcacheError> {
+        self.refresh().await;
+        let idx = self.router.node(key);
+        let retval = self.nodes[idx].client.cas(key, data).await?;
+        self.copy_to_replicas(key, data, &SetOptions::default(), retval)
+            .await
+    }
+
+    /// Stores a value the first replica accepted on the other ones, with the same options
+    /// but without the CAS condition
+    async fn copy_to_replicas(
+        &mut self,
+        key: &str,
+        data: &RawValue,
+        opts: &SetOptions,
+        result: StoreResult,
+    ) -> Result<StoreResult, MemcacheError> {
+        if result != StoreResult::Stored || self.replicas() == 1 {
+            return Ok(result);
+        }
+        let data = &RawValue {
+            cas: None,
+            ..data.clone()
+        };
+        let targets = self.router.nodes(key, self.replicas());
+        let requests =
+            self.nodes
+                .iter_mut()
+                .enumerate()
+                .filter(|(idx, _)| targets[1..].contains(idx))
+                .map(|(_, node)| async move {
+                    (node.client.set_with(key, data, opts).await, &node.addr)
+                });
+        for (x, addr) in join_all(requests).await {
+            match x {
+                Ok(StoreResult::Stored) => {}
+                Ok(x) => error!("sharded: {} not copied to {}: {:?}", key, addr, x),
+                Err(e) => error!("sharded: {} not copied to {}: {:?}", key, addr, e),
+            }
+        }
+        Ok(result)
+    }
+
+    /// DELETE a value from all the replicas, see [`Client::delete`]
+    pub async fn delete(&mut self, key: &str) -> Result<Option<()>, MemcacheError> {
+        self.refresh().await;
+        replicated!(self, key, |client| client.delete(key))
+    }
+
+    /// DELETE a value using the meta delete command, see [`Client::delete_with`]
+    pub async fn delete_with(
+        &mut self,
+        key: &str,
+        opts: &DeleteOptions,
+    ) -> Result<DeleteResult, MemcacheError> {
+        self.refresh().await;
+        replicated!(self, key, |client| client.delete_with(key, opts))
+    }
+
+    /// Increment or decrement a counter on all the replicas, see [`Client::arithmetic`]
+    pub async fn arithmetic(
+        &mut self,
+        key: &str,
+        opts: &ArithmeticOptions,
+    ) -> Result<ArithmeticResult, MemcacheError> {
+        self.refresh().await;
+        replicated!(self, key, |client| client.arithmetic(key, opts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modula_matches_python_memcached() {
+        use crate::hash::Crc32Hasher;
+
+        let names = [("a", 1), ("b", 1), ("c", 1)];
+        let router = Router::new(
+            &ShardedOptions::new()
+                .distribution(Distribution::Modula)
+                .hasher(Crc32Hasher),
+            &names,
+        );
+        // crc32("foo") >> 16 & 0x7fff == 3187
+        assert_eq!(router.node("foo"), 3187 % 3);
+    }
+
+    #[test]
+    fn distributions_move_few_keys() {
+        for distribution in [Distribution::Ketama, Distribution::Rendezvous] {
+            let names = [("10.0.0.1", 1), ("10.0.0.2", 1), ("10.0.0.3", 1)];
+            let router = Router::new(&ShardedOptions::new().distribution(distribution), &names);
+            let keys: Vec<String> = (0..1000).map(|x| format!("key{}", x)).collect();
+            let before: Vec<usize> = keys.iter().map(|x| router.node(x)).collect();
+            for idx in 0..3 {
+                let share = before.iter().filter(|x| **x == idx).count();
+                assert!(share > 150, "server {} only got {} keys", idx, share);
+            }
+
+            // the keys of the remaining servers stay where they were
+            let router = Router::new(
+                &ShardedOptions::new().distribution(distribution),
+                &names[..2],
+            );
+            for (key, idx) in keys.iter().zip(before) {
+                if idx < 2 {
+                    assert_eq!(router.node(key), idx, "{:?}", distribution);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn weights_give_proportional_shares() {
+        let names = [("10.0.0.1", 1), ("10.0.0.2", 3)];
+        for distribution in [
+            Distribution::Ketama,
+            Distribution::Rendezvous,
+            Distribution::Modula,
+        ] {
+            let router = Router::new(&ShardedOptions::new().distribution(distribution), &names);
+            let heavy = (0..4000)
+                .filter(|x| router.node(&format!("key{}", x)) == 1)
+                .count();
+            assert!(
+                (2700..3300).contains(&heavy),
+                "{:?}: {} keys on the heavy server",
+                distribution,
+                heavy
+            );
+        }
+    }
+
+    #[test]
+    fn replicas_are_distinct_servers() {
+        let names = [("10.0.0.1", 1), ("10.0.0.2", 2), ("10.0.0.3", 1)];
+        for distribution in [
+            Distribution::Ketama,
+            Distribution::Rendezvous,
+            Distribution::Modula,
+        ] {
+            let router = Router::new(&ShardedOptions::new().distribution(distribution), &names);
+            for x in 0..100 {
+                let key = format!("key{}", x);
+                let nodes = router.nodes(&key, 2);
+                assert_eq!(nodes.len(), 2);
+                assert_eq!(nodes[0], router.node(&key));
+                assert_ne!(nodes[0], nodes[1]);
+                assert_eq!(router.nodes(&key, 5).len(), 3);
+            }
+        }
+    }
+
+    #[test]
+    fn modula_handles_large_weights() {
+        let names = [
+            ("10.0.0.1", u32::MAX),
+            ("10.0.0.2", 0),
+            ("10.0.0.3", u32::MAX),
+        ];
+        let router = Router::new(
+            &ShardedOptions::new().distribution(Distribution::Modula),
+            &names,
+        );
+        let first = (0..1000)
+            .filter(|x| router.node(&format!("key{}", x)) == 0)
+            .count();
+        assert!(
+            (400..600).contains(&first),
+            "{} keys on the first server",
+            first
+        );
+        // the server without weight never gets any key
+        assert_eq!(router.nodes("key", 3).len(), 2);
+        assert!(!router.nodes("key", 3).contains(&1));
+    }
+
+    #[test]
+    fn points_per_server_sizes_the_ring() {
+        let names = [("10.0.0.1", 1), ("10.0.0.2", 1)];
+        for (points, expected) in [(DEFAULT_POINTS_PER_SERVER, 320), (40, 80), (1, 8)] {
+            let router = Router::new(&ShardedOptions::new().points_per_server(points), &names);
+            match router.placement {
+                Placement::Ring(x) => assert_eq!(x.len(), expected),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cas_append_copies_the_mode() {
+        use crate::protocol::StoreMode;
+        use tokio::io::AsyncReadExt;
+
+        let options = ShardedOptions::new().replicas(2);
+        let (mut c, mut servers) = ShardedClient::mock(&[b"HD\r\n", b"HD\r\n"], &options).await;
+        let targets = c.router.nodes("key", 2);
+        let data = RawValue::from_vec(b"-tail".to_vec()).set_cas(Some(5));
+        let opts = SetOptions::new().mode(StoreMode::Append).ttl(Some(60));
+        let retval = c.set_with("key", &data, &opts).await.unwrap();
+        assert_eq!(retval, StoreResult::Stored);
+        drop(c);
+
+        let mut sent = Vec::new();
+        for server in servers.iter_mut() {
+            let mut x = String::new();
+            server.read_to_string(&mut x).await.unwrap();
+            sent.push(x);
+        }
+        assert!(sent[targets[0]].contains(" MA C5"), "{}", sent[targets[0]]);
+        // the other replica appends the fragment too, unconditionally
+        let copy = &sent[targets[1]];
+        assert!(copy.starts_with("ms key S5 T60 F0 MA\r\n"), "{}", copy);
+    }
+
+    #[test]
+    fn cluster_stats_sum_counters() {
+        let server = |uptime, items| ServerStats {
+            uptime,
+            curr_items: items,
+            get_hits: 10,
+            ..Default::default()
+        };
+        let stats = ClusterStats::new(vec![
+            ("a".to_string(), Ok(server(100, 3))),
+            ("b".to_string(), Err(MemcacheError::Timeout)),
+            ("c".to_string(), Ok(server(20, 4))),
+        ]);
+        assert_eq!(stats.total.uptime, 20);
+        assert_eq!(stats.total.curr_items, 7);
+        assert_eq!(stats.total.get_hits, 20);
+    }
+}
@@ -0,0 +1,341 @@
+//! Client handle shared between tasks
+//!
+//! [`SharedClient`] owns a [`Client`] in a background task. Clones of the handle send their
+//! commands to that task over a channel, so any number of tasks can use the same connection
+//! without wrapping the client in a mutex. Commands are executed one at a time in the order
+//! they were received.
+//!
+//! [`SharedClient::close`] stops the background task: the command being executed is allowed to
+//! finish, queued commands are cancelled and the connection is shut down.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::error::MemcacheError;
+use crate::protocol::{
+    ArithmeticOptions, ArithmeticResult, DeleteOptions, DeleteResult, GetOptions, GetResponse,
+    RawValue, SetOptions, StoreResult,
+};
+use crate::{AsyncReadWriteUnpin, BoxedConnection, Client};
+
+use log::debug;
+
+/// Number of commands that can be queued before senders have to wait
+const QUEUE_SIZE: usize = 128;
+
+/// Future returned by the closures passed to [`SharedClient::run`]
+pub type CommandFuture<'a, R> = Pin<Box<dyn Future<Output = Result<R, MemcacheError>> + Send + 'a>>;
+
+type Job<T> = Box<
+    dyn for<'a> FnOnce(&'a mut Client<T>) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> + Send,
+>;
+
+enum Message<T: AsyncReadWriteUnpin> {
+    Job(Job<T>),
+    Close(oneshot::Sender<CloseStats>),
+}
+
+/// Summary of the work done by a [`SharedClient`], returned by [`SharedClient::close`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CloseStats {
+    /// Commands executed
+    pub completed: u64,
+    /// Commands that were queued when the client was closed and never sent. Their callers get
+    /// [`MemcacheError::NotConnected`].
+    pub cancelled: u64,
+}
+
+/// Cheaply cloneable handle to a client running in a background task
+pub struct SharedClient<T: AsyncReadWriteUnpin> {
+    sender: mpsc::Sender<Message<T>>,
+    closing: Arc<AtomicBool>,
+}
+
+impl<T: AsyncReadWriteUnpin> Clone for SharedClient<T> {
+    fn clone(&self) -> Self {
+        SharedClient {
+            sender: self.sender.clone(),
+            closing: self.closing.clone(),
+        }
+    }
+}
+
+impl<T: AsyncReadWriteUnpin> std::fmt::Debug for SharedClient<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedClient").finish_non_exhaustive()
+    }
+}
+
+impl SharedClient<BoxedConnection> {
+    /// Connect to the server described by a connection URL, see [`Client::connect`]. Has to be
+    /// called from within a tokio runtime.
+    pub async fn connect(url: &str) -> Result<Self, MemcacheError> {
+        Ok(SharedClient::new(Client::connect(url).await?))
+    }
+}
+
+impl<T: AsyncReadWriteUnpin + Send + 'static> SharedClient<T> {
+    /// Move `client` to a background task. Has to be called from within a tokio runtime. The
+    /// task ends once all the handles are dropped or [`SharedClient::close`] is called.
+    pub fn new(client: Client<T>) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let closing = Arc::new(AtomicBool::new(false));
+        tokio::spawn(serve(client, receiver, closing.clone()));
+        SharedClient { sender, closing }
+    }
+
+    /// Stop the background task. The command currently being executed is allowed to finish,
+    /// the queued ones are cancelled and the connection is shut down. Commands issued after
+    /// this fail with [`MemcacheError::NotConnected`].
+    pub async fn close(&self) -> Result<CloseStats, MemcacheError> {
+        self.closing.store(true, Ordering::Release);
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Message::Close(sender))
+            .await
+            .map_err(|_| MemcacheError::NotConnected)?;
+        receiver.await.map_err(|_| MemcacheError::NotConnected)
+    }
+
+    /// Run any command on the shared client, e.g.
+    /// `shared.run(|c| Box::pin(async move { c.version().await }))`
+    pub async fn run<R, F>(&self, f: F) -> Result<R, MemcacheError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&'a mut Client<T>) -> CommandFuture<'a, R> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job: Job<T> = Box::new(move |client| {
+            Box::pin(async move {
+                // the caller may have given up waiting, nothing to do then
+                let _ = sender.send(f(client).await);
+            })
+        });
+        self.sender
+            .send(Message::Job(job))
+            .await
+            .map_err(|_| MemcacheError::NotConnected)?;
+        receiver.await.map_err(|_| MemcacheError::NotConnected)?
+    }
+
+    /// GET a value, see [`Client::get`]
+    pub async fn get(&self, key: &str) -> Result<Option<RawValue>, MemcacheError> {
+        let key = key.to_string();
+        self.run(move |c| Box::pin(async move { c.get(&key).await }))
+            .await
+    }
+
+    /// GET a value with additional information, see [`Client::get_with`]
+    pub async fn get_with(
+        &self,
+        key: &str,
+        opts: &GetOptions,
+    ) -> Result<Option<GetResponse>, MemcacheError> {
+        let key = key.to_string();
+        let opts = opts.clone();
+        self.run(move |c| Box::pin(async move { c.get_with(&key, &opts).await }))
+            .await
+    }
+
+    /// GET any number of values, see [`Client::get_many`]
+    pub async fn get_many(
+        &self,
+        key_list: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Vec<(String, RawValue)>, MemcacheError> {
+        let key_list: Vec<String> = key_list
+            .into_iter()
+            .map(|x| x.as_ref().to_string())
+            .collect();
+        self.run(move |c| Box::pin(async move { c.get_many(&key_list).await }))
+            .await
+    }
+
+    /// STORE a value, see [`Client::set`]
+    pub async fn set(&self, key: &str, data: RawValue) -> Result<(), MemcacheError> {
+        let key = key.to_string();
+        self.run(move |c| Box::pin(async move { c.set(&key, &data).await }))
+            .await
+    }
+
+    /// STORE a value with additional options, see [`Client::set_with`]
+    pub async fn set_with(
+        &self,
+        key: &str,
+        data: RawValue,
+        opts: &SetOptions,
+    ) -> Result<StoreResult, MemcacheError> {
+        let key = key.to_string();
+        let opts = opts.clone();
+        self.run(move |c| Box::pin(async move { c.set_with(&key, &data, &opts).await }))
+            .await
+    }
+
+    /// DELETE a value, see [`Client::delete`]
+    pub async fn delete(&self, key: &str) -> Result<Option<()>, MemcacheError> {
+        let key = key.to_string();
+        self.run(move |c| Box::pin(async move { c.delete(&key).await }))
+            .await
+    }
+
+    /// DELETE a value using the meta delete command, see [`Client::delete_with`]
+    pub async fn delete_with(
+        &self,
+        key: &str,
+        opts: &DeleteOptions,
+    ) -> Result<DeleteResult, MemcacheError> {
+        let key = key.to_string();
+        let opts = opts.clone();
+        self.run(move |c| Box::pin(async move { c.delete_with(&key, &opts).await }))
+            .await
+    }
+
+    /// Increment or decrement a counter, see [`Client::arithmetic`]
+    pub async fn arithmetic(
+        &self,
+        key: &str,
+        opts: &ArithmeticOptions,
+    ) -> Result<ArithmeticResult, MemcacheError> {
+        let key = key.to_string();
+        let opts = opts.clone();
+        self.run(move |c| Box::pin(async move { c.arithmetic(&key, &opts).await }))
+            .await
+    }
+
+    /// Read memcached version, see [`Client::version`]
+    pub async fn version(&self) -> Result<String, MemcacheError> {
+        self.run(|c| Box::pin(async move { c.version().await }))
+            .await
+    }
+}
+
+/// Body of the background task
+async fn serve<T: AsyncReadWriteUnpin>(
+    mut client: Client<T>,
+    mut receiver: mpsc::Receiver<Message<T>>,
+    closing: Arc<AtomicBool>,
+) {
+    let mut stats = CloseStats::default();
+    let mut closers = Vec::new();
+    while let Some(message) = receiver.recv().await {
+        match message {
+            Message::Job(_) if closing.load(Ordering::Acquire) => stats.cancelled += 1,
+            Message::Job(job) => {
+                job(&mut client).await;
+                stats.completed += 1;
+            }
+            Message::Close(x) => {
+                // keep receiving to drain the queue, but refuse new messages
+                receiver.close();
+                closers.push(x);
+            }
+        }
+    }
+
+    debug!("shared client: stopping, {:?}", stats);
+    if let Err(e) = client.connection.shutdown().await {
+        debug!("shared client: shutdown failed: {}", e);
+    }
+    for x in closers {
+        let _ = x.send(stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared(response: &[u8]) -> SharedClient<impl AsyncReadWriteUnpin + Send + 'static> {
+        SharedClient::new(Client::new(tokio::io::join(
+            std::io::Cursor::new(response.to_vec()),
+            tokio::io::sink(),
+        )))
+    }
+
+    #[tokio::test]
+    async fn commands_are_served_in_order() {
+        let c = shared(b"VA 1 ka f0 c1\r\nx\r\nVA 1 kb f0 c2\r\ny\r\nVERSION 1.6.0\r\n");
+        // the responses are only matched if the requests are sent in the same order
+        let (a, b) = futures_util::future::join(c.get("a"), c.clone().get("b")).await;
+        assert_eq!(a.unwrap().unwrap().data, b"x");
+        assert_eq!(b.unwrap().unwrap().data, b"y");
+        assert_eq!(c.version().await.unwrap(), "1.6.0");
+        let stats = c.close().await.unwrap();
+        assert_eq!(
+            stats,
+            CloseStats {
+                completed: 3,
+                cancelled: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn close_cancels_queued_commands() {
+        let c = shared(b"");
+        let (started, running) = oneshot::channel();
+        let (release, released) = oneshot::channel::<()>();
+        let blocker = tokio::spawn({
+            let c = c.clone();
+            async move {
+                c.run(move |_| {
+                    Box::pin(async move {
+                        let _ = started.send(());
+                        let _ = released.await;
+                        Ok(())
+                    })
+                })
+                .await
+            }
+        });
+        running.await.unwrap();
+
+        let queued: Vec<_> = (0..2)
+            .map(|_| {
+                let c = c.clone();
+                tokio::spawn(async move { c.get("a").await })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+        let closer = tokio::spawn({
+            let c = c.clone();
+            async move { c.close().await }
+        });
+        tokio::task::yield_now().await;
+        release.send(()).unwrap();
+
+        blocker.await.unwrap().unwrap();
+        for x in queued {
+            assert!(matches!(x.await.unwrap(), Err(MemcacheError::NotConnected)));
+        }
+        let stats = closer.await.unwrap().unwrap();
+        assert_eq!(
+            stats,
+            CloseStats {
+                completed: 1,
+                cancelled: 2
+            }
+        );
+        assert!(matches!(
+            c.version().await,
+            Err(MemcacheError::NotConnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn dropped_caller_does_not_block() {
+        let c = shared(b"VA 1 ka f0 c1\r\nx\r\nVERSION 1.6.0\r\n");
+        let abandoned = tokio::spawn({
+            let c = c.clone();
+            async move { c.get("a").await }
+        });
+        tokio::task::yield_now().await;
+        abandoned.abort();
+        // the abandoned command is still executed, the next one reads its own response
+        assert_eq!(c.version().await.unwrap(), "1.6.0");
+    }
+}
@@ -0,0 +1,622 @@
+//! Typed representations of the `stats` command family
+//!
+//! reference: [`protocol.txt`](https://github.com/memcached/memcached/blob/master/doc/protocol.txt)
+
+use crate::error::MemcacheError;
+
+use log::error;
+
+/// General statistics of a server as reported by `stats`
+#[derive(Debug, Default, Clone)]
+pub struct ServerStats {
+    /// Process id of the server
+    pub pid: u32,
+    /// Seconds since the server started
+    pub uptime: u64,
+    /// Version of the server
+    pub version: String,
+    /// Number of open connections
+    pub curr_connections: u64,
+    /// Number of connections opened since the server started
+    pub total_connections: u64,
+    /// Number of items currently stored
+    pub curr_items: u64,
+    /// Number of items stored since the server started
+    pub total_items: u64,
+    /// Bytes currently used to store items
+    pub bytes: u64,
+    /// Bytes the server is allowed to use for storage
+    pub limit_maxbytes: u64,
+    /// Number of retrieval requests
+    pub cmd_get: u64,
+    /// Number of storage requests
+    pub cmd_set: u64,
+    /// Number of keys requested and found
+    pub get_hits: u64,
+    /// Number of keys requested and not found
+    pub get_misses: u64,
+    /// Number of valid items removed to free memory
+    pub evictions: u64,
+    /// Bytes read from the network
+    pub bytes_read: u64,
+    /// Bytes written to the network
+    pub bytes_written: u64,
+    /// All the remaining statistics, as reported by the server
+    pub other: Vec<(String, String)>,
+}
+
+/// Statistics of a single slab class as reported by `stats slabs`
+#[derive(Debug, Default, Clone)]
+pub struct SlabClassStats {
+    /// Slab class id
+    pub class: u32,
+    /// Space each item in this class uses
+    pub chunk_size: u64,
+    /// Number of chunks that fit in a single page (1 MiB by default)
+    pub chunks_per_page: u64,
+    /// Number of pages allocated to this class
+    pub total_pages: u64,
+    /// Number of chunks allocated to this class
+    pub total_chunks: u64,
+    /// Number of chunks that have been allocated to items
+    pub used_chunks: u64,
+    /// Chunks not yet allocated to items or freed via delete
+    pub free_chunks: u64,
+    /// Number of free chunks at the end of the last allocated page
+    pub free_chunks_end: u64,
+    /// Number of bytes requested to be stored in this class. Older servers only, None if not
+    /// reported.
+    pub mem_requested: Option<u64>,
+    /// Number of get requests serviced by this class
+    pub get_hits: u64,
+    /// Number of set requests stored data in this class
+    pub cmd_set: u64,
+    /// Number of successful deletes from this class
+    pub delete_hits: u64,
+    /// Number of incrs modifying this class
+    pub incr_hits: u64,
+    /// Number of decrs modifying this class
+    pub decr_hits: u64,
+    /// Number of CAS commands modifying this class
+    pub cas_hits: u64,
+    /// Number of CAS commands that failed to modify a value due to a bad CAS id
+    pub cas_badval: u64,
+    /// Number of touches serviced by this class
+    pub touch_hits: u64,
+}
+
+/// Result of the `stats slabs` command
+#[derive(Debug, Default, Clone)]
+pub struct SlabStats {
+    /// Per class statistics, ordered by class id
+    pub classes: Vec<SlabClassStats>,
+    /// Total number of slab classes allocated
+    pub active_slabs: u64,
+    /// Total amount of memory allocated to slab pages
+    pub total_malloced: u64,
+}
+
+/// Result of the `stats sizes` command
+#[derive(Debug, Default, Clone)]
+pub struct SizeStats {
+    /// False if the server has the size histogram disabled (`sizes_status disabled`)
+    pub enabled: bool,
+    /// List of `(size, count)` buckets, ordered by size. Each item is counted in the bucket of
+    /// its total size rounded up to the nearest 32 bytes.
+    pub buckets: Vec<(u64, u64)>,
+}
+
+/// Result of the `stats settings` command
+#[derive(Debug, Default, Clone)]
+pub struct SettingsStats {
+    /// Maximum number of bytes allowed in the cache
+    pub maxbytes: u64,
+    /// Maximum number of simultaneous connections
+    pub maxconns: u64,
+    /// TCP port to listen on
+    pub tcpport: u32,
+    /// UDP port to listen on
+    pub udpport: u32,
+    /// Verbosity level
+    pub verbosity: u32,
+    /// Whether the server evicts items when out of memory (`-M` disables it)
+    pub evictions: bool,
+    /// Chunk size growth factor
+    pub growth_factor: f64,
+    /// Minimum space allocated for key+value+flags
+    pub chunk_size: u64,
+    /// Number of worker threads
+    pub num_threads: u32,
+    /// Whether CAS is enabled
+    pub cas_enabled: bool,
+    /// Protocol the server was configured to accept
+    pub binding_protocol: String,
+    /// Whether SASL authentication is enabled
+    pub auth_enabled_sasl: bool,
+    /// Maximum size of a single item. Values larger than this are rejected by the server.
+    pub item_size_max: u64,
+    /// Whether slab page reassignment is allowed
+    pub slab_reassign: bool,
+    /// Slab automove mode
+    pub slab_automove: u32,
+    /// Whether the LRU crawler thread is running
+    pub lru_crawler: bool,
+    /// Percentage of a slab class reserved for the HOT LRU
+    pub hot_lru_pct: u32,
+    /// Percentage of a slab class reserved for the WARM LRU
+    pub warm_lru_pct: u32,
+    /// Whether the TEMP LRU is enabled
+    pub temp_lru: bool,
+    /// All the remaining settings, as reported by the server
+    pub other: Vec<(String, String)>,
+}
+
+/// State of a single server connection as reported by `stats conns`
+#[derive(Debug, Default, Clone)]
+pub struct ConnectionStats {
+    /// File descriptor of the connection on the server
+    pub fd: u32,
+    /// Address of the remote peer (empty for listening sockets)
+    pub addr: String,
+    /// Address of the socket that accepted the connection
+    pub listen_addr: String,
+    /// Internal connection state (e.g. `conn_parse_cmd`, `conn_waiting`)
+    pub state: String,
+    /// Seconds since the connection last issued a command
+    pub secs_since_last_cmd: u64,
+}
+
+/// Error for the line `STAT <name> <value>`
+fn bad_stat(name: &str, value: &str) -> MemcacheError {
+    MemcacheError::bad_response(format!("STAT {} {}", name, value))
+}
+
+fn parse_u64(name: &str, value: &str) -> Result<u64, MemcacheError> {
+    value.parse::<u64>().map_err(|_| {
+        error!("stats: bad value for {}: {}", name, value);
+        bad_stat(name, value)
+    })
+}
+
+fn parse_u32(name: &str, value: &str) -> Result<u32, MemcacheError> {
+    value.parse::<u32>().map_err(|_| {
+        error!("stats: bad value for {}: {}", name, value);
+        bad_stat(name, value)
+    })
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, MemcacheError> {
+    match value {
+        "yes" | "on" | "true" | "1" => Ok(true),
+        "no" | "off" | "false" | "0" => Ok(false),
+        x => {
+            error!("stats: bad value for {}: {}", name, x);
+            Err(bad_stat(name, x))
+        }
+    }
+}
+
+impl ServerStats {
+    /// Build the structure from raw `STAT <name> <value>` pairs. Statistics without a dedicated
+    /// field end up in `other`.
+    pub(crate) fn from_raw(raw: &[(String, String)]) -> Result<Self, MemcacheError> {
+        let mut retval = ServerStats::default();
+        for (name, value) in raw {
+            match name.as_str() {
+                "pid" => retval.pid = parse_u32(name, value)?,
+                "uptime" => retval.uptime = parse_u64(name, value)?,
+                "version" => retval.version = value.clone(),
+                "curr_connections" => retval.curr_connections = parse_u64(name, value)?,
+                "total_connections" => retval.total_connections = parse_u64(name, value)?,
+                "curr_items" => retval.curr_items = parse_u64(name, value)?,
+                "total_items" => retval.total_items = parse_u64(name, value)?,
+                "bytes" => retval.bytes = parse_u64(name, value)?,
+                "limit_maxbytes" => retval.limit_maxbytes = parse_u64(name, value)?,
+                "cmd_get" => retval.cmd_get = parse_u64(name, value)?,
+                "cmd_set" => retval.cmd_set = parse_u64(name, value)?,
+                "get_hits" => retval.get_hits = parse_u64(name, value)?,
+                "get_misses" => retval.get_misses = parse_u64(name, value)?,
+                "evictions" => retval.evictions = parse_u64(name, value)?,
+                "bytes_read" => retval.bytes_read = parse_u64(name, value)?,
+                "bytes_written" => retval.bytes_written = parse_u64(name, value)?,
+                _ => retval.other.push((name.clone(), value.clone())),
+            }
+        }
+        Ok(retval)
+    }
+}
+
+impl SlabStats {
+    /// Build the structure from raw `STAT <name> <value>` pairs. Unknown statistics are ignored.
+    pub(crate) fn from_raw(raw: &[(String, String)]) -> Result<Self, MemcacheError> {
+        let mut retval = SlabStats::default();
+        for (name, value) in raw {
+            let Some((class, stat)) = name.split_once(':') else {
+                match name.as_str() {
+                    "active_slabs" => retval.active_slabs = parse_u64(name, value)?,
+                    "total_malloced" => retval.total_malloced = parse_u64(name, value)?,
+                    _ => {}
+                }
+                continue;
+            };
+            let Ok(class) = class.parse::<u32>() else {
+                error!("stats slabs: bad slab class {}", name);
+                return Err(bad_stat(name, value));
+            };
+            let entry = match retval.classes.iter().position(|x| x.class == class) {
+                Some(idx) => &mut retval.classes[idx],
+                None => {
+                    retval.classes.push(SlabClassStats {
+                        class,
+                        ..Default::default()
+                    });
+                    retval.classes.last_mut().unwrap()
+                }
+            };
+            match stat {
+                "chunk_size" => entry.chunk_size = parse_u64(name, value)?,
+                "chunks_per_page" => entry.chunks_per_page = parse_u64(name, value)?,
+                "total_pages" => entry.total_pages = parse_u64(name, value)?,
+                "total_chunks" => entry.total_chunks = parse_u64(name, value)?,
+                "used_chunks" => entry.used_chunks = parse_u64(name, value)?,
+                "free_chunks" => entry.free_chunks = parse_u64(name, value)?,
+                "free_chunks_end" => entry.free_chunks_end = parse_u64(name, value)?,
+                "mem_requested" => entry.mem_requested = Some(parse_u64(name, value)?),
+                "get_hits" => entry.get_hits = parse_u64(name, value)?,
+                "cmd_set" => entry.cmd_set = parse_u64(name, value)?,
+                "delete_hits" => entry.delete_hits = parse_u64(name, value)?,
+                "incr_hits" => entry.incr_hits = parse_u64(name, value)?,
+                "decr_hits" => entry.decr_hits = parse_u64(name, value)?,
+                "cas_hits" => entry.cas_hits = parse_u64(name, value)?,
+                "cas_badval" => entry.cas_badval = parse_u64(name, value)?,
+                "touch_hits" => entry.touch_hits = parse_u64(name, value)?,
+                _ => {}
+            }
+        }
+        retval.classes.sort_by_key(|x| x.class);
+        Ok(retval)
+    }
+}
+
+impl SizeStats {
+    /// Build the structure from raw `STAT <name> <value>` pairs.
+    pub(crate) fn from_raw(raw: &[(String, String)]) -> Result<Self, MemcacheError> {
+        let mut retval = SizeStats {
+            enabled: true,
+            buckets: Vec::with_capacity(raw.len()),
+        };
+        for (name, value) in raw {
+            if name == "sizes_status" {
+                retval.enabled = value != "disabled";
+                continue;
+            }
+            let Ok(size) = name.parse::<u64>() else {
+                error!("stats sizes: bad bucket {}", name);
+                return Err(bad_stat(name, value));
+            };
+            retval.buckets.push((size, parse_u64(name, value)?));
+        }
+        retval.buckets.sort_by_key(|x| x.0);
+        Ok(retval)
+    }
+}
+
+impl SettingsStats {
+    /// Build the structure from raw `STAT <name> <value>` pairs. Settings without a dedicated
+    /// field end up in `other`.
+    pub(crate) fn from_raw(raw: &[(String, String)]) -> Result<Self, MemcacheError> {
+        let mut retval = SettingsStats::default();
+        for (name, value) in raw {
+            match name.as_str() {
+                "maxbytes" => retval.maxbytes = parse_u64(name, value)?,
+                "maxconns" => retval.maxconns = parse_u64(name, value)?,
+                "tcpport" => retval.tcpport = parse_u32(name, value)?,
+                "udpport" => retval.udpport = parse_u32(name, value)?,
+                "verbosity" => retval.verbosity = parse_u32(name, value)?,
+                "evictions" => retval.evictions = parse_bool(name, value)?,
+                "growth_factor" => {
+                    let Ok(x) = value.parse::<f64>() else {
+                        error!("stats: bad value for {}: {}", name, value);
+                        return Err(bad_stat(name, value));
+                    };
+                    retval.growth_factor = x;
+                }
+                "chunk_size" => retval.chunk_size = parse_u64(name, value)?,
+                "num_threads" => retval.num_threads = parse_u32(name, value)?,
+                "cas_enabled" => retval.cas_enabled = parse_bool(name, value)?,
+                "binding_protocol" => retval.binding_protocol = value.clone(),
+                "auth_enabled_sasl" => retval.auth_enabled_sasl = parse_bool(name, value)?,
+                "item_size_max" => retval.item_size_max = parse_u64(name, value)?,
+                "slab_reassign" => retval.slab_reassign = parse_bool(name, value)?,
+                "slab_automove" => retval.slab_automove = parse_u32(name, value)?,
+                "lru_crawler" => retval.lru_crawler = parse_bool(name, value)?,
+                "hot_lru_pct" => retval.hot_lru_pct = parse_u32(name, value)?,
+                "warm_lru_pct" => retval.warm_lru_pct = parse_u32(name, value)?,
+                "temp_lru" => retval.temp_lru = parse_bool(name, value)?,
+                _ => retval.other.push((name.clone(), value.clone())),
+            }
+        }
+        Ok(retval)
+    }
+}
+
+impl ConnectionStats {
+    /// Build the connection list from raw `STAT <fd>:<name> <value>` pairs. Unknown statistics
+    /// are ignored.
+    pub(crate) fn from_raw(raw: &[(String, String)]) -> Result<Vec<Self>, MemcacheError> {
+        let mut retval: Vec<ConnectionStats> = Vec::new();
+        for (name, value) in raw {
+            let Some((fd, stat)) = name.split_once(':') else {
+                error!("stats conns: malformed statistic {}", name);
+                return Err(bad_stat(name, value));
+            };
+            let Ok(fd) = fd.parse::<u32>() else {
+                error!("stats conns: bad file descriptor {}", name);
+                return Err(bad_stat(name, value));
+            };
+            let entry = match retval.iter().position(|x| x.fd == fd) {
+                Some(idx) => &mut retval[idx],
+                None => {
+                    retval.push(ConnectionStats {
+                        fd,
+                        ..Default::default()
+                    });
+                    retval.last_mut().unwrap()
+                }
+            };
+            match stat {
+                "addr" => entry.addr = value.clone(),
+                "listen_addr" => entry.listen_addr = value.clone(),
+                "state" => entry.state = value.clone(),
+                "secs_since_last_cmd" => entry.secs_since_last_cmd = parse_u64(name, value)?,
+                _ => {}
+            }
+        }
+        Ok(retval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(lines: &[(&str, &str)]) -> Vec<(String, String)> {
+        lines
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn server_stats() {
+        let stats = ServerStats::from_raw(&raw(&[
+            ("pid", "1234"),
+            ("version", "1.6.21"),
+            ("curr_items", "42"),
+            ("get_hits", "7"),
+            ("rusage_user", "0.123456"),
+        ]))
+        .expect("ServerStats::from_raw() failed");
+        assert_eq!(stats.pid, 1234);
+        assert_eq!(stats.version, "1.6.21");
+        assert_eq!(stats.curr_items, 42);
+        assert_eq!(stats.get_hits, 7);
+        assert_eq!(
+            stats.other,
+            vec![("rusage_user".to_string(), "0.123456".to_string())]
+        );
+    }
+
+    #[test]
+    fn server_stats_fixture() {
+        // from memcached 1.6.21, trimmed
+        let stats = ServerStats::from_raw(&raw(&[
+            ("pid", "8"),
+            ("uptime", "3600"),
+            ("time", "1700003600"),
+            ("version", "1.6.21"),
+            ("libevent", "2.1.12-stable"),
+            ("pointer_size", "64"),
+            ("rusage_user", "0.512345"),
+            ("rusage_system", "1.024680"),
+            ("max_connections", "1024"),
+            ("curr_connections", "3"),
+            ("total_connections", "17"),
+            ("cmd_get", "1000"),
+            ("cmd_set", "250"),
+            ("cmd_flush", "0"),
+            ("get_hits", "900"),
+            ("get_misses", "100"),
+            ("bytes_read", "123456"),
+            ("bytes_written", "654321"),
+            ("limit_maxbytes", "67108864"),
+            ("threads", "4"),
+            ("bytes", "20480"),
+            ("curr_items", "200"),
+            ("total_items", "250"),
+            ("evictions", "5"),
+        ]))
+        .expect("ServerStats::from_raw() failed");
+        assert_eq!(stats.pid, 8);
+        assert_eq!(stats.uptime, 3600);
+        assert_eq!(stats.version, "1.6.21");
+        assert_eq!(stats.curr_connections, 3);
+        assert_eq!(stats.total_connections, 17);
+        assert_eq!(stats.cmd_get, 1000);
+        assert_eq!(stats.cmd_set, 250);
+        assert_eq!(stats.get_hits, 900);
+        assert_eq!(stats.get_misses, 100);
+        assert_eq!(stats.bytes_read, 123456);
+        assert_eq!(stats.bytes_written, 654321);
+        assert_eq!(stats.limit_maxbytes, 64 << 20);
+        assert_eq!(stats.bytes, 20480);
+        assert_eq!(stats.curr_items, 200);
+        assert_eq!(stats.total_items, 250);
+        assert_eq!(stats.evictions, 5);
+        let other: Vec<&str> = stats.other.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            other,
+            vec![
+                "time",
+                "libevent",
+                "pointer_size",
+                "rusage_user",
+                "rusage_system",
+                "max_connections",
+                "cmd_flush",
+                "threads"
+            ]
+        );
+
+        assert!(ServerStats::from_raw(&raw(&[("pid", "-1")])).is_err());
+        assert!(ServerStats::from_raw(&raw(&[("curr_items", "")])).is_err());
+    }
+
+    #[test]
+    fn settings_stats() {
+        // from memcached 1.6.21 started with `-m 64 -c 1024 -t 4`
+        let stats = SettingsStats::from_raw(&raw(&[
+            ("maxbytes", "67108864"),
+            ("maxconns", "1024"),
+            ("tcpport", "11211"),
+            ("udpport", "0"),
+            ("inter", "NULL"),
+            ("verbosity", "0"),
+            ("oldest", "0"),
+            ("evictions", "on"),
+            ("domain_socket", "NULL"),
+            ("umask", "700"),
+            ("shutdown_command", "no"),
+            ("growth_factor", "1.25"),
+            ("chunk_size", "48"),
+            ("num_threads", "4"),
+            ("num_threads_per_udp", "4"),
+            ("stat_key_prefix", ":"),
+            ("detail_enabled", "no"),
+            ("reqs_per_event", "20"),
+            ("cas_enabled", "yes"),
+            ("tcp_backlog", "1024"),
+            ("binding_protocol", "auto-negotiate"),
+            ("auth_enabled_sasl", "no"),
+            ("auth_enabled_ascii", "no"),
+            ("item_size_max", "1048576"),
+            ("maxconns_fast", "yes"),
+            ("hashpower_init", "0"),
+            ("slab_reassign", "yes"),
+            ("slab_automove", "1"),
+            ("slab_automove_ratio", "0.80"),
+            ("slab_automove_window", "30"),
+            ("slab_chunk_max", "524288"),
+            ("lru_crawler", "yes"),
+            ("lru_crawler_sleep", "100"),
+            ("lru_crawler_tocrawl", "0"),
+            ("tail_repair_time", "0"),
+            ("flush_enabled", "yes"),
+            ("dump_enabled", "yes"),
+            ("hash_algorithm", "murmur3"),
+            ("lru_maintainer_thread", "yes"),
+            ("lru_segmented", "yes"),
+            ("hot_lru_pct", "20"),
+            ("warm_lru_pct", "40"),
+            ("hot_max_factor", "0.20"),
+            ("warm_max_factor", "2.00"),
+            ("temp_lru", "no"),
+            ("temporary_ttl", "61"),
+            ("idle_timeout", "0"),
+        ]))
+        .expect("SettingsStats::from_raw() failed");
+        assert_eq!(stats.maxbytes, 64 << 20);
+        assert_eq!(stats.maxconns, 1024);
+        assert_eq!(stats.tcpport, 11211);
+        assert_eq!(stats.udpport, 0);
+        assert_eq!(stats.verbosity, 0);
+        assert!(stats.evictions);
+        assert_eq!(stats.growth_factor, 1.25);
+        assert_eq!(stats.chunk_size, 48);
+        assert_eq!(stats.num_threads, 4);
+        assert!(stats.cas_enabled);
+        assert_eq!(stats.binding_protocol, "auto-negotiate");
+        assert!(!stats.auth_enabled_sasl);
+        assert_eq!(stats.item_size_max, 1 << 20);
+        assert!(stats.slab_reassign);
+        assert_eq!(stats.slab_automove, 1);
+        assert!(stats.lru_crawler);
+        assert_eq!(stats.hot_lru_pct, 20);
+        assert_eq!(stats.warm_lru_pct, 40);
+        assert!(!stats.temp_lru);
+        assert_eq!(stats.other.len(), 28);
+        assert_eq!(stats.other[0], ("inter".to_string(), "NULL".to_string()));
+
+        assert!(SettingsStats::from_raw(&raw(&[("item_size_max", "1m")])).is_err());
+        assert!(SettingsStats::from_raw(&raw(&[("evictions", "maybe")])).is_err());
+        assert!(SettingsStats::from_raw(&raw(&[("growth_factor", "x")])).is_err());
+    }
+
+    #[test]
+    fn connection_stats() {
+        // from memcached 1.6.21: the listening sockets, a UDP socket and two clients
+        let conns = ConnectionStats::from_raw(&raw(&[
+            ("26:addr", "tcp:0.0.0.0:11211"),
+            ("26:state", "conn_listening"),
+            ("26:secs_since_last_cmd", "3600"),
+            ("27:addr", "udp:0.0.0.0:11211"),
+            ("27:state", "conn_read"),
+            ("27:secs_since_last_cmd", "3600"),
+            ("28:addr", "tcp:127.0.0.1:51234"),
+            ("28:listen_addr", "tcp:0.0.0.0:11211"),
+            ("28:state", "conn_parse_cmd"),
+            ("28:secs_since_last_cmd", "0"),
+            ("29:addr", "tcp:127.0.0.1:51236"),
+            ("29:listen_addr", "tcp:0.0.0.0:11211"),
+            ("29:state", "conn_waiting"),
+            ("29:secs_since_last_cmd", "42"),
+            ("29:some_future_stat", "1"),
+        ]))
+        .expect("ConnectionStats::from_raw() failed");
+        assert_eq!(conns.len(), 4);
+        assert_eq!(conns[0].fd, 26);
+        assert_eq!(conns[0].addr, "tcp:0.0.0.0:11211");
+        assert_eq!(conns[0].listen_addr, "");
+        assert_eq!(conns[0].state, "conn_listening");
+        assert_eq!(conns[1].addr, "udp:0.0.0.0:11211");
+        assert_eq!(conns[2].fd, 28);
+        assert_eq!(conns[2].state, "conn_parse_cmd");
+        assert_eq!(conns[2].secs_since_last_cmd, 0);
+        assert_eq!(conns[3].addr, "tcp:127.0.0.1:51236");
+        assert_eq!(conns[3].listen_addr, "tcp:0.0.0.0:11211");
+        assert_eq!(conns[3].state, "conn_waiting");
+        assert_eq!(conns[3].secs_since_last_cmd, 42);
+
+        assert!(ConnectionStats::from_raw(&raw(&[])).unwrap().is_empty());
+        assert!(ConnectionStats::from_raw(&raw(&[("addr", "tcp:1.2.3.4:1")])).is_err());
+        assert!(ConnectionStats::from_raw(&raw(&[("x:state", "conn_new_cmd")])).is_err());
+        assert!(ConnectionStats::from_raw(&raw(&[("28:secs_since_last_cmd", "x")])).is_err());
+    }
+
+    #[test]
+    fn slab_stats() {
+        let stats = SlabStats::from_raw(&raw(&[
+            ("5:chunk_size", "240"),
+            ("5:used_chunks", "3"),
+            ("1:chunk_size", "96"),
+            ("1:chunks_per_page", "10922"),
+            ("1:mem_requested", "1234"),
+            ("1:some_future_stat", "1"),
+            ("active_slabs", "2"),
+            ("total_malloced", "2097152"),
+        ]))
+        .expect("SlabStats::from_raw() failed");
+        assert_eq!(stats.active_slabs, 2);
+        assert_eq!(stats.total_malloced, 2097152);
+        assert_eq!(stats.classes.len(), 2);
+        assert_eq!(stats.classes[0].class, 1);
+        assert_eq!(stats.classes[0].chunks_per_page, 10922);
+        assert_eq!(stats.classes[0].mem_requested, Some(1234));
+        assert_eq!(stats.classes[1].chunk_size, 240);
+        assert_eq!(stats.classes[1].used_chunks, 3);
+        assert_eq!(stats.classes[1].mem_requested, None);
+
+        assert!(SlabStats::from_raw(&raw(&[("x:chunk_size", "1")])).is_err());
+        assert!(SlabStats::from_raw(&raw(&[("1:chunk_size", "abc")])).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+//! Classic text protocol versions of the meta commands
+//!
+//! Used by [`Meta`] in text-only mode (see [`Meta::text_only`]), for proxies like twemproxy
+//! that reject the meta commands. Only the options the text commands can express are
+//! supported, the others fail with an [`std::io::ErrorKind::Unsupported`] error.
+
+use std::borrow::Cow;
+
+use tokio::io::AsyncWriteExt;
+
+use crate::error::MemcacheError;
+use crate::protocol::{
+    error_line, io_error, read_data_block, read_line, read_text_response, text_command,
+    ArithmeticMode, ArithmeticOptions, ArithmeticResult, DeleteOptions, DeleteResult, GetOptions,
+    GetResponse, Meta, RawValue, SetOptions, StoreMode, StoreResult,
+};
+use crate::AsyncReadWriteUnpin;
+
+use log::{debug, error};
+
+/// Error returned for commands and options the text protocol lacks
+pub(crate) fn unsupported(cmd: &str) -> MemcacheError {
+    error!("{}: not supported in text-only mode", cmd);
+    MemcacheError::IOError(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "not supported in text-only mode",
+    ))
+}
+
+/// Reads a `VALUE <key> <flags> <bytes> [<cas>]` line and its data, None once `END` is read
+async fn read_value<T: AsyncReadWriteUnpin>(
+    io: &mut T,
+    cmd: &str,
+) -> Result<Option<(String, RawValue)>, MemcacheError> {
+    let mut buffer = Vec::new();
+    read_line(io, &mut buffer).await?;
+    if buffer.len() >= 2 {
+        buffer.truncate(buffer.len() - 2);
+    }
+    if buffer == b"END" {
+        return Ok(None);
+    }
+    let response = String::from_utf8(buffer).map_err(|e| {
+        error!("{}: non-ASCII response", cmd);
+        MemcacheError::bad_response(e.as_bytes())
+    })?;
+    if let Some(e) = error_line(&response) {
+        debug!("{}: {}", cmd, response);
+        return Err(e);
+    }
+    let mut tokens = response.split_ascii_whitespace();
+    if tokens.next() != Some("VALUE") {
+        error!("{}: malformed response {}", cmd, response);
+        return Err(MemcacheError::bad_response(&response));
+    }
+    let (Some(key), Some(flags), Some(size)) = (
+        tokens.next(),
+        tokens.next().and_then(|x| x.parse::<u32>().ok()),
+        tokens.next().and_then(|x| x.parse::<usize>().ok()),
+    ) else {
+        error!("{}: malformed response {}", cmd, response);
+        return Err(MemcacheError::bad_response(&response));
+    };
+    let cas = match tokens.next() {
+        Some(x) => {
+            let Ok(x) = x.parse::<u64>() else {
+                error!("{}: bad cas value", cmd);
+                return Err(MemcacheError::bad_response(&response));
+            };
+            Some(x)
+        }
+        None => None,
+    };
+    let data = read_data_block(io, size).await?;
+    Ok(Some((
+        key.to_string(),
+        RawValue {
+            data,
+            flags,
+            time: None,
+            cas,
+        },
+    )))
+}
+
+impl Meta {
+    /// GET using `gets` (or `gats` to update the TTL)
+    pub(crate) async fn get_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        opts: &GetOptions,
+    ) -> Result<Option<GetResponse>, MemcacheError> {
+        if opts.return_ttl
+            || opts.return_last_access
+            || opts.return_hit_before
+            || opts.vivify_ttl.is_some()
+            || opts.recache_ttl.is_some()
+            || opts.opaque.is_some()
+        {
+            return Err(unsupported("get"));
+        }
+        let request = match opts.touch_ttl {
+            Some(ttl) => format!("gats {} {}\r\n", ttl, key),
+            None => format!("gets {}\r\n", key),
+        };
+        io.write_all(request.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        let Some((found, value)) = read_value(io, "get").await? else {
+            debug!("get: no key");
+            return Ok(None);
+        };
+        if found != key || read_value(io, "get").await?.is_some() {
+            error!("get: unexpected key {}", found);
+            return Err(MemcacheError::bad_response(format!("VALUE {}", found)));
+        }
+        Ok(Some(GetResponse {
+            value,
+            last_access: None,
+            hit_before: None,
+            won_recache: false,
+            stale: false,
+            recache_pending: false,
+        }))
+    }
+
+    /// STORE using the storage command matching `opts.mode`, or `cas` if `cas` is set
+    pub(crate) async fn set_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        data: &RawValue<&[u8]>,
+        cas: Option<u64>,
+        opts: &SetOptions,
+    ) -> Result<StoreResult, MemcacheError> {
+        if opts.invalidate || opts.opaque.is_some() {
+            return Err(unsupported("set"));
+        }
+        self.check_value_size(data, "set")?;
+        let command = match (opts.mode, cas) {
+            (StoreMode::Set, Some(_)) => "cas",
+            (StoreMode::Set, None) => "set",
+            (_, Some(_)) => return Err(unsupported("set")),
+            (StoreMode::Add, None) => "add",
+            (StoreMode::Append, None) => "append",
+            (StoreMode::Prepend, None) => "prepend",
+            (StoreMode::Replace, None) => "replace",
+        };
+        let mut request = format!(
+            "{} {} {} {} {}",
+            command,
+            key,
+            data.flags,
+            data.time.unwrap_or(0),
+            data.data.len()
+        );
+        if let Some(cas) = cas {
+            request.push_str(&format!(" {}", cas));
+        }
+        request.push_str("\r\n");
+        let marker = [0x0D, 0x0A];
+        io.write_all(request.as_bytes())
+            .await
+            .and(io.write_all(data.data).await)
+            .and(io.write_all(&marker).await)
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        let response = read_text_response(io, "set").await?;
+        match response.as_str() {
+            "STORED" => Ok(StoreResult::Stored),
+            "NOT_STORED" => Ok(StoreResult::NotStored),
+            "EXISTS" => Ok(StoreResult::Exists),
+            "NOT_FOUND" => Ok(StoreResult::NotFound),
+            x => {
+                error!("set: unexpected response {}", x);
+                Err(MemcacheError::bad_response(x))
+            }
+        }
+    }
+
+    /// Pipelines a `set` for every item
+    async fn write_sets_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        keydata: &[(&str, RawValue<&[u8]>)],
+    ) -> Result<(), MemcacheError> {
+        let marker = [0x0D, 0x0A];
+        for (key, data) in keydata.iter() {
+            let request = format!(
+                "set {} {} {} {}\r\n",
+                key,
+                data.flags,
+                data.time.unwrap_or(0),
+                data.data.len()
+            );
+            io.write_all(request.as_bytes())
+                .await
+                .and(io.write_all(data.data).await)
+                .and(io.write_all(&marker).await)
+                .map_err(io_error)?;
+        }
+        io.flush().await.map_err(io_error)
+    }
+
+    /// Pipelines a `set` for every item, then reads the responses
+    pub(crate) async fn set_multiple_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        keydata: &[(&str, RawValue<&[u8]>)],
+    ) -> Result<(), MemcacheError> {
+        self.write_sets_text(io, keydata).await?;
+
+        let mut retval = Ok(());
+        for _ in keydata.iter() {
+            match read_text_response(io, "set_multiple").await {
+                Ok(x) if x == "STORED" => {}
+                Ok(x) if x == "NOT_STORED" => retval = retval.and(Err(MemcacheError::NotStored)),
+                Ok(x) => {
+                    error!("set_multiple: unexpected response {}", x);
+                    retval = retval.and(Err(MemcacheError::bad_response(&x)));
+                }
+                Err(e @ MemcacheError::Protocol(_)) => retval = retval.and(Err(e)),
+                Err(e) => return Err(e),
+            }
+        }
+        retval
+    }
+
+    /// Pipelines a `set` for every item, then reads the outcome of each of them
+    pub(crate) async fn set_many_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        keydata: &[(&str, RawValue<&[u8]>)],
+    ) -> Result<Vec<StoreResult>, MemcacheError> {
+        self.write_sets_text(io, keydata).await?;
+
+        let mut retval = Vec::with_capacity(keydata.len());
+        let mut error = None;
+        for _ in keydata.iter() {
+            match read_text_response(io, "set_many").await {
+                Ok(x) if x == "STORED" => retval.push(StoreResult::Stored),
+                Ok(x) if x == "NOT_STORED" => retval.push(StoreResult::NotStored),
+                Ok(x) => {
+                    error!("set_many: unexpected response {}", x);
+                    return Err(MemcacheError::bad_response(&x));
+                }
+                Err(e @ MemcacheError::Protocol(_)) => {
+                    error.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(retval),
+        }
+    }
+
+    /// Pipelines a `delete` for every key, then reads whether each of them existed
+    pub(crate) async fn delete_many_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        keys: &[Cow<'_, str>],
+    ) -> Result<Vec<bool>, MemcacheError> {
+        let mut send = String::new();
+        for key in keys {
+            send.push_str(&format!("delete {}\r\n", key));
+        }
+        io.write_all(send.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        let mut retval = Vec::with_capacity(keys.len());
+        let mut error = None;
+        for _ in keys {
+            match read_text_response(io, "delete_many").await {
+                Ok(x) if x == "DELETED" => retval.push(true),
+                Ok(x) if x == "NOT_FOUND" => retval.push(false),
+                Ok(x) => {
+                    error!("delete_many: unexpected response {}", x);
+                    return Err(MemcacheError::bad_response(&x));
+                }
+                Err(e @ MemcacheError::Protocol(_)) => {
+                    error.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(retval),
+        }
+    }
+
+    /// Pipelines a `touch` for every key, then reads whether each of them existed
+    pub(crate) async fn touch_many_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        keys: &[Cow<'_, str>],
+        ttl: u32,
+    ) -> Result<Vec<bool>, MemcacheError> {
+        let mut send = String::new();
+        for key in keys {
+            send.push_str(&format!("touch {} {}\r\n", key, ttl));
+        }
+        io.write_all(send.as_bytes())
+            .await
+            .and(io.flush().await)
+            .map_err(io_error)?;
+
+        let mut retval = Vec::with_capacity(keys.len());
+        let mut error = None;
+        for _ in keys {
+            match read_text_response(io, "touch_many").await {
+                Ok(x) if x == "TOUCHED" => retval.push(true),
+                Ok(x) if x == "NOT_FOUND" => retval.push(false),
+                Ok(x) => {
+                    error!("touch_many: unexpected response {}", x);
+                    return Err(MemcacheError::bad_response(&x));
+                }
+                Err(e @ MemcacheError::Protocol(_)) => {
+                    error.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(retval),
+        }
+    }
+
+    /// DELETE using `delete`, only without options
+    pub(crate) async fn delete_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        opts: &DeleteOptions,
+    ) -> Result<DeleteResult, MemcacheError> {
+        if opts.invalidate || opts.ttl.is_some() || opts.cas.is_some() || opts.opaque.is_some() {
+            return Err(unsupported("delete_with"));
+        }
+        Ok(match self.delete(io, key).await? {
+            Some(()) => DeleteResult::Deleted,
+            None => DeleteResult::NotFound,
+        })
+    }
+
+    /// Arithmetic using `incr` or `decr`, counters can't be created or touched
+    pub(crate) async fn arithmetic_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+        key: &str,
+        opts: &ArithmeticOptions,
+    ) -> Result<ArithmeticResult, MemcacheError> {
+        if opts.vivify_ttl.is_some() || opts.ttl.is_some() || opts.opaque.is_some() {
+            return Err(unsupported("arithmetic"));
+        }
+        let command = match opts.mode {
+            ArithmeticMode::Increment => "incr",
+            ArithmeticMode::Decrement => "decr",
+        };
+        let request = format!("{} {} {}\r\n", command, key, opts.delta);
+        let response = text_command(io, &request, "arithmetic").await?;
+        if response == "NOT_FOUND" {
+            debug!("arithmetic: no key");
+            return Ok(ArithmeticResult::NotFound);
+        }
+        let Ok(value) = response.parse::<u64>() else {
+            error!("arithmetic: unexpected response {}", response);
+            return Err(MemcacheError::bad_response(&response));
+        };
+        Ok(ArithmeticResult::Updated(
+            opts.return_value.then_some(value),
+        ))
+    }
+
+    /// Round-trip using `version`, the text protocol has no no-op
+    pub(crate) async fn noop_text<T: AsyncReadWriteUnpin>(
+        &self,
+        io: &mut T,
+    ) -> Result<(), MemcacheError> {
+        self.version(io).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock(response: &[u8]) -> tokio::io::Join<std::io::Cursor<Vec<u8>>, tokio::io::Sink> {
+        tokio::io::join(std::io::Cursor::new(response.to_vec()), tokio::io::sink())
+    }
+
+    #[tokio::test]
+    async fn text_only_commands() {
+        let protocol = Meta::new().text_only(true);
+        let mut io = mock(b"VALUE foo 5 3 42\r\nbar\r\nEND\r\n");
+        let value = protocol.get(&mut io, "foo").await.unwrap().unwrap();
+        assert_eq!(value.data, b"bar");
+        assert_eq!(value.flags, 5);
+        assert_eq!(value.cas, Some(42));
+
+        let mut io = mock(b"EXISTS\r\n");
+        let data = RawValue::from_vec(b"bar".to_vec()).set_cas(Some(41));
+        let result = protocol.cas(&mut io, "foo", &data).await.unwrap();
+        assert_eq!(result, StoreResult::Exists);
+
+        let mut io = mock(b"7\r\n");
+        let result = protocol
+            .arithmetic(&mut io, "n", &ArithmeticOptions::new().return_value(true))
+            .await
+            .unwrap();
+        assert_eq!(result, ArithmeticResult::Updated(Some(7)));
+
+        let mut io = mock(b"");
+        let opts = GetOptions::new().return_ttl(true);
+        assert!(protocol.get_with(&mut io, "foo", &opts).await.is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Read/write timeouts and deadlines for the connection used by a single command
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// Connection wrapper failing reads and writes that make no progress for too long
+pub(crate) struct TimedIo<'a, T> {
+    io: &'a mut T,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    read_deadline: Option<Pin<Box<Sleep>>>,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<'a, T> TimedIo<'a, T> {
+    pub(crate) fn new(
+        io: &'a mut T,
+        read_timeout: Option<Duration>,
+        write_timeout: Option<Duration>,
+    ) -> Self {
+        TimedIo {
+            io,
+            read_timeout,
+            write_timeout,
+            read_deadline: None,
+            write_deadline: None,
+            deadline: None,
+        }
+    }
+
+    /// Fails all reads and writes still pending at `deadline`, ignoring the other timeouts
+    pub(crate) fn with_deadline(io: &'a mut T, deadline: tokio::time::Instant) -> Self {
+        let mut retval = Self::new(io, None, None);
+        retval.deadline = Some(Box::pin(tokio::time::sleep_until(deadline)));
+        retval
+    }
+}
+
+/// Polls the per-call deadline, if any
+fn passed(deadline: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> bool {
+    match deadline.as_mut() {
+        Some(x) => x.as_mut().poll(cx).is_ready(),
+        None => false,
+    }
+}
+
+/// Polls the deadline of a pending operation, starting it on first use
+fn expired(
+    timeout: Option<Duration>,
+    deadline: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+) -> bool {
+    let Some(timeout) = timeout else {
+        return false;
+    };
+    deadline
+        .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)))
+        .as_mut()
+        .poll(cx)
+        .is_ready()
+}
+
+fn timed_out(what: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, format!("{} timed out", what))
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for TimedIo<'_, T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut *this.io).poll_read(cx, buf) {
+            Poll::Pending => {
+                if expired(this.read_timeout, &mut this.read_deadline, cx)
+                    || passed(&mut this.deadline, cx)
+                {
+                    return Poll::Ready(Err(timed_out("read")));
+                }
+                Poll::Pending
+            }
+            x => {
+                this.read_deadline = None;
+                x
+            }
+        }
+    }
+}
+
+impl<T: AsyncBufRead + Unpin> AsyncBufRead for TimedIo<'_, T> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match Pin::new(&mut *this.io).poll_fill_buf(cx) {
+            Poll::Pending => {
+                if expired(this.read_timeout, &mut this.read_deadline, cx)
+                    || passed(&mut this.deadline, cx)
+                {
+                    return Poll::Ready(Err(timed_out("read")));
+                }
+                Poll::Pending
+            }
+            x => {
+                this.read_deadline = None;
+                x
+            }
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut *self.get_mut().io).consume(amt)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for TimedIo<'_, T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut *this.io).poll_write(cx, buf) {
+            Poll::Pending => {
+                if expired(this.write_timeout, &mut this.write_deadline, cx)
+                    || passed(&mut this.deadline, cx)
+                {
+                    return Poll::Ready(Err(timed_out("write")));
+                }
+                Poll::Pending
+            }
+            x => {
+                this.write_deadline = None;
+                x
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut *this.io).poll_flush(cx) {
+            Poll::Pending => {
+                if expired(this.write_timeout, &mut this.write_deadline, cx)
+                    || passed(&mut this.deadline, cx)
+                {
+                    return Poll::Ready(Err(timed_out("write")));
+                }
+                Poll::Pending
+            }
+            x => {
+                this.write_deadline = None;
+                x
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Client, ClientOptions, ErrorKind};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_read() {
+        let (mut client, _server) = tokio::io::duplex(64);
+        let mut io = TimedIo::new(&mut client, Some(Duration::from_secs(1)), None);
+        let mut buf = [0; 8];
+        let err = io.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_write() {
+        let (mut client, _server) = tokio::io::duplex(64);
+        let mut io = TimedIo::new(&mut client, None, Some(Duration::from_secs(1)));
+        let err = io.write_all(&[0; 1024]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline() {
+        let (mut client, _server) = tokio::io::duplex(64);
+        let at = tokio::time::Instant::now() + Duration::from_secs(1);
+        let mut io = TimedIo::with_deadline(&mut client, at);
+        let mut buf = [0; 8];
+        let err = io.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(tokio::time::Instant::now(), at);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_mark_the_connection() {
+        let options = ClientOptions::new()
+            .read_timeout(Some(Duration::from_secs(1)))
+            .write_timeout(Some(Duration::from_secs(1)));
+
+        let (client, _server) = tokio::io::duplex(64);
+        let mut c = Client::with_options(tokio::io::BufStream::new(client), &options).unwrap();
+        let err = c.get::<String>("key").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert!(c.broken);
+
+        let (client, _server) = tokio::io::duplex(64);
+        let mut c = Client::with_options(tokio::io::BufStream::new(client), &options).unwrap();
+        let err = c.set("key", &[0u8; 1 << 16][..]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert!(c.broken);
+    }
+}
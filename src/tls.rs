@@ -0,0 +1,92 @@
+//! TLS transports based on rustls (`tls` feature) or the platform TLS library (`native-tls`
+//! feature)
+
+use tokio::io::BufStream;
+use tokio::net::TcpStream;
+
+use crate::error::MemcacheError;
+use crate::Client;
+
+use log::{debug, error};
+
+/// Extracts the host part of a "host:port" address, used for SNI and certificate validation
+fn host_of(addr: &str) -> &str {
+    let host = match addr.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Performs the rustls handshake over an established connection to `addr`
+#[cfg(feature = "tls")]
+pub(crate) async fn rustls_handshake(
+    addr: &str,
+    stream: TcpStream,
+    config: std::sync::Arc<tokio_rustls::rustls::ClientConfig>,
+) -> Result<tokio_rustls::client::TlsStream<TcpStream>, MemcacheError> {
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    let Ok(server_name) = ServerName::try_from(host_of(addr).to_string()) else {
+        error!("connect_tls: invalid server name in {}", addr);
+        return Err(MemcacheError::IOError(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid server name",
+        )));
+    };
+    tokio_rustls::TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await
+        .map_err(MemcacheError::IOError)
+}
+
+#[cfg(feature = "tls")]
+impl Client<BufStream<tokio_rustls::client::TlsStream<TcpStream>>> {
+    /// Connect to a memcached server over TLS. `addr` is a "host:port" string, the host part
+    /// is used to validate the server certificate.
+    pub async fn connect_tls(
+        addr: &str,
+        config: std::sync::Arc<tokio_rustls::rustls::ClientConfig>,
+    ) -> Result<Self, MemcacheError> {
+        debug!("connect_tls {}", addr);
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(MemcacheError::IOError)?;
+        let stream = rustls_handshake(addr, stream, config).await?;
+        Ok(Client::new(BufStream::new(stream)))
+    }
+}
+
+/// Performs the native TLS handshake over an established connection to `addr`
+#[cfg(feature = "native-tls")]
+pub(crate) async fn native_tls_handshake(
+    addr: &str,
+    stream: TcpStream,
+    connector: tokio_native_tls::native_tls::TlsConnector,
+) -> Result<tokio_native_tls::TlsStream<TcpStream>, MemcacheError> {
+    tokio_native_tls::TlsConnector::from(connector)
+        .connect(host_of(addr), stream)
+        .await
+        .map_err(|e| {
+            error!("connect_native_tls: {}", e);
+            MemcacheError::IOError(std::io::Error::other(e))
+        })
+}
+
+#[cfg(feature = "native-tls")]
+impl Client<BufStream<tokio_native_tls::TlsStream<TcpStream>>> {
+    /// Connect to a memcached server over TLS using the platform TLS library and trust store.
+    /// `addr` is a "host:port" string, the host part is used to validate the server
+    /// certificate.
+    pub async fn connect_native_tls(
+        addr: &str,
+        connector: tokio_native_tls::native_tls::TlsConnector,
+    ) -> Result<Self, MemcacheError> {
+        debug!("connect_native_tls {}", addr);
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(MemcacheError::IOError)?;
+        let stream = native_tls_handshake(addr, stream, connector).await?;
+        Ok(Client::new(BufStream::new(stream)))
+    }
+}
@@ -0,0 +1,225 @@
+//! Values serialized with serde
+//!
+//! [`Client::get_as`] and [`Client::set_as`] store any serializable type. The format is recorded
+//! in bits 8 to 15 of the flags (see [`FORMAT_MASK`]), so values are always decoded with the
+//! format they were written with and several formats can share a cache. Values written by
+//! other clients without a format are read as JSON. [`Format`] is also a
+//! [`Transcoder`], to combine it with other encodings.
+//!
+//! [`encode`] and [`decode`] build and read the [`RawValue`] directly, e.g. to set a TTL:
+//! ```no_run
+//! # async fn example(client: &mut yamemcache::Client<yamemcache::BoxedConnection>)
+//! # -> Result<(), yamemcache::error::MemcacheError> {
+//! use yamemcache::typed::{encode, Format};
+//!
+//! let value = encode(&vec![1, 2, 3], Format::Json)?.set_time(Some(60));
+//! client.set("list", &value).await?;
+//! let list: Option<Vec<u32>> = client.get_as("list").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::MemcacheError;
+use crate::protocol::RawValue;
+use crate::value::Transcoder;
+use crate::{AsyncReadWriteUnpin, Client};
+
+use log::error;
+
+/// Bits of the flags holding the [`Format`] of a value
+pub const FORMAT_MASK: u32 = 0xff00;
+const FORMAT_SHIFT: u32 = 8;
+
+/// Serialization format of a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// JSON, readable by about any other client
+    #[default]
+    Json,
+    /// Compact binary encoding, only readable by Rust clients
+    #[cfg(feature = "bincode")]
+    Bincode,
+    /// MessagePack, structs are encoded as maps with their field names
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl Format {
+    /// Identifier of the format stored in the flags
+    fn id(&self) -> u32 {
+        match self {
+            Format::Json => 1,
+            #[cfg(feature = "bincode")]
+            Format::Bincode => 2,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => 3,
+        }
+    }
+
+    /// Format recorded in `flags`, JSON if there is none
+    pub fn from_flags(flags: u32) -> Option<Self> {
+        match (flags & FORMAT_MASK) >> FORMAT_SHIFT {
+            0 | 1 => Some(Format::Json),
+            #[cfg(feature = "bincode")]
+            2 => Some(Format::Bincode),
+            #[cfg(feature = "msgpack")]
+            3 => Some(Format::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// `flags` with the format recorded in them
+    pub fn to_flags(&self, flags: u32) -> u32 {
+        (flags & !FORMAT_MASK) | (self.id() << FORMAT_SHIFT)
+    }
+}
+
+/// Serializes `value` into a [`RawValue`] with the format recorded in the flags
+pub fn encode<V: Serialize + ?Sized>(value: &V, format: Format) -> Result<RawValue, MemcacheError> {
+    let data = match format {
+        Format::Json => serde_json::to_vec(value).map_err(|e| encode_error(format, e))?,
+        #[cfg(feature = "bincode")]
+        Format::Bincode => bincode::serde::encode_to_vec(value, bincode::config::standard())
+            .map_err(|e| encode_error(format, e))?,
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack => {
+            rmp_serde::to_vec_named(value).map_err(|e| encode_error(format, e))?
+        }
+    };
+    Ok(RawValue::from_vec(data).set_flags(format.to_flags(0)))
+}
+
+/// Deserializes a [`RawValue`] with the format recorded in its flags
+pub fn decode<V: DeserializeOwned>(value: &RawValue) -> Result<V, MemcacheError> {
+    decode_slice(&value.data, value.flags)
+}
+
+fn decode_slice<V: DeserializeOwned>(data: &[u8], flags: u32) -> Result<V, MemcacheError> {
+    let Some(format) = Format::from_flags(flags) else {
+        error!("decode: unknown format in flags {:#x}", flags);
+        return Err(MemcacheError::IOError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unknown format",
+        )));
+    };
+    match format {
+        Format::Json => serde_json::from_slice(data).map_err(|e| decode_error(format, e)),
+        #[cfg(feature = "bincode")]
+        Format::Bincode => bincode::serde::decode_from_slice(data, bincode::config::standard())
+            .map(|(x, _)| x)
+            .map_err(|e| decode_error(format, e)),
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack => rmp_serde::from_slice(data).map_err(|e| decode_error(format, e)),
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Transcoder<V> for Format {
+    fn encode(&self, value: &V) -> Result<(Vec<u8>, u32), MemcacheError> {
+        encode(value, *self).map(|x| (x.data, x.flags))
+    }
+
+    /// Decodes with the format recorded in `flags`, which may not be this one
+    fn decode(&self, data: &[u8], flags: u32) -> Result<V, MemcacheError> {
+        decode_slice(data, flags)
+    }
+}
+
+fn encode_error<E: std::error::Error + Send + Sync + 'static>(
+    format: Format,
+    e: E,
+) -> MemcacheError {
+    error!("encode: {:?} serialization failed: {}", format, e);
+    MemcacheError::IOError(std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+fn decode_error<E: std::error::Error + Send + Sync + 'static>(
+    format: Format,
+    e: E,
+) -> MemcacheError {
+    error!("decode: {:?} deserialization failed: {}", format, e);
+    MemcacheError::IOError(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+impl<T: AsyncReadWriteUnpin> Client<T> {
+    /// GET a value and deserialize it, see the [module documentation](crate::typed)
+    pub async fn get_as<V: DeserializeOwned>(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<V>, MemcacheError> {
+        match self.get(key).await? {
+            Some(x) => decode(&x).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Serialize a value as JSON and STORE it
+    pub async fn set_as<V: Serialize + ?Sized>(
+        &mut self,
+        key: &str,
+        value: &V,
+    ) -> Result<(), MemcacheError> {
+        self.set_as_with(key, value, Format::default()).await
+    }
+
+    /// Serialize a value with the given format and STORE it
+    pub async fn set_as_with<V: Serialize + ?Sized>(
+        &mut self,
+        key: &str,
+        value: &V,
+        format: Format,
+    ) -> Result<(), MemcacheError> {
+        let value = encode(value, format)?;
+        self.set(key, &value).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = vec![("a".to_string(), 1u32), ("b".to_string(), 2u32)];
+        let raw = encode(&value, Format::Json).unwrap();
+        assert_eq!(raw.data, br#"[["a",1],["b",2]]"#);
+        assert_eq!(raw.flags & FORMAT_MASK, 0x100);
+        assert_eq!(decode::<Vec<(String, u32)>>(&raw).unwrap(), value);
+
+        // other bits of the flags are kept
+        assert_eq!(Format::Json.to_flags(0xff_0003), 0xff_0103);
+        let raw = RawValue::from_vec(b"1".to_vec()).set_flags(0x7f00);
+        assert!(decode::<u32>(&raw).is_err());
+
+        // as a transcoder
+        let (data, flags) = Transcoder::encode(&Format::Json, &value).unwrap();
+        assert_eq!(
+            (data.as_slice(), flags),
+            (br#"[["a",1],["b",2]]"#.as_slice(), 0x100)
+        );
+        let decoded: Vec<(String, u32)> = Format::Json.decode(&data, flags).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trip() {
+        let value = vec![("a".to_string(), 1u32), ("b".to_string(), 2u32)];
+        let raw = encode(&value, Format::Bincode).unwrap();
+        assert_eq!(raw.data, [2, 1, b'a', 1, 1, b'b', 2]);
+        assert_eq!(Format::from_flags(raw.flags), Some(Format::Bincode));
+        assert_eq!(decode::<Vec<(String, u32)>>(&raw).unwrap(), value);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trip() {
+        let value = vec![("a".to_string(), 1u32)];
+        let raw = encode(&value, Format::MessagePack).unwrap();
+        assert_eq!(raw.data, [0x91, 0x92, 0xa1, b'a', 1]);
+        assert_eq!(Format::from_flags(raw.flags), Some(Format::MessagePack));
+        assert_eq!(decode::<Vec<(String, u32)>>(&raw).unwrap(), value);
+    }
+}
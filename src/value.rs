@@ -0,0 +1,306 @@
+//! Conversions between Rust types and stored values
+//!
+//! [`Client::get`](crate::Client::get) and [`Client::set`](crate::Client::set) accept any type
+//! implementing [`FromMemcacheValue`] and [`ToMemcacheValue`]. Strings and byte buffers are
+//! stored as is, numbers as decimal text so that counters can still be updated with
+//! [`Client::arithmetic`](crate::Client::arithmetic). The type of a value is recorded in the
+//! flags with the same bits as python-memcached and pymemcache.
+//!
+//! A [`Transcoder`] takes over the encoding of any other type, e.g. to combine a serialization
+//! with encryption. Use it with [`Client::get_transcoded`] and [`Client::set_transcoded`].
+//! ```no_run
+//! # async fn example(client: &mut yamemcache::Client<yamemcache::BoxedConnection>)
+//! # -> Result<(), yamemcache::error::MemcacheError> {
+//! client.set("visits", 42u64).await?;
+//! let visits = client.get::<u64>("visits").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::borrow::Cow;
+
+use bytes::Bytes;
+
+use crate::error::MemcacheError;
+use crate::protocol::{BytesValue, RawValue};
+use crate::{AsyncReadWriteUnpin, Client};
+
+use log::error;
+
+/// Flags of a byte buffer
+pub const FLAG_BYTES: u32 = 0;
+/// Flags of an integer
+pub const FLAG_INTEGER: u32 = 2;
+/// Flags of an UTF-8 string
+pub const FLAG_TEXT: u32 = 16;
+/// Flags of a floating point number
+pub const FLAG_FLOAT: u32 = 32;
+
+/// Type that can be stored in memcached
+pub trait ToMemcacheValue {
+    /// Value as stored in memcached, with its type recorded in the flags. The data is
+    /// borrowed when it is already stored as is.
+    fn to_memcache_value(&self) -> RawValue<Cow<'_, [u8]>>;
+}
+
+/// Type that can be read from memcached
+pub trait FromMemcacheValue: Sized {
+    /// Converts a value read from memcached
+    fn from_memcache_value(value: RawValue) -> Result<Self, MemcacheError>;
+}
+
+/// Encoding of the values of type `V`, recording whatever it needs in the flags
+pub trait Transcoder<V: ?Sized> {
+    /// Encodes a value, returns the data and flags to store
+    fn encode(&self, value: &V) -> Result<(Vec<u8>, u32), MemcacheError>;
+    /// Decodes the data and flags of a stored value
+    fn decode(&self, data: &[u8], flags: u32) -> Result<V, MemcacheError>
+    where
+        V: Sized;
+}
+
+impl<V: ?Sized, C: Transcoder<V>> Transcoder<V> for &C {
+    fn encode(&self, value: &V) -> Result<(Vec<u8>, u32), MemcacheError> {
+        (**self).encode(value)
+    }
+
+    fn decode(&self, data: &[u8], flags: u32) -> Result<V, MemcacheError>
+    where
+        V: Sized,
+    {
+        (**self).decode(data, flags)
+    }
+}
+
+/// [`Transcoder`] of the types stored natively, encoded as by [`Client::set`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Native;
+
+impl<V: ToMemcacheValue + FromMemcacheValue> Transcoder<V> for Native {
+    fn encode(&self, value: &V) -> Result<(Vec<u8>, u32), MemcacheError> {
+        let value = value.to_memcache_value();
+        Ok((value.data.into_owned(), value.flags))
+    }
+
+    fn decode(&self, data: &[u8], flags: u32) -> Result<V, MemcacheError> {
+        V::from_memcache_value(RawValue::from_vec(data.to_vec()).set_flags(flags))
+    }
+}
+
+fn invalid_value(msg: &str) -> MemcacheError {
+    error!("value: {}", msg);
+    MemcacheError::IOError(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+impl<V: ToMemcacheValue + ?Sized> ToMemcacheValue for &V {
+    fn to_memcache_value(&self) -> RawValue<Cow<'_, [u8]>> {
+        (**self).to_memcache_value()
+    }
+}
+
+/// Value with its data stored in `data`
+fn value(data: Cow<'_, [u8]>, flags: u32) -> RawValue<Cow<'_, [u8]>> {
+    RawValue {
+        data,
+        flags,
+        time: None,
+        cas: None,
+    }
+}
+
+impl<D: AsRef<[u8]>> ToMemcacheValue for RawValue<D> {
+    fn to_memcache_value(&self) -> RawValue<Cow<'_, [u8]>> {
+        RawValue {
+            data: Cow::Borrowed(self.data.as_ref()),
+            flags: self.flags,
+            time: self.time,
+            cas: self.cas,
+        }
+    }
+}
+
+impl FromMemcacheValue for RawValue {
+    fn from_memcache_value(value: RawValue) -> Result<Self, MemcacheError> {
+        Ok(value)
+    }
+}
+
+impl FromMemcacheValue for BytesValue {
+    fn from_memcache_value(value: RawValue) -> Result<Self, MemcacheError> {
+        Ok(value.into())
+    }
+}
+
+impl ToMemcacheValue for [u8] {
+    fn to_memcache_value(&self) -> RawValue<Cow<'_, [u8]>> {
+        value(Cow::Borrowed(self), FLAG_BYTES)
+    }
+}
+
+impl ToMemcacheValue for Vec<u8> {
+    fn to_memcache_value(&self) -> RawValue<Cow<'_, [u8]>> {
+        self.as_slice().to_memcache_value()
+    }
+}
+
+impl FromMemcacheValue for Vec<u8> {
+    fn from_memcache_value(value: RawValue) -> Result<Self, MemcacheError> {
+        Ok(value.data)
+    }
+}
+
+impl ToMemcacheValue for Bytes {
+    fn to_memcache_value(&self) -> RawValue<Cow<'_, [u8]>> {
+        value(Cow::Borrowed(self), FLAG_BYTES)
+    }
+}
+
+impl FromMemcacheValue for Bytes {
+    fn from_memcache_value(value: RawValue) -> Result<Self, MemcacheError> {
+        Ok(value.data.into())
+    }
+}
+
+impl ToMemcacheValue for str {
+    fn to_memcache_value(&self) -> RawValue<Cow<'_, [u8]>> {
+        value(Cow::Borrowed(self.as_bytes()), FLAG_TEXT)
+    }
+}
+
+impl ToMemcacheValue for String {
+    fn to_memcache_value(&self) -> RawValue<Cow<'_, [u8]>> {
+        self.as_str().to_memcache_value()
+    }
+}
+
+impl FromMemcacheValue for String {
+    fn from_memcache_value(value: RawValue) -> Result<Self, MemcacheError> {
+        value
+            .into_string()
+            .map_err(|_| invalid_value("not an UTF-8 string"))
+    }
+}
+
+macro_rules! number_value {
+    ($flags:expr, $($ty:ty),*) => {$(
+        impl ToMemcacheValue for $ty {
+            fn to_memcache_value(&self) -> RawValue<Cow<'_, [u8]>> {
+                value(Cow::Owned(self.to_string().into_bytes()), $flags)
+            }
+        }
+
+        impl FromMemcacheValue for $ty {
+            fn from_memcache_value(value: RawValue) -> Result<Self, MemcacheError> {
+                // incr and decr may leave trailing spaces after shrinking a counter
+                std::str::from_utf8(&value.data)
+                    .ok()
+                    .and_then(|x| x.trim_end().parse().ok())
+                    .ok_or_else(|| invalid_value(concat!("not a valid ", stringify!($ty))))
+            }
+        }
+    )*};
+}
+
+number_value!(
+    FLAG_INTEGER,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize
+);
+number_value!(FLAG_FLOAT, f32, f64);
+
+impl<T: AsyncReadWriteUnpin> Client<T> {
+    /// GET a value and decode it with `transcoder`
+    pub async fn get_transcoded<V, C: Transcoder<V>>(
+        &mut self,
+        key: &str,
+        transcoder: C,
+    ) -> Result<Option<V>, MemcacheError> {
+        match self.get::<RawValue>(key).await? {
+            Some(x) => transcoder.decode(&x.data, x.flags).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Encode a value with `transcoder` and STORE it
+    pub async fn set_transcoded<V: ?Sized, C: Transcoder<V>>(
+        &mut self,
+        key: &str,
+        value: &V,
+        transcoder: C,
+    ) -> Result<(), MemcacheError> {
+        let (data, flags) = transcoder.encode(value)?;
+        self.set(key, RawValue::from_vec(data).set_flags(flags))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<V: ToMemcacheValue + FromMemcacheValue>(value: V) -> (V, u32) {
+        let raw = value.to_memcache_value();
+        let flags = raw.flags;
+        let raw = RawValue::from_vec(raw.data.into_owned()).set_flags(flags);
+        (V::from_memcache_value(raw).unwrap(), flags)
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(round_trip(42u64), (42, FLAG_INTEGER));
+        assert_eq!(round_trip(-7i32), (-7, FLAG_INTEGER));
+        assert_eq!(round_trip(0.1f64), (0.1, FLAG_FLOAT));
+        assert_eq!(round_trip("hi".to_string()), ("hi".to_string(), FLAG_TEXT));
+        assert_eq!(round_trip(vec![0u8, 255]), (vec![0, 255], FLAG_BYTES));
+        let bytes = Bytes::from_static(b"abc");
+        assert_eq!(round_trip(bytes.clone()), (bytes, FLAG_BYTES));
+        assert_eq!(*"hi".to_memcache_value().data, *b"hi");
+        assert_eq!(*42u64.to_memcache_value().data, *b"42");
+
+        // counters written by incr, or by other clients without flags
+        let raw = RawValue::from_vec(b"10 ".to_vec());
+        assert_eq!(u64::from_memcache_value(raw).unwrap(), 10);
+        let raw = RawValue::from_vec(b"-1".to_vec());
+        assert!(u64::from_memcache_value(raw).is_err());
+        let raw = RawValue::from_vec(vec![0xff]);
+        assert!(String::from_memcache_value(raw).is_err());
+    }
+
+    /// Stores strings xored with a key, the key is recorded in the flags
+    struct Xor(u8);
+
+    impl Transcoder<String> for Xor {
+        fn encode(&self, value: &String) -> Result<(Vec<u8>, u32), MemcacheError> {
+            Ok((value.bytes().map(|x| x ^ self.0).collect(), self.0 as u32))
+        }
+
+        fn decode(&self, data: &[u8], flags: u32) -> Result<String, MemcacheError> {
+            let data = data.iter().map(|x| x ^ flags as u8).collect();
+            String::from_memcache_value(RawValue::from_vec(data))
+        }
+    }
+
+    #[test]
+    fn transcoder() {
+        let (data, flags) = Xor(0x20).encode(&"abc".to_string()).unwrap();
+        assert_eq!((data.as_slice(), flags), (b"ABC".as_slice(), 0x20));
+        assert_eq!(Xor(0).decode(&data, flags).unwrap(), "abc");
+
+        let (data, flags) = Native.encode(&42u32).unwrap();
+        assert_eq!((data.as_slice(), flags), (b"42".as_slice(), FLAG_INTEGER));
+        assert_eq!(
+            Transcoder::<u32>::decode(&Native, &data, flags).unwrap(),
+            42
+        );
+    }
+}
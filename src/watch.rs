@@ -0,0 +1,118 @@
+//! Parsing of the log lines streamed by the `watch` command
+//!
+//! reference: [`protocol.txt`](https://github.com/memcached/memcached/blob/master/doc/protocol.txt)
+
+/// Log stream to subscribe to with `watch`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchTarget {
+    /// Every item fetch
+    Fetchers,
+    /// Every item evicted from the cache
+    Evictions,
+    /// Every item stored in the cache
+    Mutations,
+}
+
+impl WatchTarget {
+    /// Target name as used on the wire
+    pub(crate) fn token(&self) -> &'static str {
+        match self {
+            WatchTarget::Fetchers => "fetchers",
+            WatchTarget::Evictions => "evictions",
+            WatchTarget::Mutations => "mutations",
+        }
+    }
+}
+
+/// A single entry of the watcher log
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WatchEvent {
+    /// Server time of the event in seconds (`ts`)
+    pub timestamp: Option<f64>,
+    /// Global id of the entry, gaps mean entries were lost (`gid`)
+    pub gid: Option<u64>,
+    /// Type of the event, e.g. `item_get`, `item_store` or `eviction`. Set to `skipped` when
+    /// the server dropped entries because the client was too slow to read them.
+    pub kind: String,
+    /// Key of the item, URI encoded as logged by the server
+    pub key: Option<String>,
+    /// All the `name=value` pairs of the entry, including the ones above
+    pub fields: Vec<(String, String)>,
+}
+
+impl WatchEvent {
+    /// Returns the raw value of the named field
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parses a single line of the watcher log, `None` if the line carries no event
+pub(crate) fn parse_line(line: &str) -> Option<WatchEvent> {
+    let line = line.trim_end();
+    if line.is_empty() {
+        return None;
+    }
+
+    // "[skipped: <count>]" is sent when the log buffer overflowed
+    if let Some(count) = line
+        .strip_prefix("[skipped:")
+        .and_then(|x| x.strip_suffix(']'))
+    {
+        return Some(WatchEvent {
+            kind: "skipped".to_string(),
+            fields: vec![("count".to_string(), count.trim().to_string())],
+            ..Default::default()
+        });
+    }
+
+    let mut retval = WatchEvent::default();
+    for token in line.split_ascii_whitespace() {
+        let Some((name, value)) = token.split_once('=') else {
+            continue;
+        };
+        match name {
+            "ts" => retval.timestamp = value.parse().ok(),
+            "gid" => retval.gid = value.parse().ok(),
+            "type" => retval.kind = value.to_string(),
+            "key" => retval.key = Some(value.to_string()),
+            _ => {}
+        }
+        retval.fields.push((name.to_string(), value.to_string()));
+    }
+    if retval.fields.is_empty() {
+        return None;
+    }
+    Some(retval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_watch_lines() {
+        let Some(x) = parse_line(
+            "ts=1700000000.123456 gid=42 type=item_get key=foo%20bar status=found clsid=1 cfd=20\r\n",
+        ) else {
+            panic!("parse_line() failed");
+        };
+        assert_eq!(x.timestamp, Some(1700000000.123456));
+        assert_eq!(x.gid, Some(42));
+        assert_eq!(x.kind, "item_get");
+        assert_eq!(x.key.as_deref(), Some("foo%20bar"));
+        assert_eq!(x.field("status"), Some("found"));
+        assert_eq!(x.field("missing"), None);
+
+        let Some(x) = parse_line("[skipped: 17]\r\n") else {
+            panic!("parse_line() failed on skipped");
+        };
+        assert_eq!(x.kind, "skipped");
+        assert_eq!(x.field("count"), Some("17"));
+
+        assert!(parse_line("\r\n").is_none());
+    }
+}